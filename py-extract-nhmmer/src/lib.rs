@@ -0,0 +1,505 @@
+//! Python bindings to `extract_nhmmer_tblout`, built into the
+//! `extract_nhmmer` module with maturin.
+//!
+//! ```python
+//! import extract_nhmmer
+//!
+//! hits = extract_nhmmer.read_tblout("hits.tbl")
+//! with extract_nhmmer.Extractor("hits.tbl", "genome.fa", e_value_threshold=1e-10) as records:
+//!     for record in records:
+//!         print(record.id, len(record))
+//! ```
+
+use std::{collections::VecDeque, path::PathBuf};
+
+use extract_nhmmer_tblout::{
+    expr::Expr,
+    filter::{Rank, Round},
+    tblout::{Format, Strand},
+    translate::GeneticCode,
+    Backend, Coords, ExtractorBuilder, HitFilter, Mask, SortBy, Tblout,
+};
+use pyo3::{exceptions::PyValueError, prelude::*};
+
+/// Parse an option given as a string, e.g. `coords="env"`.
+fn parse<T: std::str::FromStr<Err = anyhow::Error>>(s: &str) -> PyResult<T> {
+    s.parse()
+        .map_err(|e: anyhow::Error| PyValueError::new_err(e.to_string()))
+}
+
+/// One path, or a list of them.
+fn paths(paths: &Bound<'_, PyAny>) -> PyResult<Vec<PathBuf>> {
+    match paths.extract::<PathBuf>() {
+        Ok(path) => Ok(vec![path]),
+        Err(_) => paths.extract(),
+    }
+}
+
+/// The tblout layout to read, if not the one in its metadata.
+fn format(program: Option<&str>, domtblout: bool) -> PyResult<Option<Format>> {
+    if domtblout {
+        return Ok(Some(Format::Domtblout));
+    }
+    program.map(parse).transpose()
+}
+
+/// A hit in a tblout.
+#[pyclass(frozen, skip_from_py_object, module = "extract_nhmmer")]
+#[derive(Clone)]
+struct Hit(extract_nhmmer_tblout::Hit);
+
+#[pymethods]
+impl Hit {
+    /// The name of the target sequence.
+    #[getter]
+    fn target_name(&self) -> &str {
+        &self.0.target_name
+    }
+
+    /// The accession of the target sequence, or `-`.
+    #[getter]
+    fn target_accession(&self) -> &str {
+        &self.0.target_accession
+    }
+
+    /// The name of the query profile.
+    #[getter]
+    fn query_name(&self) -> &str {
+        &self.0.query_name
+    }
+
+    /// The accession of the query profile, or `-`.
+    #[getter]
+    fn query_accession(&self) -> &str {
+        &self.0.query_accession
+    }
+
+    /// Where the hit starts and ends in the profile.
+    #[getter]
+    fn hmm(&self) -> Option<(u64, u64)> {
+        self.0.hmm_from.zip(self.0.hmm_to)
+    }
+
+    /// Where the alignment starts and ends in the target (start > end on
+    /// the minus strand).
+    #[getter]
+    fn ali(&self) -> Option<(u64, u64)> {
+        self.0.ali()
+    }
+
+    /// Where the envelope starts and ends in the target.
+    #[getter]
+    fn env(&self) -> Option<(u64, u64)> {
+        self.0.env()
+    }
+
+    /// The length of the target sequence.
+    #[getter]
+    fn sq_len(&self) -> Option<u64> {
+        self.0.sq_len
+    }
+
+    /// The strand of the hit, `+` or `-`.
+    #[getter]
+    fn strand(&self) -> &'static str {
+        match self.0.strand {
+            Strand::Positive => "+",
+            Strand::Negative => "-",
+        }
+    }
+
+    /// The E-value of the hit.
+    #[getter]
+    fn e_value(&self) -> f64 {
+        self.0.e_value
+    }
+
+    /// The bit score of the hit.
+    #[getter]
+    fn score(&self) -> f64 {
+        self.0.score
+    }
+
+    /// The biased-composition correction.
+    #[getter]
+    fn bias(&self) -> f64 {
+        self.0.bias
+    }
+
+    /// The description of the target, if any.
+    #[getter]
+    fn description(&self) -> Option<&str> {
+        self.0.description.as_deref()
+    }
+
+    /// The round of a jackhmmer search the hit was found in, from 1.
+    #[getter]
+    fn round(&self) -> Option<u64> {
+        self.0.round
+    }
+
+    /// The line of the tblout the hit is on.
+    #[getter]
+    fn line(&self) -> &str {
+        &self.0.line
+    }
+
+    fn __repr__(&self) -> String {
+        let ali = match self.0.ali() {
+            Some((from, to)) => format!("({}, {})", from, to),
+            None => "None".to_string(),
+        };
+        format!(
+            "Hit(query_name='{}', target_name='{}', ali={}, strand='{}', e_value={:e}, score={})",
+            self.0.query_name,
+            self.0.target_name,
+            ali,
+            self.strand(),
+            self.0.e_value,
+            self.0.score
+        )
+    }
+}
+
+/// An extracted sequence, laid out like a Biopython `SeqRecord` read from
+/// a fasta: `id` is the first word of the header and `description` the
+/// whole of it.
+#[pyclass(frozen, get_all, module = "extract_nhmmer")]
+struct Record {
+    id: String,
+    description: String,
+    seq: String,
+}
+
+#[pymethods]
+impl Record {
+    /// The same as `id`.
+    #[getter]
+    fn name(&self) -> &str {
+        &self.id
+    }
+
+    /// The record as a fasta entry, on a single line.
+    fn format(&self) -> String {
+        format!(">{}\n{}\n", self.description, self.seq)
+    }
+
+    fn __len__(&self) -> usize {
+        self.seq.len()
+    }
+
+    fn __str__(&self) -> String {
+        self.format()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Record(id='{}', len={})", self.id, self.seq.len())
+    }
+}
+
+impl From<noodles_fasta::Record> for Record {
+    fn from(record: noodles_fasta::Record) -> Self {
+        let id = String::from_utf8_lossy(record.name()).into_owned();
+        let description = match record.description() {
+            Some(d) => format!("{} {}", id, String::from_utf8_lossy(d)),
+            None => id.clone(),
+        };
+        Self {
+            id,
+            description,
+            seq: String::from_utf8_lossy(record.sequence().as_ref()).into_owned(),
+        }
+    }
+}
+
+/// Read the hits in one or more tblouts.
+#[pyfunction]
+#[pyo3(signature = (tbl, *, program = None, domtblout = false, lenient = false))]
+fn read_tblout(
+    tbl: &Bound<'_, PyAny>,
+    program: Option<&str>,
+    domtblout: bool,
+    lenient: bool,
+) -> PyResult<Vec<Hit>> {
+    let tblout = Tblout::from_paths(&paths(tbl)?, format(program, domtblout)?, lenient)?;
+    Ok(tblout.into_hits().into_iter().map(Hit).collect())
+}
+
+/// Keep the hits that pass the filters, as `extract_nhmmer_tblout filter`.
+#[pyfunction]
+#[pyo3(signature = (
+    hits,
+    *,
+    e_value_threshold = 0.00001,
+    min_score = None,
+    inc_only = false,
+    targets = None,
+    queries = None,
+    top_n = None,
+    rank_by = "evalue",
+    best_per_target = false,
+    min_model_coverage = None,
+    round = "last",
+    filter_expr = None,
+))]
+#[allow(clippy::too_many_arguments)]
+fn filter_hits(
+    hits: Vec<PyRef<'_, Hit>>,
+    e_value_threshold: f64,
+    min_score: Option<f64>,
+    inc_only: bool,
+    targets: Option<Vec<String>>,
+    queries: Option<Vec<String>>,
+    top_n: Option<usize>,
+    rank_by: &str,
+    best_per_target: bool,
+    min_model_coverage: Option<f64>,
+    round: &str,
+    filter_expr: Option<&str>,
+) -> PyResult<Vec<Hit>> {
+    let filter = HitFilter {
+        e_value_threshold,
+        min_score,
+        inc_only,
+        targets: targets.map(|t| t.into_iter().collect()),
+        queries: queries.map(|q| q.into_iter().collect()),
+        top_n,
+        rank_by: parse::<Rank>(rank_by)?,
+        best_per_target,
+        min_model_coverage,
+        round: parse::<Round>(round)?,
+        expr: filter_expr.map(parse::<Expr>).transpose()?,
+        ..Default::default()
+    };
+    let hits: Vec<_> = hits.iter().map(|hit| hit.0.clone()).collect();
+    let keep = filter.select(&hits);
+    Ok(hits
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(hit, keep)| keep.then_some(Hit(hit)))
+        .collect())
+}
+
+/// Extracts the sequences of the hits in tblouts from a fasta, as
+/// `extract_nhmmer_tblout extract`. Iterate over it for the records, and
+/// close it (or use it as a context manager) to clean up any index it made.
+#[pyclass(unsendable, module = "extract_nhmmer")]
+struct Extractor {
+    inner: Option<extract_nhmmer_tblout::Extractor>,
+    next_hit: usize,
+    buf: VecDeque<Record>,
+}
+
+impl Extractor {
+    fn inner(&mut self) -> PyResult<&mut extract_nhmmer_tblout::Extractor> {
+        self.inner
+            .as_mut()
+            .ok_or_else(|| PyValueError::new_err("The extractor is closed"))
+    }
+}
+
+#[pymethods]
+impl Extractor {
+    #[new]
+    #[pyo3(signature = (
+        tbl,
+        fasta,
+        *,
+        backend = "native",
+        backend_path = None,
+        program = None,
+        domtblout = false,
+        e_value_threshold = 0.00001,
+        min_score = None,
+        header_format = None,
+        with_coords = false,
+        esl_names = false,
+        seqkit_names = false,
+        meta_in_description = false,
+        species_id = None,
+        revcomp_minus = false,
+        coords = "ali",
+        flank = (0, 0),
+        min_length = None,
+        max_length = None,
+        merge_distance = None,
+        full_seq = false,
+        translate = None,
+        mask = "keep",
+        sort = None,
+        skip_missing = false,
+        strict_duplicates = false,
+        lenient = false,
+        clip = false,
+        threads = 1,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        tbl: &Bound<'_, PyAny>,
+        fasta: &Bound<'_, PyAny>,
+        backend: &str,
+        backend_path: Option<PathBuf>,
+        program: Option<&str>,
+        domtblout: bool,
+        e_value_threshold: f64,
+        min_score: Option<f64>,
+        header_format: Option<String>,
+        with_coords: bool,
+        esl_names: bool,
+        seqkit_names: bool,
+        meta_in_description: bool,
+        species_id: Option<String>,
+        revcomp_minus: bool,
+        coords: &str,
+        flank: (u64, u64),
+        min_length: Option<u64>,
+        max_length: Option<u64>,
+        merge_distance: Option<u64>,
+        full_seq: bool,
+        translate: Option<u8>,
+        mask: &str,
+        sort: Option<&str>,
+        skip_missing: bool,
+        strict_duplicates: bool,
+        lenient: bool,
+        clip: bool,
+        threads: usize,
+    ) -> PyResult<Self> {
+        let mut tbls = paths(tbl)?.into_iter();
+        let first = tbls
+            .next()
+            .ok_or_else(|| PyValueError::new_err("No tblout given"))?;
+        let mut builder = tbls.fold(ExtractorBuilder::new(first), |b, tbl| b.add_tbl(tbl));
+        builder = paths(fasta)?
+            .into_iter()
+            .fold(builder, |b, fasta| b.add_fasta(fasta));
+
+        let backend = match backend {
+            "native" => Backend::Native,
+            "esl-sfetch" => Backend::EslSfetch(backend_path.unwrap_or("esl-sfetch".into())),
+            "samtools" => Backend::Samtools(backend_path.unwrap_or("samtools".into())),
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown backend \"{}\"",
+                    backend
+                )))
+            }
+        };
+        builder = builder
+            .backend(backend)
+            .e_value_threshold(e_value_threshold)
+            .with_coords(with_coords)
+            .esl_names(esl_names)
+            .seqkit_names(seqkit_names)
+            .meta_in_description(meta_in_description)
+            .revcomp_minus(revcomp_minus)
+            .coords(parse::<Coords>(coords)?)
+            .flank(flank.0, flank.1)
+            .mask(parse::<Mask>(mask)?)
+            .skip_missing(skip_missing)
+            .strict_duplicates(strict_duplicates)
+            .lenient(lenient)
+            .clip(clip)
+            .full_seq(full_seq)
+            .threads(threads)
+            .progress(false);
+        if let Some(format) = format(program, domtblout)? {
+            builder = builder.format(format);
+        }
+        if let Some(min_score) = min_score {
+            builder = builder.min_score(min_score);
+        }
+        if let Some(header_format) = header_format {
+            builder = builder.header_format(header_format);
+        }
+        if let Some(species_id) = species_id {
+            builder = builder.species_id(species_id);
+        }
+        if let Some(min_length) = min_length {
+            builder = builder.min_length(min_length);
+        }
+        if let Some(max_length) = max_length {
+            builder = builder.max_length(max_length);
+        }
+        if let Some(distance) = merge_distance {
+            builder = builder.merge_distance(distance);
+        }
+        if let Some(id) = translate {
+            builder = builder.translate(GeneticCode::new(id)?);
+        }
+        if let Some(sort) = sort {
+            builder = builder.sort(parse::<SortBy>(sort)?);
+        }
+
+        Ok(Self {
+            inner: Some(builder.build()?),
+            next_hit: 0,
+            buf: VecDeque::new(),
+        })
+    }
+
+    /// The hits that passed the filters, in the order they are extracted.
+    #[getter]
+    fn hits(&mut self) -> PyResult<Vec<Hit>> {
+        Ok(self.inner()?.hits().iter().cloned().map(Hit).collect())
+    }
+
+    /// The header of the sequence extracted for each hit.
+    #[getter]
+    fn headers(&mut self) -> PyResult<Vec<String>> {
+        Ok(self.inner()?.headers().to_vec())
+    }
+
+    /// Clean up any index made for the fasta. Further records can not be
+    /// extracted.
+    fn close(&mut self) -> PyResult<()> {
+        if let Some(inner) = self.inner.take() {
+            inner.close()?;
+        }
+        Ok(())
+    }
+
+    fn __len__(&mut self) -> PyResult<usize> {
+        Ok(self.inner()?.len())
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    /// Extract the next batch of sequences once the last is used up.
+    fn __next__(&mut self) -> PyResult<Option<Record>> {
+        if self.buf.is_empty() {
+            let start = self.next_hit;
+            let inner = self.inner()?;
+            let batch = inner.batch_size();
+            let records = inner
+                .records_from(start)
+                .take(batch)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            self.next_hit += records.len();
+            self.buf.extend(records.into_iter().map(Record::from));
+        }
+        Ok(self.buf.pop_front())
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&mut self, _args: &Bound<'_, pyo3::types::PyTuple>) -> PyResult<bool> {
+        self.close()?;
+        Ok(false)
+    }
+}
+
+#[pymodule]
+fn extract_nhmmer(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Hit>()?;
+    m.add_class::<Record>()?;
+    m.add_class::<Extractor>()?;
+    m.add_function(wrap_pyfunction!(read_tblout, m)?)?;
+    m.add_function(wrap_pyfunction!(filter_hits, m)?)?;
+    Ok(())
+}
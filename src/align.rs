@@ -0,0 +1,157 @@
+//! Aligning the extracted sequences to their models with `hmmalign`.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process::Command as Cmd,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use noodles_fasta as fasta;
+use tempfile::tempdir;
+
+/// The alignment formats that can be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlignFormat {
+    /// Stockholm, which can hold several alignments one after another.
+    #[default]
+    Stockholm,
+    /// Aligned fasta.
+    Afa,
+}
+
+impl FromStr for AlignFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stockholm" => Ok(AlignFormat::Stockholm),
+            "afa" => Ok(AlignFormat::Afa),
+            _ => bail!("Unknown alignment format \"{}\"", s),
+        }
+    }
+}
+
+impl AlignFormat {
+    /// The name `hmmalign --outformat` knows the format by.
+    fn outformat(&self) -> &'static str {
+        match self {
+            AlignFormat::Stockholm => "Stockholm",
+            AlignFormat::Afa => "afa",
+        }
+    }
+
+    /// The usual extension of files in the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AlignFormat::Stockholm => "sto",
+            AlignFormat::Afa => "afa",
+        }
+    }
+}
+
+/// Aligns sequences to the models of an HMM file with `hmmalign`. Each
+/// model is written out on its own, so HMM files holding many models do
+/// not need to be split (or `hmmfetch`ed from) first.
+#[derive(Debug, Clone)]
+pub struct Hmmalign {
+    hmmalign: PathBuf,
+    format: AlignFormat,
+    /// The text of each model, from its `HMMER3` line through its `//`.
+    models: Vec<String>,
+    /// The index into `models` of each model name and accession.
+    names: HashMap<String, usize>,
+}
+
+impl Hmmalign {
+    /// Read the models in the HMM file at `hmm`, to be aligned to with the
+    /// `hmmalign` at `hmmalign`.
+    pub fn new<P: Into<PathBuf>>(hmmalign: P, hmm: &Path, format: AlignFormat) -> Result<Self> {
+        let contents = fs::read_to_string(hmm)
+            .with_context(|| format!("Could not read models from {}", hmm.display()))?;
+
+        let mut models = Vec::new();
+        let mut names = HashMap::new();
+        let mut model = String::new();
+        for line in contents.lines() {
+            model.push_str(line);
+            model.push('\n');
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("NAME" | "ACC"), Some(name)) => {
+                    names.entry(name.to_string()).or_insert(models.len());
+                }
+                (Some("//"), _) => models.push(std::mem::take(&mut model)),
+                _ => (),
+            }
+        }
+        if models.is_empty() {
+            bail!("No models found in {}", hmm.display());
+        }
+
+        Ok(Self {
+            hmmalign: hmmalign.into(),
+            format,
+            models,
+            names,
+        })
+    }
+
+    /// The format the alignments are written in.
+    pub fn format(&self) -> AlignFormat {
+        self.format
+    }
+
+    /// Align `records` to the model called `query` (by name, or failing
+    /// that by `accession`), writing the alignment to `writer`. With only
+    /// one model in the file, it is used whatever it is called.
+    pub fn align<W: Write>(
+        &self,
+        query: &str,
+        accession: &str,
+        records: &[fasta::Record],
+        mut writer: W,
+    ) -> Result<()> {
+        let model = match self.names.get(query).or_else(|| self.names.get(accession)) {
+            Some(&i) => &self.models[i],
+            None if self.models.len() == 1 => &self.models[0],
+            None => bail!("Model {} not found in the HMM file", query),
+        };
+
+        let tmpdir = tempdir().context("Could not create tempdir")?;
+        let hmm = tmpdir.path().join("model.hmm");
+        fs::write(&hmm, model).context("Could not write model")?;
+        let seqs = tmpdir.path().join("seqs.fa");
+        let mut file =
+            BufWriter::new(File::create(&seqs).context("Could not create sequence file")?);
+        let mut fasta_writer = fasta::Writer::new(&mut file);
+        for record in records {
+            fasta_writer.write_record(record)?;
+        }
+        file.flush()?;
+
+        let mut cmd = Cmd::new(&self.hmmalign);
+        cmd.arg("--outformat")
+            .arg(self.format.outformat())
+            .arg(&hmm)
+            .arg(&seqs);
+        debug!("Running {:?}", cmd);
+        let output = cmd
+            .output()
+            .with_context(|| format!("Could not run {}", self.hmmalign.display()))?;
+        if !output.status.success() {
+            bail!(
+                "hmmalign failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        writer.write_all(&output.stdout)?;
+
+        tmpdir.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Existing annotations (GFF3) to look up the features hits land in.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+/// A feature of an annotation, 1-based and inclusive.
+#[derive(Debug, Clone)]
+struct Feature {
+    start: u64,
+    end: u64,
+    id: String,
+}
+
+/// The features of a GFF3 file on each sequence, sorted by start.
+#[derive(Debug, Clone, Default)]
+pub struct Annotation {
+    by_seqid: HashMap<String, Features>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Features {
+    features: Vec<Feature>,
+    /// The furthest end of the features up to each one, so the search for
+    /// overlaps can stop once nothing before reaches the query.
+    reach: Vec<u64>,
+}
+
+impl Annotation {
+    /// Read the features of the given `types` (e.g. `gene`) from a GFF3
+    /// file, all of them if `types` is empty. Features are named by their
+    /// `ID` attribute, or `Name` if they have none, and skipped if they have
+    /// neither.
+    pub fn from_gff3<P: AsRef<Path>>(path: P, types: &[String]) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read annotation {}", path.display()))?;
+
+        let mut by_seqid: HashMap<String, Features> = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            // any sequences come last
+            if line.starts_with("##FASTA") {
+                break;
+            }
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 9 {
+                bail!(
+                    "{}:{}: expected 9 columns, found {}",
+                    path.display(),
+                    i + 1,
+                    fields.len()
+                );
+            }
+            if !types.is_empty() && !types.iter().any(|t| t == fields[2]) {
+                continue;
+            }
+            let parse = |field: &str| {
+                field.parse::<u64>().with_context(|| {
+                    format!(
+                        "{}:{}: invalid coordinate \"{}\"",
+                        path.display(),
+                        i + 1,
+                        field
+                    )
+                })
+            };
+            let (start, end) = (parse(fields[3])?, parse(fields[4])?);
+            let Some(id) = attribute(fields[8], "ID").or_else(|| attribute(fields[8], "Name"))
+            else {
+                continue;
+            };
+            by_seqid
+                .entry(unescape(fields[0]))
+                .or_default()
+                .features
+                .push(Feature { start, end, id });
+        }
+
+        for features in by_seqid.values_mut() {
+            features.features.sort_by_key(|f| (f.start, f.end));
+            let mut reach = 0;
+            features.reach = features
+                .features
+                .iter()
+                .map(|f| {
+                    reach = reach.max(f.end);
+                    reach
+                })
+                .collect();
+        }
+        Ok(Self { by_seqid })
+    }
+
+    /// The IDs of the features on `seqid` overlapping `from..=to`, in order
+    /// of their start.
+    pub fn overlapping(&self, seqid: &str, from: u64, to: u64) -> Vec<&str> {
+        let Some(features) = self.by_seqid.get(seqid) else {
+            return Vec::new();
+        };
+        let (from, to) = (from.min(to), from.max(to));
+        let mut i = features.features.partition_point(|f| f.start <= to);
+        let mut ids = Vec::new();
+        while i > 0 && features.reach[i - 1] >= from {
+            i -= 1;
+            let feature = &features.features[i];
+            if feature.end >= from {
+                ids.push(feature.id.as_str());
+            }
+        }
+        ids.reverse();
+        ids
+    }
+}
+
+/// The value of the attribute `key` in a GFF3 attribute column. Only the
+/// first of several comma separated values is used.
+fn attribute(attributes: &str, key: &str) -> Option<String> {
+    attributes
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v.split(',').next())
+        .map(unescape)
+}
+
+/// Decode the percent-encoding of GFF3 columns and attributes.
+fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
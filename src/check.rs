@@ -0,0 +1,164 @@
+//! Checking that a tblout goes with a fasta before extracting its hits, so
+//! that mismatched pairs are caught up front rather than part way through.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use noodles_fasta as fasta;
+
+use crate::{
+    fetcher::SequenceFetcher,
+    index::with_suffix,
+    prepare::{is_fastq, uncompressed_name, Compression},
+    stream::StreamFetcher,
+    tblout::Tblout,
+    twobit::{is_twobit, TwoBitFetcher},
+};
+
+/// Something about a tblout that does not fit the fasta.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The tblout metadata names another fasta as the target file.
+    TargetFile { recorded: PathBuf, given: PathBuf },
+    /// A target is not in the fasta.
+    MissingTarget { target: String, hits: usize },
+    /// A target has another length in the tblout than in the fasta.
+    Length {
+        target: String,
+        tblout: u64,
+        fasta: u64,
+    },
+    /// A hit reaches outside of its target in the fasta.
+    OutOfRange {
+        target: String,
+        query: String,
+        from: u64,
+        to: u64,
+        length: u64,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::TargetFile { recorded, given } => write!(
+                f,
+                "The tblout was searched against {}, not {}",
+                recorded.display(),
+                given.display()
+            ),
+            Problem::MissingTarget { target, hits } => {
+                write!(f, "{}: not in the fasta ({} hits)", target, hits)
+            }
+            Problem::Length {
+                target,
+                tblout,
+                fasta,
+            } => write!(
+                f,
+                "{}: length {} in the tblout, but {} in the fasta",
+                target, tblout, fasta
+            ),
+            Problem::OutOfRange {
+                target,
+                query,
+                from,
+                to,
+                length,
+            } => write!(
+                f,
+                "{}: the hit of {} at {}..{} reaches outside of it (length {})",
+                target, query, from, to, length
+            ),
+        }
+    }
+}
+
+/// Everything about `tblout` that does not fit the fasta at `fasta`: a
+/// target file in its metadata that is not this fasta, targets that are not
+/// in it or have another length, and hits outside of their targets. The
+/// lengths are read from the `.fai` next to the fasta if there is one.
+pub fn check(tblout: &Tblout, fasta: &Path) -> Result<Vec<Problem>> {
+    let lengths = target_lengths(fasta)?;
+    let mut problems = Vec::new();
+
+    let recorded = &tblout.meta().target_file;
+    if !recorded.as_os_str().is_empty() && !same_file(recorded, fasta) {
+        problems.push(Problem::TargetFile {
+            recorded: recorded.clone(),
+            given: fasta.to_path_buf(),
+        });
+    }
+
+    let mut missing: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut mismatched: HashMap<&str, u64> = HashMap::new();
+    for hit in tblout.hits() {
+        let Some(&length) = lengths.get(&hit.target_name) else {
+            *missing.entry(&hit.target_name).or_default() += 1;
+            continue;
+        };
+        if let Some(sq_len) = hit.sq_len.filter(|&l| l != length) {
+            if mismatched.insert(&hit.target_name, sq_len).is_none() {
+                problems.push(Problem::Length {
+                    target: hit.target_name.clone(),
+                    tblout: sq_len,
+                    fasta: length,
+                });
+            }
+        }
+        let out_of_range = [hit.ali(), hit.env()]
+            .into_iter()
+            .flatten()
+            .find(|&(from, to)| from.min(to) < 1 || from.max(to) > length);
+        if let Some((from, to)) = out_of_range {
+            problems.push(Problem::OutOfRange {
+                target: hit.target_name.clone(),
+                query: hit.query_name.clone(),
+                from,
+                to,
+                length,
+            });
+        }
+    }
+    problems.extend(
+        missing
+            .into_iter()
+            .map(|(target, hits)| Problem::MissingTarget {
+                target: target.to_string(),
+                hits,
+            }),
+    );
+    Ok(problems)
+}
+
+/// The length of each sequence in the fasta at `path`, from its index if
+/// it has one, or else from a pass over it.
+fn target_lengths(path: &Path) -> Result<HashMap<String, u64>> {
+    let fai = with_suffix(path, ".fai");
+    if fai.is_file() && Compression::detect(path)?.is_none() && !is_fastq(path)? {
+        let index = fasta::fai::read(&fai)
+            .with_context(|| format!("Could not read index {}", fai.display()))?;
+        return Ok(index
+            .iter()
+            .map(|r| (String::from_utf8_lossy(r.name()).into_owned(), r.length()))
+            .collect());
+    }
+    if is_twobit(path)? {
+        return TwoBitFetcher::new(path)?.lengths();
+    }
+    StreamFetcher::new(path)?.lengths()
+}
+
+/// Whether `recorded` and `given` are the same fasta: the same file, or
+/// files of the same name, compressed or not, e.g. when the search ran
+/// elsewhere.
+fn same_file(recorded: &Path, given: &Path) -> bool {
+    match (recorded.canonicalize(), given.canonicalize()) {
+        (Ok(a), Ok(b)) if a == b => true,
+        _ => uncompressed_name(recorded) == uncompressed_name(given),
+    }
+}
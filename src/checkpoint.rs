@@ -0,0 +1,79 @@
+//! How far an extraction into a file has got, so that an interrupted run
+//! can pick up where it left off with `--resume`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use extract_nhmmer_tblout::index::with_suffix;
+use serde::{Deserialize, Serialize};
+
+/// The sequences written to an output so far.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The number of hits being extracted.
+    pub hits: usize,
+    /// The number of their sequences written.
+    pub done: usize,
+    /// The header of the last sequence written, to check that a resumed run
+    /// is extracting the same hits.
+    pub last: Option<String>,
+    /// The length of the output once they were written.
+    pub offset: u64,
+}
+
+impl Checkpoint {
+    /// Where the checkpoint of `output` is kept.
+    pub fn path(output: &Path) -> PathBuf {
+        with_suffix(output, ".checkpoint")
+    }
+
+    /// Read the checkpoint of `output`, checking it is for `headers`.
+    pub fn read(output: &Path, headers: &[String]) -> Result<Self> {
+        let path = Self::path(output);
+        let contents = fs::read_to_string(&path).with_context(|| {
+            format!(
+                "Could not read checkpoint {}; was the run interrupted?",
+                path.display()
+            )
+        })?;
+        let checkpoint: Self = serde_json::from_str(&contents)
+            .with_context(|| format!("Could not parse checkpoint {}", path.display()))?;
+
+        let last = checkpoint
+            .done
+            .checked_sub(1)
+            .and_then(|i| headers.get(i))
+            .cloned();
+        ensure!(
+            checkpoint.hits == headers.len() && checkpoint.last == last,
+            "The checkpoint {} is from a run extracting other hits",
+            path.display()
+        );
+        Ok(checkpoint)
+    }
+
+    /// Save the checkpoint of `output`, replacing the last one in one go so
+    /// an interruption does not leave half of it.
+    pub fn write(&self, output: &Path) -> Result<()> {
+        let path = Self::path(output);
+        let partial = with_suffix(&path, ".tmp");
+        fs::write(&partial, serde_json::to_string(self)?)
+            .with_context(|| format!("Could not write checkpoint {}", partial.display()))?;
+        fs::rename(&partial, &path)
+            .with_context(|| format!("Could not write checkpoint {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Remove the checkpoint of `output`, once it is finished.
+    pub fn remove(output: &Path) -> Result<()> {
+        let path = Self::path(output);
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Could not remove checkpoint {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
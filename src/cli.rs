@@ -0,0 +1,1079 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::{
+    arg, command, crate_version, value_parser, Arg, ArgAction, ArgGroup, ArgMatches, Command,
+};
+use clap_complete::Shell;
+use extract_nhmmer_tblout::{
+    expr::Expr,
+    filter::{self, Rank, Round},
+    tblout::{Columns, Format},
+    HitFilter, Trim,
+};
+use regex::Regex;
+
+/// The arguments controlling which hits are kept, shared by all of the
+/// subcommands.
+pub fn filter_args() -> Vec<Arg> {
+    vec![
+        Arg::new("e_value_threshold")
+            .short('v')
+            .long("e-value-threshold")
+            .value_parser(value_parser!(f64))
+            .required(false)
+            .default_value("0.00001")
+            .help("E-value threshold for hits to extract."),
+        Arg::new("min_score")
+            .long("min-score")
+            .value_parser(value_parser!(f64))
+            .required(false)
+            .help("Minimum bit score for hits to extract. Unlike E-values, scores do not depend on the size of the database."),
+        Arg::new("thresholds")
+            .long("thresholds")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Per-query cutoffs, overriding -v and --min-score. Each line has a query name or accession, an E-value threshold and optionally a minimum score, separated by tabs; - keeps the global cutoff."),
+        Arg::new("inc_only")
+            .long("inc-only")
+            .action(ArgAction::SetTrue)
+            .help("Only keep hits that the program included as significant (`!` in the inc column, or within --incE/--incT of the search for nhmmer)."),
+        Arg::new("targets")
+            .long("targets")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Only keep hits on the targets listed in this file, one per line."),
+        Arg::new("target_regex")
+            .long("target-regex")
+            .value_parser(value_parser!(Regex))
+            .required(false)
+            .help("Only keep hits on targets whose name matches this regular expression."),
+        Arg::new("queries")
+            .long("queries")
+            .value_parser(value_parser!(String))
+            .value_delimiter(',')
+            .required(false)
+            .help("Only keep hits from these queries, by name or accession, separated by commas."),
+        Arg::new("query_regex")
+            .long("query-regex")
+            .value_parser(value_parser!(Regex))
+            .required(false)
+            .help("Only keep hits from queries whose name or accession matches this regular expression."),
+        Arg::new("top_n")
+            .long("top-n")
+            .value_parser(value_parser!(usize))
+            .required(false)
+            .help("Only keep the best N passing hits of each query."),
+        Arg::new("rank_by")
+            .long("rank-by")
+            .value_parser(["evalue", "score"])
+            .required(false)
+            .default_value("evalue")
+            .help("How hits are ranked for --top-n."),
+        Arg::new("best_per_target")
+            .long("best-per-target")
+            .action(ArgAction::SetTrue)
+            .help("When hits overlap on the same target, only keep the one with the lowest E-value."),
+        Arg::new("non_redundant")
+            .long("non-redundant")
+            .action(ArgAction::SetTrue)
+            .help("When hits of different queries overlap on the same target (by --non-redundant-overlap), only keep the one with the highest score, so that related models do not extract the same locus over and over."),
+        Arg::new("non_redundant_overlap")
+            .long("non-redundant-overlap")
+            .value_parser(value_parser!(f64))
+            .required(false)
+            .requires("non_redundant")
+            .help("The fraction of the shorter of two hits they have to overlap by to be redundant with --non-redundant, by default half."),
+        Arg::new("round")
+            .long("round")
+            .required(false)
+            .default_value("last")
+            .help("Which round of a jackhmmer search to keep the hits of: a number, last or all. Each round lists the targets found so far again, so the last round of each query is usually the one wanted."),
+        Arg::new("min_model_coverage")
+            .long("min-model-coverage")
+            .value_parser(value_parser!(f64))
+            .required(false)
+            .help("Only keep hits covering at least this fraction of their model (from the hmm from/to columns). The model lengths are read from --hmm unless the tblout has them (domtblout)."),
+        Arg::new("hmm")
+            .long("hmm")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("The HMM (or CM) file searched with, to read the model lengths from."),
+        Arg::new("filter_expr")
+            .long("filter-expr")
+            .value_name("expr")
+            .required(false)
+            .help("Only keep hits this expression is true for, e.g. \"evalue < 1e-6 && score > 40 && (ali_to - ali_from).abs() > 200\". It can use the columns by name (target, query, ali_from, env_to, sq_len, strand, evalue, score, bias, included, length, ...), numbers, quoted strings, + - * /, abs(), ln() and log10(), comparisons, && || and !. Comparisons with a column the tblout does not have are false."),
+    ]
+}
+
+/// Build a `HitFilter` from the arguments in `filter_args`.
+pub fn hit_filter(matches: &ArgMatches) -> Result<HitFilter> {
+    let targets = matches
+        .get_one::<PathBuf>("targets")
+        .map(filter::read_names)
+        .transpose()?;
+
+    let model_lengths = matches
+        .get_one::<PathBuf>("hmm")
+        .map(filter::read_model_lengths)
+        .transpose()?
+        .unwrap_or_default();
+
+    let thresholds = matches
+        .get_one::<PathBuf>("thresholds")
+        .map(filter::read_thresholds)
+        .transpose()?
+        .unwrap_or_default();
+
+    Ok(HitFilter {
+        e_value_threshold: *matches
+            .get_one::<f64>("e_value_threshold")
+            .expect("defaulted by clap"),
+        min_score: matches.get_one::<f64>("min_score").copied(),
+        inc_only: matches.get_flag("inc_only"),
+        targets,
+        target_regex: matches.get_one::<Regex>("target_regex").cloned(),
+        queries: matches
+            .get_many::<String>("queries")
+            .map(|queries| queries.cloned().collect()),
+        query_regex: matches.get_one::<Regex>("query_regex").cloned(),
+        top_n: matches.get_one::<usize>("top_n").copied(),
+        best_per_target: matches.get_flag("best_per_target"),
+        non_redundant: matches.get_flag("non_redundant").then(|| {
+            matches
+                .get_one::<f64>("non_redundant_overlap")
+                .copied()
+                .unwrap_or(0.5)
+        }),
+        min_model_coverage: matches.get_one::<f64>("min_model_coverage").copied(),
+        model_lengths,
+        thresholds,
+        rank_by: matches
+            .get_one::<String>("rank_by")
+            .expect("defaulted by clap")
+            .parse::<Rank>()?,
+        round: matches
+            .get_one::<String>("round")
+            .expect("defaulted by clap")
+            .parse::<Round>()?,
+        expr: matches
+            .get_one::<String>("filter_expr")
+            .map(|expr| expr.parse::<Expr>())
+            .transpose()?,
+    })
+}
+
+/// The tblout argument, and how to read it.
+fn tbl_args() -> Vec<Arg> {
+    vec![
+        arg!(<TBL> "Path to the nhmmer tblout file, or - to read it from stdin.")
+            .required(true)
+            .value_parser(value_parser!(PathBuf)),
+        Arg::new("tbl")
+            .long("tbl")
+            .value_name("TBL")
+            .value_parser(value_parser!(PathBuf))
+            .action(ArgAction::Append)
+            .help("Another tblout to read hits from. Can be repeated. The hits of all tblouts are read together, as if from one."),
+        Arg::new("tbl_list")
+            .long("tbl-list")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("A file listing more tblouts to read hits from, one path per line. Blank lines and lines starting with # are skipped."),
+        Arg::new("program")
+            .short('p')
+            .long("program")
+            .value_parser(["nhmmer", "hmmsearch", "phmmer", "jackhmmer", "cmsearch", "cmscan"])
+            .required(false)
+            .help("The program that produced the tblout. By default this is read from the tblout metadata. Hits from protein searches (hmmsearch, phmmer and jackhmmer) cover the whole target sequence. For Infernal (cmsearch/cmscan) the strand column decides the orientation."),
+        Arg::new("domtblout")
+            .short('d')
+            .long("domtblout")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("program")
+            .help("The input is a --domtblout file. Each domain is extracted on its own, using the envelope coordinates."),
+        Arg::new("input_format")
+            .long("format")
+            .value_name("format")
+            .value_parser(["blast6", "mmseqs"])
+            .required(false)
+            .conflicts_with_all(["program", "domtblout"])
+            .help("The input is not a tblout: blast6 for BLAST tabular output (-outfmt 6), or mmseqs for MMseqs2 convertalis output. The subject (target) is the target, its start and end decide the strand, and the query coordinates stand in for the profile ones."),
+        Arg::new("columns")
+            .long("columns")
+            .value_parser(|s: &str| s.parse::<Columns>().map_err(|e| e.to_string()))
+            .required(false)
+            .requires("input_format")
+            .help("The columns of --format mmseqs output, as given to --format-output, e.g. query,target,evalue,bits,tstart,tend. Only query, target, tstart, tend, evalue and bits are needed; qstart, qend, qlen, tlen and theader are read if there. By default the m8 layout."),
+        Arg::new("lenient")
+            .long("lenient")
+            .action(ArgAction::SetTrue)
+            .help("Skip tblout lines that can not be parsed, e.g. from a truncated run, instead of failing. Each one is logged, and the number skipped is reported at the end."),
+    ]
+}
+
+/// The other tblout, and how its hits are matched, for `intersect` and
+/// `subtract`.
+fn compare_args() -> Vec<Arg> {
+    vec![
+        arg!(<OTHER> "Path to the tblout to compare with. The filters apply to its hits too.")
+            .value_parser(value_parser!(PathBuf)),
+        Arg::new("min_overlap")
+            .long("min-overlap")
+            .value_parser(value_parser!(f64))
+            .required(false)
+            .default_value("0")
+            .help("The fraction of both hits their overlap has to cover for them to match, e.g. 0.5 for a reciprocal overlap of half. By default any overlap will do."),
+        Arg::new("same_strand")
+            .long("same-strand")
+            .action(ArgAction::SetTrue)
+            .help("Only match hits on the same strand."),
+    ]
+}
+
+/// Where to write the output, shared by all of the subcommands.
+fn output_args() -> Vec<Arg> {
+    vec![
+        Arg::new("output")
+            .short('o')
+            .long("output")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Write the output to this file instead of stdout. Files ending in .gz are gzip compressed."),
+        Arg::new("compress")
+            .long("compress")
+            .action(ArgAction::SetTrue)
+            .requires("output")
+            .help("Gzip compress the output file, whatever its name."),
+        Arg::new("bgzf")
+            .long("bgzf")
+            .action(ArgAction::SetTrue)
+            .requires("output")
+            .conflicts_with("compress")
+            .help("Compress the output file with block gzip (BGZF), so it can be indexed."),
+    ]
+}
+
+/// The paths of all of the tblouts to read, in the order given.
+pub fn tbl_paths(matches: &ArgMatches) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![matches
+        .get_one::<PathBuf>("TBL")
+        .expect("tbl is required")
+        .clone()];
+    if let Some(tbls) = matches.get_many::<PathBuf>("tbl") {
+        paths.extend(tbls.cloned());
+    }
+    if let Some(list) = matches.get_one::<PathBuf>("tbl_list") {
+        let contents = fs::read_to_string(list)
+            .with_context(|| format!("Could not read tblout list {}", list.display()))?;
+        paths.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(PathBuf::from),
+        );
+    }
+    Ok(paths)
+}
+
+/// The tblout layout given by `--program`, `--domtblout` or `--format`, if
+/// any.
+pub fn format(matches: &ArgMatches) -> Result<Option<Format>> {
+    if matches.get_flag("domtblout") {
+        return Ok(Some(Format::Domtblout));
+    }
+    let input_format = matches.get_one::<String>("input_format");
+    if let Some(&columns) = matches.get_one::<Columns>("columns") {
+        ensure!(
+            input_format.is_some_and(|f| f == "mmseqs"),
+            "--columns only applies to --format mmseqs"
+        );
+        return Ok(Some(Format::Mmseqs(columns)));
+    }
+    Ok(matches
+        .get_one::<String>("program")
+        .or(input_format)
+        .map(|p| p.parse().expect("validated by clap")))
+}
+
+/// The extra options to run nhmmer with, split into words as a shell would.
+pub fn nhmmer_options(matches: &ArgMatches) -> Result<Vec<String>> {
+    match matches.get_one::<String>("nhmmer_options") {
+        Some(options) => split_words(options)
+            .with_context(|| format!("Could not read --nhmmer-options \"{}\"", options)),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Split `s` into words at whitespace, as a POSIX shell does: anything in
+/// single quotes is taken as it is, and a backslash escapes the next
+/// character, or in double quotes only `"`, `\`, `$` and `` ` ``.
+fn split_words(s: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    // the word so far, if one has started (`''` is an empty word)
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => bail!("Unterminated double quote"),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => {
+                let escaped = chars.next().context("Nothing after the last backslash")?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// The arguments controlling how the sequences are extracted and named,
+/// shared by `extract` and `run`.
+fn extraction_args() -> Vec<Arg> {
+    vec![
+        Arg::new("esl-sfetch")
+            .short('e')
+            .long("esl-sfetch")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Path to esl-sfetch. If not installed, it's part of HMMER. Required for the esl-sfetch backend."),
+        Arg::new("samtools")
+            .long("samtools")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Path to samtools. Required for the samtools backend."),
+        Arg::new("backend")
+            .short('b')
+            .long("backend")
+            .value_parser(["esl-sfetch", "samtools", "native"])
+            .required(false)
+            .default_value("esl-sfetch")
+            .help("How to extract sequences. `samtools` uses samtools faidx instead of esl-sfetch, and `native` reads the fasta in-process without either."),
+        Arg::new("revcomp_minus")
+            .long("revcomp-minus")
+            .action(ArgAction::SetTrue)
+            .help("Extract minus strand hits forwards, then reverse complement them, recording the strand in the header."),
+        Arg::new("coords")
+            .short('c')
+            .long("coords")
+            .value_parser(["ali", "env"])
+            .required(false)
+            .help("Extract the alignment (ali) or envelope (env) coordinates. By default domains use the envelope and other hits the alignment."),
+        Arg::new("merge_distance")
+            .long("merge-distance")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .help("Merge hits on the same target and strand that are within this many bases of each other, extracting their union."),
+        Arg::new("stitch")
+            .long("stitch")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["flank", "flank_5p", "flank_3p", "upstream", "downstream", "extend_orf", "circular", "circular_targets"])
+            .help("Join the hits of each query on the same target and strand into one sequence, in order along the strand, e.g. for markers with several exons found as separate hits. The segments and where they end in the sequence are added to its description."),
+        Arg::new("full_seq")
+            .long("full-seq")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["merge_distance", "stitch", "flank", "flank_5p", "flank_3p", "trim", "trim_5p", "trim_3p", "upstream", "downstream", "extend_orf"])
+            .help("Extract the whole sequence of each target with a passing hit, once, instead of the hits, e.g. the contigs a marker was found on. Each is named after its best hit."),
+        Arg::new("flank")
+            .long("flank")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .default_value("0")
+            .help("Extend each hit by this many bases on each side, clamped to the ends of the sequence."),
+        Arg::new("flank_5p")
+            .long("flank-5p")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .help("Bases to extend upstream (5') of the hit, relative to its strand. Overrides --flank."),
+        Arg::new("flank_3p")
+            .long("flank-3p")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .help("Bases to extend downstream (3') of the hit, relative to its strand. Overrides --flank."),
+        Arg::new("trim")
+            .long("trim")
+            .value_parser(|s: &str| s.parse::<Trim>().map_err(|e| e.to_string()))
+            .required(false)
+            .conflicts_with_all(["upstream", "downstream"])
+            .help("Trim each hit by this many bases, or this fraction of it (e.g. 0.1), on each side, to keep the core of the alignment. Hits trimmed to nothing are skipped. Done before any --flank is added."),
+        Arg::new("trim_5p")
+            .long("trim-5p")
+            .value_parser(|s: &str| s.parse::<Trim>().map_err(|e| e.to_string()))
+            .required(false)
+            .conflicts_with_all(["upstream", "downstream"])
+            .help("Bases (or the fraction of the hit) to trim off its upstream (5') end, relative to its strand. Overrides --trim."),
+        Arg::new("trim_3p")
+            .long("trim-3p")
+            .value_parser(|s: &str| s.parse::<Trim>().map_err(|e| e.to_string()))
+            .required(false)
+            .conflicts_with_all(["upstream", "downstream"])
+            .help("Bases (or the fraction of the hit) to trim off its downstream (3') end, relative to its strand. Overrides --trim."),
+        Arg::new("upstream")
+            .long("upstream")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .conflicts_with_all(["downstream", "flank", "flank_5p", "flank_3p"])
+            .help("Extract this many bases upstream (5') of each hit, relative to its strand, instead of the hit, e.g. for promoters. Clamped to the start of the sequence; hits right at the start are skipped."),
+        Arg::new("downstream")
+            .long("downstream")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .conflicts_with_all(["flank", "flank_5p", "flank_3p"])
+            .help("Extract this many bases downstream (3') of each hit, relative to its strand, instead of the hit, e.g. for UTRs. Clamped to the end of the sequence; hits right at the end are skipped."),
+        Arg::new("circular")
+            .long("circular")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("extend_orf")
+            .help("Treat the sequences as circular (plasmids, organelles, viruses): flanks wrap around the origin instead of being clamped, and hits past the end of the sequence are extracted across it. Regions across the origin end past the end of the sequence in the BED, GFF and manifest."),
+        Arg::new("circular_targets")
+            .long("circular-targets")
+            .value_parser(value_parser!(String))
+            .value_delimiter(',')
+            .required(false)
+            .conflicts_with("extend_orf")
+            .help("Only treat these sequences as circular, separated by commas. Implies --circular."),
+        Arg::new("mask")
+            .long("mask")
+            .value_parser(["keep", "upper", "hard"])
+            .required(false)
+            .default_value("keep")
+            .help("What to do with soft-masked (lowercase) bases in the fasta: keep them lowercase, uppercase them, or hard-mask them to N. Done before --translate."),
+        Arg::new("translate")
+            .long("translate")
+            .action(ArgAction::SetTrue)
+            .help("Translate the extracted sequences into protein, on the strand of each hit."),
+        Arg::new("extend_orf")
+            .long("extend-orf")
+            .action(ArgAction::SetTrue)
+            .help("Extend each hit to the open reading frame it is in on its strand: downstream through the next in-frame stop codon, and upstream to the first start codon (ATG) after the previous stop codon. --flank is added to the extended hit."),
+        Arg::new("genetic_code")
+            .long("genetic-code")
+            .value_parser(value_parser!(u8))
+            .required(false)
+            .requires("coding")
+            .help("The NCBI genetic code table to translate with, and to find the stop codons of --extend-orf with. Defaults to 1, the standard code."),
+        Arg::new("trim_translation")
+            .long("trim-translation")
+            .action(ArgAction::SetTrue)
+            .requires("translate")
+            .help("Cut each translation at its first stop codon."),
+        Arg::new("align_with")
+            .long("align-with")
+            .value_name("HMM")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Align the extracted sequences of each query to its model in this HMM file with hmmalign, and write the alignments instead of the sequences. With --split-by-query each query gets its own file."),
+        Arg::new("hmmalign")
+            .long("hmmalign")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .default_value("hmmalign")
+            .help("Path to hmmalign, for --align-with. By default it is looked for on the PATH."),
+        Arg::new("align_format")
+            .long("align-format")
+            .value_parser(["stockholm", "afa"])
+            .required(false)
+            .default_value("stockholm")
+            .help("The format of the --align-with alignments, Stockholm or aligned fasta."),
+        Arg::new("bed")
+            .long("bed")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Also write the extracted regions to this BED file (target, 0-based start, end, header, score, strand). The score is the bit score rounded and clamped to 0-1000."),
+        Arg::new("gff")
+            .long("gff")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Also write the extracted regions to this GFF3 file, with the query name, E-value and score as attributes."),
+        Arg::new("sam")
+            .long("sam")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .conflicts_with("translate")
+            .help("Also write the extracted sequences as alignments on their targets to this SAM file (BAM if it ends in .bam), with the E-value (ZE), score (AS, ZS) and query (ZQ) as tags, e.g. to view the hits in IGV. The headers are the read names, so a --header-format with spaces is refused."),
+        Arg::new("manifest")
+            .long("manifest")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Also write a manifest of the extracted sequences (header, target, coordinates, strand, E-value, score, query). JSON if the file ends in .json, otherwise TSV."),
+        Arg::new("annotate")
+            .long("annotate")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Record the features of this GFF3 file that each hit overlaps (by ID, or Name), in a features column of the --manifest and the {features} placeholder of --header-format."),
+        Arg::new("annotate_types")
+            .long("annotate-types")
+            .value_parser(value_parser!(String))
+            .value_delimiter(',')
+            .required(false)
+            .default_value("gene")
+            .help("The feature types of --annotate to record, separated by commas. `all` records every feature."),
+        Arg::new("split_by_query")
+            .long("split-by-query")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .conflicts_with("output")
+            .help("Write one fasta per query into this directory, named by the query accession (or name, if it has none)."),
+        Arg::new("split_by_strand")
+            .long("split-by-strand")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["per_hit", "dedup", "align_with"])
+            .requires("named_output")
+            .help("Write the plus and minus strand sequences to separate files, named from --output (or each file of --split-by-query) with .plus or .minus before the extension, e.g. hits.plus.fa and hits.minus.fa. Both files are written, even if one is empty."),
+        Arg::new("outdir")
+            .long("outdir")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .requires("per_hit")
+            .conflicts_with_all(["output", "split_by_query"])
+            .help("The directory to write the sequences into with --per-hit."),
+        Arg::new("per_hit")
+            .long("per-hit")
+            .action(ArgAction::SetTrue)
+            .requires("outdir")
+            .conflicts_with_all(["dedup", "align_with", "fai"])
+            .help("Write each sequence to its own fasta in --outdir, named by its header with anything but letters, digits, '.', '-' and '_' replaced by '_', for tools that take one sequence per file."),
+        Arg::new("dedup")
+            .long("dedup")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["split_by_query", "align_with"])
+            .help("Write each distinct sequence once, under the header of the first hit with it. Case is ignored, so soft-masking does not keep copies apart. The manifest gets a representative column with the header kept for each hit."),
+        Arg::new("dedup_members")
+            .long("dedup-members")
+            .action(ArgAction::SetTrue)
+            .requires("dedup")
+            .help("Add the headers of the collapsed duplicates to the description of each kept sequence, as members=a,b. The sequences are then held until all are extracted."),
+        Arg::new("min_length")
+            .long("min-length")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .help("Skip hits whose extracted region (after flanking, merging and --extend-orf) is shorter than this."),
+        Arg::new("max_length")
+            .long("max-length")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .help("Skip hits whose extracted region (after flanking, merging and --extend-orf) is longer than this."),
+        Arg::new("header_format")
+            .long("header-format")
+            .value_parser(value_parser!(String))
+            .required(false)
+            .help("Template for the headers, instead of `species:Eevalue:name`. Placeholders: {species}, {target}, {from}, {to}, {strand}, {evalue}, {score}, {query}, {name} (target/from-to) and {features} (with --annotate)."),
+        Arg::new("rename_map")
+            .long("rename-map")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Rename targets in the headers, e.g. scaffold IDs to public accessions. Each line of this file has a target name and its new name, separated by a tab. The BED, GFF and manifest keep the names in the fasta."),
+        Arg::new("with_coords")
+            .long("with-coords")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("header_format")
+            .help("Name the sequences target:from-to(strand) in the headers, so hits on the same sequence can be told apart."),
+        Arg::new("esl_names")
+            .long("esl-names")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["header_format", "with_coords"])
+            .help("Name the sequences target/from-to exactly as `esl-sfetch -c` does (from > to on the minus strand), for pipelines that expect its names."),
+        Arg::new("seqkit_names")
+            .long("seqkit-names")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["header_format", "with_coords", "esl_names"])
+            .help("Name the sequences target_from-to:strand as `seqkit subseq` does, so the output of either can be used in place of the other."),
+        Arg::new("meta_in_description")
+            .long("meta-in-description")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["header_format", "with_coords"])
+            .help("Name the sequences only target_from_to (or as --esl-names or --seqkit-names), and put the query, E-value, score, strand and species in the description as key=value pairs, for tools that cut names at the first colon."),
+        Arg::new("sort")
+            .long("sort")
+            .value_parser(["evalue", "score", "coord", "length"])
+            .required(false)
+            .help("Write the sequences in this order instead of that of the tblout: lowest E-value, highest score or longest region first, or by target and start."),
+        Arg::new("strict_names")
+            .long("strict-names")
+            .action(ArgAction::SetTrue)
+            .help("Fail if two hits would get the same header, instead of adding _2, _3 and so on to the later ones."),
+        Arg::new("no_index")
+            .long("no-index")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["reuse_index", "keep_index"])
+            .help("Read the hits straight out of the fasta, decompressing it on the fly, instead of copying and indexing it. Takes two passes over the fasta, so is quicker for a handful of hits. No backend is needed."),
+        Arg::new("workdir")
+            .long("workdir")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .conflicts_with("no_index")
+            .help("Prepare and index the fasta in this directory instead of a temporary one, and keep it there. Later runs against the same fasta use it as it is, unless the fasta has changed since."),
+        Arg::new("tmpdir")
+            .long("tmpdir")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Make the temporary directory the fasta is prepared and indexed in here, instead of in TMPDIR. The free space is checked before the fasta is copied or decompressed."),
+        Arg::new("prepare_in_place")
+            .long("prepare-in-place")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["workdir", "no_index"])
+            .help("Prepare and index the fasta in a temporary directory next to it, on the same disk, instead of in the temporary directory. It is removed at the end."),
+        Arg::new("reuse_index")
+            .long("reuse-index")
+            .action(ArgAction::SetTrue)
+            .help("If the fasta already has an index next to it (.ssi for esl-sfetch, .fai for samtools and native), use it in place instead of copying and indexing the fasta."),
+        Arg::new("keep_index")
+            .long("keep-index")
+            .action(ArgAction::SetTrue)
+            .help("Save the index next to the fasta (.ssi for esl-sfetch, .fai for samtools and native), for use with --reuse-index. Only done for uncompressed fasta with Unix line endings, or BGZF with the native backend (which also saves a .gzi)."),
+        Arg::new("overwrite_index")
+            .long("overwrite-index")
+            .action(ArgAction::SetTrue)
+            .requires("keep_index")
+            .help("Let --keep-index replace an existing index."),
+        Arg::new("threads")
+            .short('t')
+            .long("threads")
+            .value_parser(value_parser!(usize))
+            .required(false)
+            .default_value("1")
+            .help("Number of threads to extract with."),
+        Arg::new("decompress_threads")
+            .long("decompress-threads")
+            .value_parser(value_parser!(usize))
+            .required(false)
+            .default_value("1")
+            .help("Number of threads to decompress gzip or xz compressed fasta with. Gzip needs pigz for this, and is decompressed on one thread with gunzip without it."),
+        Arg::new("strict")
+            .long("strict")
+            .action(ArgAction::SetTrue)
+            .overrides_with("skip_missing")
+            .help("Fail, naming the target, if any hit is on a target that is not in the fasta. This is the default."),
+        Arg::new("skip_missing")
+            .long("skip-missing")
+            .action(ArgAction::SetTrue)
+            .overrides_with("strict")
+            .help("Skip hits on targets that are not in the fasta, with a warning counting them at the end, instead of failing."),
+        Arg::new("strict_duplicates")
+            .long("strict-duplicates")
+            .action(ArgAction::SetTrue)
+            .help("Fail, naming them, if the fasta has a sequence name more than once. Without it the later copies of a name are renamed name__2, name__3 and so on, and hits are put on the copy of the length in the tblout."),
+        Arg::new("clip")
+            .long("clip")
+            .action(ArgAction::SetTrue)
+            .help("Clip hits reaching outside of their sequence in the fasta to it, dropping any entirely outside, instead of failing. This only happens if the tblout is from a search of a different fasta."),
+        Arg::new("exclude_bed")
+            .long("exclude-bed")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Skip hits overlapping the regions in this BED file, e.g. repeats or known contaminants. Hits are checked before flanking and --extend-orf."),
+        Arg::new("max_excluded")
+            .long("max-excluded")
+            .value_parser(value_parser!(f64))
+            .required(false)
+            .requires("exclude_bed")
+            .help("The fraction of a hit that may overlap the --exclude-bed regions before it is skipped. [default: 0]"),
+        Arg::new("trim_excluded")
+            .long("trim-excluded")
+            .action(ArgAction::SetTrue)
+            .requires("exclude_bed")
+            .help("Trim --exclude-bed regions off the ends of hits, only skipping those with excluded bases left inside them (beyond --max-excluded)."),
+        Arg::new("fail_if_empty")
+            .long("fail-if-empty")
+            .action(ArgAction::SetTrue)
+            .help("Exit with an error if no sequences were extracted, after writing the (empty) output."),
+        Arg::new("min_hits")
+            .long("min-hits")
+            .value_parser(value_parser!(usize))
+            .required(false)
+            .help("Exit with an error if fewer than this many sequences were extracted, after writing the output."),
+        Arg::new("run_manifest")
+            .long("run-manifest")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("Write a JSON record of the run to this file, with the version and command line, checksums of the files read and written, the filter settings, and how many hits were read, filtered, extracted and skipped."),
+        Arg::new("line_width")
+            .long("line-width")
+            .value_parser(value_parser!(u64).range(1..))
+            .required(false)
+            .default_value("80")
+            .help("Wrap the sequences written at this many bases per line."),
+        Arg::new("single_line")
+            .long("single-line")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("line_width")
+            .help("Write each sequence on one line, however long."),
+        Arg::new("no_progress")
+            .long("no-progress")
+            .action(ArgAction::SetTrue)
+            .help("Do not show progress bars while the fasta is copied and the hits are extracted. They are only shown when stderr is a terminal."),
+        Arg::new("species_id")
+            .short('s')
+            .long("species-id")
+            .value_parser(value_parser!(String))
+            .required(false)
+            .default_value("")
+            .help("Species ID to add to the start of the header. Useful for downstream processing."),
+        Arg::new("taxid")
+            .long("taxid")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .conflicts_with("species_id")
+            .requires("taxdump")
+            .help("Use the species of this NCBI taxid, looked up in --taxdump, as the species ID."),
+        Arg::new("taxdump")
+            .long("taxdump")
+            .value_parser(value_parser!(PathBuf))
+            .required(false)
+            .help("An NCBI taxdump directory (or its names.dmp, or a table of taxids and names separated by tabs) to look up --taxid in. Species IDs in a --batch file that are taxids are looked up too."),
+        Arg::new("species_format")
+            .long("species-format")
+            .value_parser(value_parser!(String))
+            .required(false)
+            .requires("taxdump")
+            .help("How species IDs are made from the names in --taxdump: full (Homo_sapiens), initial (H_sapiens), abbrev (Hsap, the default), or G+S for the first G letters of the genus and S of the species (e.g. 3+3 for Homsap)."),
+    ]
+}
+
+/// How much is logged to stderr, and how, for all of the subcommands.
+fn logging_args() -> Vec<Arg> {
+    vec![
+        Arg::new("verbose")
+            .long("verbose")
+            .action(ArgAction::Count)
+            .global(true)
+            .help("Log more: once for debug messages (e.g. the commands run), twice for everything. RUST_LOG overrides this."),
+        Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .action(ArgAction::SetTrue)
+            .global(true)
+            .conflicts_with("verbose")
+            .help("Only log warnings and errors, and do not show progress bars."),
+        Arg::new("log_format")
+            .long("log-format")
+            .value_parser(["text", "json"])
+            .required(false)
+            .default_value("text")
+            .global(true)
+            .help("Log timestamped text, or one JSON object per line for workflow managers."),
+    ]
+}
+
+/// Options read from a config file, for all of the subcommands.
+fn config_arg() -> Arg {
+    Arg::new("config")
+        .long("config")
+        .value_parser(value_parser!(PathBuf))
+        .global(true)
+        .help("Read options from a TOML file, by their long names (e.g. e-value-threshold = 1e-5, or header-format = \"{query}\"). Options under a [subcommand] table only apply to that subcommand. Options given on the command line win over the config.")
+}
+
+/// The arguments that read the hits as coding sequences, which
+/// `--genetic-code` applies to.
+fn coding_group() -> ArgGroup {
+    ArgGroup::new("coding")
+        .args(["translate", "extend_orf"])
+        .multiple(true)
+}
+
+/// The arguments naming the files the sequences are written to, which
+/// `--split-by-strand` names its files from.
+fn named_output_group() -> ArgGroup {
+    ArgGroup::new("named_output")
+        .args(["output", "split_by_query"])
+        .multiple(true)
+}
+
+pub fn build() -> Command {
+    command!()
+        .version(crate_version!())
+        .author("Max Carter-Brown <max.carter-brown@aru.ac.uk>")
+        .about("Extracts sequences from a fasta file using nhmmer tblout file.")
+        .arg_required_else_help(true)
+        .subcommand_required(true)
+        .args(logging_args())
+        .arg(config_arg())
+        .subcommand(
+            Command::new("extract")
+                .about("Extract the sequences of the hits from the fasta.")
+                .args(tbl_args())
+                .mut_arg("TBL", |arg| arg.required(false).required_unless_present("batch"))
+                .mut_arg("tbl", |arg| arg.help("Another tblout to read hits from. Can be repeated. The hits of all tblouts are filtered together, and the fasta is only indexed once."))
+                .arg(
+                    arg!([FASTA] "Path to the fasta file used for nhmmer output. If not specified, the target file from the tblout file is used (this probably only works when that file path is absolute). Gzip, zstd, xz and bzip2 compressed fasta is decompressed first, and FASTQ (e.g. of long reads) is converted to fasta, without its qualities. UCSC 2bit genomes are read in place.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .long("fasta")
+                        .value_name("FASTA")
+                        .value_parser(value_parser!(PathBuf))
+                        .action(ArgAction::Append)
+                        .help("Another fasta file to look for targets in, e.g. when each chromosome is in its own file. Can be repeated. Each target has to be in exactly one file."),
+                )
+                .arg(
+                    Arg::new("fasta_dir")
+                        .long("fasta-dir")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false)
+                        .help("Look for targets in every fasta, FASTQ or 2bit file (.fa, .fasta, .fna, .fas, .ffn, .faa, .fq, .fastq or .2bit, compressed or not) in this directory, as with --fasta."),
+                )
+                .args(extraction_args())
+                .args(filter_args())
+                .args(output_args())
+                .arg(
+                    Arg::new("fai")
+                        .long("fai")
+                        .action(ArgAction::SetTrue)
+                        .requires("fasta_output")
+                        .conflicts_with("align_with")
+                        .help("Write a .fai index for the output file, or each file with --batch-dir (and a .gzi with --bgzf), for use with samtools faidx."),
+                )
+                .arg(
+                    Arg::new("batch")
+                        .long("batch")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false)
+                        .conflicts_with_all(["TBL", "FASTA", "tbl", "tbl_list", "fasta", "fasta_dir", "gff", "sam", "run_manifest", "split_by_query", "split_by_strand", "per_hit", "align_with"])
+                        .help("Extract many samples with the same settings. Each line of this file has a tblout, the fasta it was searched against and optionally a species ID (which overrides --species-id), separated by tabs. The BED and manifest cover all of the samples."),
+                )
+                .arg(
+                    Arg::new("batch_dir")
+                        .long("batch-dir")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false)
+                        .requires("batch")
+                        .help("Write one fasta per sample into this directory, named by its species ID (or tblout, if it has none), instead of all of them to the output."),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["batch", "fai", "bed", "gff", "sam", "manifest", "run_manifest", "split_by_query", "split_by_strand", "per_hit", "align_with"])
+                        .help("Only read and filter the hits, and write the target, coordinates, strand and header of each hit that would be extracted as a TSV, without preparing or indexing the fasta. Hits are not extended with --extend-orf, and flanks are only clamped to target lengths in the tblout."),
+                )
+                .arg(
+                    Arg::new("resume")
+                        .long("resume")
+                        .action(ArgAction::SetTrue)
+                        .requires("output")
+                        .conflicts_with_all(["batch", "dry_run", "compress", "bgzf", "dedup", "split_by_query", "split_by_strand", "per_hit", "align_with"])
+                        .help("Carry on an interrupted extraction into the --output file from its .checkpoint, which is saved every 1000 sequences while writing to an uncompressed file. Unless told where with --workdir or --tmpdir, these runs prepare the fasta in <output>.workdir, kept until the output is finished, so it is not prepared again. Can not be used with --dedup, as the duplicates already written are not known."),
+                )
+                .group(ArgGroup::new("fasta_output").args(["output", "batch_dir"]))
+                .group(named_output_group())
+                .group(coding_group()),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Search the genome with nhmmer, then extract the sequences of the hits.")
+                .arg(arg!(<HMM> "Path to the query HMM (or alignment, or sequence) for nhmmer.").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(<FASTA> "Path to the fasta file to search.").value_parser(value_parser!(PathBuf)))
+                .arg(
+                    Arg::new("nhmmer")
+                        .long("nhmmer")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false)
+                        .default_value("nhmmer")
+                        .help("Path to nhmmer. By default it is looked for on the PATH."),
+                )
+                .arg(
+                    Arg::new("nhmmer_options")
+                        .long("nhmmer-options")
+                        .allow_hyphen_values(true)
+                        .required(false)
+                        .help("Extra options to run nhmmer with, e.g. \"--cpu 4 --dna\". They are split into words as a shell would, so quote any value with spaces in it."),
+                )
+                .arg(
+                    Arg::new("tblout")
+                        .long("tblout")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false)
+                        .help("Keep the tblout nhmmer writes in this file. By default it is removed once the hits are extracted."),
+                )
+                .args(extraction_args())
+                .args(filter_args())
+                .args(output_args())
+                .arg(
+                    Arg::new("fai")
+                        .long("fai")
+                        .action(ArgAction::SetTrue)
+                        .requires("output")
+                        .conflicts_with("align_with")
+                        .help("Write a .fai index for the output file (and a .gzi with --bgzf) for use with samtools faidx."),
+                )
+                .group(named_output_group())
+                .group(coding_group()),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Check that a tblout goes with a fasta before extracting, writing out every problem found: missing targets, lengths, hits outside of their targets, and the target file.")
+                .args(tbl_args())
+                .arg(
+                    arg!([FASTA] "Path to the fasta file to check against. If not specified, the target file from the tblout file is used.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("filter")
+                .about("Write a tblout containing only the hits that pass the filters, with its header and metadata.")
+                .args(tbl_args())
+                .args(filter_args())
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Summarise the hits in a tblout, per query or per target.")
+                .args(tbl_args())
+                .arg(
+                    Arg::new("by")
+                        .long("by")
+                        .value_parser(["query", "target"])
+                        .required(false)
+                        .default_value("query")
+                        .help("Whether to summarise the hits of each query or of each target."),
+                )
+                .args(filter_args())
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Write a report on the hits in a tblout to share, as a self-contained HTML page or markdown: the hits of each query, their E-values, the hits on each target and a table of the extracted sequences.")
+                .args(tbl_args())
+                .arg(
+                    Arg::new("to")
+                        .short('t')
+                        .long("to")
+                        .value_parser(["html", "markdown"])
+                        .required(false)
+                        .help("The format of the report. By default markdown if the output ends in .md, and HTML otherwise."),
+                )
+                .arg(
+                    Arg::new("manifest")
+                        .long("manifest")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false)
+                        .help("The manifest written by extract, to list the sequences it extracted rather than the hits passing the filters."),
+                )
+                .arg(
+                    Arg::new("max_rows")
+                        .long("max-rows")
+                        .value_parser(value_parser!(usize))
+                        .required(false)
+                        .default_value("1000")
+                        .help("The most targets and sequences to list in their tables."),
+                )
+                .args(filter_args())
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Convert a tblout to a delimited table or JSON.")
+                .args(tbl_args())
+                .arg(
+                    Arg::new("to")
+                        .short('t')
+                        .long("to")
+                        .value_parser(["tsv", "csv", "json", "jsonl"])
+                        .required(false)
+                        .default_value("tsv")
+                        .help("The format to convert to: a delimited table, a JSON array of records, or one JSON record per line. Missing values are empty, or null in JSON, and start and end have the alignment coordinates in increasing order on either strand."),
+                )
+                .arg(
+                    Arg::new("filtered")
+                        .long("filtered")
+                        .action(ArgAction::SetTrue)
+                        .help("Only convert the hits that pass the filters."),
+                )
+                .args(filter_args())
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("intersect")
+                .about("Write a tblout of the hits that overlap a hit in another tblout of the same genome, e.g. from another version of the profile.")
+                .args(tbl_args())
+                .args(compare_args())
+                .args(filter_args())
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("subtract")
+                .about("Write a tblout of the hits that overlap no hit in another tblout of the same genome.")
+                .args(tbl_args())
+                .args(compare_args())
+                .args(filter_args())
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Write a completion script for a shell to stdout.")
+                .arg(arg!(<SHELL> "The shell to complete for.").value_parser(value_parser!(Shell))),
+        )
+        .subcommand(
+            Command::new("man")
+                .about("Write the man page to stdout, or man pages for every subcommand to a directory.")
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Write extract_nhmmer_tblout.1 and a page for each subcommand (e.g. extract_nhmmer_tblout-extract.1) to this directory."),
+                ),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(s: &str) -> Vec<String> {
+        split_words(s).unwrap()
+    }
+
+    #[test]
+    fn split_words_as_a_shell() {
+        assert_eq!(words("  --cpu 4\t--dna "), ["--cpu", "4", "--dna"]);
+        assert_eq!(words(""), Vec::<String>::new());
+        assert_eq!(
+            words("--tformat fasta --qformat 'a b' --x \"c \\\"d\\\" e\""),
+            ["--tformat", "fasta", "--qformat", "a b", "--x", "c \"d\" e"]
+        );
+        assert_eq!(words("a\\ b c"), ["a b", "c"]);
+        assert_eq!(words("pre'fix 'suf\"fix\""), ["prefix suffix"]);
+        assert_eq!(words("'' x"), ["", "x"]);
+        assert_eq!(words("'a\\b' \"a\\b\""), ["a\\b", "a\\b"]);
+    }
+
+    #[test]
+    fn split_words_needs_closing_quotes() {
+        assert!(split_words("--x 'a b").is_err());
+        assert!(split_words("--x \"a b").is_err());
+        assert!(split_words("--x a\\").is_err());
+    }
+}
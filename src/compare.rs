@@ -0,0 +1,102 @@
+//! Comparing the hits of two tblouts from searches of the same genome,
+//! e.g. with two versions of a profile.
+
+use std::collections::HashMap;
+
+use crate::tblout::Hit;
+
+/// When a hit of one tblout is taken to be the same as a hit of another.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Matching {
+    /// The fraction of each of the two hits their overlap has to cover; any
+    /// overlap will do if 0.
+    pub min_overlap: f64,
+    /// Only hits on the same strand match.
+    pub same_strand: bool,
+}
+
+/// The hits of the other tblout on each target, sorted by start.
+struct Targets<'a> {
+    by_target: HashMap<&'a str, Spans<'a>>,
+}
+
+#[derive(Default)]
+struct Spans<'a> {
+    hits: Vec<(u64, u64, &'a Hit)>,
+    /// The furthest end of the hits up to each one, so the search for
+    /// overlaps can stop once nothing before reaches the query.
+    reach: Vec<u64>,
+}
+
+impl<'a> Targets<'a> {
+    fn new(hits: &'a [Hit]) -> Self {
+        let mut by_target: HashMap<&str, Spans> = HashMap::new();
+        for hit in hits {
+            let (from, to) = span(hit).unwrap_or((1, u64::MAX));
+            by_target
+                .entry(&hit.target_name)
+                .or_default()
+                .hits
+                .push((from, to, hit));
+        }
+        for spans in by_target.values_mut() {
+            spans.hits.sort_by_key(|&(from, to, _)| (from, to));
+            let mut reach = 0;
+            spans.reach = spans
+                .hits
+                .iter()
+                .map(|&(_, to, _)| {
+                    reach = reach.max(to);
+                    reach
+                })
+                .collect();
+        }
+        Self { by_target }
+    }
+
+    /// Whether any hit matches `hit`.
+    fn matches(&self, hit: &Hit, matching: &Matching) -> bool {
+        let Some(spans) = self.by_target.get(hit.target_name.as_str()) else {
+            return false;
+        };
+        let strand = |other: &Hit| !matching.same_strand || other.strand == hit.strand;
+        // hits without coordinates (e.g. from hmmsearch) cover their target
+        let Some((from, to)) = span(hit) else {
+            return spans.hits.iter().any(|&(_, _, other)| strand(other));
+        };
+
+        let mut i = spans.hits.partition_point(|&(start, _, _)| start <= to);
+        while i > 0 && spans.reach[i - 1] >= from {
+            i -= 1;
+            let (start, end, other) = spans.hits[i];
+            if end < from || !strand(other) {
+                continue;
+            }
+            if span(other).is_none() {
+                return true;
+            }
+            let overlap = (end.min(to) - start.max(from) + 1) as f64;
+            let covers = |len: u64| overlap >= matching.min_overlap * len as f64;
+            if covers(to - from + 1) && covers(end - start + 1) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Where `hit` starts and ends on its target, lowest first.
+fn span(hit: &Hit) -> Option<(u64, u64)> {
+    let (from, to) = hit.ali().or(hit.env())?;
+    Some((from.min(to), from.max(to)))
+}
+
+/// Whether each of `hits` matches one of `others`: they are on the same
+/// target (and strand, if asked), and overlap by enough of both. Hits
+/// without coordinates match any hit on their target.
+pub fn matched(hits: &[Hit], others: &[Hit], matching: &Matching) -> Vec<bool> {
+    let targets = Targets::new(others);
+    hits.iter()
+        .map(|hit| targets.matches(hit, matching))
+        .collect()
+}
@@ -0,0 +1,154 @@
+//! Options read from a TOML config file with `--config`, so that option
+//! sets used run after run can be kept (and versioned) in one place.
+
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, Command};
+use toml::{Table, Value};
+
+/// The command line `args` with the options set in the config at `path`
+/// added after the subcommand, for `cmd` to parse again. Options given on
+/// the command line, or that conflict with ones that are, win over the
+/// config.
+///
+/// Keys are the long names of options, with `-` or `_`. Top level keys
+/// apply to each subcommand with that option, and keys in a table named
+/// after a subcommand (e.g. `[extract]`) only to that subcommand, over the
+/// top level.
+pub fn args(
+    mut cmd: Command,
+    path: &Path,
+    mut args: Vec<OsString>,
+    matches: &ArgMatches,
+) -> Result<Vec<OsString>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read config {}", path.display()))?;
+    let table: Table = contents
+        .parse()
+        .with_context(|| format!("Could not parse config {}", path.display()))?;
+
+    // propagate the global options to the subcommands
+    cmd.build();
+    let (name, sub_matches) = matches.subcommand().expect("subcommand is required");
+    let sub = cmd
+        .find_subcommand(name)
+        .expect("the subcommand was parsed");
+
+    let mut options: Vec<(&Arg, &Value)> = Vec::new();
+    for (key, value) in &table {
+        if let Value::Table(_) = value {
+            continue;
+        }
+        match option(sub, key) {
+            Some(arg) => set(&mut options, arg, value),
+            None if cmd.get_subcommands().any(|s| option(s, key).is_some()) => (),
+            None => bail!("{}: unknown option \"{}\"", path.display(), key),
+        }
+    }
+    for (key, value) in &table {
+        let Value::Table(table) = value else {
+            continue;
+        };
+        let Some(subcommand) = cmd.find_subcommand(key) else {
+            bail!("{}: unknown subcommand [{}]", path.display(), key);
+        };
+        for (k, v) in table {
+            match option(subcommand, k) {
+                Some(arg) if subcommand.get_name() == name => set(&mut options, arg, v),
+                Some(_) => (),
+                None => bail!("{}: unknown option \"{}\" in [{}]", path.display(), k, key),
+            }
+        }
+    }
+
+    let on_command_line = |arg: &Arg| {
+        sub_matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+    };
+    let mut config_args = Vec::new();
+    for (arg, value) in options {
+        let overridden = on_command_line(arg)
+            || sub.get_arguments().filter(|a| on_command_line(a)).any(|a| {
+                sub.get_arg_conflicts_with(a)
+                    .iter()
+                    .chain(sub.get_arg_conflicts_with(arg).iter())
+                    .any(|c| c.get_id() == a.get_id() || c.get_id() == arg.get_id())
+            });
+        if !overridden {
+            config_args.extend(option_args(arg, value).with_context(|| {
+                format!("{}: invalid value for \"{}\"", path.display(), arg.get_id())
+            })?);
+        }
+    }
+
+    let at = args
+        .iter()
+        .skip(1)
+        .position(|a| a == name)
+        .expect("the subcommand is in the arguments")
+        + 2;
+    args.splice(at..at, config_args);
+    Ok(args)
+}
+
+/// Set `arg` to `value`, over any value it was set to before.
+fn set<'a>(options: &mut Vec<(&'a Arg, &'a Value)>, arg: &'a Arg, value: &'a Value) {
+    options.retain(|(a, _)| a.get_id() != arg.get_id());
+    options.push((arg, value));
+}
+
+/// The option of `cmd` with the long name `key`, which can be written with
+/// underscores instead of dashes.
+fn option<'a>(cmd: &'a Command, key: &str) -> Option<&'a Arg> {
+    let long = key.replace('_', "-");
+    if long == "config" {
+        return None;
+    }
+    cmd.get_arguments()
+        .find(|a| a.get_long() == Some(long.as_str()))
+}
+
+/// The command line arguments that set `arg` to `value`.
+fn option_args(arg: &Arg, value: &Value) -> Result<Vec<OsString>> {
+    let long = format!("--{}", arg.get_long().expect("options have long names"));
+    let values = match value {
+        Value::Array(values) => values.iter().map(scalar).collect::<Result<Vec<_>>>()?,
+        value => vec![scalar(value)?],
+    };
+
+    Ok(match arg.get_action() {
+        ArgAction::SetTrue => match value {
+            Value::Boolean(true) => vec![long.into()],
+            Value::Boolean(false) => Vec::new(),
+            _ => bail!("expected true or false"),
+        },
+        ArgAction::Count => match value {
+            Value::Boolean(b) => vec![long.into(); *b as usize],
+            Value::Integer(n) if *n >= 0 => vec![long.into(); *n as usize],
+            _ => bail!("expected true, false or a count"),
+        },
+        ArgAction::Append => values
+            .iter()
+            .map(|v| format!("{}={}", long, v).into())
+            .collect(),
+        _ => match (values.as_slice(), arg.get_value_delimiter()) {
+            ([value], _) => vec![format!("{}={}", long, value).into()],
+            (values, Some(delimiter)) => {
+                vec![format!("{}={}", long, values.join(&delimiter.to_string())).into()]
+            }
+            _ => bail!("expected a single value"),
+        },
+    })
+}
+
+/// A value as it would be written on the command line.
+fn scalar(value: &Value) -> Result<String> {
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(d) => d.to_string(),
+        Value::Array(_) | Value::Table(_) => bail!("expected a single value"),
+    })
+}
@@ -0,0 +1,192 @@
+//! Conversion of tblout hits into other tabular formats.
+
+use std::{io::Write, str::FromStr};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::tblout::Hit;
+
+/// The formats a tblout can be converted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Tab separated values.
+    Tsv,
+    /// Comma separated values.
+    Csv,
+    /// A JSON array of records.
+    Json,
+    /// One JSON record per line.
+    Jsonl,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tsv" => Ok(Format::Tsv),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => bail!("Unknown format \"{}\"", s),
+        }
+    }
+}
+
+const COLUMNS: [&str; 20] = [
+    "target_name",
+    "target_accession",
+    "query_name",
+    "query_accession",
+    "hmm_from",
+    "hmm_to",
+    "ali_from",
+    "ali_to",
+    "env_from",
+    "env_to",
+    "sq_len",
+    "query_len",
+    "domain",
+    "strand",
+    "e_value",
+    "score",
+    "bias",
+    "description",
+    "start",
+    "end",
+];
+
+/// A hit as a JSON record, with missing values as `null`.
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    target_name: &'a str,
+    target_accession: &'a str,
+    query_name: &'a str,
+    query_accession: &'a str,
+    hmm_from: Option<u64>,
+    hmm_to: Option<u64>,
+    ali_from: Option<u64>,
+    ali_to: Option<u64>,
+    env_from: Option<u64>,
+    env_to: Option<u64>,
+    sq_len: Option<u64>,
+    query_len: Option<u64>,
+    domain: Option<u64>,
+    strand: String,
+    e_value: f64,
+    score: f64,
+    bias: f64,
+    description: Option<&'a str>,
+    start: Option<u64>,
+    end: Option<u64>,
+}
+
+impl<'a> From<&'a Hit> for Record<'a> {
+    fn from(hit: &'a Hit) -> Self {
+        let (start, end) = span(hit).unzip();
+        Self {
+            target_name: &hit.target_name,
+            target_accession: &hit.target_accession,
+            query_name: &hit.query_name,
+            query_accession: &hit.query_accession,
+            hmm_from: hit.hmm_from,
+            hmm_to: hit.hmm_to,
+            ali_from: hit.ali_from,
+            ali_to: hit.ali_to,
+            env_from: hit.env_from,
+            env_to: hit.env_to,
+            sq_len: hit.sq_len,
+            query_len: hit.query_len,
+            domain: hit.domain,
+            strand: hit.strand.to_string(),
+            e_value: hit.e_value,
+            score: hit.score,
+            bias: hit.bias,
+            description: hit.description.as_deref(),
+            start,
+            end,
+        }
+    }
+}
+
+/// Where the alignment (or failing that, the envelope) of `hit` starts and
+/// ends on the target, lowest first whatever the strand.
+fn span(hit: &Hit) -> Option<(u64, u64)> {
+    let (from, to) = hit.ali().or(hit.env())?;
+    Some((from.min(to), from.max(to)))
+}
+
+/// Write `hits` as a delimited table with a header row, or as JSON. The
+/// `start` and `end` columns have the coordinates of the alignment on the
+/// target in increasing order, whatever the strand.
+pub fn write_hits<W: Write>(mut writer: W, hits: &[Hit], format: Format) -> Result<()> {
+    let sep = match format {
+        Format::Tsv => "\t",
+        Format::Csv => ",",
+        Format::Json | Format::Jsonl => return write_json(writer, hits, format),
+    };
+
+    writeln!(writer, "{}", COLUMNS.join(sep))?;
+    for hit in hits {
+        let fields = [
+            hit.target_name.clone(),
+            hit.target_accession.clone(),
+            hit.query_name.clone(),
+            hit.query_accession.clone(),
+            opt(hit.hmm_from),
+            opt(hit.hmm_to),
+            opt(hit.ali_from),
+            opt(hit.ali_to),
+            opt(hit.env_from),
+            opt(hit.env_to),
+            opt(hit.sq_len),
+            opt(hit.query_len),
+            opt(hit.domain),
+            hit.strand.to_string(),
+            format!("{:e}", hit.e_value),
+            hit.score.to_string(),
+            hit.bias.to_string(),
+            hit.description.clone().unwrap_or_default(),
+            opt(span(hit).map(|(start, _)| start)),
+            opt(span(hit).map(|(_, end)| end)),
+        ];
+        let fields: Vec<String> = match format {
+            Format::Csv => fields.iter().map(|f| csv_escape(f)).collect(),
+            _ => fields.into_iter().collect(),
+        };
+        writeln!(writer, "{}", fields.join(sep))?;
+    }
+    Ok(())
+}
+
+/// Write `hits` as JSON records, in an array or one per line.
+fn write_json<W: Write>(mut writer: W, hits: &[Hit], format: Format) -> Result<()> {
+    let array = format == Format::Json;
+    if array {
+        writeln!(writer, "[")?;
+    }
+    for (i, hit) in hits.iter().enumerate() {
+        serde_json::to_writer(&mut writer, &Record::from(hit))?;
+        let last = i + 1 == hits.len();
+        writeln!(writer, "{}", if array && !last { "," } else { "" })?;
+    }
+    if array {
+        writeln!(writer, "]")?;
+    }
+    Ok(())
+}
+
+/// An optional column, empty when missing.
+fn opt(field: Option<u64>) -> String {
+    field.map(|f| f.to_string()).unwrap_or_default()
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
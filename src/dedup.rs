@@ -0,0 +1,93 @@
+//! Collapsing identical extracted sequences into one.
+
+use std::collections::HashMap;
+
+use noodles_fasta as fasta;
+
+use fasta::record::Definition;
+
+/// What became of a record given to [`Dedup::push`].
+#[derive(Debug)]
+pub enum Seen {
+    /// The first record with its sequence, to be written now.
+    New(fasta::Record),
+    /// The first record with its sequence, held back until
+    /// [`Dedup::finish`] to record its members.
+    Held,
+    /// A record with the same sequence as the kept one with this name.
+    Duplicate(String),
+}
+
+/// Keeps the first of each distinct sequence. Sequences are compared
+/// ignoring case, so soft-masking does not keep copies apart.
+#[derive(Debug, Default)]
+pub struct Dedup {
+    /// The index into `names` of each distinct sequence, uppercased.
+    seen: HashMap<Vec<u8>, usize>,
+    /// The name of each kept record.
+    names: Vec<String>,
+    /// The names of the duplicates of each kept record.
+    members: Vec<Vec<String>>,
+    /// The kept records, if they are held back to record their members.
+    held: Option<Vec<fasta::Record>>,
+}
+
+impl Dedup {
+    /// Start collapsing sequences. With `record_members`, the kept records
+    /// are held back until [`Dedup::finish`], which adds the names of their
+    /// duplicates to their descriptions.
+    pub fn new(record_members: bool) -> Self {
+        Self {
+            held: record_members.then(Vec::new),
+            ..Default::default()
+        }
+    }
+
+    /// Check `record` against the sequences seen so far.
+    pub fn push(&mut self, record: fasta::Record) -> Seen {
+        let key = record.sequence().as_ref().to_ascii_uppercase();
+        if let Some(&i) = self.seen.get(&key) {
+            self.members[i].push(String::from_utf8_lossy(record.name()).into_owned());
+            return Seen::Duplicate(self.names[i].clone());
+        }
+
+        self.seen.insert(key, self.names.len());
+        self.names
+            .push(String::from_utf8_lossy(record.name()).into_owned());
+        self.members.push(Vec::new());
+        match &mut self.held {
+            Some(held) => {
+                held.push(record);
+                Seen::Held
+            }
+            None => Seen::New(record),
+        }
+    }
+
+    /// The number of records collapsed into others so far.
+    pub fn collapsed(&self) -> usize {
+        self.members.iter().map(Vec::len).sum()
+    }
+
+    /// The held back records, with `members=` and the names of their
+    /// duplicates added to the descriptions of those that have any. Empty
+    /// unless members are recorded.
+    pub fn finish(self) -> Vec<fasta::Record> {
+        let held = self.held.unwrap_or_default();
+        held.into_iter()
+            .zip(self.members)
+            .map(|(record, members)| {
+                if members.is_empty() {
+                    return record;
+                }
+                let note = format!("members={}", members.join(","));
+                let description = match record.description() {
+                    Some(d) => format!("{} {}", String::from_utf8_lossy(d), note),
+                    None => note,
+                };
+                let definition = Definition::new(record.name(), Some(description.into_bytes()));
+                fasta::Record::new(definition, record.sequence().clone())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,698 @@
+//! A small expression language over the columns of a hit, for filters the
+//! threshold options can not express, e.g.
+//! `evalue < 1e-6 && (score > 40 || included) && (ali_to - ali_from).abs() > 200`.
+//!
+//! Numbers can be added, subtracted, multiplied and divided, and have the
+//! methods `abs()`, `ln()` and `log10()`. Numbers and strings (in quotes)
+//! are compared with `<`, `<=`, `>`, `>=`, `==` and `!=`, and the results
+//! combined with `&&`, `||` and `!`. A comparison with a column the hit
+//! does not have (e.g. `env_from` of an Infernal hit) is false.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::tblout::{Hit, Strand};
+
+/// A column of a hit that can be used in an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Target,
+    TargetAccession,
+    Query,
+    QueryAccession,
+    HmmFrom,
+    HmmTo,
+    AliFrom,
+    AliTo,
+    EnvFrom,
+    EnvTo,
+    SqLen,
+    QueryLen,
+    Domain,
+    Round,
+    Strand,
+    Evalue,
+    Score,
+    Bias,
+    Included,
+    Description,
+    Length,
+}
+
+impl Column {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "target" | "target_name" => Column::Target,
+            "target_accession" => Column::TargetAccession,
+            "query" | "query_name" => Column::Query,
+            "query_accession" => Column::QueryAccession,
+            "hmm_from" => Column::HmmFrom,
+            "hmm_to" => Column::HmmTo,
+            "ali_from" => Column::AliFrom,
+            "ali_to" => Column::AliTo,
+            "env_from" => Column::EnvFrom,
+            "env_to" => Column::EnvTo,
+            "sq_len" => Column::SqLen,
+            "query_len" | "qlen" => Column::QueryLen,
+            "domain" => Column::Domain,
+            "round" => Column::Round,
+            "strand" => Column::Strand,
+            "evalue" | "e_value" => Column::Evalue,
+            "score" => Column::Score,
+            "bias" => Column::Bias,
+            "included" => Column::Included,
+            "description" => Column::Description,
+            "length" => Column::Length,
+            _ => return None,
+        })
+    }
+
+    fn kind(&self) -> Kind {
+        match self {
+            Column::Target
+            | Column::TargetAccession
+            | Column::Query
+            | Column::QueryAccession
+            | Column::Strand
+            | Column::Description => Kind::Str,
+            Column::Included => Kind::Bool,
+            _ => Kind::Num,
+        }
+    }
+
+    fn value<'a>(&self, hit: &'a Hit) -> Value<'a> {
+        let num = |n: Option<u64>| n.map_or(Value::Missing, |n| Value::Num(n as f64));
+        match self {
+            Column::Target => Value::Str(&hit.target_name),
+            Column::TargetAccession => Value::Str(&hit.target_accession),
+            Column::Query => Value::Str(&hit.query_name),
+            Column::QueryAccession => Value::Str(&hit.query_accession),
+            Column::HmmFrom => num(hit.hmm_from),
+            Column::HmmTo => num(hit.hmm_to),
+            Column::AliFrom => num(hit.ali_from),
+            Column::AliTo => num(hit.ali_to),
+            Column::EnvFrom => num(hit.env_from),
+            Column::EnvTo => num(hit.env_to),
+            Column::SqLen => num(hit.sq_len),
+            Column::QueryLen => num(hit.query_len),
+            Column::Domain => num(hit.domain),
+            Column::Round => num(hit.round),
+            Column::Strand => Value::Str(match hit.strand {
+                Strand::Positive => "+",
+                Strand::Negative => "-",
+            }),
+            Column::Evalue => Value::Num(hit.e_value),
+            Column::Score => Value::Num(hit.score),
+            Column::Bias => Value::Num(hit.bias),
+            Column::Included => hit.included.map_or(Value::Missing, Value::Bool),
+            Column::Description => hit
+                .description
+                .as_deref()
+                .map_or(Value::Missing, Value::Str),
+            // of the alignment, or the whole target without one
+            Column::Length => num(hit
+                .ali()
+                .map(|(from, to)| from.max(to) - from.min(to) + 1)
+                .or(hit.sq_len)),
+        }
+    }
+}
+
+/// The type of a value, checked when parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Num,
+    Str,
+    Bool,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Num => "a number",
+            Kind::Str => "a string",
+            Kind::Bool => "true or false",
+        })
+    }
+}
+
+/// A value while evaluating, `Missing` for a column the hit does not have.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value<'a> {
+    Num(f64),
+    Str(&'a str),
+    Bool(bool),
+    Missing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Abs,
+    Ln,
+    Log10,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Num(f64),
+    Str(String),
+    Bool(bool),
+    Column(Column),
+    Neg(Box<Node>),
+    Not(Box<Node>),
+    Method(Box<Node>, Method),
+    Binary(Box<Node>, Op, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn eval<'a>(&'a self, hit: &'a Hit) -> Value<'a> {
+        match self {
+            Node::Num(n) => Value::Num(*n),
+            Node::Str(s) => Value::Str(s),
+            Node::Bool(b) => Value::Bool(*b),
+            Node::Column(column) => column.value(hit),
+            Node::Neg(node) => match node.eval(hit) {
+                Value::Num(n) => Value::Num(-n),
+                _ => Value::Missing,
+            },
+            // a comparison with a missing column is false, so its negation
+            // is true
+            Node::Not(node) => Value::Bool(!node.eval(hit).is_true()),
+            Node::Method(node, method) => match node.eval(hit) {
+                Value::Num(n) => Value::Num(match method {
+                    Method::Abs => n.abs(),
+                    Method::Ln => n.ln(),
+                    Method::Log10 => n.log10(),
+                }),
+                _ => Value::Missing,
+            },
+            Node::And(a, b) => Value::Bool(a.eval(hit).is_true() && b.eval(hit).is_true()),
+            Node::Or(a, b) => Value::Bool(a.eval(hit).is_true() || b.eval(hit).is_true()),
+            Node::Binary(a, op, b) => match (a.eval(hit), b.eval(hit)) {
+                (Value::Num(a), Value::Num(b)) => match op {
+                    Op::Add => Value::Num(a + b),
+                    Op::Sub => Value::Num(a - b),
+                    Op::Mul => Value::Num(a * b),
+                    Op::Div => Value::Num(a / b),
+                    _ => Value::Bool(op.compare(a.partial_cmp(&b))),
+                },
+                (Value::Str(a), Value::Str(b)) => Value::Bool(op.compare(Some(a.cmp(b)))),
+                (Value::Bool(a), Value::Bool(b)) => Value::Bool(op.compare(Some(a.cmp(&b)))),
+                _ => Value::Missing,
+            },
+        }
+    }
+}
+
+impl Value<'_> {
+    fn is_true(&self) -> bool {
+        *self == Value::Bool(true)
+    }
+}
+
+impl Op {
+    /// Whether two values ordered by `ordering` pass this comparison.
+    fn compare(&self, ordering: Option<std::cmp::Ordering>) -> bool {
+        use std::cmp::Ordering::*;
+        matches!(
+            (self, ordering),
+            (Op::Lt, Some(Less))
+                | (Op::Le, Some(Less | Equal))
+                | (Op::Gt, Some(Greater))
+                | (Op::Ge, Some(Greater | Equal))
+                | (Op::Eq, Some(Equal))
+                | (Op::Ne, Some(Less | Greater))
+        )
+    }
+}
+
+/// A parsed filter expression, true or false for each hit.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    /// Whether `hit` passes the expression.
+    pub fn matches(&self, hit: &Hit) -> bool {
+        self.root.eval(hit).is_true()
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for Expr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let (root, kind) = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            bail!("Unexpected {} in \"{}\"", token, s);
+        }
+        ensure!(
+            kind == Kind::Bool,
+            "The expression \"{}\" is {}, not true or false",
+            s,
+            kind
+        );
+        Ok(Self {
+            source: s.to_string(),
+            root,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    /// Punctuation and operators.
+    Sym(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Str(s) => write!(f, "\"{}\"", s),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Sym(sym) => write!(f, "\"{}\"", sym),
+        }
+    }
+}
+
+/// The operators and punctuation, longest first so `<=` is not read as `<`.
+const SYMBOLS: &[&str] = &[
+    "&&", "||", "<=", ">=", "==", "!=", "<", ">", "!", "+", "-", "*", "/", "(", ")", ".",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            // numbers, with any fraction and exponent
+            let mut end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.'))
+                .unwrap_or(rest.len());
+            if rest[..end].ends_with(['e', 'E']) && rest[end..].starts_with(['-', '+']) {
+                end += 1 + rest[end + 1..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len() - end - 1);
+            }
+            let number = &rest[..end];
+            tokens.push(Token::Num(number.parse().with_context(|| {
+                format!("Invalid number \"{}\" in \"{}\"", number, s)
+            })?));
+            end
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            end
+        } else if c == '"' || c == '\'' {
+            let end = rest[1..]
+                .find(c)
+                .with_context(|| format!("Unterminated string in \"{}\"", s))?;
+            tokens.push(Token::Str(rest[1..=end].to_string()));
+            end + 2
+        } else {
+            let sym = SYMBOLS
+                .iter()
+                .find(|sym| rest.starts_with(**sym))
+                .with_context(|| format!("Unexpected \"{}\" in \"{}\"", c, s))?;
+            tokens.push(Token::Sym(sym));
+            sym.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser, from the loosest binding operator (`||`)
+/// to the tightest (method calls). Each rule returns its node and its kind.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Move past the symbol `sym` if it is next.
+    fn eat(&mut self, sym: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Sym(s)) if *s == sym);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, sym: &str) -> Result<()> {
+        if self.eat(sym) {
+            return Ok(());
+        }
+        match self.peek() {
+            Some(token) => bail!("Expected \"{}\", found {}", sym, token),
+            None => bail!("Expected \"{}\" at the end", sym),
+        }
+    }
+
+    fn or(&mut self) -> Result<(Node, Kind)> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            let right = self.and()?;
+            left = (
+                Node::Or(
+                    Box::new(bool_operand(left, "||")?),
+                    Box::new(bool_operand(right, "||")?),
+                ),
+                Kind::Bool,
+            );
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<(Node, Kind)> {
+        let mut left = self.not()?;
+        while self.eat("&&") {
+            let right = self.not()?;
+            left = (
+                Node::And(
+                    Box::new(bool_operand(left, "&&")?),
+                    Box::new(bool_operand(right, "&&")?),
+                ),
+                Kind::Bool,
+            );
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<(Node, Kind)> {
+        if self.eat("!") {
+            let operand = self.not()?;
+            return Ok((Node::Not(Box::new(bool_operand(operand, "!")?)), Kind::Bool));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<(Node, Kind)> {
+        let left = self.sum()?;
+        let op = match self.peek() {
+            Some(Token::Sym("<")) => Op::Lt,
+            Some(Token::Sym("<=")) => Op::Le,
+            Some(Token::Sym(">")) => Op::Gt,
+            Some(Token::Sym(">=")) => Op::Ge,
+            Some(Token::Sym("==")) => Op::Eq,
+            Some(Token::Sym("!=")) => Op::Ne,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.sum()?;
+        ensure!(
+            left.1 == right.1,
+            "Can not compare {} with {}",
+            left.1,
+            right.1
+        );
+        Ok((
+            Node::Binary(Box::new(left.0), op, Box::new(right.0)),
+            Kind::Bool,
+        ))
+    }
+
+    fn sum(&mut self) -> Result<(Node, Kind)> {
+        let mut left = self.product()?;
+        loop {
+            let op = match () {
+                _ if self.eat("+") => Op::Add,
+                _ if self.eat("-") => Op::Sub,
+                _ => return Ok(left),
+            };
+            let right = self.product()?;
+            left = arithmetic(left, op, right)?;
+        }
+    }
+
+    fn product(&mut self) -> Result<(Node, Kind)> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match () {
+                _ if self.eat("*") => Op::Mul,
+                _ if self.eat("/") => Op::Div,
+                _ => return Ok(left),
+            };
+            let right = self.unary()?;
+            left = arithmetic(left, op, right)?;
+        }
+    }
+
+    fn unary(&mut self) -> Result<(Node, Kind)> {
+        if self.eat("-") {
+            let (node, kind) = self.unary()?;
+            ensure!(kind == Kind::Num, "Can not negate {}", kind);
+            return Ok((Node::Neg(Box::new(node)), Kind::Num));
+        }
+        self.method()
+    }
+
+    fn method(&mut self) -> Result<(Node, Kind)> {
+        let (mut node, kind) = self.primary()?;
+        while self.eat(".") {
+            let method = match self.peek() {
+                Some(Token::Ident(name)) => match name.as_str() {
+                    "abs" => Method::Abs,
+                    "ln" => Method::Ln,
+                    "log10" => Method::Log10,
+                    _ => bail!("Unknown method \"{}\"", name),
+                },
+                Some(token) => bail!("Expected a method after \".\", found {}", token),
+                None => bail!("Expected a method at the end"),
+            };
+            self.pos += 1;
+            self.expect("(")?;
+            self.expect(")")?;
+            ensure!(
+                kind == Kind::Num,
+                "Methods only apply to numbers, not {}",
+                kind
+            );
+            node = Node::Method(Box::new(node), method);
+        }
+        Ok((node, kind))
+    }
+
+    fn primary(&mut self) -> Result<(Node, Kind)> {
+        let token = self
+            .peek()
+            .cloned()
+            .context("The expression ends too soon")?;
+        self.pos += 1;
+        Ok(match token {
+            Token::Num(n) => (Node::Num(n), Kind::Num),
+            Token::Str(s) => (Node::Str(s), Kind::Str),
+            Token::Ident(name) => match name.as_str() {
+                "true" => (Node::Bool(true), Kind::Bool),
+                "false" => (Node::Bool(false), Kind::Bool),
+                _ => {
+                    let column = Column::from_name(&name)
+                        .with_context(|| format!("Unknown column \"{}\"", name))?;
+                    (Node::Column(column), column.kind())
+                }
+            },
+            Token::Sym("(") => {
+                let inner = self.or()?;
+                self.expect(")")?;
+                inner
+            }
+            token => bail!("Unexpected {}", token),
+        })
+    }
+}
+
+/// Check that the operand of `op` is true or false.
+fn bool_operand((node, kind): (Node, Kind), op: &str) -> Result<Node> {
+    ensure!(
+        kind == Kind::Bool,
+        "\"{}\" needs true or false, not {}",
+        op,
+        kind
+    );
+    Ok(node)
+}
+
+/// `left op right`, for numbers.
+fn arithmetic(left: (Node, Kind), op: Op, right: (Node, Kind)) -> Result<(Node, Kind)> {
+    ensure!(
+        left.1 == Kind::Num && right.1 == Kind::Num,
+        "Can only do arithmetic with numbers, not {} and {}",
+        left.1,
+        right.1
+    );
+    Ok((
+        Node::Binary(Box::new(left.0), op, Box::new(right.0)),
+        Kind::Num,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit() -> Hit {
+        Hit {
+            target_name: "chr1".to_string(),
+            target_accession: "-".to_string(),
+            query_name: "LINE1".to_string(),
+            query_accession: "-".to_string(),
+            hmm_from: Some(1),
+            hmm_to: Some(300),
+            ali_from: Some(1200),
+            ali_to: Some(901),
+            env_from: None,
+            env_to: None,
+            sq_len: Some(5000),
+            query_len: Some(300),
+            domain: None,
+            round: None,
+            strand: Strand::Negative,
+            e_value: 1e-20,
+            score: 50.0,
+            bias: 0.5,
+            included: Some(true),
+            description: None,
+            line: String::new(),
+        }
+    }
+
+    fn eval(s: &str) -> bool {
+        s.parse::<Expr>().unwrap().matches(&hit())
+    }
+
+    fn error(s: &str) -> String {
+        s.parse::<Expr>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        assert!(eval("1 + 2 * 3 == 7"));
+        assert!(eval("(1 + 2) * 3 == 9"));
+        assert!(eval("10 - 4 - 3 == 3"));
+        assert!(eval("12 / 3 / 2 == 2"));
+        assert!(eval("-score.abs() < 0"));
+        assert!(eval("-(ali_to - ali_from) == 299"));
+        // && binds tighter than ||
+        assert!(eval("true || false && false"));
+        assert!(eval("false && false || true"));
+        assert!(!eval("false && (false || true)"));
+        // ! applies to the whole comparison
+        assert!(eval("!score > 100"));
+        assert!(eval("!!included"));
+    }
+
+    #[test]
+    fn columns() {
+        assert!(eval("evalue < 1e-6 && (score > 40 || included)"));
+        assert!(eval("evalue == 1E-20 && bias <= 0.5"));
+        assert!(eval("(ali_to - ali_from).abs() + 1 == length"));
+        assert!(eval("sq_len.log10() > 3.69 && sq_len.log10() < 3.7"));
+        assert!(eval("score.ln() > 3.9"));
+    }
+
+    #[test]
+    fn string_comparison() {
+        assert!(eval("target == \"chr1\""));
+        assert!(eval("target != 'chr2'"));
+        assert!(eval("target < \"chr2\" && target >= \"chr1\""));
+        assert!(eval("strand == \"-\""));
+        assert!(eval("query_name == \"LINE1\" && target_accession == \"-\""));
+        assert!(!eval("target == \"chr\""));
+    }
+
+    #[test]
+    fn missing_columns_are_false() {
+        assert!(!eval("env_from > 0"));
+        assert!(!eval("env_from <= 0"));
+        assert!(eval("!(env_from > 0)"));
+        assert!(!eval("description == \"x\""));
+        assert!(!eval("domain + 1 > 0"));
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        assert_eq!(
+            error("target == \"chr1"),
+            "Unterminated string in \"target == \"chr1\""
+        );
+        assert_eq!(error("score @ 3"), "Unexpected \"@\" in \"score @ 3\"");
+        assert_eq!(
+            error("score > 1e"),
+            "Invalid number \"1e\" in \"score > 1e\""
+        );
+        assert_eq!(error("scor > 3"), "Unknown column \"scor\"");
+        assert_eq!(error("score.sqrt() > 3"), "Unknown method \"sqrt\"");
+        assert_eq!(
+            error("target.abs() == 1"),
+            "Methods only apply to numbers, not a string"
+        );
+        assert_eq!(
+            error("score > \"40\""),
+            "Can not compare a number with a string"
+        );
+        assert_eq!(
+            error("target + 1 > 2"),
+            "Can only do arithmetic with numbers, not a string and a number"
+        );
+        assert_eq!(error("-target == \"x\""), "Can not negate a string");
+        assert_eq!(
+            error("score && included"),
+            "\"&&\" needs true or false, not a number"
+        );
+        assert_eq!(error("!score"), "\"!\" needs true or false, not a number");
+        assert_eq!(
+            error("score + 1"),
+            "The expression \"score + 1\" is a number, not true or false"
+        );
+        assert_eq!(error("(score > 1"), "Expected \")\" at the end");
+        assert_eq!(error("score > 1)"), "Unexpected \")\" in \"score > 1)\"");
+        assert_eq!(error("score >"), "The expression ends too soon");
+        assert_eq!(error("score > > 1"), "Unexpected \">\"");
+        assert_eq!(error(""), "The expression ends too soon");
+    }
+
+    #[test]
+    fn displays_as_written() {
+        let expr: Expr = "score  >  40".parse().unwrap();
+        assert_eq!(expr.to_string(), "score  >  40");
+    }
+}
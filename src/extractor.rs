@@ -0,0 +1,2578 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use fasta::record::Definition;
+use indicatif::ProgressBar;
+use log::{info, warn};
+use noodles_fasta as fasta;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use tempfile::{tempdir, tempdir_in, Builder, TempDir};
+
+use crate::{
+    annotation::Annotation,
+    fetcher::{EslSfetch, Samtools, SequenceFetcher},
+    filter::{HitFilter, Rank},
+    header::{Template, Values},
+    index::{self, with_suffix},
+    manifest::Entry,
+    merge::merge_hits,
+    native::{reverse_complement, NativeFetcher},
+    orf::extend_orf,
+    prepare::{self, fasta_files, prepare_fasta, Compression, Duplicates},
+    progress,
+    regions::Regions,
+    sam::{self, SamWriter},
+    stitch::{stitch_groups, stitched_hit},
+    stream::StreamFetcher,
+    tblout::{Format, Hit, Strand, Tblout},
+    translate::{trim_to_stop, GeneticCode},
+    twobit::{self, TwoBitFetcher},
+    workdir,
+};
+
+/// How sequences are pulled out of the prepared fasta.
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// Shell out to `esl-sfetch` (part of HMMER's easel) at this path.
+    EslSfetch(PathBuf),
+    /// Shell out to `samtools faidx` at this path.
+    Samtools(PathBuf),
+    /// Slice the sequences in-process.
+    Native,
+    /// Open a [`SequenceFetcher`] of your own on the prepared fasta.
+    Custom(fn(&Path) -> Result<Box<dyn SequenceFetcher>>),
+}
+
+/// Which coordinates of a hit are extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coords {
+    /// The alignment, `ali_from..ali_to`.
+    Ali,
+    /// The envelope, `env_from..env_to`. Hits without an envelope (e.g.
+    /// from Infernal) fall back to the alignment.
+    Env,
+}
+
+impl FromStr for Coords {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ali" => Ok(Coords::Ali),
+            "env" => Ok(Coords::Env),
+            _ => bail!("Unknown coordinates \"{}\"", s),
+        }
+    }
+}
+
+/// What is done with soft-masked (lowercase) bases in the extracted
+/// sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mask {
+    /// Leave them lowercase.
+    #[default]
+    Keep,
+    /// Uppercase them, unmasking them.
+    Upper,
+    /// Replace them with `N`, hard-masking them.
+    Hard,
+}
+
+impl FromStr for Mask {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(Mask::Keep),
+            "upper" => Ok(Mask::Upper),
+            "hard" => Ok(Mask::Hard),
+            _ => bail!("Unknown masking \"{}\"", s),
+        }
+    }
+}
+
+impl Mask {
+    fn apply(&self, seq: &mut [u8]) {
+        match self {
+            Mask::Keep => (),
+            Mask::Upper => seq.make_ascii_uppercase(),
+            Mask::Hard => seq
+                .iter_mut()
+                .filter(|b| b.is_ascii_lowercase())
+                .for_each(|b| *b = b'N'),
+        }
+    }
+}
+
+/// How much is trimmed off an end of each hit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trim {
+    /// This many bases.
+    Bases(u64),
+    /// This fraction of the hit, rounded down.
+    Fraction(f64),
+}
+
+impl Default for Trim {
+    fn default() -> Self {
+        Trim::Bases(0)
+    }
+}
+
+impl FromStr for Trim {
+    type Err = anyhow::Error;
+
+    /// A whole number of bases, or a fraction below a half, e.g. `0.1`.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(bases) = s.parse() {
+            return Ok(Trim::Bases(bases));
+        }
+        match s.parse::<f64>() {
+            Ok(fraction) if (0.0..0.5).contains(&fraction) => Ok(Trim::Fraction(fraction)),
+            _ => bail!(
+                "Can not trim \"{}\", expected a number of bases or a fraction below 0.5",
+                s
+            ),
+        }
+    }
+}
+
+impl Trim {
+    /// The bases to trim off a hit of `length`.
+    fn bases(&self, length: u64) -> u64 {
+        match self {
+            Trim::Bases(bases) => *bases,
+            Trim::Fraction(fraction) => (length as f64 * fraction) as u64,
+        }
+    }
+}
+
+/// The order the extracted sequences are written in, instead of that of
+/// the tblout. Ties keep their order in the tblout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// Lowest E-value first.
+    Evalue,
+    /// Highest bit score first.
+    Score,
+    /// By target name, then start on it.
+    Coord,
+    /// Longest extracted region first.
+    Length,
+}
+
+impl FromStr for SortBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "evalue" => Ok(SortBy::Evalue),
+            "score" => Ok(SortBy::Score),
+            "coord" => Ok(SortBy::Coord),
+            "length" => Ok(SortBy::Length),
+            _ => bail!("Unknown sort order \"{}\"", s),
+        }
+    }
+}
+
+impl SortBy {
+    fn compare(&self, a: &Hit, b: &Hit, opts: &Options) -> Ordering {
+        match self {
+            SortBy::Evalue => a.e_value.total_cmp(&b.e_value),
+            SortBy::Score => b.score.total_cmp(&a.score),
+            SortBy::Coord => {
+                let start =
+                    |hit: &Hit| span(hit, opts).map_or((1, hit.sq_len), |(lo, hi)| (lo, Some(hi)));
+                a.target_name
+                    .cmp(&b.target_name)
+                    .then_with(|| start(a).cmp(&start(b)))
+            }
+            SortBy::Length => region_length(b, opts).cmp(&region_length(a, opts)),
+        }
+    }
+}
+
+/// How the extracted sequences are oriented and named.
+#[derive(Debug, Clone, Default)]
+struct Options {
+    species_id: String,
+    revcomp_minus: bool,
+    /// `None` uses the envelope for domains and the alignment otherwise.
+    coords: Option<Coords>,
+    flank_5p: u64,
+    flank_3p: u64,
+    /// Trimmed off each hit before any flanks are added.
+    trim_5p: Trim,
+    trim_3p: Trim,
+    /// Replaces the default `species:Eevalue:name` headers.
+    header_format: Option<Template>,
+    /// Name the sequences `target/from-to` as `esl-sfetch -c` does.
+    esl_names: bool,
+    /// Name the sequences `target_from-to:strand` as `seqkit subseq` does.
+    seqkit_names: bool,
+    /// Name the sequences only by their target and coordinates, and put
+    /// the rest in the description.
+    meta_in_description: bool,
+    /// Translate the extracted sequences with this code.
+    translate: Option<GeneticCode>,
+    /// Cut translations at their first stop.
+    trim_translation: bool,
+    /// What to do with soft-masked bases.
+    mask: Mask,
+    /// Features to record the overlaps of hits with.
+    annotation: Option<Annotation>,
+    /// Extract the region next to each hit instead of the hit.
+    adjacent: Option<Adjacent>,
+    /// Regions may wrap around the end of circular targets.
+    circular: bool,
+    /// The circular targets; all of them if empty.
+    circular_targets: HashSet<String>,
+    /// New names for targets in the headers.
+    rename: HashMap<String, String>,
+}
+
+impl Options {
+    /// Whether any hits are trimmed.
+    fn is_trimmed(&self) -> bool {
+        self.trim_5p != Trim::Bases(0) || self.trim_3p != Trim::Bases(0)
+    }
+
+    /// Whether `target` is circular.
+    fn is_circular(&self, target: &str) -> bool {
+        self.circular
+            && (self.circular_targets.is_empty() || self.circular_targets.contains(target))
+    }
+}
+
+/// A region next to a hit, relative to its strand.
+#[derive(Debug, Clone, Copy)]
+enum Adjacent {
+    /// This many bases before (5' of) the hit.
+    Upstream(u64),
+    /// This many bases after (3' of) the hit.
+    Downstream(u64),
+}
+
+/// Configures and builds an [`Extractor`].
+#[derive(Debug, Clone)]
+pub struct ExtractorBuilder {
+    tbls: Vec<PathBuf>,
+    header_format: Option<String>,
+    with_coords: bool,
+    merge_distance: Option<u64>,
+    stitch: bool,
+    full_seq: bool,
+    min_length: Option<u64>,
+    max_length: Option<u64>,
+    extend_orf: Option<GeneticCode>,
+    threads: usize,
+    decompress_threads: usize,
+    no_index: bool,
+    workdir: Option<PathBuf>,
+    tmpdir: Option<PathBuf>,
+    prepare_in_place: bool,
+    reuse_index: bool,
+    keep_index: bool,
+    overwrite_index: bool,
+    progress: bool,
+    skip_missing: bool,
+    strict_duplicates: bool,
+    clip: bool,
+    sort: Option<SortBy>,
+    strict_names: bool,
+    exclude: Option<Regions>,
+    max_excluded: f64,
+    trim_excluded: bool,
+    fastas: Vec<PathBuf>,
+    fasta_dir: Option<PathBuf>,
+    format: Option<Format>,
+    lenient: bool,
+    backend: Backend,
+    filter: HitFilter,
+    options: Options,
+}
+
+impl ExtractorBuilder {
+    /// Start building an extractor for the tblout file at `tbl`.
+    pub fn new<P: Into<PathBuf>>(tbl: P) -> Self {
+        Self {
+            tbls: vec![tbl.into()],
+            header_format: None,
+            with_coords: false,
+            merge_distance: None,
+            stitch: false,
+            full_seq: false,
+            min_length: None,
+            max_length: None,
+            extend_orf: None,
+            threads: 1,
+            decompress_threads: 1,
+            no_index: false,
+            workdir: None,
+            tmpdir: None,
+            prepare_in_place: false,
+            reuse_index: false,
+            keep_index: false,
+            overwrite_index: false,
+            progress: false,
+            skip_missing: false,
+            strict_duplicates: false,
+            clip: false,
+            sort: None,
+            strict_names: false,
+            exclude: None,
+            max_excluded: 0.0,
+            trim_excluded: false,
+            fastas: Vec::new(),
+            fasta_dir: None,
+            format: None,
+            lenient: false,
+            backend: Backend::Native,
+            filter: HitFilter::default(),
+            options: Options::default(),
+        }
+    }
+
+    /// Also read the hits of the tblout file at `tbl`. The hits of all of
+    /// the tblouts are filtered together.
+    pub fn add_tbl<P: Into<PathBuf>>(mut self, tbl: P) -> Self {
+        self.tbls.push(tbl.into());
+        self
+    }
+
+    /// The fasta file used for the search. If not set, the target file
+    /// recorded in the (first) tblout metadata is used.
+    pub fn fasta<P: Into<PathBuf>>(mut self, fasta: P) -> Self {
+        self.fastas = vec![fasta.into()];
+        self
+    }
+
+    /// Also look for targets in the fasta file at `fasta`, for hits whose
+    /// targets are spread over several files. Each target has to be in
+    /// exactly one of them.
+    pub fn add_fasta<P: Into<PathBuf>>(mut self, fasta: P) -> Self {
+        self.fastas.push(fasta.into());
+        self
+    }
+
+    /// Also look for targets in every fasta file in `dir`, as with
+    /// [`ExtractorBuilder::add_fasta`].
+    pub fn fasta_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.fasta_dir = Some(dir.into());
+        self
+    }
+
+    /// Force the tblout layout instead of reading it from the metadata.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Skip tblout lines that can not be parsed, instead of failing. The
+    /// skipped lines are counted in [`Extractor::skipped_lines`].
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// The backend used to extract sequences. Defaults to
+    /// [`Backend::Native`].
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// The filters hits have to pass to be extracted.
+    pub fn filter(mut self, filter: HitFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Hits with an E-value above this are skipped. Defaults to `1e-5`.
+    pub fn e_value_threshold(mut self, e_value_threshold: f64) -> Self {
+        self.filter.e_value_threshold = e_value_threshold;
+        self
+    }
+
+    /// Hits with a bit score below this are skipped.
+    pub fn min_score(mut self, min_score: f64) -> Self {
+        self.filter.min_score = Some(min_score);
+        self
+    }
+
+    /// Species ID added to the start of each header.
+    pub fn species_id<S: Into<String>>(mut self, species_id: S) -> Self {
+        self.options.species_id = species_id.into();
+        self
+    }
+
+    /// Extract minus strand hits forwards and reverse complement them
+    /// here, recording the strand in the header.
+    pub fn revcomp_minus(mut self, revcomp_minus: bool) -> Self {
+        self.options.revcomp_minus = revcomp_minus;
+        self
+    }
+
+    /// Whether to extract the alignment or the envelope of each hit. By
+    /// default domains use the envelope and everything else the alignment.
+    pub fn coords(mut self, coords: Coords) -> Self {
+        self.options.coords = Some(coords);
+        self
+    }
+
+    /// Extend each hit by this many bases upstream (5') and downstream
+    /// (3'), relative to its strand. Flanks are clamped to the ends of the
+    /// target sequence.
+    pub fn flank(mut self, five_prime: u64, three_prime: u64) -> Self {
+        self.options.flank_5p = five_prime;
+        self.options.flank_3p = three_prime;
+        self
+    }
+
+    /// Trim each hit by this much at its upstream (5') and downstream (3')
+    /// end, relative to its strand, e.g. to drop the ragged edges of
+    /// alignments. Hits trimmed to nothing are skipped.
+    pub fn trim(mut self, five_prime: Trim, three_prime: Trim) -> Self {
+        self.options.trim_5p = five_prime;
+        self.options.trim_3p = three_prime;
+        self
+    }
+
+    /// Extract this many bases upstream (5') of each hit, relative to its
+    /// strand, instead of the hit itself, e.g. for promoters. Replaces any
+    /// flanks; hits at the start of their target are skipped.
+    pub fn upstream(mut self, bases: u64) -> Self {
+        self.options.adjacent = Some(Adjacent::Upstream(bases));
+        self
+    }
+
+    /// Extract this many bases downstream (3') of each hit, relative to its
+    /// strand, instead of the hit itself. Replaces any flanks; hits at the
+    /// end of their target are skipped.
+    pub fn downstream(mut self, bases: u64) -> Self {
+        self.options.adjacent = Some(Adjacent::Downstream(bases));
+        self
+    }
+
+    /// Treat the targets as circular, e.g. plasmids or organelles, so flanks
+    /// and adjacent regions wrap around the origin instead of being clamped,
+    /// and hits past the end of the target (from a search of a doubled
+    /// sequence) are extracted across it. Regions across the origin are
+    /// reported with coordinates past the end of the target.
+    pub fn circular(mut self, circular: bool) -> Self {
+        self.options.circular = circular;
+        self
+    }
+
+    /// Only treat these targets as circular, with
+    /// [`ExtractorBuilder::circular`].
+    pub fn circular_targets<I, S>(mut self, targets: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options.circular_targets = targets.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Give targets new names in the headers, e.g. public accessions for
+    /// the scaffold IDs of an assembly. Targets are still looked up in the
+    /// fasta, and written to the BED, GFF and manifest, by their names in
+    /// the tblout.
+    pub fn rename_targets(mut self, names: HashMap<String, String>) -> Self {
+        self.options.rename = names;
+        self
+    }
+
+    /// A template for the headers, with placeholders `{species}`,
+    /// `{target}`, `{from}`, `{to}`, `{strand}`, `{evalue}`, `{score}`,
+    /// `{query}` and `{name}` (the `target/from-to` name esl-sfetch gives).
+    pub fn header_format<S: Into<String>>(mut self, header_format: S) -> Self {
+        self.header_format = Some(header_format.into());
+        self
+    }
+
+    /// Put `target:from-to(strand)` in the headers instead of the
+    /// `esl-sfetch` name. Ignored if a header format is given.
+    pub fn with_coords(mut self, with_coords: bool) -> Self {
+        self.with_coords = with_coords;
+        self
+    }
+
+    /// Name each sequence `target/from-to` (or `target` for hits without
+    /// coordinates), exactly as `esl-sfetch -c from..to` does, with `from >
+    /// to` on the minus strand, for pipelines that expect its names. Unlike
+    /// the default names, domains are not numbered and nothing is added.
+    /// Replaced if a header format is given.
+    pub fn esl_names(mut self, esl_names: bool) -> Self {
+        self.options.esl_names = esl_names;
+        self
+    }
+
+    /// Name each sequence `target_from-to:strand` (or `target` for hits
+    /// without coordinates), as `seqkit subseq` names the regions of a BED
+    /// file, with `from < to` on either strand and the strand as `+` or
+    /// `-`, so its output and ours can be used in place of each other.
+    /// Replaced if a header format is given, and by
+    /// [`ExtractorBuilder::esl_names`].
+    pub fn seqkit_names(mut self, seqkit_names: bool) -> Self {
+        self.options.seqkit_names = seqkit_names;
+        self
+    }
+
+    /// Name each sequence only `target_from_to` (or `target` for hits
+    /// without coordinates), and put the query, E-value, score, strand and
+    /// species in its description as `key=value` pairs instead, for tools
+    /// that cut names at the first colon. The name is still replaced if a
+    /// header format, [`ExtractorBuilder::esl_names`] or
+    /// [`ExtractorBuilder::seqkit_names`] is given.
+    pub fn meta_in_description(mut self, meta_in_description: bool) -> Self {
+        self.options.meta_in_description = meta_in_description;
+        self
+    }
+
+    /// Translate the extracted sequences into protein with `code`. They
+    /// are already on the strand of the hit, so they are read from their
+    /// first base.
+    pub fn translate(mut self, code: GeneticCode) -> Self {
+        self.options.translate = Some(code);
+        self
+    }
+
+    /// Cut each translation at its first stop codon, keeping only the open
+    /// stretch it starts with.
+    pub fn trim_translation(mut self, trim_translation: bool) -> Self {
+        self.options.trim_translation = trim_translation;
+        self
+    }
+
+    /// Extend each hit to the open reading frame it is in on its strand,
+    /// using the stop codons of `code`: downstream through the next stop
+    /// codon, and upstream to the first start codon after the stop codon
+    /// before it. Flanks are added to the extended hit.
+    pub fn extend_orf(mut self, code: GeneticCode) -> Self {
+        self.extend_orf = Some(code);
+        self
+    }
+
+    /// Merge hits on the same target and strand that are within this many
+    /// bases of each other into one.
+    pub fn merge_distance(mut self, distance: u64) -> Self {
+        self.merge_distance = Some(distance);
+        self
+    }
+
+    /// Join the hits of each query on the same target and strand into one
+    /// sequence, in order along the strand, e.g. for markers with several
+    /// exons found as separate hits. The boundaries of the segments are
+    /// recorded in the description. Length filters apply to the hits before
+    /// they are stitched, and the BED, GFF and manifest give the region the
+    /// stitched hits span. Overlapping hits are stitched as they are, so may
+    /// want merging first.
+    pub fn stitch(mut self, stitch: bool) -> Self {
+        self.stitch = stitch;
+        self
+    }
+
+    /// Extract the whole sequence of each target with a passing hit, once,
+    /// instead of the hits, e.g. the contigs a marker was found on. Each is
+    /// named and described after its best hit (by E-value), and on the plus
+    /// strand. Can not be combined with anything changing the regions of
+    /// the hits.
+    pub fn full_seq(mut self, full_seq: bool) -> Self {
+        self.full_seq = full_seq;
+        self
+    }
+
+    /// Skip hits whose extracted region, after flanking, merging and ORF
+    /// extension, is shorter than this.
+    pub fn min_length(mut self, min_length: u64) -> Self {
+        self.min_length = Some(min_length);
+        self
+    }
+
+    /// Skip hits whose extracted region, after flanking, merging and ORF
+    /// extension, is longer than this.
+    pub fn max_length(mut self, max_length: u64) -> Self {
+        self.max_length = Some(max_length);
+        self
+    }
+
+    /// The number of threads to extract with. Defaults to 1.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// The number of threads to decompress gzip (with `pigz`, if it is
+    /// installed) or xz compressed fasta with. Defaults to 1.
+    pub fn decompress_threads(mut self, threads: usize) -> Self {
+        self.decompress_threads = threads.max(1);
+        self
+    }
+
+    /// Read the regions straight out of the fasta, decompressing it on the
+    /// fly, instead of preparing a copy and indexing it. This takes a pass
+    /// over the fasta for the lengths of its sequences and another for each
+    /// batch of hits, so is quicker for a handful of hits, e.g. to look at
+    /// a few hits on a large genome. The backend is not used.
+    pub fn no_index(mut self, no_index: bool) -> Self {
+        self.no_index = no_index;
+        self
+    }
+
+    /// Prepare and index the fasta in `dir` instead of a temporary directory,
+    /// and keep it there for later runs, which use it as it is as long as
+    /// the fasta it was prepared from has not changed since. Each fasta gets
+    /// its own directory in `dir`.
+    pub fn workdir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.workdir = Some(dir.into());
+        self
+    }
+
+    /// Make the temporary directory the fasta is prepared and indexed in
+    /// inside `dir`, instead of wherever `TMPDIR` points, e.g. to put a
+    /// large genome on a disk with the space for it.
+    pub fn tmpdir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.tmpdir = Some(dir.into());
+        self
+    }
+
+    /// Prepare and index each fasta in a temporary directory next to it,
+    /// rather than in the temporary directory, so that copies take space on
+    /// the disk the fasta is on. The directories are removed with
+    /// [`Extractor::close`].
+    pub fn prepare_in_place(mut self, prepare_in_place: bool) -> Self {
+        self.prepare_in_place = prepare_in_place;
+        self
+    }
+
+    /// Use the fasta in place if it already has an index for the backend
+    /// next to it (`.ssi` for esl-sfetch, `.fai` for samtools and native), instead of
+    /// copying and indexing it.
+    pub fn reuse_index(mut self, reuse_index: bool) -> Self {
+        self.reuse_index = reuse_index;
+        self
+    }
+
+    /// Save the index that is built next to the fasta, so later runs can
+    /// reuse it. Only done for uncompressed fasta used as is.
+    pub fn keep_index(mut self, keep_index: bool) -> Self {
+        self.keep_index = keep_index;
+        self
+    }
+
+    /// Allow [`ExtractorBuilder::keep_index`] to replace an existing index.
+    pub fn overwrite_index(mut self, overwrite_index: bool) -> Self {
+        self.overwrite_index = overwrite_index;
+        self
+    }
+
+    /// Parse the header format, read the tblout and filter its hits.
+    fn read_tblout(&mut self) -> Result<Tblout> {
+        if self.with_coords && self.header_format.is_none() {
+            self.header_format = Some(if self.options.species_id.is_empty() {
+                "{target}:{from}-{to}({strand}):E{evalue}".to_string()
+            } else {
+                "{species}:E{evalue}:{target}:{from}-{to}({strand})".to_string()
+            });
+        }
+        if let Some(template) = &self.header_format {
+            self.options.header_format = Some(Template::parse(template)?);
+        }
+
+        // read the tblout to get the metadata and the hits
+        let mut tblout = Tblout::from_paths(&self.tbls, self.format, self.lenient)?;
+        let format = tblout.format();
+        ensure!(
+            self.options.translate.is_none() || !format.is_protein(),
+            "Can not translate the hits of a protein search"
+        );
+        ensure!(
+            self.extend_orf.is_none() || !format.is_protein(),
+            "Can not extend the hits of a protein search to open reading frames"
+        );
+        ensure!(
+            self.options.adjacent.is_none() || !format.is_protein(),
+            "Can not extract the regions next to the hits of a protein search"
+        );
+        ensure!(
+            self.extend_orf.is_none() || !self.options.circular,
+            "Can not extend hits to open reading frames on circular targets"
+        );
+        if self.stitch {
+            ensure!(
+                self.extend_orf.is_none() && self.options.adjacent.is_none(),
+                "Can not stitch hits extended to open reading frames, or the regions next to them"
+            );
+            ensure!(
+                self.options.flank_5p == 0 && self.options.flank_3p == 0,
+                "Can not stitch flanked hits"
+            );
+            ensure!(
+                !self.options.circular,
+                "Can not stitch hits on circular targets"
+            );
+        }
+        ensure!(
+            !self.full_seq
+                || (!self.stitch
+                    && self.merge_distance.is_none()
+                    && self.extend_orf.is_none()
+                    && self.options.adjacent.is_none()
+                    && self.options.flank_5p == 0
+                    && self.options.flank_3p == 0
+                    && !self.options.is_trimmed()),
+            "Can not flank, trim, merge, stitch or extend whole target sequences"
+        );
+        tblout.apply_filter(&self.filter);
+        Ok(tblout)
+    }
+
+    /// The hits of `tblout`, merged if asked to, or one for each target
+    /// covering all of it with [`ExtractorBuilder::full_seq`].
+    fn hits(&self, tblout: Tblout) -> Vec<Hit> {
+        let hits = tblout.into_hits();
+        if self.full_seq {
+            return whole_targets(hits);
+        }
+        match self.merge_distance {
+            Some(distance) => merge_hits(hits, distance),
+            None => hits,
+        }
+    }
+
+    /// Trim or drop the hits overlapping the excluded regions.
+    fn exclude_hits(&self, hits: &mut Vec<Hit>) {
+        let Some(regions) = &self.exclude else {
+            return;
+        };
+        let before = hits.len();
+        let mut trimmed = 0;
+        hits.retain_mut(|hit| {
+            let (lo, hi) = match hit_coords(hit, &self.options) {
+                Some((from, to)) => (from.min(to), from.max(to)),
+                None => match hit.sq_len {
+                    Some(length) => (1, length),
+                    None => return true,
+                },
+            };
+            let (mut start, mut end) = (lo, hi);
+            if self.trim_excluded && hit_coords(hit, &self.options).is_some() {
+                let overlapping = regions.overlapping(&hit.target_name, lo, hi);
+                if let Some(&(_, to)) = overlapping.first().filter(|r| r.0 <= lo) {
+                    start = to + 1;
+                }
+                if let Some(&(from, _)) = overlapping.last().filter(|r| r.1 >= hi) {
+                    end = from.saturating_sub(1);
+                }
+                if start > end {
+                    return false;
+                }
+            }
+            let excluded = regions.overlap(&hit.target_name, start, end);
+            if excluded as f64 > self.max_excluded * (end - start + 1) as f64 {
+                return false;
+            }
+            if (start, end) != (lo, hi) {
+                trimmed += 1;
+                let (from, to) = match hit.strand {
+                    Strand::Positive => (start, end),
+                    Strand::Negative => (end, start),
+                };
+                hit.ali_from = Some(from);
+                hit.ali_to = Some(to);
+                hit.env_from = Some(from);
+                hit.env_to = Some(to);
+            }
+            true
+        });
+        info!(
+            "Skipped {} hits overlapping the excluded regions{}",
+            before - hits.len(),
+            match self.trim_excluded {
+                true => format!(", trimming {} others", trimmed),
+                false => String::new(),
+            }
+        );
+    }
+
+    /// Whether the region extracted for `hit` passes the length filters.
+    /// Hits covering a target of unknown length pass.
+    fn in_length_range(&self, hit: &Hit) -> bool {
+        let Some(length) = region_length(hit, &self.options) else {
+            return true;
+        };
+        self.min_length.is_none_or(|min| length >= min)
+            && self.max_length.is_none_or(|max| length <= max)
+    }
+
+    /// Read and filter the hits as [`ExtractorBuilder::build`] would, but
+    /// only work out what would be extracted, without preparing, indexing or
+    /// even opening the fasta. Hits are not extended to open reading frames,
+    /// and flanks are only clamped to target lengths in the tblout.
+    pub fn dry_run(mut self) -> Result<Vec<Planned>> {
+        let tblout = self.read_tblout()?;
+        let mut hits = self.hits(tblout);
+        self.exclude_hits(&mut hits);
+        if self.extend_orf.is_some() {
+            warn!("Not extending hits to open reading frames in a dry run");
+        }
+        if self.options.adjacent.is_some() || self.options.is_trimmed() {
+            let before = hits.len();
+            hits.retain(|hit| !empty_region(hit, &self.options));
+            log_empty_regions(before - hits.len(), &self.options);
+        }
+        if self.min_length.is_some() || self.max_length.is_some() {
+            let before = hits.len();
+            hits.retain(|hit| self.in_length_range(hit));
+            info!(
+                "Skipped {} hits outside of the length range",
+                before - hits.len()
+            );
+        }
+
+        if self.stitch {
+            let files = vec![0; hits.len()];
+            (hits, _, _) = stitch(hits, files);
+        }
+
+        if let Some(sort) = self.sort {
+            hits.sort_by(|a, b| sort.compare(a, b, &self.options));
+        }
+        let headers = unique_headers(&hits, &self.options, self.strict_names)?;
+        Ok(hits
+            .iter()
+            .zip(headers)
+            .map(|(hit, header)| Planned {
+                target: hit.target_name.clone(),
+                region: coords(hit, &self.options)
+                    .map(|(from, to)| (from.min(to), from.max(to)))
+                    .or(hit.sq_len.map(|len| (1, len))),
+                strand: hit.strand,
+                header,
+            })
+            .collect())
+    }
+
+    /// Skip hits on targets that are not in the fasta, instead of failing.
+    /// The skipped hits are counted in [`Extractor::missing_targets`].
+    pub fn skip_missing(mut self, skip_missing: bool) -> Self {
+        self.skip_missing = skip_missing;
+        self
+    }
+
+    /// Fail, naming them, if a fasta has a sequence name more than once,
+    /// instead of telling the copies apart. By default the later copies are
+    /// renamed `name__2`, `name__3` and so on in the prepared fasta, and
+    /// each hit on the name is put on the copy of the length in the tblout
+    /// (or the first, if that does not settle it). Headers, the BED, GFF and
+    /// manifest all use the name in the fasta, and
+    /// [`Extractor::write_sam`] fails, as its targets have to be unique.
+    /// Only fasta that is prepared is checked, not fasta used in place, e.g.
+    /// with [`ExtractorBuilder::reuse_index`].
+    pub fn strict_duplicates(mut self, strict_duplicates: bool) -> Self {
+        self.strict_duplicates = strict_duplicates;
+        self
+    }
+
+    /// Clip hits reaching outside of their target in the fasta to it, and
+    /// drop hits entirely outside of it, instead of failing. This only
+    /// happens when the tblout is from a search of another fasta.
+    pub fn clip(mut self, clip: bool) -> Self {
+        self.clip = clip;
+        self
+    }
+
+    /// Keep soft-masked (lowercase) bases as they are, uppercase them or
+    /// replace them with `N`. Done before any translation.
+    pub fn mask(mut self, mask: Mask) -> Self {
+        self.options.mask = mask;
+        self
+    }
+
+    /// Write the sequences in this order, instead of that of the tblout.
+    pub fn sort(mut self, sort: SortBy) -> Self {
+        self.sort = Some(sort);
+        self
+    }
+
+    /// Fail if two hits would get the same header, instead of adding `_2`,
+    /// `_3` and so on to the later ones.
+    pub fn strict_names(mut self, strict_names: bool) -> Self {
+        self.strict_names = strict_names;
+        self
+    }
+
+    /// Record the features of `annotation` that each hit overlaps, in the
+    /// manifest and the `{features}` placeholder of the header format.
+    pub fn annotate(mut self, annotation: Annotation) -> Self {
+        self.options.annotation = Some(annotation);
+        self
+    }
+
+    /// Drop hits overlapping `regions`, e.g. repeats or known contaminants,
+    /// by more than [`ExtractorBuilder::max_excluded_fraction`] of their
+    /// length. Hits are checked before flanking and ORF extension.
+    pub fn exclude(mut self, regions: Regions) -> Self {
+        self.exclude = Some(regions);
+        self
+    }
+
+    /// The fraction of a hit that may overlap the excluded regions before it
+    /// is dropped. Defaults to 0, dropping any hit that overlaps them.
+    pub fn max_excluded_fraction(mut self, fraction: f64) -> Self {
+        self.max_excluded = fraction;
+        self
+    }
+
+    /// Trim excluded regions off the ends of hits before checking how much
+    /// of them is excluded, so only hits with excluded bases inside them are
+    /// dropped. Hits covering their whole target are never trimmed.
+    pub fn trim_excluded(mut self, trim_excluded: bool) -> Self {
+        self.trim_excluded = trim_excluded;
+        self
+    }
+
+    /// Show progress bars on stderr while the fasta is copied or
+    /// decompressed and the hits are extracted, if stderr is a terminal.
+    /// Off by default.
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Read the tblout, prepare and index the fasta.
+    pub fn build(mut self) -> Result<Extractor> {
+        let tblout = self.read_tblout()?;
+        let skipped_lines = tblout.skipped();
+        let hits_passing = tblout.hits().len();
+        let hits_read = hits_passing + tblout.filtered();
+        let target_file = tblout.meta().target_file.clone();
+        let format = tblout.format();
+        let program = match tblout.meta().program.as_str() {
+            "" => format.program().to_string(),
+            program => program.to_string(),
+        };
+        let mut hits = self.hits(tblout);
+
+        let mut sources = self.fastas.clone();
+        if let Some(dir) = &self.fasta_dir {
+            let found = fasta_files(dir)?;
+            ensure!(
+                !found.is_empty(),
+                "No fasta files found in {}",
+                dir.display()
+            );
+            sources.extend(found);
+        }
+        if sources.is_empty() {
+            sources.push(target_file);
+        }
+
+        let tmpdir = match &self.tmpdir {
+            Some(dir) => tempdir_in(dir)
+                .with_context(|| format!("Could not create tempdir in {}", dir.display()))?,
+            None => tempdir().context("Could not create tempdir")?,
+        };
+        let mut in_place = Vec::new();
+        let mut genomes = sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                // keep fasta files with the same name apart
+                let dir = match (&self.workdir, sources.len()) {
+                    (Some(workdir), _) => workdir::genome_dir(workdir, source)?,
+                    (None, _) if self.prepare_in_place => {
+                        let parent = source
+                            .parent()
+                            .filter(|parent| !parent.as_os_str().is_empty())
+                            .unwrap_or(Path::new("."));
+                        let dir = Builder::new()
+                            .prefix(".extract_nhmmer_tblout-")
+                            .tempdir_in(parent)
+                            .with_context(|| {
+                                format!("Could not create a directory next to {}", source.display())
+                            })?;
+                        let path = dir.path().to_path_buf();
+                        in_place.push(dir);
+                        path
+                    }
+                    (None, 1) => tmpdir.path().to_path_buf(),
+                    (None, _) => tmpdir.path().join(i.to_string()),
+                };
+                fs::create_dir_all(&dir).context("Could not create tempdir")?;
+                self.open_genome(source, &dir)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let pool = if self.threads > 1 {
+            Some(
+                ThreadPoolBuilder::new()
+                    .num_threads(self.threads)
+                    .build()
+                    .context("Could not start the thread pool")?,
+            )
+        } else {
+            None
+        };
+
+        // check every target is there before anything is extracted
+        let lengths = target_lengths(&genomes)?;
+        resolve_duplicates(&mut hits, &genomes, &mut self.options);
+        let mut missing: BTreeMap<String, usize> = BTreeMap::new();
+        for hit in hits
+            .iter()
+            .filter(|hit| !lengths.contains_key(&hit.target_name))
+        {
+            *missing.entry(hit.target_name.clone()).or_default() += 1;
+        }
+        if let Some(target) = missing.keys().next() {
+            if !self.skip_missing {
+                bail!(
+                    "Sequence {} is not in the fasta ({} of the targets hit are missing; use --skip-missing to skip their hits)",
+                    target,
+                    missing.len()
+                );
+            }
+            hits.retain(|hit| !missing.contains_key(&hit.target_name));
+        }
+
+        // flanks and ORFs are clamped to the target length, and headers and
+        // length filters may need it for hits covering the whole target, so
+        // the lengths in the fasta are used over any in the tblout
+        let mut mismatched = 0;
+        let mut clipped = 0;
+        let before = hits.len();
+        for hit in hits.iter_mut() {
+            let length = lengths[&hit.target_name];
+            if hit.sq_len.is_some_and(|sq_len| sq_len != length) {
+                mismatched += 1;
+            }
+            hit.sq_len = Some(length);
+
+            // hits on circular targets may run once past the end
+            let circular = self.options.is_circular(&hit.target_name);
+            let out_of_range = [hit.ali(), hit.env()]
+                .into_iter()
+                .flatten()
+                .find(|&(from, to)| {
+                    let (lo, hi) = (from.min(to), from.max(to));
+                    match circular {
+                        true => lo < 1 || lo > 2 * length || hi - lo >= length,
+                        false => lo < 1 || hi > length,
+                    }
+                });
+            if let Some((from, to)) = out_of_range {
+                ensure!(
+                    self.clip,
+                    "The hit of {} at {}..{} on {} reaches outside of its sequence in the fasta (length {}). Was this fasta searched? Use --clip to clip hits to their sequences",
+                    hit.query_name,
+                    from,
+                    to,
+                    hit.target_name,
+                    length
+                );
+                clipped += 1;
+            }
+        }
+        if mismatched > 0 {
+            warn!(
+                "{} hits have a different sequence length in the tblout than in the fasta",
+                mismatched
+            );
+        }
+        if clipped > 0 {
+            hits.retain_mut(clip);
+            info!(
+                "Clipped {} hits to their sequences, dropping {} entirely outside of them",
+                clipped,
+                before - hits.len()
+            );
+        }
+        self.exclude_hits(&mut hits);
+        let length_filtered = self.min_length.is_some() || self.max_length.is_some();
+
+        // which of the fasta files the target of each hit is in
+        let mut files = if genomes.len() > 1 {
+            let mut targets = HashMap::new();
+            for (i, genome) in genomes.iter().enumerate() {
+                for name in genome.target_lengths()?.into_keys() {
+                    if let Some(j) = targets.insert(name.clone(), i) {
+                        bail!(
+                            "Sequence {} is in both {} and {}",
+                            name,
+                            sources[j].display(),
+                            sources[i].display()
+                        );
+                    }
+                }
+            }
+            hits.iter()
+                .map(|hit| {
+                    targets.get(&hit.target_name).copied().with_context(|| {
+                        format!("Sequence {} not found in any fasta", hit.target_name)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![0; hits.len()]
+        };
+
+        if let Some(code) = &self.extend_orf {
+            info!("Extending hits to open reading frames");
+            extend_orfs(&mut hits, &files, &mut genomes, &self.options, code)?;
+        }
+
+        if self.options.adjacent.is_some() || self.options.is_trimmed() {
+            let before = hits.len();
+            (hits, files) = hits
+                .into_iter()
+                .zip(files)
+                .filter(|(hit, _)| !empty_region(hit, &self.options))
+                .unzip();
+            log_empty_regions(before - hits.len(), &self.options);
+        }
+
+        if length_filtered {
+            let before = hits.len();
+            (hits, files) = hits
+                .into_iter()
+                .zip(files)
+                .filter(|(hit, _)| self.in_length_range(hit))
+                .unzip();
+            info!(
+                "Skipped {} hits outside of the length range",
+                before - hits.len()
+            );
+        }
+
+        let mut segments = vec![Vec::new(); hits.len()];
+        if self.stitch {
+            (hits, files, segments) = stitch(hits, files);
+        }
+
+        if let Some(sort) = self.sort {
+            let mut sorted: Vec<(Hit, (usize, Vec<Hit>))> = hits
+                .into_iter()
+                .zip(files.into_iter().zip(segments))
+                .collect();
+            sorted.sort_by(|a, b| sort.compare(&a.0, &b.0, &self.options));
+            (hits, (files, segments)) = sorted.into_iter().unzip();
+        }
+        let headers = unique_headers(&hits, &self.options, self.strict_names)?;
+
+        Ok(Extractor {
+            tmpdir,
+            in_place,
+            genomes,
+            files,
+            threads: self.threads,
+            pool,
+            progress: self.progress,
+            missing,
+            skipped_lines,
+            hits_read,
+            hits_passing,
+            tbls: self.tbls,
+            sources,
+            hits,
+            segments,
+            headers,
+            format,
+            program,
+            options: self.options,
+        })
+    }
+
+    /// Prepare and index the fasta at `source`, using `dir` for any copy.
+    fn open_genome(&self, source: &Path, dir: &Path) -> Result<Genome> {
+        if twobit::is_twobit(source)? {
+            info!("Reading 2bit {:?} in place", source);
+            if !matches!(self.backend, Backend::Native) {
+                warn!("2bit genomes are always read natively, whatever the backend");
+            }
+            let fetcher = TwoBitFetcher::new(source)?;
+            let fetchers = (0..self.threads)
+                .map(|_| fetcher.try_clone())
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(Genome {
+                fasta: source.to_path_buf(),
+                fetchers,
+                duplicates: Duplicates::new(),
+            });
+        }
+        if self.no_index {
+            info!("Reading {:?} without indexing it", source);
+            let fetcher = StreamFetcher::new(source)?;
+            let fetchers = (0..self.threads)
+                .map(|_| fetcher.try_clone())
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(Genome {
+                fasta: source.to_path_buf(),
+                fetchers,
+                duplicates: Duplicates::new(),
+            });
+        }
+
+        // FASTQ is always converted, and an index next to it is of the FASTQ
+        let fastq = prepare::is_fastq(source)?;
+        let source_index = if self.reuse_index && !fastq {
+            existing_index(source, &self.backend)
+        } else {
+            None
+        };
+        // BGZF can be read in place, without decompressing it first
+        let bgzf = matches!(self.backend, Backend::Native) && !fastq && index::is_bgzf(source)?;
+        // a copy an earlier run prepared and indexed in the workdir
+        let kept = match &self.workdir {
+            Some(_) if source_index.is_none() && !bgzf => {
+                workdir::prepared(dir, source)?.and_then(|(fasta, duplicates)| {
+                    existing_index(&fasta, &self.backend).map(|i| (fasta, duplicates, i))
+                })
+            }
+            _ => None,
+        };
+        let prepared = source_index.is_none() && kept.is_none() && !bgzf;
+        // fasta used in place is not checked for duplicate names
+        let (fasta, mut duplicates) = match (&source_index, &kept) {
+            (Some(index), _) => {
+                info!("Reusing index {:?}", index);
+                (source.to_path_buf(), Duplicates::new())
+            }
+            (None, Some((fasta, duplicates, _))) => {
+                info!("Reusing the fasta prepared in {:?}", fasta);
+                (fasta.clone(), duplicates.clone())
+            }
+            (None, None) if bgzf => {
+                info!("Input fasta is BGZF-compressed, reading it in place");
+                (source.to_path_buf(), Duplicates::new())
+            }
+            (None, None) => {
+                if self.workdir.is_some() {
+                    workdir::clear(dir)?;
+                }
+                prepare::check_space(source, dir)?;
+                // copy the fasta into the temporary or working directory
+                let fasta = prepare_fasta(source, dir, self.progress, self.decompress_threads)?;
+                info!("New fasta location: {:?}", fasta);
+                (fasta, Duplicates::new())
+            }
+        };
+        let index = source_index.clone().or(kept.map(|(_, _, index)| index));
+
+        // the prepared fasta is indexed in memory once, for the backends and
+        // to find names it has more than once
+        let mut fai = None;
+        if prepared && !matches!(self.backend, Backend::Custom(_)) {
+            info!("Indexing fasta");
+            let mut built = fasta::index(&fasta)
+                .with_context(|| format!("Could not index fasta {}", fasta.display()))?;
+            duplicates =
+                prepare::rename_duplicates(source, &fasta, &built, self.strict_duplicates)?;
+            // renaming moves the sequences along
+            if !duplicates.is_empty() {
+                built = fasta::index(&fasta)
+                    .with_context(|| format!("Could not index fasta {}", fasta.display()))?;
+            }
+            fai = Some(built);
+        }
+
+        // index the fasta
+        let fetcher: Box<dyn SequenceFetcher> = match &self.backend {
+            Backend::EslSfetch(esl_sfetch) => Box::new(EslSfetch::new(
+                esl_sfetch,
+                &fasta,
+                dir,
+                index.is_some(),
+                fai.as_ref(),
+            )?),
+            Backend::Samtools(samtools) => {
+                // samtools uses the index built above instead of its own
+                if let Some(fai) = &fai {
+                    index::write_fai(&with_suffix(&fasta, ".fai"), fai)?;
+                }
+                Box::new(Samtools::new(
+                    samtools,
+                    &fasta,
+                    dir,
+                    index.is_some() || fai.is_some(),
+                )?)
+            }
+            Backend::Native => Box::new(match (&index, fai) {
+                (Some(index), _) => {
+                    let index = fasta::fai::read(index)
+                        .with_context(|| format!("Could not read index {}", index.display()))?;
+                    NativeFetcher::with_index(&fasta, index)?
+                }
+                (None, Some(fai)) => NativeFetcher::with_index(&fasta, fai)?,
+                (None, None) if bgzf => NativeFetcher::from_bgzf(&fasta)?,
+                (None, None) => NativeFetcher::from_path(&fasta)?,
+            }),
+            Backend::Custom(open) => open(&fasta)?,
+        };
+        if self.keep_index && fastq {
+            warn!(
+                "Not keeping the index of {:?}, as it is of the fasta converted from it",
+                source
+            );
+        } else if self.keep_index && !duplicates.is_empty() {
+            warn!(
+                "Not keeping the index of {:?}, as it is of the copy with its duplicate names renamed",
+                source
+            );
+        } else if self.keep_index && source_index.is_none() {
+            keep_index(source, &fasta, fetcher.as_ref(), self.overwrite_index)?;
+        }
+        if prepared && self.workdir.is_some() {
+            // the native backend only indexes in memory
+            if matches!(self.backend, Backend::Native) {
+                fetcher.keep_index(&fasta, true)?;
+            }
+            workdir::record(dir, source, &fasta, &duplicates)?;
+        }
+
+        // one fetcher per thread
+        let mut fetchers = (1..self.threads)
+            .map(|_| fetcher.try_clone())
+            .collect::<Result<Vec<_>>>()?;
+        fetchers.push(fetcher);
+        Ok(Genome {
+            fasta,
+            fetchers,
+            duplicates,
+        })
+    }
+}
+
+/// A hit that would be extracted, from [`ExtractorBuilder::dry_run`].
+#[derive(Debug, Clone)]
+pub struct Planned {
+    /// The sequence the hit is on.
+    pub target: String,
+    /// The 1-based, inclusive region that would be extracted, lowest
+    /// coordinate first. `None` for a whole target of unknown length.
+    pub region: Option<(u64, u64)>,
+    /// The strand of the hit.
+    pub strand: Strand,
+    /// The header the extracted sequence would get.
+    pub header: String,
+}
+
+/// A prepared and indexed fasta file.
+struct Genome {
+    fasta: PathBuf,
+    /// One fetcher per thread.
+    fetchers: Vec<Box<dyn SequenceFetcher>>,
+    /// The names the fasta has more than once, with what each copy was
+    /// renamed in the prepared fasta.
+    duplicates: Duplicates,
+}
+
+impl Genome {
+    /// The length of each sequence.
+    fn target_lengths(&self) -> Result<HashMap<String, u64>> {
+        self.fetchers[0].lengths()
+    }
+
+    /// How many hits each thread extracts at a time. Backends that run a
+    /// program once per batch want batches large enough to make spawning it
+    /// cheap but small enough to keep the sequences of a batch in memory.
+    /// With more than one thread, batches are large enough to be worth
+    /// splitting.
+    fn batch_size(&self, threads: usize) -> usize {
+        let batch_size = self.fetchers[0].batch_size().max(1);
+        if threads > 1 {
+            batch_size.max(1_000)
+        } else {
+            batch_size
+        }
+    }
+
+    /// Extract the sequences for `hits`, in order, splitting them between
+    /// the threads.
+    fn extract(
+        &mut self,
+        hits: &[Hit],
+        segments: &[Vec<Hit>],
+        headers: &[String],
+        pool: Option<&ThreadPool>,
+        threads: usize,
+        opts: &Options,
+    ) -> Result<Vec<fasta::Record>> {
+        let size = hits.len().div_ceil(threads).max(1);
+        let chunks = hits
+            .chunks(size)
+            .zip(segments.chunks(size))
+            .zip(headers.chunks(size));
+        let jobs = chunks.zip(self.fetchers.iter_mut()).collect();
+        let extracted = run_jobs(pool, jobs, |(((chunk, segments), headers), fetcher)| {
+            fetch_hits(fetcher.as_mut(), chunk, segments, headers, opts)
+        });
+
+        let mut records = Vec::with_capacity(hits.len());
+        for chunk in extracted {
+            records.extend(chunk?);
+        }
+        Ok(records)
+    }
+}
+
+/// Extracts the sequences of tblout hits from a fasta file. Build one
+/// with [`ExtractorBuilder`].
+pub struct Extractor {
+    tmpdir: TempDir,
+    /// The directories next to the fasta files they were prepared in, with
+    /// [`ExtractorBuilder::prepare_in_place`].
+    in_place: Vec<TempDir>,
+    genomes: Vec<Genome>,
+    /// The index into `genomes` of the fasta holding each hit's target.
+    files: Vec<usize>,
+    threads: usize,
+    /// Only used with more than one thread.
+    pool: Option<ThreadPool>,
+    progress: bool,
+    /// The number of hits skipped on each target not in the fasta.
+    missing: BTreeMap<String, usize>,
+    /// The number of tblout lines skipped as they could not be parsed.
+    skipped_lines: usize,
+    /// The number of hits read from the tblouts, before any were filtered.
+    hits_read: usize,
+    /// The number of those that passed the filters.
+    hits_passing: usize,
+    /// The tblouts and fasta files read.
+    tbls: Vec<PathBuf>,
+    sources: Vec<PathBuf>,
+    hits: Vec<Hit>,
+    /// The hits stitched into each of `hits`, in the order they are joined;
+    /// empty for hits that are not stitched.
+    segments: Vec<Vec<Hit>>,
+    /// The header of each hit's sequence, unique across them.
+    headers: Vec<String>,
+    format: Format,
+    program: String,
+    options: Options,
+}
+
+impl Extractor {
+    /// The number of hits that passed the filters.
+    pub fn len(&self) -> usize {
+        self.hits.len()
+    }
+
+    /// Whether no hits passed the filters.
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    /// The hits that passed the filters, in the order they are extracted.
+    pub fn hits(&self) -> &[Hit] {
+        &self.hits
+    }
+
+    /// The header of the sequence extracted for each hit.
+    pub fn headers(&self) -> &[String] {
+        &self.headers
+    }
+
+    /// The targets that are not in the fasta, with the number of hits on
+    /// each that were skipped. Only ever filled with
+    /// [`ExtractorBuilder::skip_missing`].
+    pub fn missing_targets(&self) -> &BTreeMap<String, usize> {
+        &self.missing
+    }
+
+    /// The number of tblout lines that were skipped as they could not be
+    /// parsed. Only ever non-zero with [`ExtractorBuilder::lenient`].
+    pub fn skipped_lines(&self) -> usize {
+        self.skipped_lines
+    }
+
+    /// The number of hits read from the tblouts, before any were filtered.
+    pub fn hits_read(&self) -> usize {
+        self.hits_read
+    }
+
+    /// The number of hits that passed the filters, before any were skipped
+    /// as their targets are missing, or were merged or stitched.
+    pub fn hits_passing(&self) -> usize {
+        self.hits_passing
+    }
+
+    /// The tblouts the hits were read from, as given.
+    pub fn tbls(&self) -> &[PathBuf] {
+        &self.tbls
+    }
+
+    /// The fasta files the targets were extracted from, as given (or found
+    /// in the fasta directory, or named in the tblout), before they were
+    /// prepared.
+    pub fn sources(&self) -> &[PathBuf] {
+        &self.sources
+    }
+
+    /// The location of the prepared fasta, or the first of them if the
+    /// targets are in several files.
+    pub fn fasta(&self) -> &Path {
+        &self.genomes[0].fasta
+    }
+
+    /// The number of hits whose sequences [`Extractor::records`] extracts
+    /// at a time.
+    pub fn batch_size(&self) -> usize {
+        self.genomes[0]
+            .batch_size(self.threads)
+            .saturating_mul(self.threads)
+    }
+
+    /// An iterator over the extracted sequences, with renamed headers.
+    pub fn records(&mut self) -> Records<'_> {
+        self.records_from(0)
+    }
+
+    /// An iterator over the extracted sequences of the hits from `start`
+    /// on, e.g. to resume an interrupted run.
+    pub fn records_from(&mut self, start: usize) -> Records<'_> {
+        let bar = progress::hits(self.hits.len() as u64, self.progress);
+        bar.set_position(start as u64);
+        Records {
+            extractor: self,
+            next_hit: start,
+            buf: VecDeque::new(),
+            bar,
+        }
+    }
+
+    /// Write a 6 column BED of the regions that are extracted, named by
+    /// the headers of the extracted sequences. BED scores are integers
+    /// from 0 to 1000, so the score column is the bit score rounded and
+    /// clamped to that range.
+    pub fn write_bed<W: Write>(&self, mut writer: W) -> Result<()> {
+        let regions = self.regions()?;
+        let originals = original_names(&self.genomes);
+        for ((hit, header), (start, end)) in self.hits.iter().zip(&self.headers).zip(regions) {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                original_name(&originals, &hit.target_name),
+                start - 1,
+                end,
+                header,
+                hit.score.round().clamp(0.0, 1000.0) as u32,
+                hit.strand
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write a GFF3 feature for each region that is extracted, with the
+    /// header of the extracted sequence as its ID.
+    pub fn write_gff<W: Write>(&self, mut writer: W) -> Result<()> {
+        let kind = if self.format.is_protein() {
+            "protein_match"
+        } else {
+            "nucleotide_match"
+        };
+
+        writeln!(writer, "##gff-version 3")?;
+        let regions = self.regions()?;
+        let originals = original_names(&self.genomes);
+        for ((hit, header), (start, end)) in self.hits.iter().zip(&self.headers).zip(regions) {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t.\tID={};Name={};evalue={:e};score={}",
+                gff_escape(original_name(&originals, &hit.target_name), true),
+                gff_escape(&self.program, true),
+                kind,
+                start,
+                end,
+                hit.score,
+                hit.strand,
+                gff_escape(header, false),
+                gff_escape(&hit.query_name, false),
+                hit.e_value,
+                hit.score
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write the extracted sequences as alignments on the regions of their
+    /// targets they came from, as SAM or BAM, with the E-value, score and
+    /// query as tags. Stitched hits are spliced alignments skipping the
+    /// bases between their segments. The sequences are extracted again for
+    /// this, and hits that wrap around the end of a circular target are
+    /// left out. This fails if a fasta has a name more than once, as the
+    /// targets of the alignments have to be unique.
+    pub fn write_sam<W: Write>(&mut self, writer: W, format: sam::Format) -> Result<()> {
+        ensure!(
+            self.options.translate.is_none(),
+            "Can not align translated sequences to their targets"
+        );
+        let mut genomes = self.genomes.iter().zip(&self.sources);
+        if let Some((genome, source)) = genomes.find(|(g, _)| !g.duplicates.is_empty()) {
+            let names: Vec<&str> = genome.duplicates.keys().map(String::as_str).collect();
+            bail!(
+                "Can not align to {}, as it has the same name for more than one sequence: {}",
+                source.display(),
+                names.join(", ")
+            );
+        }
+        for header in &self.headers {
+            sam::check_read_name(header)?;
+        }
+        let lengths = target_lengths(&self.genomes)?;
+        let mut targets: Vec<(String, u64)> =
+            lengths.iter().map(|(k, &v)| (k.clone(), v)).collect();
+        targets.sort();
+        let regions = self.regions()?;
+        let mut writer = SamWriter::new(writer, format, &targets)?;
+
+        let bar = progress::hits(self.hits.len() as u64, self.progress);
+        let batch_size = self.batch_size().max(1);
+        let mut wrapped = 0;
+        for start in (0..self.hits.len()).step_by(batch_size) {
+            let end = (start + batch_size).min(self.hits.len());
+            let records = self.extract(start..end)?;
+            for (i, record) in (start..end).zip(records) {
+                bar.inc(1);
+                let (hit, (lo, hi)) = (&self.hits[i], regions[i]);
+                if lengths.get(&hit.target_name).is_some_and(|&len| hi > len) {
+                    wrapped += 1;
+                    continue;
+                }
+                // the sequences are on the strand of their hits
+                let sequence = match hit.strand {
+                    Strand::Positive => record.sequence().as_ref().to_vec(),
+                    Strand::Negative => reverse_complement(record.sequence().as_ref())
+                        .as_ref()
+                        .to_vec(),
+                };
+                writer.write(&sam::Alignment {
+                    name: &self.headers[i],
+                    target: &hit.target_name,
+                    pos: lo,
+                    strand: hit.strand,
+                    cigar: cigar(&self.segments[i], (lo, hi), &self.options),
+                    sequence: &sequence,
+                    query: &hit.query_name,
+                    e_value: hit.e_value,
+                    score: hit.score,
+                })?;
+            }
+        }
+        bar.finish_and_clear();
+        if wrapped > 0 {
+            warn!(
+                "Left {} hits across the end of circular targets out of the alignments",
+                wrapped
+            );
+        }
+        writer.finish()
+    }
+
+    /// A manifest entry for each extracted sequence, in output order.
+    pub fn manifest(&self) -> Result<Vec<Entry>> {
+        let originals = original_names(&self.genomes);
+        Ok(self
+            .hits
+            .iter()
+            .zip(&self.headers)
+            .zip(self.regions()?)
+            .map(|((hit, header), (from, to))| Entry {
+                header: header.clone(),
+                target: original_name(&originals, &hit.target_name).to_string(),
+                from,
+                to,
+                strand: hit.strand.to_string(),
+                e_value: hit.e_value,
+                score: hit.score,
+                query: hit.query_name.clone(),
+                features: features(hit, &self.options)
+                    .map(|ids| ids.into_iter().map(String::from).collect()),
+                representative: None,
+            })
+            .collect())
+    }
+
+    /// The 1-based, inclusive region extracted for each hit, lowest
+    /// coordinate first.
+    fn regions(&self) -> Result<Vec<(u64, u64)>> {
+        let opts = &self.options;
+        // hits covering the whole target need its length
+        let lengths = if self.hits.iter().any(|hit| coords(hit, opts).is_none()) {
+            target_lengths(&self.genomes)?
+        } else {
+            HashMap::new()
+        };
+
+        self.hits
+            .iter()
+            .map(|hit| match coords(hit, opts) {
+                Some((from, to)) => Ok((from.min(to), from.max(to))),
+                None => {
+                    let len = lengths.get(&hit.target_name).with_context(|| {
+                        format!("Sequence {} not found in fasta", hit.target_name)
+                    })?;
+                    Ok((1, *len))
+                }
+            })
+            .collect()
+    }
+
+    /// Remove the temporary directory holding the prepared fasta.
+    pub fn close(self) -> Result<()> {
+        self.tmpdir.close()?;
+        for dir in self.in_place {
+            dir.close()?;
+        }
+        Ok(())
+    }
+
+    /// Extract the sequences for a batch of hits, in hit order. The hits
+    /// are grouped by the fasta their target is in.
+    fn extract(&mut self, batch: Range<usize>) -> Result<Vec<fasta::Record>> {
+        let hits = &self.hits[batch.clone()];
+        let segments = &self.segments[batch.clone()];
+        let headers = &self.headers[batch.clone()];
+        let files = &self.files[batch];
+        let (pool, opts) = (self.pool.as_ref(), &self.options);
+
+        // most batches only need the one fasta
+        if let Some(&file) = files.first().filter(|&&f| files.iter().all(|&g| g == f)) {
+            return self.genomes[file].extract(hits, segments, headers, pool, self.threads, opts);
+        }
+
+        let mut records = vec![None; hits.len()];
+        for (file, genome) in self.genomes.iter_mut().enumerate() {
+            let indices: Vec<usize> = (0..hits.len()).filter(|&i| files[i] == file).collect();
+            if indices.is_empty() {
+                continue;
+            }
+            let group: Vec<Hit> = indices.iter().map(|&i| hits[i].clone()).collect();
+            let group_segments: Vec<Vec<Hit>> =
+                indices.iter().map(|&i| segments[i].clone()).collect();
+            let group_headers: Vec<String> = indices.iter().map(|&i| headers[i].clone()).collect();
+            let extracted = genome.extract(
+                &group,
+                &group_segments,
+                &group_headers,
+                pool,
+                self.threads,
+                opts,
+            )?;
+            for (i, record) in indices.into_iter().zip(extracted) {
+                records[i] = Some(record);
+            }
+        }
+        Ok(records.into_iter().flatten().collect())
+    }
+}
+
+/// Run `f` on each of `jobs`, on the pool if there is one, keeping the
+/// results in order.
+fn run_jobs<T, R, F>(pool: Option<&ThreadPool>, jobs: Vec<T>, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Send + Sync,
+{
+    match pool {
+        Some(pool) => pool.install(|| jobs.into_par_iter().map(f).collect()),
+        None => jobs.into_iter().map(f).collect(),
+    }
+}
+
+/// How the sequence of a hit is fetched.
+enum Pieces {
+    /// In one piece.
+    Whole,
+    /// Forwards in two pieces, up to the end of its circular target and on
+    /// from the start.
+    Wrapped,
+    /// As each of the hits stitched into it, in the order they are fetched.
+    Stitched(Vec<usize>),
+}
+
+/// Extract `hits`, stitched from `segments` where there are any, with
+/// `fetcher`, in one batch, naming them `headers`.
+fn fetch_hits(
+    fetcher: &mut dyn SequenceFetcher,
+    hits: &[Hit],
+    segments: &[Vec<Hit>],
+    headers: &[String],
+    opts: &Options,
+) -> Result<Vec<fasta::Record>> {
+    let mut regions = Vec::with_capacity(hits.len());
+    let mut pieces = Vec::with_capacity(hits.len());
+    for (hit, segments) in hits.iter().zip(segments) {
+        let name = hit.target_name.as_str();
+        if !segments.is_empty() {
+            // minus strand segments reverse complemented here are joined
+            // forwards, and the whole sequence reverse complemented after
+            let mut order: Vec<usize> = (0..segments.len()).collect();
+            if opts.revcomp_minus && hit.strand == Strand::Negative {
+                order.reverse();
+            }
+            regions.extend(order.iter().map(|&i| (name, coords(&segments[i], opts))));
+            pieces.push(Pieces::Stitched(order));
+            continue;
+        }
+        match (coords(hit, opts), hit.sq_len) {
+            (Some((from, to)), Some(len)) if from.max(to) > len => {
+                regions.push((name, Some((from.min(to), len))));
+                regions.push((name, Some((1, from.max(to) - len))));
+                pieces.push(Pieces::Wrapped);
+            }
+            (coords, _) => {
+                regions.push((name, coords));
+                pieces.push(Pieces::Whole);
+            }
+        }
+    }
+    let records = fetcher.fetch_batch(&regions)?;
+    ensure!(
+        records.len() == regions.len(),
+        "The backend returned {} sequences for {} hits",
+        records.len(),
+        regions.len()
+    );
+
+    let mut records = records.into_iter();
+    let mut renamed = Vec::with_capacity(hits.len());
+    for (((hit, segments), header), pieces) in hits.iter().zip(segments).zip(headers).zip(pieces) {
+        let mut record = records.next().expect("one record per region");
+        match pieces {
+            Pieces::Whole => (),
+            Pieces::Wrapped => {
+                let rest = records.next().expect("one record per region");
+                let mut joined = record.sequence().as_ref().to_vec();
+                joined.extend_from_slice(rest.sequence().as_ref());
+                let sequence = match hit.strand {
+                    Strand::Negative if !opts.revcomp_minus => reverse_complement(&joined),
+                    _ => joined.into(),
+                };
+                record = fasta::Record::new(record.definition().clone(), sequence);
+            }
+            Pieces::Stitched(order) => {
+                let mut lengths = vec![0; segments.len()];
+                let mut joined = Vec::new();
+                for (n, i) in order.into_iter().enumerate() {
+                    if n > 0 {
+                        record = records.next().expect("one record per region");
+                    }
+                    lengths[i] = record.sequence().len();
+                    joined.extend_from_slice(record.sequence().as_ref());
+                }
+                let note = stitched_note(segments, &lengths, opts);
+                let description = match record.description() {
+                    Some(d) => format!("{} {}", String::from_utf8_lossy(d), note),
+                    None => note,
+                };
+                let definition = Definition::new(record.name(), Some(description.into_bytes()));
+                record = fasta::Record::new(definition, joined.into());
+            }
+        }
+        renamed.push(rename(record, hit, header, opts));
+    }
+    Ok(renamed)
+}
+
+/// The CIGAR of a sequence extracted from `lo..=hi`: one match, or for
+/// one stitched from `segments` a match for each, skipping the bases
+/// between them. Where segments overlap, their bases are in the sequence
+/// twice, so the second copy is an insertion.
+fn cigar(segments: &[Hit], (lo, hi): (u64, u64), opts: &Options) -> Vec<(u64, sam::Op)> {
+    let mut spans: Vec<(u64, u64)> = segments
+        .iter()
+        .filter_map(|segment| span(segment, opts))
+        .collect();
+    if spans.is_empty() {
+        return vec![(hi - lo + 1, sam::Op::Match)];
+    }
+    spans.sort();
+    let mut cigar = Vec::new();
+    let mut end = spans[0].0 - 1;
+    for (from, to) in spans {
+        if from > end + 1 {
+            cigar.push((from - end - 1, sam::Op::Skip));
+        } else if from <= end {
+            cigar.push((to.min(end) - from + 1, sam::Op::Insertion));
+        }
+        if to > end {
+            cigar.push((to - end.max(from - 1), sam::Op::Match));
+            end = to;
+        }
+    }
+    cigar
+}
+
+/// The note added to the description of a sequence stitched from
+/// `segments` of `lengths`: the coordinates of each, and where each but
+/// the last ends in the sequence, before any translation.
+fn stitched_note(segments: &[Hit], lengths: &[usize], opts: &Options) -> String {
+    let regions: Vec<String> = segments
+        .iter()
+        .map(|segment| {
+            let (lo, hi) = span(segment, opts).expect("stitched hits have coordinates");
+            match segment.strand {
+                Strand::Positive => format!("{}-{}", lo, hi),
+                Strand::Negative => format!("{}-{}", hi, lo),
+            }
+        })
+        .collect();
+    let boundaries: Vec<String> = lengths[..lengths.len() - 1]
+        .iter()
+        .scan(0, |end, length| {
+            *end += length;
+            Some(end.to_string())
+        })
+        .collect();
+    format!(
+        "segments={} boundaries={}",
+        regions.join(","),
+        boundaries.join(",")
+    )
+}
+
+/// Edit the header of the extracted sequence of `hit`, reverse
+/// complementing it if we are doing that ourselves.
+fn rename(r: fasta::Record, hit: &Hit, header: &str, opts: &Options) -> fasta::Record {
+    let description = match opts.meta_in_description {
+        true => Some(meta_description(hit, r.description(), opts).into_bytes()),
+        false => r.description().map(|e| e.to_vec()),
+    };
+    let def = Definition::new(header.as_bytes(), description);
+
+    let mut sequence = if opts.revcomp_minus && hit.strand == Strand::Negative {
+        reverse_complement(r.sequence().as_ref())
+    } else {
+        r.sequence().to_owned()
+    };
+
+    if opts.mask != Mask::Keep {
+        let mut masked = sequence.as_ref().to_vec();
+        opts.mask.apply(&mut masked);
+        sequence = masked.into();
+    }
+
+    if let Some(code) = &opts.translate {
+        let protein = code.translate(sequence.as_ref());
+        sequence = match opts.trim_translation {
+            true => trim_to_stop(&protein).to_vec(),
+            false => protein,
+        }
+        .into();
+    }
+
+    fasta::Record::new(def, sequence)
+}
+
+/// The description of the extracted sequence of `hit` with
+/// [`ExtractorBuilder::meta_in_description`]: its metadata as `key=value`
+/// pairs, then the `description` it had.
+fn meta_description(hit: &Hit, description: Option<&[u8]>, opts: &Options) -> String {
+    let mut meta = format!(
+        "query={} evalue={:e} score={} strand={}",
+        hit.query_name, hit.e_value, hit.score, hit.strand
+    );
+    if !opts.species_id.is_empty() {
+        meta.push_str(&format!(" species={}", opts.species_id));
+    }
+    if let Some(description) = description.filter(|d| !d.is_empty()) {
+        meta.push(' ');
+        meta.push_str(&String::from_utf8_lossy(description));
+    }
+    meta
+}
+
+/// The best of `hits` (by E-value) on each target, in the order the targets
+/// are first hit, without coordinates so the whole target is extracted.
+fn whole_targets(hits: Vec<Hit>) -> Vec<Hit> {
+    let before = hits.len();
+    let mut best: Vec<Hit> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for hit in hits {
+        match index.get(&hit.target_name) {
+            Some(&i) if Rank::EValue.compare(&hit, &best[i]) == Ordering::Less => best[i] = hit,
+            Some(_) => (),
+            None => {
+                index.insert(hit.target_name.clone(), best.len());
+                best.push(hit);
+            }
+        }
+    }
+    for hit in &mut best {
+        (hit.ali_from, hit.ali_to) = (None, None);
+        (hit.env_from, hit.env_to) = (None, None);
+        hit.domain = None;
+        hit.strand = Strand::Positive;
+    }
+    info!(
+        "Extracting the whole sequences of the {} targets of {} hits",
+        best.len(),
+        before
+    );
+    best
+}
+
+/// Stitch `hits`, whose targets are in `files`, into one hit for each group
+/// of them, with the hits stitched into each. Hits on their own are left
+/// as they are, without segments.
+fn stitch(hits: Vec<Hit>, files: Vec<usize>) -> (Vec<Hit>, Vec<usize>, Vec<Vec<Hit>>) {
+    let groups = stitch_groups(&hits);
+    let stitched = groups.iter().filter(|group| group.len() > 1).count();
+    if stitched > 0 {
+        info!(
+            "Stitched {} hits into {} sequences",
+            groups
+                .iter()
+                .map(Vec::len)
+                .filter(|&n| n > 1)
+                .sum::<usize>(),
+            stitched
+        );
+    }
+
+    let mut stitched_hits = Vec::with_capacity(groups.len());
+    let mut stitched_files = Vec::with_capacity(groups.len());
+    let mut segments = Vec::with_capacity(groups.len());
+    for group in groups {
+        stitched_files.push(files[group[0]]);
+        if let [i] = group[..] {
+            stitched_hits.push(hits[i].clone());
+            segments.push(Vec::new());
+        } else {
+            stitched_hits.push(stitched_hit(&hits, &group));
+            segments.push(group.iter().map(|&i| hits[i].clone()).collect());
+        }
+    }
+    (stitched_hits, stitched_files, segments)
+}
+
+/// Clip the coordinates of `hit` to its target, returning false if nothing
+/// of it is left.
+fn clip(hit: &mut Hit) -> bool {
+    let length = hit.sq_len.expect("lengths are filled in before clipping");
+    let clip_pair = |coords: Option<(u64, u64)>| -> Option<Option<(u64, u64)>> {
+        let Some((from, to)) = coords else {
+            return Some(None);
+        };
+        if from.min(to) > length || from.max(to) < 1 {
+            return None;
+        }
+        Some(Some((from.clamp(1, length), to.clamp(1, length))))
+    };
+    let (Some(ali), Some(env)) = (clip_pair(hit.ali()), clip_pair(hit.env())) else {
+        return false;
+    };
+    hit.ali_from = ali.map(|a| a.0);
+    hit.ali_to = ali.map(|a| a.1);
+    hit.env_from = env.map(|e| e.0);
+    hit.env_to = env.map(|e| e.1);
+    true
+}
+
+/// The coordinates of `hit` picked by `opts`, before flanking.
+fn hit_coords(hit: &Hit, opts: &Options) -> Option<(u64, u64)> {
+    match (opts.coords, hit.domain) {
+        (Some(Coords::Ali), _) => hit.ali(),
+        // domains are extracted by their envelope unless asked otherwise
+        (Some(Coords::Env), _) | (None, Some(_)) => hit.env().or(hit.ali()),
+        (None, None) => hit.ali(),
+    }
+}
+
+/// The lowest and highest coordinate to extract for `hit`, with flanks or
+/// the adjacent region clamped to the target. `None` means the whole target.
+/// An adjacent region off the end of the target is empty, with `lo > hi`.
+/// Regions wrapping around the origin of a circular target are unrolled, so
+/// `hi` is past the end of it.
+fn span(hit: &Hit, opts: &Options) -> Option<(u64, u64)> {
+    let (from, to) = hit_coords(hit, opts)?;
+
+    let (lo, hi) = (from.min(to) as i64, from.max(to) as i64);
+    let before = matches!(
+        (opts.adjacent, hit.strand),
+        (Some(Adjacent::Upstream(_)), Strand::Positive)
+            | (Some(Adjacent::Downstream(_)), Strand::Negative)
+    );
+    let (mut lo, mut hi) = match opts.adjacent {
+        Some(Adjacent::Upstream(n) | Adjacent::Downstream(n)) if before => (lo - n as i64, lo - 1),
+        Some(Adjacent::Upstream(n) | Adjacent::Downstream(n)) => (hi + 1, hi + n as i64),
+        None => {
+            let length = (hi - lo + 1) as u64;
+            let (below, above, trim_below, trim_above) = match hit.strand {
+                Strand::Positive => (opts.flank_5p, opts.flank_3p, opts.trim_5p, opts.trim_3p),
+                Strand::Negative => (opts.flank_3p, opts.flank_5p, opts.trim_3p, opts.trim_5p),
+            };
+            (
+                lo + trim_below.bases(length) as i64 - below as i64,
+                hi - trim_above.bases(length) as i64 + above as i64,
+            )
+        }
+    };
+
+    match hit.sq_len.map(|len| len as i64) {
+        Some(len) if opts.is_circular(&hit.target_name) => {
+            if lo < 1 {
+                (lo, hi) = (lo + len, hi + len);
+            } else if lo > len {
+                (lo, hi) = (lo - len, hi - len);
+            }
+            // at most once around
+            hi = hi.min(lo + len - 1);
+        }
+        Some(len) => (lo, hi) = (lo.max(1), hi.min(len)),
+        None => lo = lo.max(1),
+    }
+    Some((lo as u64, hi.max(0) as u64))
+}
+
+/// The length of the region extracted for `hit`, if it is known.
+fn region_length(hit: &Hit, opts: &Options) -> Option<u64> {
+    match coords(hit, opts) {
+        Some((from, to)) => Some(from.max(to) - from.min(to) + 1),
+        None => hit.sq_len,
+    }
+}
+
+fn log_empty_regions(skipped: usize, opts: &Options) {
+    if skipped == 0 {
+        return;
+    }
+    match opts.adjacent {
+        Some(_) => info!(
+            "Skipped {} hits at the end of their target, with nothing next to them",
+            skipped
+        ),
+        None => info!("Skipped {} hits too short to trim", skipped),
+    }
+}
+
+/// Whether the adjacent region of `hit` is off the end of its target, or
+/// nothing is left of it once trimmed.
+fn empty_region(hit: &Hit, opts: &Options) -> bool {
+    span(hit, opts).is_some_and(|(lo, hi)| lo > hi)
+}
+
+/// The coordinates to extract for `hit`; `None` means the whole target.
+/// Minus strand hits run from high to low, so they are reverse complemented
+/// by the backend, unless we are doing that ourselves.
+fn coords(hit: &Hit, opts: &Options) -> Option<(u64, u64)> {
+    let (lo, hi) = span(hit, opts)?;
+    match hit.strand {
+        Strand::Positive => Some((lo, hi)),
+        Strand::Negative if opts.revcomp_minus => Some((lo, hi)),
+        Strand::Negative => Some((hi, lo)),
+    }
+}
+
+/// Extend each of `hits`, whose targets are in `genomes[files[i]]`, to the
+/// open reading frame it is in. The stretch of the target read around each
+/// hit starts at a few kb and doubles until both stop codons are found, or
+/// the target ends. The alignment and envelope both become the ORF.
+fn extend_orfs(
+    hits: &mut [Hit],
+    files: &[usize],
+    genomes: &mut [Genome],
+    opts: &Options,
+    code: &GeneticCode,
+) -> Result<()> {
+    for (file, genome) in genomes.iter_mut().enumerate() {
+        let mut pending: Vec<usize> = (0..hits.len())
+            .filter(|&i| files[i] == file && hit_coords(&hits[i], opts).is_some())
+            .collect();
+        let mut window = 3_000;
+
+        while !pending.is_empty() {
+            // the stretch around each hit, on its strand
+            let stretches = pending
+                .iter()
+                .map(|&i| {
+                    let hit = &hits[i];
+                    let (from, to) = hit_coords(hit, opts).expect("only hits with coordinates");
+                    let sq_len = hit.sq_len.with_context(|| {
+                        format!("Sequence {} not found in fasta", hit.target_name)
+                    })?;
+                    let (lo, hi) = (from.min(to), from.max(to));
+                    Ok((
+                        lo,
+                        hi,
+                        lo.saturating_sub(window).max(1),
+                        (hi + window).min(sq_len),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let regions: Vec<_> = pending
+                .iter()
+                .zip(&stretches)
+                .map(|(&i, &(_, _, start, end))| {
+                    let hit = &hits[i];
+                    match hit.strand {
+                        Strand::Positive => (hit.target_name.as_str(), Some((start, end))),
+                        Strand::Negative => (hit.target_name.as_str(), Some((end, start))),
+                    }
+                })
+                .collect();
+            let records = genome.fetchers[0].fetch_batch(&regions)?;
+            ensure!(
+                records.len() == regions.len(),
+                "The backend returned {} sequences for {} hits",
+                records.len(),
+                regions.len()
+            );
+
+            let mut unfinished = Vec::new();
+            for ((i, (lo, hi, start, end)), record) in
+                pending.into_iter().zip(stretches).zip(records)
+            {
+                let hit = &mut hits[i];
+                let seq = record.sequence().as_ref();
+                let sq_len = hit.sq_len.unwrap_or_default();
+                // offsets into the stretch, which reads 5' to 3' on the strand
+                let (orf_start, orf_end, five_end, three_end) = match hit.strand {
+                    Strand::Positive => {
+                        let (s, e) =
+                            extend_orf(seq, (lo - start) as usize, (hi - start + 1) as usize, code);
+                        (s, e, start == 1, end == sq_len)
+                    }
+                    Strand::Negative => {
+                        let (s, e) =
+                            extend_orf(seq, (end - hi) as usize, (end - lo + 1) as usize, code);
+                        (s, e, end == sq_len, start == 1)
+                    }
+                };
+                if (orf_start.is_none() && !five_end) || (orf_end.is_none() && !three_end) {
+                    unfinished.push(i);
+                    continue;
+                }
+
+                // without a stop codon before the end of the target, that
+                // side is left where it was
+                let (from, to) = match hit.strand {
+                    Strand::Positive => (
+                        orf_start.map_or(lo, |s| start + s as u64),
+                        orf_end.map_or(hi, |e| start + e as u64 - 1),
+                    ),
+                    Strand::Negative => (
+                        orf_start.map_or(hi, |s| end - s as u64),
+                        orf_end.map_or(lo, |e| end + 1 - e as u64),
+                    ),
+                };
+                hit.ali_from = Some(from);
+                hit.ali_to = Some(to);
+                hit.env_from = Some(from);
+                hit.env_to = Some(to);
+            }
+            pending = unfinished;
+            window *= 2;
+        }
+    }
+    Ok(())
+}
+
+/// Save the index built for `prepared` next to `source`. Decompressed or
+/// cleaned up copies have different offsets, so only linked fasta, or BGZF
+/// read in place, is done.
+fn keep_index(
+    source: &Path,
+    prepared: &Path,
+    fetcher: &dyn SequenceFetcher,
+    overwrite: bool,
+) -> Result<()> {
+    let linked = fs::symlink_metadata(prepared).is_ok_and(|m| m.file_type().is_symlink());
+    if !linked && prepared != source {
+        warn!("Not keeping the index, as the fasta had to be converted");
+        return Ok(());
+    }
+    fetcher.keep_index(source, overwrite)
+}
+
+/// The index `backend` would use that is already next to `fasta`, if any.
+/// Compressed fasta always has to be prepared.
+fn existing_index(fasta: &Path, backend: &Backend) -> Option<PathBuf> {
+    // compressed fasta is decompressed first, so any index would not match
+    if Compression::detect(fasta).ok().flatten().is_some() {
+        return None;
+    }
+    let index = with_suffix(
+        fasta,
+        match backend {
+            Backend::EslSfetch(_) => ".ssi",
+            Backend::Samtools(_) | Backend::Native => ".fai",
+            Backend::Custom(_) => return None,
+        },
+    );
+    index.is_file().then_some(index)
+}
+
+/// The length of each sequence in any of `genomes`.
+fn target_lengths(genomes: &[Genome]) -> Result<HashMap<String, u64>> {
+    let mut lengths = HashMap::new();
+    for genome in genomes {
+        lengths.extend(genome.target_lengths()?);
+    }
+    Ok(lengths)
+}
+
+/// Put each hit on a name the fasta has more than once on the copy of the
+/// length in the tblout, or the first, and name the renamed copies in the
+/// headers as they are in the fasta (or the rename map).
+fn resolve_duplicates(hits: &mut [Hit], genomes: &[Genome], opts: &mut Options) {
+    let duplicates: HashMap<&String, &Vec<(String, u64)>> = genomes
+        .iter()
+        .flat_map(|genome| &genome.duplicates)
+        .collect();
+    if duplicates.is_empty() {
+        return;
+    }
+
+    let mut ambiguous = 0;
+    for hit in hits.iter_mut() {
+        let Some(copies) = duplicates.get(&hit.target_name) else {
+            continue;
+        };
+        let same_length: Vec<&(String, u64)> = copies
+            .iter()
+            .filter(|(_, length)| hit.sq_len == Some(*length))
+            .collect();
+        let (name, _) = match same_length.as_slice() {
+            [copy] => copy,
+            _ => {
+                ambiguous += 1;
+                same_length.first().copied().unwrap_or(&copies[0])
+            }
+        };
+        hit.target_name = name.clone();
+    }
+    if ambiguous > 0 {
+        warn!(
+            "{} hits are on names the fasta has more than once and could not be told apart by length, so were put on the first copy of that length (or the first)",
+            ambiguous
+        );
+    }
+
+    for (name, copies) in duplicates {
+        let header = opts.rename.get(name).unwrap_or(name).clone();
+        for (copy, _) in &copies[1..] {
+            opts.rename.insert(copy.clone(), header.clone());
+        }
+    }
+}
+
+/// The name in the fasta of each copy of a name it has more than once that
+/// was renamed in the prepared fasta.
+fn original_names(genomes: &[Genome]) -> HashMap<&str, &str> {
+    genomes
+        .iter()
+        .flat_map(|genome| &genome.duplicates)
+        .flat_map(|(name, copies)| {
+            copies[1..]
+                .iter()
+                .map(|(copy, _)| (copy.as_str(), name.as_str()))
+        })
+        .collect()
+}
+
+/// The name `target` has in the fasta, if it is a renamed copy.
+fn original_name<'a>(originals: &HashMap<&str, &'a str>, target: &'a str) -> &'a str {
+    originals.get(target).copied().unwrap_or(target)
+}
+
+/// The name of the extracted sequence, before the header is edited. This
+/// follows `esl-sfetch -c`, except that domains are numbered instead.
+fn extracted_name(hit: &Hit, opts: &Options) -> String {
+    let target = target_name(hit, opts);
+    let name = match (hit.domain, coords(hit, opts)) {
+        (Some(domain), _) => format!("{}/domain{}", target, domain),
+        (None, Some((from, to))) => format!("{}/{}-{}", target, from, to),
+        (None, None) => target.to_string(),
+    };
+    if opts.revcomp_minus {
+        format!("{}({})", name, hit.strand)
+    } else {
+        name
+    }
+}
+
+/// The name of the target of `hit` in headers, after any renaming.
+fn target_name<'a>(hit: &'a Hit, opts: &'a Options) -> &'a str {
+    opts.rename
+        .get(&hit.target_name)
+        .unwrap_or(&hit.target_name)
+}
+
+/// The name given to the extracted sequence of `hit`.
+fn header_name(hit: &Hit, opts: &Options) -> String {
+    let append_name = extracted_name(hit, opts);
+    if let Some(template) = &opts.header_format {
+        return template.render(&Values {
+            hit,
+            species_id: &opts.species_id,
+            target: target_name(hit, opts),
+            name: &append_name,
+            coords: coords(hit, opts),
+            features: features(hit, opts).map(|ids| ids.join(",")),
+        });
+    }
+    if opts.esl_names {
+        let target = target_name(hit, opts);
+        return match span(hit, opts) {
+            Some((lo, hi)) if hit.strand == Strand::Negative => {
+                format!("{}/{}-{}", target, hi, lo)
+            }
+            Some((lo, hi)) => format!("{}/{}-{}", target, lo, hi),
+            None => target.to_string(),
+        };
+    }
+    if opts.seqkit_names {
+        let target = target_name(hit, opts);
+        return match span(hit, opts) {
+            Some((lo, hi)) => format!("{}_{}-{}:{}", target, lo, hi, hit.strand),
+            None => target.to_string(),
+        };
+    }
+    if opts.meta_in_description {
+        let target = target_name(hit, opts);
+        return match coords(hit, opts) {
+            Some((from, to)) => format!("{}_{}_{}", target, from, to),
+            None => target.to_string(),
+        };
+    }
+    if opts.species_id.is_empty() {
+        format!("{}:E{:e}", append_name, hit.e_value)
+    } else {
+        format!("{}:E{:e}:{}", opts.species_id, hit.e_value, append_name)
+    }
+}
+
+/// The IDs of the annotated features `hit` itself (without flanks)
+/// overlaps, if there is an annotation.
+fn features<'a>(hit: &Hit, opts: &'a Options) -> Option<Vec<&'a str>> {
+    let annotation = opts.annotation.as_ref()?;
+    let (from, to) = hit_coords(hit, opts).or(hit.sq_len.map(|len| (1, len)))?;
+    Some(annotation.overlapping(&hit.target_name, from, to))
+}
+
+/// The header of each of `hits`, made unique by adding `_2`, `_3` and so on
+/// to the later of any that are the same, or failing if `strict`.
+fn unique_headers(hits: &[Hit], opts: &Options, strict: bool) -> Result<Vec<String>> {
+    let mut headers: Vec<String> = hits.iter().map(|hit| header_name(hit, opts)).collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for header in &headers {
+        *counts.entry(header).or_default() += 1;
+    }
+    let duplicated: HashSet<String> = counts
+        .into_iter()
+        .filter(|&(_, n)| n > 1)
+        .map(|(header, _)| header.to_string())
+        .collect();
+    if duplicated.is_empty() {
+        return Ok(headers);
+    }
+    if strict {
+        let mut example: Vec<&String> = duplicated.iter().collect();
+        example.sort();
+        bail!(
+            "{} headers are given to more than one hit, e.g. {}. Use --with-coords or a --header-format telling them apart",
+            duplicated.len(),
+            example[0]
+        );
+    }
+
+    let mut taken: HashSet<String> = headers.iter().cloned().collect();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut renamed = 0;
+    for header in headers.iter_mut().filter(|h| duplicated.contains(*h)) {
+        let n = seen.entry(header.clone()).or_default();
+        *n += 1;
+        if *n == 1 {
+            continue;
+        }
+        // skip any suffix some other hit already has
+        let unique = loop {
+            let candidate = format!("{}_{}", header, n);
+            if taken.insert(candidate.clone()) {
+                break candidate;
+            }
+            *n += 1;
+        };
+        *header = unique;
+        renamed += 1;
+    }
+    warn!(
+        "{} hits had the same header as an earlier one, and were numbered to tell them apart",
+        renamed
+    );
+    Ok(headers)
+}
+
+/// Percent-encode the characters GFF3 reserves. Columns only allow a small
+/// set of punctuation, attribute values everything but the separators.
+fn gff_escape(s: &str, column: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        let keep = if column {
+            c.is_ascii_alphanumeric() || ".:^*$@!+_?-|".contains(c)
+        } else {
+            !c.is_control() && !";=&,%".contains(c)
+        };
+        if keep {
+            escaped.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    escaped
+}
+
+/// An iterator over the extracted sequences of an [`Extractor`].
+pub struct Records<'a> {
+    extractor: &'a mut Extractor,
+    next_hit: usize,
+    buf: VecDeque<fasta::Record>,
+    bar: ProgressBar,
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<fasta::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.buf.pop_front() {
+                self.bar.inc(1);
+                return Some(Ok(record));
+            }
+
+            if self.next_hit >= self.extractor.hits.len() {
+                self.bar.finish_and_clear();
+                return None;
+            }
+
+            let start = self.next_hit;
+            let end = start
+                .saturating_add(self.extractor.batch_size())
+                .min(self.extractor.hits.len());
+            self.next_hit = end;
+
+            match self.extractor.extract(start..end) {
+                Ok(records) => self.buf.extend(records),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! The backends that pull sequences out of a prepared fasta.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::Command as Cmd,
+    sync::Arc,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use fasta::record::Definition;
+use log::{debug, info, warn};
+use noodles_fasta as fasta;
+use tempfile::NamedTempFile;
+
+use crate::{
+    index::{self, with_suffix},
+    native::NativeFetcher,
+};
+
+/// A region of a sequence to fetch: `from..=to` (1-based), with `from > to`
+/// meaning the reverse complement, or the whole sequence if there are no
+/// coordinates.
+pub type Region<'a> = (&'a str, Option<(u64, u64)>);
+
+/// Something that can fetch regions out of a fasta. Implement this to add
+/// a backend, and use it with [`Backend::Custom`](crate::Backend::Custom).
+///
+/// The fetched records are renamed afterwards, so the names they come
+/// back with do not matter, but their descriptions are kept.
+pub trait SequenceFetcher: Send {
+    /// Fetch one region.
+    fn fetch(&mut self, region: Region<'_>) -> Result<fasta::Record>;
+
+    /// Fetch many regions, in order. Backends that run a program should
+    /// override this to run it once per batch.
+    fn fetch_batch(&mut self, regions: &[Region<'_>]) -> Result<Vec<fasta::Record>> {
+        regions.iter().map(|&region| self.fetch(region)).collect()
+    }
+
+    /// How many regions are best fetched at once.
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// The length of each sequence in the fasta.
+    fn lengths(&self) -> Result<HashMap<String, u64>>;
+
+    /// Another fetcher for the same fasta, for another thread.
+    fn try_clone(&self) -> Result<Box<dyn SequenceFetcher>>;
+
+    /// Save the index of the fasta next to `source`, which the prepared
+    /// fasta is the same as or links to. Replace an existing index only if
+    /// `overwrite` is set.
+    fn keep_index(&self, source: &Path, overwrite: bool) -> Result<()> {
+        let _ = (source, overwrite);
+        warn!("Not keeping the index, as this backend has none");
+        Ok(())
+    }
+}
+
+impl SequenceFetcher for NativeFetcher {
+    fn fetch(&mut self, (name, coords): Region<'_>) -> Result<fasta::Record> {
+        match coords {
+            Some((from, to)) => NativeFetcher::fetch(self, name, from, to),
+            None => self.fetch_full(name),
+        }
+    }
+
+    fn lengths(&self) -> Result<HashMap<String, u64>> {
+        Ok(lengths(self.index()))
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn SequenceFetcher>> {
+        Ok(Box::new(NativeFetcher::try_clone(self)?))
+    }
+
+    fn keep_index(&self, source: &Path, overwrite: bool) -> Result<()> {
+        save_index(source, ".fai", overwrite, |dst| {
+            index::write_fai(dst, self.index())
+        })?;
+        if let Some(gzi) = self.gzi() {
+            save_index(source, ".gzi", overwrite, |dst| index::write_gzi(dst, gzi))?;
+        }
+        Ok(())
+    }
+}
+
+/// Shells out to `esl-sfetch`, once per batch.
+#[derive(Debug, Clone)]
+pub(crate) struct EslSfetch {
+    esl_sfetch: PathBuf,
+    fasta: PathBuf,
+    /// Where the coordinate files are written.
+    tmpdir: PathBuf,
+    /// The length of each sequence, which the `.ssi` does not give, so are
+    /// read once up front and shared between the threads.
+    lengths: Arc<HashMap<String, u64>>,
+}
+
+impl EslSfetch {
+    /// Index `fasta` with `esl-sfetch --index`, unless it already is, and
+    /// read the lengths of its sequences from `fai` if it has been indexed
+    /// in memory already, or else by indexing it.
+    pub(crate) fn new(
+        esl_sfetch: &Path,
+        fasta: &Path,
+        tmpdir: &Path,
+        indexed: bool,
+        fai: Option<&fasta::fai::Index>,
+    ) -> Result<Self> {
+        if !indexed {
+            let index_fasta = Cmd::new(esl_sfetch).arg("--index").arg(fasta).output()?;
+            ensure!(
+                index_fasta.status.success(),
+                "esl-sfetch --index failed: {}",
+                String::from_utf8_lossy(&index_fasta.stderr).trim()
+            );
+        }
+        let index = match fai {
+            Some(fai) => lengths(fai),
+            None => lengths(
+                &fasta::index(fasta)
+                    .with_context(|| format!("Could not index fasta {}", fasta.display()))?,
+            ),
+        };
+        Ok(Self {
+            esl_sfetch: esl_sfetch.to_path_buf(),
+            fasta: fasta.to_path_buf(),
+            tmpdir: tmpdir.to_path_buf(),
+            lengths: Arc::new(index),
+        })
+    }
+
+    /// Run `esl-sfetch <mode> fasta file` and parse the sequences it prints.
+    fn run(&self, mode: &str, file: &Path) -> Result<Vec<fasta::Record>> {
+        let mut cmd = Cmd::new(&self.esl_sfetch);
+        cmd.arg(mode).arg(&self.fasta).arg(file);
+        debug!("Running {:?}", cmd);
+        let extract_sequences = cmd.output()?;
+        if !extract_sequences.status.success() {
+            bail!(
+                "esl-sfetch failed: {}",
+                String::from_utf8_lossy(&extract_sequences.stderr).trim()
+            );
+        }
+
+        // parse the fasta properly
+        parse(&extract_sequences.stdout)
+    }
+}
+
+impl SequenceFetcher for EslSfetch {
+    fn fetch(&mut self, region: Region<'_>) -> Result<fasta::Record> {
+        let mut records = self.fetch_batch(&[region])?;
+        Ok(records.remove(0))
+    }
+
+    fn fetch_batch(&mut self, regions: &[Region<'_>]) -> Result<Vec<fasta::Record>> {
+        // regions with coordinates are fetched as subsequences with -Cf,
+        // the others as whole sequences with -f
+        let (with_coords, whole): (Vec<_>, Vec<_>) =
+            (0..regions.len()).partition(|&i| regions[i].1.is_some());
+
+        let mut extracted: Vec<Option<fasta::Record>> = vec![None; regions.len()];
+        if !with_coords.is_empty() {
+            // write all of the coordinates to a file so esl-sfetch only runs
+            // once.
+            let mut coords_file = temp_file(&self.tmpdir, "coords")?;
+            for &i in &with_coords {
+                let (name, coords) = regions[i];
+                let (from, to) = coords.expect("partitioned on coordinates");
+                writeln!(
+                    coords_file,
+                    "{}/{}-{} {} {} {}",
+                    name, from, to, from, to, name
+                )?;
+            }
+            coords_file.flush()?;
+
+            let records = self.run("-Cf", coords_file.get_ref().path())?;
+            check_count(&records, with_coords.len(), "esl-sfetch")?;
+            for (i, record) in with_coords.into_iter().zip(records) {
+                extracted[i] = Some(record);
+            }
+        }
+        if !whole.is_empty() {
+            let mut names = temp_file(&self.tmpdir, "names")?;
+            for &i in &whole {
+                writeln!(names, "{}", regions[i].0)?;
+            }
+            names.flush()?;
+
+            let records = self.run("-f", names.get_ref().path())?;
+            check_count(&records, whole.len(), "esl-sfetch")?;
+            for (i, record) in whole.into_iter().zip(records) {
+                extracted[i] = Some(record);
+            }
+        }
+
+        Ok(extracted.into_iter().flatten().collect())
+    }
+
+    fn batch_size(&self) -> usize {
+        10_000
+    }
+
+    fn lengths(&self) -> Result<HashMap<String, u64>> {
+        Ok(HashMap::clone(&self.lengths))
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn SequenceFetcher>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn keep_index(&self, source: &Path, overwrite: bool) -> Result<()> {
+        copy_index(&self.fasta, source, ".ssi", overwrite)
+    }
+}
+
+/// Shells out to `samtools faidx`, once or twice per batch.
+#[derive(Debug, Clone)]
+pub(crate) struct Samtools {
+    samtools: PathBuf,
+    fasta: PathBuf,
+    /// Where the region files are written.
+    tmpdir: PathBuf,
+    /// `samtools faidx` drops the descriptions, so they are read up front.
+    descriptions: HashMap<String, Vec<u8>>,
+}
+
+impl Samtools {
+    /// Index `fasta` with `samtools faidx`, unless it already is.
+    pub(crate) fn new(samtools: &Path, fasta: &Path, tmpdir: &Path, indexed: bool) -> Result<Self> {
+        if !indexed {
+            let index_fasta = Cmd::new(samtools).arg("faidx").arg(fasta).output()?;
+            ensure!(
+                index_fasta.status.success(),
+                "samtools faidx failed: {}",
+                String::from_utf8_lossy(&index_fasta.stderr).trim()
+            );
+        }
+        Ok(Self {
+            samtools: samtools.to_path_buf(),
+            fasta: fasta.to_path_buf(),
+            tmpdir: tmpdir.to_path_buf(),
+            descriptions: read_descriptions(fasta)?,
+        })
+    }
+}
+
+impl SequenceFetcher for Samtools {
+    fn fetch(&mut self, region: Region<'_>) -> Result<fasta::Record> {
+        let mut records = self.fetch_batch(&[region])?;
+        Ok(records.remove(0))
+    }
+
+    fn fetch_batch(&mut self, regions: &[Region<'_>]) -> Result<Vec<fasta::Record>> {
+        // samtools reverse complements every region of a run or none of
+        // them, so reverse regions are fetched separately
+        let (reverse, forward): (Vec<_>, Vec<_>) =
+            (0..regions.len()).partition(|&i| regions[i].1.is_some_and(|(from, to)| from > to));
+
+        let mut extracted: Vec<Option<fasta::Record>> = vec![None; regions.len()];
+        for (indices, reverse) in [(forward, false), (reverse, true)] {
+            if indices.is_empty() {
+                continue;
+            }
+            let mut regions_file = temp_file(&self.tmpdir, "regions")?;
+            for &i in &indices {
+                match regions[i] {
+                    (name, Some((from, to))) => {
+                        writeln!(regions_file, "{}:{}-{}", name, from.min(to), from.max(to))?
+                    }
+                    (name, None) => writeln!(regions_file, "{}", name)?,
+                }
+            }
+            regions_file.flush()?;
+
+            let mut cmd = Cmd::new(&self.samtools);
+            cmd.arg("faidx")
+                .arg(&self.fasta)
+                .arg("-r")
+                .arg(regions_file.get_ref().path());
+            if reverse {
+                cmd.arg("-i");
+            }
+            debug!("Running {:?}", cmd);
+            let output = cmd.output()?;
+            if !output.status.success() {
+                bail!(
+                    "samtools faidx failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+
+            let records = parse(&output.stdout)?;
+            check_count(&records, indices.len(), "samtools")?;
+            for (i, record) in indices.into_iter().zip(records) {
+                let description = self.descriptions.get(regions[i].0).cloned();
+                let def = Definition::new(record.name(), description);
+                extracted[i] = Some(fasta::Record::new(def, record.sequence().clone()));
+            }
+        }
+
+        Ok(extracted.into_iter().flatten().collect())
+    }
+
+    fn batch_size(&self) -> usize {
+        10_000
+    }
+
+    fn lengths(&self) -> Result<HashMap<String, u64>> {
+        let fai = with_suffix(&self.fasta, ".fai");
+        let index = fasta::fai::read(&fai)
+            .with_context(|| format!("Could not read index {}", fai.display()))?;
+        Ok(lengths(&index))
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn SequenceFetcher>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn keep_index(&self, source: &Path, overwrite: bool) -> Result<()> {
+        copy_index(&self.fasta, source, ".fai", overwrite)
+    }
+}
+
+/// The length of each sequence in `index`.
+fn lengths(index: &fasta::fai::Index) -> HashMap<String, u64> {
+    index
+        .iter()
+        .map(|r| (String::from_utf8_lossy(r.name()).into_owned(), r.length()))
+        .collect()
+}
+
+/// A file in `tmpdir` for a batch of names or coordinates. It is removed
+/// once dropped.
+fn temp_file(tmpdir: &Path, prefix: &str) -> Result<BufWriter<NamedTempFile>> {
+    let file = tempfile::Builder::new()
+        .prefix(prefix)
+        .suffix(".txt")
+        .tempfile_in(tmpdir)
+        .with_context(|| format!("Could not create {} file", prefix))?;
+    Ok(BufWriter::new(file))
+}
+
+/// Parse the fasta a backend printed.
+fn parse(stdout: &[u8]) -> Result<Vec<fasta::Record>> {
+    let mut reader = fasta::reader::Reader::new(stdout);
+    Ok(reader.records().collect::<io::Result<Vec<_>>>()?)
+}
+
+fn check_count(records: &[fasta::Record], expected: usize, program: &str) -> Result<()> {
+    ensure!(
+        records.len() == expected,
+        "{} returned {} sequences for {} hits",
+        program,
+        records.len(),
+        expected
+    );
+    Ok(())
+}
+
+/// Write an index to `source` with `suffix` appended, unless there already
+/// is one and `overwrite` is not set.
+fn save_index<F>(source: &Path, suffix: &str, overwrite: bool, write: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    let dst = with_suffix(source, suffix);
+    if dst.exists() && !overwrite {
+        warn!(
+            "Not replacing the existing index {:?} (use --overwrite-index)",
+            dst
+        );
+        return Ok(());
+    }
+    write(&dst).with_context(|| format!("Could not save index {}", dst.display()))?;
+    info!("Saved index to {:?}", dst);
+    Ok(())
+}
+
+/// Copy the index a program wrote for `prepared` next to `source`.
+fn copy_index(prepared: &Path, source: &Path, suffix: &str, overwrite: bool) -> Result<()> {
+    save_index(source, suffix, overwrite, |dst| {
+        fs::copy(with_suffix(prepared, suffix), dst)?;
+        Ok(())
+    })
+}
+
+/// The description of each sequence in `fasta` that has one.
+fn read_descriptions(fasta: &Path) -> Result<HashMap<String, Vec<u8>>> {
+    let file = File::open(fasta).context("Could not open fasta")?;
+    let mut reader = BufReader::new(file);
+    let mut descriptions = HashMap::new();
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if let Some(definition) = line.trim_ascii_end().strip_prefix(b">") {
+            if let Some(i) = definition.iter().position(|b| b.is_ascii_whitespace()) {
+                let description = definition[i..].trim_ascii_start();
+                if !description.is_empty() {
+                    let name = String::from_utf8_lossy(&definition[..i]).into_owned();
+                    descriptions.insert(name, description.to_vec());
+                }
+            }
+        }
+        line.clear();
+    }
+    Ok(descriptions)
+}
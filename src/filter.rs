@@ -0,0 +1,403 @@
+//! Filters applied to tblout hits before anything is done with them.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+use crate::{expr::Expr, tblout::Hit};
+
+/// How hits are ranked against each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rank {
+    /// Lowest E-value first.
+    #[default]
+    EValue,
+    /// Highest bit score first.
+    Score,
+}
+
+impl FromStr for Rank {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "evalue" => Ok(Rank::EValue),
+            "score" => Ok(Rank::Score),
+            _ => bail!("Unknown ranking \"{}\"", s),
+        }
+    }
+}
+
+impl Rank {
+    /// Order two hits, best first.
+    pub fn compare(&self, a: &Hit, b: &Hit) -> Ordering {
+        match self {
+            Rank::EValue => a
+                .e_value
+                .total_cmp(&b.e_value)
+                .then(b.score.total_cmp(&a.score)),
+            Rank::Score => b
+                .score
+                .total_cmp(&a.score)
+                .then(a.e_value.total_cmp(&b.e_value)),
+        }
+    }
+}
+
+/// Which rounds of an iterative search (`jackhmmer`) hits are kept from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Round {
+    /// The last round of each query, where the search ended up.
+    #[default]
+    Last,
+    /// Every round, so a target can be hit once per round.
+    All,
+    /// Only this round, counting from 1.
+    Number(u64),
+}
+
+impl FromStr for Round {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "last" => Ok(Round::Last),
+            "all" => Ok(Round::All),
+            _ => match s.parse() {
+                Ok(n) if n > 0 => Ok(Round::Number(n)),
+                _ => bail!("Unknown round \"{}\", expected a number, last or all", s),
+            },
+        }
+    }
+}
+
+/// Cutoffs for the hits of one query, replacing the global ones.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Threshold {
+    /// Replaces [`HitFilter::e_value_threshold`] if set.
+    pub e_value: Option<f64>,
+    /// Replaces [`HitFilter::min_score`] if set.
+    pub min_score: Option<f64>,
+}
+
+/// The filters a hit has to pass to be kept.
+#[derive(Debug, Clone)]
+pub struct HitFilter {
+    /// Hits with an E-value above this are dropped.
+    pub e_value_threshold: f64,
+    /// Hits with a bit score below this are dropped.
+    pub min_score: Option<f64>,
+    /// Only keep hits the program itself included as significant.
+    pub inc_only: bool,
+    /// If set, only hits on these targets are kept.
+    pub targets: Option<HashSet<String>>,
+    /// If set, only hits on targets matching this are kept.
+    pub target_regex: Option<Regex>,
+    /// If set, only hits from these queries (by name or accession) are kept.
+    pub queries: Option<HashSet<String>>,
+    /// If set, only hits from queries whose name or accession matches this
+    /// are kept.
+    pub query_regex: Option<Regex>,
+    /// If set, only the best this many passing hits of each query are kept.
+    pub top_n: Option<usize>,
+    /// How hits are ranked for `top_n`.
+    pub rank_by: Rank,
+    /// Of hits that overlap on the same target, keep only the one with the
+    /// lowest E-value.
+    pub best_per_target: bool,
+    /// Of hits from different queries that overlap on the same target by
+    /// at least this fraction of the shorter one, keep only the one with
+    /// the highest score.
+    pub non_redundant: Option<f64>,
+    /// Hits covering less than this fraction of their model are dropped.
+    pub min_model_coverage: Option<f64>,
+    /// Model lengths by name or accession, for tblouts that do not have
+    /// them. Hits with an unknown model length fail `min_model_coverage`.
+    pub model_lengths: HashMap<String, u64>,
+    /// Cutoffs for particular queries, by name or accession.
+    pub thresholds: HashMap<String, Threshold>,
+    /// The rounds hits of iterative searches are kept from. Hits that are
+    /// not from an iterative search are not affected.
+    pub round: Round,
+    /// If set, only hits this expression is true for are kept.
+    pub expr: Option<Expr>,
+}
+
+impl Default for HitFilter {
+    fn default() -> Self {
+        Self {
+            e_value_threshold: 0.00001,
+            min_score: None,
+            inc_only: false,
+            targets: None,
+            target_regex: None,
+            queries: None,
+            query_regex: None,
+            top_n: None,
+            rank_by: Rank::default(),
+            best_per_target: false,
+            non_redundant: None,
+            min_model_coverage: None,
+            model_lengths: HashMap::new(),
+            thresholds: HashMap::new(),
+            round: Round::default(),
+            expr: None,
+        }
+    }
+}
+
+impl HitFilter {
+    /// Which of `hits` are kept. Unlike [`HitFilter::passes`], this also
+    /// applies the filters that compare hits with each other.
+    pub fn select(&self, hits: &[Hit]) -> Vec<bool> {
+        let mut keep: Vec<bool> = hits.iter().map(|hit| self.passes(hit)).collect();
+
+        if self.round == Round::Last {
+            let mut last: HashMap<&str, u64> = HashMap::new();
+            for hit in hits {
+                if let Some(round) = hit.round {
+                    let last = last.entry(&hit.query_name).or_default();
+                    *last = (*last).max(round);
+                }
+            }
+            for (i, hit) in hits.iter().enumerate() {
+                if hit
+                    .round
+                    .is_some_and(|round| round < last[hit.query_name.as_str()])
+                {
+                    keep[i] = false;
+                }
+            }
+        }
+
+        if self.best_per_target {
+            let mut per_target: HashMap<&str, Vec<usize>> = HashMap::new();
+            for (i, hit) in hits.iter().enumerate().filter(|&(i, _)| keep[i]) {
+                per_target.entry(&hit.target_name).or_default().push(i);
+            }
+            for mut indices in per_target.into_values() {
+                indices.sort_by(|&a, &b| Rank::EValue.compare(&hits[a], &hits[b]));
+                let mut kept: Vec<(u64, u64)> = Vec::new();
+                for i in indices {
+                    let span = span(&hits[i]);
+                    if kept
+                        .iter()
+                        .any(|&(from, to)| span.0 <= to && from <= span.1)
+                    {
+                        keep[i] = false;
+                    } else {
+                        kept.push(span);
+                    }
+                }
+            }
+        }
+
+        if let Some(fraction) = self.non_redundant {
+            let mut per_target: HashMap<&str, Vec<usize>> = HashMap::new();
+            for (i, hit) in hits.iter().enumerate().filter(|&(i, _)| keep[i]) {
+                per_target.entry(&hit.target_name).or_default().push(i);
+            }
+            for mut indices in per_target.into_values() {
+                indices.sort_by(|&a, &b| Rank::Score.compare(&hits[a], &hits[b]));
+                let mut kept: Vec<usize> = Vec::new();
+                for i in indices {
+                    let redundant = kept.iter().any(|&j| {
+                        hits[j].query_name != hits[i].query_name
+                            && overlap_fraction(span(&hits[i]), span(&hits[j])) >= fraction
+                    });
+                    if redundant {
+                        keep[i] = false;
+                    } else {
+                        kept.push(i);
+                    }
+                }
+            }
+        }
+
+        if let Some(top_n) = self.top_n {
+            let mut per_query: HashMap<&str, Vec<usize>> = HashMap::new();
+            for (i, hit) in hits.iter().enumerate().filter(|&(i, _)| keep[i]) {
+                per_query.entry(&hit.query_name).or_default().push(i);
+            }
+            for mut indices in per_query.into_values() {
+                indices.sort_by(|&a, &b| self.rank_by.compare(&hits[a], &hits[b]));
+                for i in indices.into_iter().skip(top_n) {
+                    keep[i] = false;
+                }
+            }
+        }
+
+        keep
+    }
+
+    /// Whether `hit` passes all of the filters on single hits.
+    pub fn passes(&self, hit: &Hit) -> bool {
+        let threshold = self.threshold(hit);
+        let e_value_threshold = threshold
+            .and_then(|t| t.e_value)
+            .unwrap_or(self.e_value_threshold);
+        let min_score = threshold.and_then(|t| t.min_score).or(self.min_score);
+
+        // not interested in low value hits
+        hit.e_value <= e_value_threshold
+            && min_score.is_none_or(|min_score| hit.score >= min_score)
+            && (!self.inc_only || hit.included == Some(true))
+            && self
+                .targets
+                .as_ref()
+                .is_none_or(|targets| targets.contains(&hit.target_name))
+            && self
+                .target_regex
+                .as_ref()
+                .is_none_or(|re| re.is_match(&hit.target_name))
+            && self.queries.as_ref().is_none_or(|queries| {
+                queries.contains(&hit.query_name) || queries.contains(&hit.query_accession)
+            })
+            && self
+                .query_regex
+                .as_ref()
+                .is_none_or(|re| re.is_match(&hit.query_name) || re.is_match(&hit.query_accession))
+            && self
+                .min_model_coverage
+                .is_none_or(|min| self.model_coverage(hit).is_some_and(|c| c >= min))
+            && match (self.round, hit.round) {
+                (Round::Number(n), Some(round)) => round == n,
+                _ => true,
+            }
+            && self.expr.as_ref().is_none_or(|expr| expr.matches(hit))
+    }
+
+    /// The cutoffs for the query of `hit`, if it has its own.
+    fn threshold(&self, hit: &Hit) -> Option<&Threshold> {
+        self.thresholds
+            .get(&hit.query_name)
+            .or_else(|| self.thresholds.get(&hit.query_accession))
+    }
+
+    /// The fraction of its model covered by `hit`, if that is known.
+    pub fn model_coverage(&self, hit: &Hit) -> Option<f64> {
+        let (from, to) = hit.hmm_from.zip(hit.hmm_to)?;
+        let length = hit.query_len.or_else(|| {
+            self.model_lengths
+                .get(&hit.query_name)
+                .or_else(|| self.model_lengths.get(&hit.query_accession))
+                .copied()
+        })?;
+        Some((from.max(to) - from.min(to) + 1) as f64 / length as f64)
+    }
+}
+
+/// The span of `hit` on its target, lowest coordinate first. Hits without
+/// coordinates span the whole target.
+fn span(hit: &Hit) -> (u64, u64) {
+    match hit.ali() {
+        Some((from, to)) => (from.min(to), from.max(to)),
+        None => (0, u64::MAX),
+    }
+}
+
+/// The fraction of the shorter of two spans that they overlap by.
+fn overlap_fraction(a: (u64, u64), b: (u64, u64)) -> f64 {
+    let len = |(from, to): (u64, u64)| (to - from).saturating_add(1);
+    let overlap = match a.1.min(b.1).checked_sub(a.0.max(b.0)) {
+        Some(overlap) => overlap.saturating_add(1),
+        None => return 0.0,
+    };
+    overlap as f64 / len(a).min(len(b)) as f64
+}
+
+/// Read the length of each model in an HMMER or Infernal model file, by
+/// name and accession. Infernal models use their consensus length (`CLEN`).
+pub fn read_model_lengths<P: AsRef<Path>>(path: P) -> Result<HashMap<String, u64>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read models from {}", path.display()))?;
+
+    let mut lengths = HashMap::new();
+    let mut names: Vec<String> = Vec::new();
+    for line in contents.lines() {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("NAME" | "ACC"), Some(name)) => names.push(name.to_string()),
+            (Some("CLEN" | "LENG"), Some(length)) => {
+                let length: u64 = length
+                    .parse()
+                    .with_context(|| format!("invalid model length \"{}\"", length))?;
+                for name in names.drain(..) {
+                    lengths.entry(name).or_insert(length);
+                }
+            }
+            (Some("//"), _) => names.clear(),
+            _ => (),
+        }
+    }
+    Ok(lengths)
+}
+
+/// Read per-query cutoffs, one query per line: its name or accession, an
+/// E-value threshold and optionally a minimum score, separated by tabs or
+/// spaces. `-` leaves a cutoff at the global one. Blank lines and lines
+/// starting with `#` are skipped.
+pub fn read_thresholds<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Threshold>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read thresholds from {}", path.display()))?;
+
+    let cutoff = |value: Option<&str>, line_no: usize| -> Result<Option<f64>> {
+        match value {
+            None | Some("-") => Ok(None),
+            Some(value) => value.parse().map(Some).with_context(|| {
+                format!(
+                    "{}:{}: invalid cutoff \"{}\"",
+                    path.display(),
+                    line_no,
+                    value
+                )
+            }),
+        }
+    };
+
+    let mut thresholds = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let query = fields.next().expect("line is not empty");
+        let threshold = Threshold {
+            e_value: cutoff(fields.next(), i + 1)?,
+            min_score: cutoff(fields.next(), i + 1)?,
+        };
+        if fields.next().is_some() {
+            bail!(
+                "{}:{}: expected a query, an E-value and a score",
+                path.display(),
+                i + 1
+            );
+        }
+        thresholds.insert(query.to_string(), threshold);
+    }
+    Ok(thresholds)
+}
+
+/// Read a list of names, one per line. Blank lines and lines starting
+/// with `#` are skipped.
+pub fn read_names<P: AsRef<Path>>(path: P) -> Result<HashSet<String>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read names from {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
@@ -0,0 +1,130 @@
+//! Templates for the headers of extracted sequences.
+
+use std::fmt::Write;
+
+use anyhow::{bail, Result};
+
+use crate::tblout::Hit;
+
+/// A value that can be put into a header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Species,
+    Name,
+    Target,
+    From,
+    To,
+    Strand,
+    Evalue,
+    Score,
+    Query,
+    Features,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "species" => Field::Species,
+            "name" => Field::Name,
+            "target" => Field::Target,
+            "from" => Field::From,
+            "to" => Field::To,
+            "strand" => Field::Strand,
+            "evalue" => Field::Evalue,
+            "score" => Field::Score,
+            "query" => Field::Query,
+            "features" => Field::Features,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Piece {
+    Literal(String),
+    Field(Field),
+}
+
+/// A parsed `--header-format`, e.g. `{target}_{from}_{to}`.
+#[derive(Debug, Clone)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+/// What a template is filled in with for one hit.
+pub struct Values<'a> {
+    pub hit: &'a Hit,
+    pub species_id: &'a str,
+    /// The name of the target, after any renaming.
+    pub target: &'a str,
+    /// The name `esl-sfetch` would give the extracted sequence.
+    pub name: &'a str,
+    /// The extracted region, in the orientation it is extracted.
+    pub coords: Option<(u64, u64)>,
+    /// The annotated features the hit overlaps, separated by commas.
+    pub features: Option<String>,
+}
+
+impl Template {
+    /// Parse a template, checking the placeholders are all known.
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut pieces = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                pieces.push(Piece::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                bail!("Unclosed placeholder in header format \"{}\"", template);
+            };
+            let name = &rest[start + 1..start + end];
+            match Field::from_name(name) {
+                Some(field) => pieces.push(Piece::Field(field)),
+                None => bail!("Unknown placeholder {{{}}} in header format", name),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Literal(rest.to_string()));
+        }
+        Ok(Self { pieces })
+    }
+
+    /// Fill in the template. Hits without coordinates cover their whole
+    /// target, from 1 to its length.
+    pub fn render(&self, values: &Values) -> String {
+        let hit = values.hit;
+        let (from, to) = match values.coords {
+            Some((from, to)) => (Some(from), Some(to)),
+            None => (Some(1), hit.sq_len),
+        };
+
+        let mut header = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(s) => header.push_str(s),
+                Piece::Field(field) => {
+                    let _ = match field {
+                        Field::Species => write!(header, "{}", values.species_id),
+                        Field::Name => write!(header, "{}", values.name),
+                        Field::Target => write!(header, "{}", values.target),
+                        Field::From => write!(header, "{}", opt(from)),
+                        Field::To => write!(header, "{}", opt(to)),
+                        Field::Strand => write!(header, "{}", hit.strand),
+                        Field::Evalue => write!(header, "{:e}", hit.e_value),
+                        Field::Score => write!(header, "{}", hit.score),
+                        Field::Query => write!(header, "{}", hit.query_name),
+                        Field::Features => {
+                            write!(header, "{}", values.features.as_deref().unwrap_or_default())
+                        }
+                    };
+                }
+            }
+        }
+        header
+    }
+}
+
+fn opt(n: Option<u64>) -> String {
+    n.map(|n| n.to_string()).unwrap_or_default()
+}
@@ -0,0 +1,147 @@
+//! Building `.fai` and `.gzi` indexes, for plain and BGZF-compressed fasta.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use noodles_bgzf::gzi;
+use noodles_fasta::fai;
+
+/// The empty block every BGZF file ends with.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// `path` with `suffix` appended, as samtools names its indexes, e.g.
+/// `genome.fa.fai`.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = OsString::from(path);
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+/// Whether the file at `path` is BGZF, going by its EOF marker.
+pub fn is_bgzf(path: &Path) -> Result<bool> {
+    let mut file =
+        File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    if file.metadata()?.len() < BGZF_EOF.len() as u64 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::End(-(BGZF_EOF.len() as i64)))?;
+    let mut tail = [0; 28];
+    file.read_exact(&mut tail)?;
+    Ok(tail == BGZF_EOF)
+}
+
+/// Index an uncompressed fasta stream. Offsets are in the uncompressed data,
+/// as samtools expects for BGZF.
+pub fn index_fasta<R: BufRead>(mut reader: R) -> Result<fai::Index> {
+    // name, length, offset, line bases, line width
+    let mut records: Vec<(Vec<u8>, u64, u64, u64, u64)> = Vec::new();
+    let mut offset = 0;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)? as u64;
+        if n == 0 {
+            break;
+        }
+        offset += n;
+
+        if let Some(definition) = line.strip_prefix(b">") {
+            let name = definition
+                .split(|b| b.is_ascii_whitespace())
+                .next()
+                .unwrap_or_default();
+            records.push((name.to_vec(), 0, offset, 0, 0));
+            continue;
+        }
+
+        let Some(record) = records.last_mut() else {
+            bail!("Sequence found before the first header");
+        };
+        let bases = line.trim_ascii_end().len() as u64;
+        if record.3 == 0 {
+            record.3 = bases;
+            record.4 = n;
+        }
+        record.1 += bases;
+    }
+
+    Ok(records
+        .into_iter()
+        .map(|(name, length, offset, line_bases, line_width)| {
+            fai::Record::new(name, length, offset, line_bases, line_width)
+        })
+        .collect())
+}
+
+/// Build the `.gzi` index of the BGZF file at `path`: the compressed and
+/// uncompressed offset of every block. As with [`gzi::read`], this includes
+/// the first block, which the file format leaves out.
+pub fn index_bgzf(path: &Path) -> Result<gzi::Index> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Could not open {}", path.display()))?,
+    );
+    let mut entries = vec![(0, 0)];
+    let (mut compressed, mut uncompressed) = (0u64, 0u64);
+
+    let mut header = [0; 18];
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        ensure!(
+            header[..4] == [0x1f, 0x8b, 0x08, 0x04] && header[12..14] == *b"BC",
+            "{} is not BGZF",
+            path.display()
+        );
+        // BSIZE is the block size minus one
+        let block_size = u64::from(u16::from_le_bytes([header[16], header[17]])) + 1;
+        let mut rest = vec![0; block_size as usize - header.len()];
+        reader.read_exact(&mut rest)?;
+        let isize = &rest[rest.len() - 4..];
+        let block_len = u64::from(u32::from_le_bytes([isize[0], isize[1], isize[2], isize[3]]));
+
+        if compressed > 0 && block_len > 0 {
+            entries.push((compressed, uncompressed));
+        }
+        compressed += block_size;
+        uncompressed += block_len;
+    }
+
+    Ok(entries)
+}
+
+/// Write a `.fai` index to `path`.
+pub fn write_fai(path: &Path, index: &fai::Index) -> Result<()> {
+    let mut file = BufWriter::new(
+        File::create(path).with_context(|| format!("Could not create {}", path.display()))?,
+    );
+    fai::Writer::new(&mut file).write_index(index)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Write a `.gzi` index to `path`.
+pub fn write_gzi(path: &Path, index: &gzi::Index) -> Result<()> {
+    let entries = index.strip_prefix(&[(0, 0)]).unwrap_or(index);
+    let mut writer = BufWriter::new(
+        File::create(path).with_context(|| format!("Could not create {}", path.display()))?,
+    );
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for (compressed, uncompressed) in entries {
+        writer.write_all(&compressed.to_le_bytes())?;
+        writer.write_all(&uncompressed.to_le_bytes())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
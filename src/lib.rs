@@ -0,0 +1,65 @@
+//! Extract the sequences of nhmmer hits from a fasta file.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use extract_nhmmer_tblout::{Backend, ExtractorBuilder};
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     let mut extractor = ExtractorBuilder::new("hits.tbl")
+//!         .fasta("genome.fa")
+//!         .backend(Backend::Native)
+//!         .e_value_threshold(1e-10)
+//!         .build()?;
+//!
+//!     for record in extractor.records() {
+//!         let r = record?;
+//!         println!("{}", std::str::from_utf8(r.name())?);
+//!     }
+//!
+//!     extractor.close()
+//! }
+//! ```
+
+pub mod align;
+pub mod annotation;
+pub mod check;
+pub mod compare;
+pub mod convert;
+pub mod dedup;
+pub mod expr;
+mod extractor;
+mod fetcher;
+pub mod filter;
+mod header;
+pub mod index;
+pub mod manifest;
+pub mod merge;
+mod native;
+mod orf;
+mod prepare;
+pub mod progress;
+pub mod regions;
+pub mod rename;
+pub mod report;
+pub mod sam;
+pub mod stats;
+pub mod stitch;
+mod stream;
+pub mod taxonomy;
+pub mod tblout;
+pub mod translate;
+mod twobit;
+mod workdir;
+
+pub use crate::{
+    annotation::Annotation,
+    extractor::{
+        Backend, Coords, Extractor, ExtractorBuilder, Mask, Planned, Records, SortBy, Trim,
+    },
+    fetcher::{Region, SequenceFetcher},
+    filter::HitFilter,
+    native::{reverse_complement, NativeFetcher},
+    regions::Regions,
+    tblout::{Hit, Tblout},
+};
@@ -1,193 +1,1278 @@
 use std::{
-    ffi::OsStr,
-    fs::File,
-    io,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
     path::{Path, PathBuf},
-    process::Stdio,
+    process::Command as Cmd,
 };
 
-use anyhow::{Context, Result};
-use clap::{arg, command, crate_version, value_parser, Arg};
-use fasta::record::Definition;
-use hmm_tblout::Reader;
+use anyhow::{bail, ensure, Context, Result};
+use clap::ArgMatches;
+use clap_complete::Shell;
+use env_logger::Env;
+use extract_nhmmer_tblout::{
+    align::Hmmalign,
+    check,
+    compare::{self, Matching},
+    convert,
+    dedup::{Dedup, Seen},
+    index::with_suffix,
+    manifest, rename, report, sam, stats,
+    taxonomy::{self, LabelFormat},
+    tblout::Strand,
+    translate::GeneticCode,
+    Annotation, Backend, Extractor, ExtractorBuilder, Regions, Tblout, Trim,
+};
+use log::{debug, info, warn};
 use noodles_fasta as fasta;
-use std::process::Command as Cmd;
-use tempfile::tempdir;
+use tempfile::{tempdir, tempdir_in};
 
-fn get_extension_from_filename(filename: &str) -> Option<&str> {
-    Path::new(filename).extension().and_then(OsStr::to_str)
-}
+mod checkpoint;
+mod cli;
+mod config;
+mod output;
+mod provenance;
+
+use checkpoint::Checkpoint;
+use output::Output;
+use provenance::RunManifest;
 
 fn main() -> Result<()> {
     // set up the app
-    let matches = command!()
-        .version(crate_version!())
-        .author("Max Carter-Brown <max.carter-brown@aru.ac.uk>")
-        .about("Extracts sequences from a fasta file using nhmmer tblout file.")
-        .arg_required_else_help(true)
-        .arg(
-            arg!(<TBL> "Path to the nhmmer tblout file.")
-                .required(true)
-                .value_parser(value_parser!(PathBuf)),
-        )
-        .arg(
-            arg!([FASTA] "Path to the fasta file used for nhmmer output. If not specified, the target file from the tblout file is used (this probably only works when that file path is absolute).")
-                .value_parser(value_parser!(PathBuf)),
-        )
-        .arg(
-            Arg::new("esl-sfetch")
-                .short('e')
-                .long("esl-sfetch")
-                .value_parser(value_parser!(PathBuf))
-                .required(true)
-                .help("Path to esl-sfetch. If not installed, it's part of HMMER."),
-        )
-        .arg(
-            Arg::new("e_value_threshold")
-                .short('v')
-                .long("e-value-threshold")
-                .value_parser(value_parser!(f32))
-                .required(false)
-                .default_value("0.00001")
-                .help("E-value threshold for hits to extract."),
-        )
-        .arg(
-            Arg::new("species_id")
-                .short('s')
-                .long("species-id")
-                .value_parser(value_parser!(String))
-                .required(false)
-                .default_value("")
-                .help("Species ID to add to the start of the header. Useful for downstream processing."),
-        )
-        .get_matches();
+    let mut matches = cli::build().get_matches();
+    if let Some(path) = matches.get_one::<PathBuf>("config") {
+        let args = config::args(cli::build(), path, env::args_os().collect(), &matches)?;
+        matches = cli::build().get_matches_from(args);
+    }
+    init_logging(&matches);
+
+    match matches.subcommand() {
+        Some(("extract", m)) => extract(m),
+        Some(("run", m)) => run(m),
+        Some(("check", m)) => check(m),
+        Some(("filter", m)) => filter(m),
+        Some(("stats", m)) => summarise(m),
+        Some(("report", m)) => report(m),
+        Some(("convert", m)) => convert(m),
+        Some(("intersect", m)) => compare(m, true),
+        Some(("subtract", m)) => compare(m, false),
+        Some(("completions", m)) => completions(m),
+        Some(("man", m)) => man(m),
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
+/// Log to stderr at the level asked for: warnings and errors with
+/// `--quiet`, info by default, then debug and trace with each `--verbose`.
+fn init_logging(matches: &ArgMatches) {
+    let level = if matches.get_flag("quiet") {
+        "warn"
+    } else {
+        match matches.get_count("verbose") {
+            0 => "info",
+            1 => "debug",
+            _ => "trace",
+        }
+    };
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or(format!(
+        "{}={}",
+        env!("CARGO_CRATE_NAME"),
+        level
+    )));
+    if matches.get_one::<String>("log_format").map(String::as_str) == Some("json") {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "time": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+fn read_tblout(matches: &ArgMatches) -> Result<Tblout> {
+    let tblout = Tblout::from_paths(
+        &cli::tbl_paths(matches)?,
+        cli::format(matches)?,
+        matches.get_flag("lenient"),
+    )?;
+    report_skipped_lines(tblout.skipped());
+    Ok(tblout)
+}
+
+fn output(matches: &ArgMatches) -> Result<Output> {
+    Output::new(
+        matches.get_one::<PathBuf>("output").map(PathBuf::as_path),
+        matches.get_flag("compress"),
+        matches.get_flag("bgzf"),
+    )
+}
+
+fn extract(matches: &ArgMatches) -> Result<()> {
+    if let Some(jobs) = matches.get_one::<PathBuf>("batch") {
+        return extract_batch(matches, jobs);
+    }
 
     // get the matches
-    let tbl = matches
-        .get_one::<PathBuf>("TBL")
-        .expect("tbl is required")
-        .clone();
+    let mut tbls = cli::tbl_paths(matches)?.into_iter();
+    let tbl = tbls.next().expect("tbl is required");
 
     let fasta_match = matches.get_one::<PathBuf>("FASTA").cloned();
 
-    let esl_sfetch = matches
-        .get_one::<PathBuf>("esl-sfetch")
-        .expect("esl-sfetch is required")
-        .clone();
+    let species_id = species_id(matches)?;
+
+    let mut builder = extractor_builder(matches, tbl, species_id)?;
+    if let Some(format) = cli::format(matches)? {
+        builder = builder.format(format);
+    }
+    for tbl in tbls {
+        builder = builder.add_tbl(tbl);
+    }
+    if let Some(fasta) = fasta_match {
+        builder = builder.fasta(fasta);
+    }
+    if let Some(fastas) = matches.get_many::<PathBuf>("fasta") {
+        for fasta in fastas {
+            builder = builder.add_fasta(fasta);
+        }
+    }
+    if let Some(dir) = matches.get_one::<PathBuf>("fasta_dir") {
+        builder = builder.fasta_dir(dir);
+    }
+    if let Some(dir) = resumable_workdir(matches) {
+        builder = builder.workdir(dir);
+    }
+    if matches.get_flag("dry_run") {
+        return dry_run(builder, matches);
+    }
+    write_outputs(builder.build()?, matches)
+}
+
+/// Where an extraction that can be resumed prepares the fasta, unless told
+/// where: next to its output, and kept until the output is finished, so
+/// that a run carrying on from its checkpoint does not prepare it again.
+fn resumable_workdir(matches: &ArgMatches) -> Option<PathBuf> {
+    if !matches.contains_id("resume") {
+        return None;
+    }
+    let path = matches.get_one::<PathBuf>("output")?;
+    let placed = [
+        "workdir",
+        "tmpdir",
+        "batch",
+        "split_by_query",
+        "outdir",
+        "align_with",
+    ]
+    .into_iter()
+    .any(|id| matches.get_one::<PathBuf>(id).is_some());
+    let unresumable = [
+        "no_index",
+        "prepare_in_place",
+        "dry_run",
+        "compress",
+        "bgzf",
+        "dedup",
+        "split_by_strand",
+        "per_hit",
+    ]
+    .into_iter()
+    .any(|id| matches.get_flag(id));
+    let compressed = path.extension().is_some_and(|e| e == "gz");
+    (!placed && !unresumable && !compressed).then(|| with_suffix(path, ".workdir"))
+}
 
-    let e_value_threshold = *matches
-        .get_one::<f32>("e_value_threshold")
+/// Write what would be extracted as a TSV, without touching the fasta.
+fn dry_run(builder: ExtractorBuilder, matches: &ArgMatches) -> Result<()> {
+    let planned = builder.dry_run()?;
+    info!("{} hits would be extracted", planned.len());
+
+    let mut out = output(matches)?;
+    writeln!(out, "target\tfrom\tto\tstrand\theader")?;
+    for p in &planned {
+        let (from, to) = match p.region {
+            Some((from, to)) => (from.to_string(), to.to_string()),
+            None => ("NA".to_string(), "NA".to_string()),
+        };
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            p.target, from, to, p.strand, p.header
+        )?;
+    }
+    out.finish()?;
+    check_extracted(matches, planned.len())
+}
+
+/// Search the genome with nhmmer, then extract the hits as `extract` would.
+fn run(matches: &ArgMatches) -> Result<()> {
+    let hmm = matches.get_one::<PathBuf>("HMM").expect("hmm is required");
+    let fasta = matches
+        .get_one::<PathBuf>("FASTA")
+        .expect("fasta is required");
+    let nhmmer = matches
+        .get_one::<PathBuf>("nhmmer")
         .expect("defaulted by clap");
 
-    let species_id = matches
-        .get_one::<String>("species_id")
+    let tmpdir = match matches.get_one::<PathBuf>("tmpdir") {
+        Some(dir) => tempdir_in(dir)
+            .with_context(|| format!("Could not create tempdir in {}", dir.display()))?,
+        None => tempdir().context("Could not create tempdir")?,
+    };
+    let tbl = match matches.get_one::<PathBuf>("tblout") {
+        Some(tbl) => tbl.clone(),
+        None => tmpdir.path().join("hits.tbl"),
+    };
+
+    // the alignments go to a file, so that only the sequences are on stdout
+    let mut cmd = Cmd::new(nhmmer);
+    cmd.arg("--tblout")
+        .arg(&tbl)
+        .arg("-o")
+        .arg(tmpdir.path().join("nhmmer.out"));
+    cmd.args(cli::nhmmer_options(matches)?);
+    cmd.arg(hmm).arg(fasta);
+
+    info!("Running nhmmer");
+    debug!("Running {:?}", cmd);
+    let status = cmd
+        .status()
+        .with_context(|| format!("Could not run {}", nhmmer.display()))?;
+    ensure!(status.success(), "nhmmer failed ({})", status);
+
+    let species_id = species_id(matches)?;
+    let extractor = extractor_builder(matches, tbl, species_id)?
+        .fasta(fasta)
+        .build()?;
+    write_outputs(extractor, matches)?;
+
+    tmpdir.close()?;
+    Ok(())
+}
+
+/// Write the sequences, and any BED, GFF, SAM or manifest asked for.
+fn write_outputs(mut extractor: Extractor, matches: &ArgMatches) -> Result<()> {
+    let extracted = extractor.len();
+    let run_manifest = match matches.get_one::<PathBuf>("run_manifest") {
+        Some(path) => Some((RunManifest::new(&extractor, matches)?, path)),
+        None => None,
+    };
+    if let Some(bed) = matches.get_one::<PathBuf>("bed") {
+        extractor.write_bed(create(bed, "BED file")?)?;
+    }
+    if let Some(gff) = matches.get_one::<PathBuf>("gff") {
+        extractor.write_gff(create(gff, "GFF file")?)?;
+    }
+    if let Some(sam) = matches.get_one::<PathBuf>("sam") {
+        extractor.write_sam(create(sam, "SAM file")?, sam::Format::from_path(sam))?;
+    }
+    // with --dedup the manifest waits for the sequences to be compared
+    let mut dedup = dedup(matches);
+    let manifest = matches.get_one::<PathBuf>("manifest");
+    if let Some(path) = manifest.filter(|_| dedup.is_none()) {
+        manifest::write_manifest(
+            create(path, "manifest")?,
+            &extractor.manifest()?,
+            manifest::Format::from_path(path),
+        )?;
+    }
+
+    info!("Iterating over tblout");
+    if let Some(hmm) = matches.get_one::<PathBuf>("align_with") {
+        let aligner = Hmmalign::new(
+            matches
+                .get_one::<PathBuf>("hmmalign")
+                .expect("defaulted by clap"),
+            hmm,
+            matches
+                .get_one::<String>("align_format")
+                .expect("defaulted by clap")
+                .parse()?,
+        )?;
+        write_alignments(&mut extractor, &aligner, matches)?;
+        report_missing(&extractor);
+        report_skipped_lines(extractor.skipped_lines());
+        extractor.close()?;
+        if let Some((run_manifest, path)) = run_manifest {
+            run_manifest.finish(matches, path)?;
+        }
+        return check_extracted(matches, extracted);
+    }
+    let split = matches.get_one::<PathBuf>("split_by_query");
+    let by_strand = matches.get_flag("split_by_strand");
+    match (split, matches.get_one::<PathBuf>("outdir")) {
+        (Some(dir), _) => split_by_query(&mut extractor, dir, by_strand, line_width(matches))?,
+        (None, Some(dir)) => write_per_hit(&mut extractor, dir, line_width(matches))?,
+        (None, None) if by_strand => split_by_strand(&mut extractor, matches)?,
+        (None, None) => {
+            let path = matches.get_one::<PathBuf>("output").map(PathBuf::as_path);
+            let resuming = matches.contains_id("resume") && matches.get_flag("resume");
+            let (mut out, start) = match path {
+                Some(path) if resuming => resume(&extractor, path, matches)?,
+                _ => (output(matches)?, 0),
+            };
+            let width = line_width(matches);
+            // only uncompressed files can be cut back to a checkpoint
+            let representatives = match (path, &out) {
+                (Some(path), Output::File(_)) if dedup.is_none() => {
+                    write_checkpointed(&mut extractor, &mut out, path, start, width)?;
+                    Vec::new()
+                }
+                _ => {
+                    debug_assert_eq!(start, 0, "--resume conflicts with --dedup");
+                    write_records(&mut extractor, &mut out, dedup.as_mut(), width)?
+                }
+            };
+            if let Some(dedup) = dedup {
+                finish_dedup(dedup, &mut out, width)?;
+                if let Some(path) = manifest {
+                    let mut entries = extractor.manifest()?;
+                    for (entry, representative) in entries.iter_mut().zip(representatives) {
+                        entry.representative = representative;
+                    }
+                    manifest::write_manifest(
+                        create(path, "manifest")?,
+                        &entries,
+                        manifest::Format::from_path(path),
+                    )?;
+                }
+            }
+            finish_fasta(out, path, matches.get_flag("fai"))?;
+            if let Some(path) = path {
+                Checkpoint::remove(path)?;
+            }
+        }
+    }
+
+    report_missing(&extractor);
+    report_skipped_lines(extractor.skipped_lines());
+    // and close the tmpdir
+    extractor.close()?;
+    // the output is finished, so the fasta prepared for resuming it is not
+    // needed any more
+    if let Some(dir) = resumable_workdir(matches).filter(|dir| dir.exists()) {
+        fs::remove_dir_all(&dir).with_context(|| format!("Could not remove {}", dir.display()))?;
+    }
+    if let Some((run_manifest, path)) = run_manifest {
+        run_manifest.finish(matches, path)?;
+    }
+
+    check_extracted(matches, extracted)
+}
+
+/// Fail if fewer sequences were extracted than `--min-hits`, or none with
+/// `--fail-if-empty`, so that workflows do not carry on with empty output.
+fn check_extracted(matches: &ArgMatches, extracted: usize) -> Result<()> {
+    ensure!(
+        extracted > 0 || !matches.get_flag("fail_if_empty"),
+        "No sequences were extracted"
+    );
+    if let Some(&min_hits) = matches.get_one::<usize>("min_hits") {
+        ensure!(
+            extracted >= min_hits,
+            "Only {} sequences were extracted, fewer than --min-hits {}",
+            extracted,
+            min_hits
+        );
+    }
+    Ok(())
+}
+
+/// An extractor for `tbl` with every setting that is the same for each
+/// job of a batch.
+fn extractor_builder(
+    matches: &ArgMatches,
+    tbl: PathBuf,
+    species_id: String,
+) -> Result<ExtractorBuilder> {
+    let flank = *matches.get_one::<u64>("flank").expect("defaulted by clap");
+    let flank_5p = matches.get_one::<u64>("flank_5p").copied().unwrap_or(flank);
+    let flank_3p = matches.get_one::<u64>("flank_3p").copied().unwrap_or(flank);
+    let trim = matches.get_one::<Trim>("trim").copied().unwrap_or_default();
+    let trim_5p = matches.get_one::<Trim>("trim_5p").copied().unwrap_or(trim);
+    let trim_3p = matches.get_one::<Trim>("trim_3p").copied().unwrap_or(trim);
+    // the files of each strand are named after the output
+    // a dry run does not extract anything, and --no-index reads the fasta
+    // itself, so neither needs a backend
+    let dry_run = matches.contains_id("dry_run") && matches.get_flag("dry_run");
+    let no_backend = dry_run || matches.get_flag("no_index");
+    let backend = match matches
+        .get_one::<String>("backend")
         .expect("defaulted by clap")
-        .clone();
+        .as_str()
+    {
+        "native" => Backend::Native,
+        "samtools" => match matches.get_one::<PathBuf>("samtools") {
+            Some(samtools) => Backend::Samtools(samtools.clone()),
+            None if no_backend => Backend::Native,
+            None => bail!("--samtools is required with `--backend samtools`"),
+        },
+        _ => match matches.get_one::<PathBuf>("esl-sfetch") {
+            Some(esl_sfetch) => Backend::EslSfetch(esl_sfetch.clone()),
+            None if no_backend => Backend::Native,
+            None => bail!("--esl-sfetch is required with `--backend esl-sfetch`"),
+        },
+    };
+
+    let mut builder = ExtractorBuilder::new(tbl)
+        .backend(backend)
+        .filter(cli::hit_filter(matches)?)
+        .species_id(species_id)
+        .revcomp_minus(matches.get_flag("revcomp_minus"))
+        .flank(flank_5p, flank_3p)
+        .trim(trim_5p, trim_3p)
+        .stitch(matches.get_flag("stitch"))
+        .full_seq(matches.get_flag("full_seq"))
+        .with_coords(matches.get_flag("with_coords"))
+        .esl_names(matches.get_flag("esl_names"))
+        .seqkit_names(matches.get_flag("seqkit_names"))
+        .meta_in_description(matches.get_flag("meta_in_description"))
+        .strict_names(matches.get_flag("strict_names"))
+        .mask(
+            matches
+                .get_one::<String>("mask")
+                .expect("defaulted by clap")
+                .parse()?,
+        )
+        .no_index(matches.get_flag("no_index"))
+        .reuse_index(matches.get_flag("reuse_index"))
+        .keep_index(matches.get_flag("keep_index"))
+        .overwrite_index(matches.get_flag("overwrite_index"))
+        .skip_missing(matches.get_flag("skip_missing"))
+        .strict_duplicates(matches.get_flag("strict_duplicates"))
+        .lenient(matches.contains_id("lenient") && matches.get_flag("lenient"))
+        .clip(matches.get_flag("clip"))
+        .progress(!matches.get_flag("no_progress") && !matches.get_flag("quiet"))
+        .threads(
+            *matches
+                .get_one::<usize>("threads")
+                .expect("defaulted by clap"),
+        )
+        .decompress_threads(
+            *matches
+                .get_one::<usize>("decompress_threads")
+                .expect("defaulted by clap"),
+        );
+    if let Some(bases) = matches.get_one::<u64>("upstream") {
+        builder = builder.upstream(*bases);
+    }
+    if let Some(bases) = matches.get_one::<u64>("downstream") {
+        builder = builder.downstream(*bases);
+    }
+    if let Some(targets) = matches.get_many::<String>("circular_targets") {
+        builder = builder.circular(true).circular_targets(targets);
+    } else {
+        builder = builder.circular(matches.get_flag("circular"));
+    }
+    if let Some(dir) = matches.get_one::<PathBuf>("workdir") {
+        builder = builder.workdir(dir);
+    }
+    if let Some(dir) = matches.get_one::<PathBuf>("tmpdir") {
+        builder = builder.tmpdir(dir);
+    }
+    builder = builder.prepare_in_place(matches.get_flag("prepare_in_place"));
+    if let Some(distance) = matches.get_one::<u64>("merge_distance") {
+        builder = builder.merge_distance(*distance);
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("rename_map") {
+        builder = builder.rename_targets(rename::read_rename_map(path)?);
+    }
+    if let Some(gff) = matches.get_one::<PathBuf>("annotate") {
+        let types: Vec<String> = matches
+            .get_many::<String>("annotate_types")
+            .expect("defaulted by clap")
+            .filter(|t| *t != "all")
+            .cloned()
+            .collect();
+        builder = builder.annotate(Annotation::from_gff3(gff, &types)?);
+    }
+    if let Some(bed) = matches.get_one::<PathBuf>("exclude_bed") {
+        builder = builder
+            .exclude(Regions::from_bed(bed)?)
+            .trim_excluded(matches.get_flag("trim_excluded"));
+        if let Some(fraction) = matches.get_one::<f64>("max_excluded") {
+            builder = builder.max_excluded_fraction(*fraction);
+        }
+    }
+    if let Some(min_length) = matches.get_one::<u64>("min_length") {
+        builder = builder.min_length(*min_length);
+    }
+    if let Some(max_length) = matches.get_one::<u64>("max_length") {
+        builder = builder.max_length(*max_length);
+    }
+    if let Some(header_format) = matches.get_one::<String>("header_format") {
+        builder = builder.header_format(header_format);
+    }
+    if let Some(sort) = matches.get_one::<String>("sort") {
+        builder = builder.sort(sort.parse()?);
+    }
+    if let Some(coords) = matches.get_one::<String>("coords") {
+        builder = builder.coords(coords.parse()?);
+    }
+    let code = matches.get_one::<u8>("genetic_code").copied().unwrap_or(1);
+    if matches.get_flag("translate") {
+        builder = builder
+            .translate(GeneticCode::new(code)?)
+            .trim_translation(matches.get_flag("trim_translation"));
+    }
+    if matches.get_flag("extend_orf") {
+        builder = builder.extend_orf(GeneticCode::new(code)?);
+    }
+    Ok(builder)
+}
 
-    // copy the fasta to a temporary directory
-    let tmpdir = tempdir().context("Could not create tempdir")?;
+/// A row of a `--batch` file: a tblout, the fasta it was searched against
+/// and, optionally, a species ID.
+struct Job {
+    tbl: PathBuf,
+    fasta: PathBuf,
+    species_id: Option<String>,
+}
 
-    // read the tblout to ge the metadata
-    let mut reader = Reader::from_path(tbl)?;
-    let meta = reader.meta();
-    let target_file = meta.target_file();
+/// Read the tab separated jobs in `path`. Blank lines and lines starting
+/// with `#` are skipped.
+fn read_jobs(path: &Path) -> Result<Vec<Job>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read batch file {}", path.display()))?;
 
-    let fasta = match fasta_match {
-        Some(f) => f,
-        None => target_file,
+    let mut jobs = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t').map(str::trim);
+        let (Some(tbl), Some(fasta)) = (fields.next(), fields.next()) else {
+            bail!(
+                "Line {} of {} should have a tblout and a fasta, separated by a tab",
+                i + 1,
+                path.display()
+            );
+        };
+        jobs.push(Job {
+            tbl: PathBuf::from(tbl),
+            fasta: PathBuf::from(fasta),
+            species_id: fields.next().filter(|s| !s.is_empty()).map(String::from),
+        });
+    }
+    Ok(jobs)
+}
+
+/// The species ID of `--taxid`, or else `--species-id`.
+fn species_id(matches: &ArgMatches) -> Result<String> {
+    match matches.get_one::<u64>("taxid") {
+        Some(&taxid) => Ok(species_labels(matches, HashSet::from([taxid]))?
+            .remove(&taxid)
+            .expect("every taxid has a label")),
+        None => Ok(matches
+            .get_one::<String>("species_id")
+            .expect("defaulted by clap")
+            .clone()),
+    }
+}
+
+/// The species IDs of `taxids`, from their names in `--taxdump`.
+fn species_labels(matches: &ArgMatches, taxids: HashSet<u64>) -> Result<HashMap<u64, String>> {
+    let path = matches
+        .get_one::<PathBuf>("taxdump")
+        .expect("required with taxids");
+    let format = match matches.get_one::<String>("species_format") {
+        Some(format) => format.parse()?,
+        None => LabelFormat::default(),
     };
+    let names = taxonomy::read_names(path, &taxids)?;
+    taxids
+        .into_iter()
+        .map(|taxid| match names.get(&taxid) {
+            Some(name) => Ok((taxid, format.label(name))),
+            None => bail!("Taxid {} is not in {}", taxid, path.display()),
+        })
+        .collect()
+}
 
-    // check if the fasta is gzipped
-    // if it is, use gunzip -c to copy to tmpdir
-    // else just copy over
-    let fasta_is_gzipped =
-        get_extension_from_filename(fasta.to_str().context("Could not convert path to string")?)
-            == Some("gz");
-    let new_fasta_path = if fasta_is_gzipped {
-        eprintln!("Input fasta is gzipped, unzipping...");
-
-        let fasta_file_name = fasta
-            .clone()
-            .file_stem()
-            .context("Could not get file stem")?
-            .to_os_string();
-
-        let fasta_file = File::create(tmpdir.path().join(&fasta_file_name))
-            .context("Could not create fasta file")?;
-        let stdio = Stdio::from(fasta_file);
-        let copy_via_gzip = Cmd::new("gunzip")
-            .arg("-c")
-            .arg(fasta.clone())
-            .stdout(stdio)
-            .spawn()?;
-        copy_via_gzip.wait_with_output()?;
-
-        fasta_file_name
+/// Extract each job in the `--batch` file at `path` with the same settings,
+/// into the one output or, with `--batch-dir`, one fasta per job. The BED
+/// and manifest cover all of the jobs.
+fn extract_batch(matches: &ArgMatches, path: &Path) -> Result<()> {
+    let jobs = read_jobs(path)?;
+    // look up all of the taxids in one pass over the taxdump
+    let taxid = matches.get_one::<u64>("taxid").copied();
+    let mut taxids: HashSet<u64> = taxid.into_iter().collect();
+    if matches.contains_id("taxdump") {
+        taxids.extend(
+            jobs.iter()
+                .filter_map(|job| job.species_id.as_deref()?.parse::<u64>().ok()),
+        );
+    }
+    let labels = if taxids.is_empty() {
+        HashMap::new()
     } else {
-        eprintln!("Input fasta is not gzipped, copying...");
-        let copy_over = Cmd::new("cp")
-            .arg(fasta.clone())
-            .arg(tmpdir.path())
-            .spawn()?;
-        copy_over.wait_with_output()?;
-
-        let fasta_file_name = fasta.clone();
-        fasta_file_name.into_os_string()
+        species_labels(matches, taxids)?
+    };
+    let default_species_id = match taxid {
+        Some(taxid) => labels[&taxid].clone(),
+        None => matches
+            .get_one::<String>("species_id")
+            .expect("defaulted by clap")
+            .clone(),
     };
+    let index = matches.get_flag("fai");
+    let width = line_width(matches);
 
-    // index the fasta
-    let new_fasta_location = tmpdir.path().join(new_fasta_path.clone());
-    eprintln!("New fasta location: {:?}", new_fasta_location);
-    eprintln!("Indexing fasta");
-    let _index_fasta = Cmd::new(esl_sfetch.clone())
-        .arg("--index")
-        .arg(new_fasta_location.clone())
-        .output()?;
-
-    eprintln!("Iterating over tblout");
-    for record in reader.records() {
-        let r = record?;
-        let eval = r.e_value().unwrap();
-
-        // not interested in low value hits
-        if eval > e_value_threshold {
+    let dir = matches.get_one::<PathBuf>("batch_dir");
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    }
+    let mut combined = match dir {
+        Some(_) => None,
+        None => Some(output(matches)?),
+    };
+    let mut bed = match matches.get_one::<PathBuf>("bed") {
+        Some(bed) => Some(create(bed, "BED file")?),
+        None => None,
+    };
+    let mut entries = Vec::new();
+    let mut names = HashSet::new();
+    let mut extracted = 0;
+    // one output is deduplicated as a whole, a directory file by file
+    let mut combined_dedup = dedup(matches).filter(|_| dir.is_none());
+
+    for job in jobs {
+        info!("Extracting {}", job.tbl.display());
+        let species_id = match job.species_id {
+            Some(id) => match id.parse::<u64>().ok().and_then(|taxid| labels.get(&taxid)) {
+                Some(label) => label.clone(),
+                None => id,
+            },
+            None => default_species_id.clone(),
+        };
+        // per job fastas are named by the species, or the tblout
+        let name = match species_id.as_str() {
+            "" => job
+                .tbl
+                .file_stem()
+                .context("Could not get file stem")?
+                .to_string_lossy()
+                .into_owned(),
+            species_id => species_id.to_string(),
+        };
+
+        let mut builder = extractor_builder(matches, job.tbl, species_id)?.fasta(job.fasta);
+        if let Some(format) = cli::format(matches)? {
+            builder = builder.format(format);
+        }
+        let mut extractor = builder.build()?;
+        if let Some(bed) = &mut bed {
+            extractor.write_bed(&mut *bed)?;
+        }
+        let first_entry = entries.len();
+        if matches.contains_id("manifest") {
+            entries.extend(extractor.manifest()?);
+        }
+
+        let representatives = match (dir, &mut combined) {
+            (Some(dir), _) => {
+                ensure!(
+                    names.insert(name.clone()),
+                    "More than one job would be written to {}.fa",
+                    name
+                );
+                let path = dir.join(format!("{}.fa", name));
+                let mut out = Output::new(Some(&path), false, false)?;
+                let mut dedup = dedup(matches);
+                let representatives =
+                    write_records(&mut extractor, &mut out, dedup.as_mut(), width)?;
+                if let Some(dedup) = dedup {
+                    finish_dedup(dedup, &mut out, width)?;
+                }
+                finish_fasta(out, Some(path.as_path()), index)?;
+                representatives
+            }
+            (None, Some(out)) => {
+                write_records(&mut extractor, out, combined_dedup.as_mut(), width)?
+            }
+            (None, None) => unreachable!("combined output without --batch-dir"),
+        };
+        for (entry, representative) in entries[first_entry..].iter_mut().zip(representatives) {
+            entry.representative = representative;
+        }
+
+        extracted += extractor.len();
+        report_missing(&extractor);
+        report_skipped_lines(extractor.skipped_lines());
+        extractor.close()?;
+    }
+
+    if let Some(mut out) = combined {
+        if let Some(dedup) = combined_dedup {
+            finish_dedup(dedup, &mut out, width)?;
+        }
+        finish_fasta(
+            out,
+            matches.get_one::<PathBuf>("output").map(PathBuf::as_path),
+            index,
+        )?;
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("manifest") {
+        manifest::write_manifest(
+            create(path, "manifest")?,
+            &entries,
+            manifest::Format::from_path(path),
+        )?;
+    }
+    check_extracted(matches, extracted)
+}
+
+/// Warn about the hits that were skipped as their targets are not in the
+/// fasta, if any.
+fn report_missing(extractor: &Extractor) {
+    let missing = extractor.missing_targets();
+    if missing.is_empty() {
+        return;
+    }
+    let mut names: Vec<&str> = missing.keys().take(5).map(String::as_str).collect();
+    if missing.len() > names.len() {
+        names.push("...");
+    }
+    warn!(
+        "Skipped {} hits on {} targets missing from the fasta: {}",
+        missing.values().sum::<usize>(),
+        missing.len(),
+        names.join(", ")
+    );
+}
+
+/// Warn about the tblout lines that were skipped as they could not be
+/// parsed, if any.
+fn report_skipped_lines(skipped: usize) {
+    if skipped > 0 {
+        warn!("Skipped {} tblout lines that could not be parsed", skipped);
+    }
+}
+
+/// Create one of the extra output files.
+fn create(path: &Path, what: &str) -> Result<BufWriter<File>> {
+    let file = File::create(path)
+        .with_context(|| format!("Could not create {} {}", what, path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+/// The number of bases per line of the fasta written.
+fn line_width(matches: &ArgMatches) -> usize {
+    match matches.get_flag("single_line") {
+        true => usize::MAX,
+        false => *matches
+            .get_one::<u64>("line_width")
+            .expect("defaulted by clap") as usize,
+    }
+}
+
+/// A fasta writer wrapping sequences at `line_width` bases.
+fn fasta_writer<W: Write>(inner: W, line_width: usize) -> fasta::Writer<W> {
+    fasta::writer::Builder::default()
+        .set_line_base_count(line_width)
+        .build_with_writer(inner)
+}
+
+/// Write the extracted sequences to `out`. With `dedup`, only the first of
+/// each distinct sequence is written, and the name of the one kept for each
+/// record is returned.
+fn write_records(
+    extractor: &mut Extractor,
+    out: &mut Output,
+    mut dedup: Option<&mut Dedup>,
+    line_width: usize,
+) -> Result<Vec<Option<String>>> {
+    let mut writer = fasta_writer(out, line_width);
+    let mut representatives = Vec::new();
+    for record in extractor.records() {
+        let record = record?;
+        let Some(dedup) = dedup.as_deref_mut() else {
+            writer.write_record(&record)?;
+            representatives.push(None);
             continue;
+        };
+        let name = String::from_utf8_lossy(record.name()).into_owned();
+        match dedup.push(record) {
+            Seen::New(record) => {
+                writer.write_record(&record)?;
+                representatives.push(Some(name));
+            }
+            Seen::Held => representatives.push(Some(name)),
+            Seen::Duplicate(kept) => representatives.push(Some(kept)),
         }
+    }
+    Ok(representatives)
+}
 
-        let target_name = r.target_name();
-        let ali_from_to = format!("{}..{}", r.ali_from().unwrap(), r.ali_to().unwrap());
+/// Open the output of an interrupted run to carry on from its checkpoint,
+/// with the index of the first hit still to extract. Without a checkpoint
+/// it starts over.
+fn resume(extractor: &Extractor, path: &Path, matches: &ArgMatches) -> Result<(Output, usize)> {
+    if !Checkpoint::path(path).exists() {
+        warn!(
+            "No checkpoint found for {}, so starting from the beginning",
+            path.display()
+        );
+        return Ok((output(matches)?, 0));
+    }
+    let checkpoint = Checkpoint::read(path, extractor.headers())?;
+    info!(
+        "Resuming after {} of {} sequences",
+        checkpoint.done, checkpoint.hits
+    );
+    Ok((Output::resume(path, checkpoint.offset)?, checkpoint.done))
+}
 
-        let extract_sequences = Cmd::new(esl_sfetch.clone())
-            .arg("-c")
-            .arg(ali_from_to)
-            .arg(new_fasta_location.clone())
-            .arg(target_name)
-            .output()?;
+/// How many sequences are written between checkpoints.
+const CHECKPOINT_INTERVAL: usize = 1000;
 
-        // parse the fasta properly and edit the header.
-        let mut parsed_fasta = fasta::reader::Reader::new(&extract_sequences.stdout[..]);
-        let stdout = io::stdout().lock();
-        let mut writer = fasta::Writer::new(stdout);
+/// Write the extracted sequences from the `start`th hit on to the file
+/// `out` at `path`, saving a checkpoint every so often.
+fn write_checkpointed(
+    extractor: &mut Extractor,
+    out: &mut Output,
+    path: &Path,
+    start: usize,
+    line_width: usize,
+) -> Result<()> {
+    let hits = extractor.len();
+    let mut done = start;
+    let mut records = extractor.records_from(start);
+    // the writer lets go of the file at each checkpoint, to flush it
+    loop {
+        let mut writer = fasta_writer(&mut *out, line_width);
+        let mut last = None;
+        for record in records
+            .by_ref()
+            .take(CHECKPOINT_INTERVAL - done % CHECKPOINT_INTERVAL)
+        {
+            let record = record?;
+            writer.write_record(&record)?;
+            last = Some(record);
+            done += 1;
+        }
+        let Some(last) = last.filter(|_| done.is_multiple_of(CHECKPOINT_INTERVAL)) else {
+            return Ok(());
+        };
+        let offset = out.position()?.expect("only files are checkpointed");
+        Checkpoint {
+            hits,
+            done,
+            last: Some(String::from_utf8_lossy(last.name()).into_owned()),
+            offset,
+        }
+        .write(path)?;
+    }
+}
 
-        for record in parsed_fasta.records() {
-            let r = record?;
+/// Write any records `dedup` held back to `out`, once all are extracted.
+fn finish_dedup(dedup: Dedup, out: &mut Output, line_width: usize) -> Result<()> {
+    info!("Collapsed {} identical sequences", dedup.collapsed());
+    let mut writer = fasta_writer(out, line_width);
+    for record in dedup.finish() {
+        writer.write_record(&record)?;
+    }
+    Ok(())
+}
 
-            let append_name = std::str::from_utf8(r.name())?;
-            let new_name = if species_id.is_empty() {
-                format!("{}:E{:e}", append_name, eval)
-            } else {
-                format!("{}:E{:e}:{}", species_id, eval, append_name)
-            };
+/// A collapser of identical sequences, if `--dedup` was given.
+fn dedup(matches: &ArgMatches) -> Option<Dedup> {
+    matches
+        .get_flag("dedup")
+        .then(|| Dedup::new(matches.get_flag("dedup_members")))
+}
 
-            let def = Definition::new(new_name.as_bytes(), r.description().map(|e| e.to_vec()));
+/// Finish writing the fasta at `path`, and index it if asked to.
+fn finish_fasta(out: Output, path: Option<&Path>, index: bool) -> Result<()> {
+    let (gzip, bgzf) = (
+        matches!(out, Output::Gzip(_)),
+        matches!(out, Output::Bgzf(_)),
+    );
+    out.finish()?;
 
-            let new_record = fasta::Record::new(def, r.sequence().to_owned());
-            writer.write_record(&new_record)?;
+    if index {
+        if gzip {
+            bail!("gzipped output can only be indexed with --bgzf");
         }
+        let path = path.expect("required by --fai");
+        output::write_indexes(path, bgzf)?;
     }
+    Ok(())
+}
 
-    // and close the tmpdir
-    tmpdir.close()?;
+/// Write the sequences into one fasta per query in `dir`, named by the
+/// query accession, or its name if it has none. With `by_strand`, each
+/// query gets a file per strand.
+fn split_by_query(
+    extractor: &mut Extractor,
+    dir: &Path,
+    by_strand: bool,
+    line_width: usize,
+) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create directory {}", dir.display()))?;
+
+    // the records come out in hit order
+    let files: Vec<String> = extractor
+        .hits()
+        .iter()
+        .map(|hit| {
+            let stem = query_file_stem(&hit.query_name, &hit.query_accession);
+            match by_strand {
+                true => format!("{}.{}.fa", stem, strand_suffix(hit.strand)),
+                false => format!("{}.fa", stem),
+            }
+        })
+        .collect();
+
+    let mut outputs: HashMap<String, Output> = HashMap::new();
+    for (file, record) in files.into_iter().zip(extractor.records()) {
+        let out = match outputs.entry(file) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let path = dir.join(e.key());
+                e.insert(Output::new(Some(&path), false, false)?)
+            }
+        };
+        fasta_writer(out, line_width).write_record(&record?)?;
+    }
+    for out in outputs.into_values() {
+        out.finish()?;
+    }
+    Ok(())
+}
+
+/// Write the plus and minus strand sequences to their own files, named
+/// from `--output` by [`strand_path`].
+fn split_by_strand(extractor: &mut Extractor, matches: &ArgMatches) -> Result<()> {
+    let path = matches
+        .get_one::<PathBuf>("output")
+        .expect("required by clap");
+    let (compress, bgzf) = (matches.get_flag("compress"), matches.get_flag("bgzf"));
+    let plus_path = strand_path(path, Strand::Positive);
+    let minus_path = strand_path(path, Strand::Negative);
+    let mut plus = Output::new(Some(&plus_path), compress, bgzf)?;
+    let mut minus = Output::new(Some(&minus_path), compress, bgzf)?;
+
+    // the records come out in hit order
+    let strands: Vec<Strand> = extractor.hits().iter().map(|hit| hit.strand).collect();
+    let width = line_width(matches);
+    for (strand, record) in strands.into_iter().zip(extractor.records()) {
+        let out = match strand {
+            Strand::Positive => &mut plus,
+            Strand::Negative => &mut minus,
+        };
+        fasta_writer(out, width).write_record(&record?)?;
+    }
+    finish_fasta(plus, Some(&plus_path), matches.get_flag("fai"))?;
+    finish_fasta(minus, Some(&minus_path), matches.get_flag("fai"))?;
+    Ok(())
+}
+
+/// `path` with `.plus` or `.minus` added before its extension (and any
+/// `.gz`), so `hits.fa.gz` becomes `hits.plus.fa.gz`.
+fn strand_path(path: &Path, strand: Strand) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (rest, gz) = match name.strip_suffix(".gz") {
+        Some(rest) => (rest, ".gz"),
+        None => (name.as_str(), ""),
+    };
+    let suffix = strand_suffix(strand);
+    let name = match rest.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}.{}.{}{}", stem, suffix, extension, gz)
+        }
+        _ => format!("{}.{}{}", rest, suffix, gz),
+    };
+    path.with_file_name(name)
+}
 
+/// How the files of a strand are told apart with `--split-by-strand`.
+fn strand_suffix(strand: Strand) -> &'static str {
+    match strand {
+        Strand::Positive => "plus",
+        Strand::Negative => "minus",
+    }
+}
+
+/// The name of the file the sequences of a query are written to with
+/// `--split-by-query`: its accession, or its name if it has none.
+fn query_file_stem(name: &str, accession: &str) -> String {
+    match accession {
+        "-" => name.replace('/', "_"),
+        accession => accession.replace('/', "_"),
+    }
+}
+
+/// Write each extracted sequence to its own fasta in `dir`, named by its
+/// header.
+fn write_per_hit(extractor: &mut Extractor, dir: &Path, line_width: usize) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create directory {}", dir.display()))?;
+
+    // headers are unique, but can clash once sanitised
+    let mut used = HashSet::new();
+    let stems: Vec<String> = extractor
+        .headers()
+        .iter()
+        .map(|header| {
+            let stem = sanitise_file_stem(header);
+            let mut unique = stem.clone();
+            let mut n = 1;
+            while !used.insert(unique.clone()) {
+                n += 1;
+                unique = format!("{}_{}", stem, n);
+            }
+            unique
+        })
+        .collect();
+
+    for (stem, record) in stems.into_iter().zip(extractor.records()) {
+        let mut out = Output::new(Some(&dir.join(format!("{}.fa", stem))), false, false)?;
+        fasta_writer(&mut out, line_width).write_record(&record?)?;
+        out.finish()?;
+    }
+    Ok(())
+}
+
+/// A header made safe to name a file with `--per-hit`: anything but
+/// letters, digits, `.`, `-` and `_` becomes `_`, and it is kept short
+/// enough for any filesystem.
+fn sanitise_file_stem(header: &str) -> String {
+    let stem: String = header
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .take(200)
+        .collect();
+    // nor hidden, nor . or ..
+    match stem.strip_prefix('.') {
+        Some(rest) => format!("_{}", rest),
+        None if stem.is_empty() => "_".to_string(),
+        None => stem,
+    }
+}
+
+/// Align the sequences of each query to its model, writing the alignments
+/// to the output one after another or, with `--split-by-query`, one file
+/// per query.
+fn write_alignments(
+    extractor: &mut Extractor,
+    aligner: &Hmmalign,
+    matches: &ArgMatches,
+) -> Result<()> {
+    // hmmalign needs all of the sequences of a query at once
+    let queries: Vec<(String, String)> = extractor
+        .hits()
+        .iter()
+        .map(|hit| (hit.query_name.clone(), hit.query_accession.clone()))
+        .collect();
+    let mut groups: Vec<((String, String), Vec<fasta::Record>)> = Vec::new();
+    let mut indices: HashMap<(String, String), usize> = HashMap::new();
+    for (query, record) in queries.into_iter().zip(extractor.records()) {
+        let i = *indices.entry(query.clone()).or_insert_with(|| {
+            groups.push((query, Vec::new()));
+            groups.len() - 1
+        });
+        groups[i].1.push(record?);
+    }
+
+    match matches.get_one::<PathBuf>("split_by_query") {
+        Some(dir) => {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create directory {}", dir.display()))?;
+            let mut names = HashSet::new();
+            for ((query, accession), records) in &groups {
+                let name = query_file_stem(query, accession);
+                ensure!(
+                    names.insert(name.clone()),
+                    "More than one query would be written to {}",
+                    name
+                );
+                let path = dir.join(format!("{}.{}", name, aligner.format().extension()));
+                let mut out = Output::new(Some(&path), false, false)?;
+                aligner.align(query, accession, records, &mut out)?;
+                out.finish()?;
+            }
+        }
+        None => {
+            let mut out = output(matches)?;
+            for ((query, accession), records) in &groups {
+                aligner.align(query, accession, records, &mut out)?;
+            }
+            out.finish()?;
+        }
+    }
+    Ok(())
+}
+
+fn check(matches: &ArgMatches) -> Result<()> {
+    let tblout = read_tblout(matches)?;
+    let fasta = match matches.get_one::<PathBuf>("FASTA") {
+        Some(fasta) => fasta.clone(),
+        None => tblout.meta().target_file.clone(),
+    };
+    ensure!(
+        !fasta.as_os_str().is_empty(),
+        "No fasta given, and the tblout does not name its target file"
+    );
+    let problems = check::check(&tblout, &fasta)?;
+
+    let mut out = output(matches)?;
+    for problem in &problems {
+        writeln!(out, "{}", problem)?;
+    }
+    out.finish()?;
+    ensure!(
+        problems.is_empty(),
+        "Found {} problems with {} and {}",
+        problems.len(),
+        cli::tbl_paths(matches)?[0].display(),
+        fasta.display()
+    );
+    info!(
+        "All {} hits are within their targets in {}",
+        tblout.hits().len(),
+        fasta.display()
+    );
+    Ok(())
+}
+
+fn filter(matches: &ArgMatches) -> Result<()> {
+    let filter = cli::hit_filter(matches)?;
+    let mut tblout = read_tblout(matches)?;
+    let total = tblout.hits().len();
+    tblout.apply_filter(&filter);
+    info!(
+        "{} of {} hits passed the filters",
+        tblout.hits().len(),
+        total
+    );
+    let mut out = output(matches)?;
+    tblout.write(&mut out)?;
+    out.finish()?;
+    Ok(())
+}
+
+/// Write the hits that match a hit of the other tblout with `intersect`,
+/// or that match none with `subtract`.
+fn compare(matches: &ArgMatches, intersect: bool) -> Result<()> {
+    let filter = cli::hit_filter(matches)?;
+    let matching = Matching {
+        min_overlap: *matches
+            .get_one::<f64>("min_overlap")
+            .expect("defaulted by clap"),
+        same_strand: matches.get_flag("same_strand"),
+    };
+    ensure!(
+        (0.0..=1.0).contains(&matching.min_overlap),
+        "--min-overlap should be between 0 and 1"
+    );
+    let mut tblout = read_tblout(matches)?;
+    tblout.apply_filter(&filter);
+    let other = matches
+        .get_one::<PathBuf>("OTHER")
+        .expect("required by clap");
+    let mut other = Tblout::from_path(other, cli::format(matches)?, matches.get_flag("lenient"))?;
+    report_skipped_lines(other.skipped());
+    other.apply_filter(&filter);
+
+    let matched = compare::matched(tblout.hits(), other.hits(), &matching);
+    let mut keep = matched.into_iter().map(|m| m == intersect);
+    let total = tblout.hits().len();
+    tblout.retain(|_| keep.next().expect("one per hit"));
+    info!("Kept {} of {} hits", tblout.hits().len(), total);
+
+    let mut out = output(matches)?;
+    tblout.write(&mut out)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn summarise(matches: &ArgMatches) -> Result<()> {
+    let filter = cli::hit_filter(matches)?;
+    let tblout = read_tblout(matches)?;
+    let group = matches
+        .get_one::<String>("by")
+        .expect("defaulted by clap")
+        .parse()?;
+    let summaries = stats::summarise(tblout.hits(), &filter, group);
+    let mut out = output(matches)?;
+    stats::write_summaries(&mut out, group, &summaries)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn report(matches: &ArgMatches) -> Result<()> {
+    let filter = cli::hit_filter(matches)?;
+    let tblout = read_tblout(matches)?;
+    let manifest = matches
+        .get_one::<PathBuf>("manifest")
+        .map(manifest::read_manifest)
+        .transpose()?;
+    let format = match matches.get_one::<String>("to") {
+        Some(to) => to.parse()?,
+        None => matches
+            .get_one::<PathBuf>("output")
+            .map_or(report::Format::Html, report::Format::from_path),
+    };
+    let max_rows = *matches
+        .get_one::<usize>("max_rows")
+        .expect("defaulted by clap");
+    let mut out = output(matches)?;
+    report::write_report(&mut out, &tblout, &filter, manifest, format, max_rows)?;
+    out.finish()?;
+    Ok(())
+}
+
+fn completions(matches: &ArgMatches) -> Result<()> {
+    let shell = *matches.get_one::<Shell>("SHELL").expect("required by clap");
+    let mut cmd = cli::build();
+    let name = cmd.get_name().to_string();
+    // generate panics on write errors, such as a closed pipe
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut cmd, name, &mut script);
+    io::stdout().write_all(&script)?;
+    Ok(())
+}
+
+fn man(matches: &ArgMatches) -> Result<()> {
+    match matches.get_one::<PathBuf>("dir") {
+        Some(dir) => {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+            clap_mangen::generate_to(cli::build(), dir)
+                .with_context(|| format!("Could not write man pages to {}", dir.display()))?;
+            info!("Wrote man pages to {}", dir.display());
+        }
+        None => clap_mangen::Man::new(cli::build()).render(&mut io::stdout())?,
+    }
+    Ok(())
+}
+
+fn convert(matches: &ArgMatches) -> Result<()> {
+    let format = matches
+        .get_one::<String>("to")
+        .expect("defaulted by clap")
+        .parse()?;
+    let mut tblout = read_tblout(matches)?;
+    if matches.get_flag("filtered") {
+        let filter = cli::hit_filter(matches)?;
+        tblout.apply_filter(&filter);
+    }
+    let mut out = output(matches)?;
+    convert::write_hits(&mut out, tblout.hits(), format)?;
+    out.finish()?;
     Ok(())
 }
@@ -0,0 +1,173 @@
+//! A manifest tracing each extracted sequence back to its hit.
+
+use std::{fs, io::Write, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// The formats a manifest can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Tab separated values, with a header row.
+    Tsv,
+    /// A JSON array of objects.
+    Json,
+}
+
+impl Format {
+    /// Pick the format from the extension of `path`, defaulting to TSV.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("json") => Format::Json,
+            _ => Format::Tsv,
+        }
+    }
+}
+
+/// One extracted sequence.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// The header given to the extracted sequence.
+    pub header: String,
+    /// The sequence it was extracted from.
+    pub target: String,
+    /// Where the extracted region starts (1-based).
+    pub from: u64,
+    /// Where the extracted region ends (inclusive).
+    pub to: u64,
+    /// The strand of the hit, `+` or `-`.
+    pub strand: String,
+    /// The E-value of the hit.
+    pub e_value: f64,
+    /// The bit score of the hit.
+    pub score: f64,
+    /// The query profile that hit.
+    pub query: String,
+    /// The IDs of the annotated features the hit overlaps, if the hits were
+    /// annotated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<String>>,
+    /// With identical sequences collapsed, the header of the sequence kept
+    /// for this one (its own, if it was kept).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub representative: Option<String>,
+}
+
+/// Write `entries` as a manifest.
+pub fn write_manifest<W: Write>(mut writer: W, entries: &[Entry], format: Format) -> Result<()> {
+    match format {
+        Format::Tsv => {
+            // the optional columns are only there if any entry has them
+            let annotated = entries.iter().any(|e| e.features.is_some());
+            let deduplicated = entries.iter().any(|e| e.representative.is_some());
+            write!(
+                writer,
+                "header\ttarget\tfrom\tto\tstrand\te_value\tscore\tquery"
+            )?;
+            if annotated {
+                write!(writer, "\tfeatures")?;
+            }
+            if deduplicated {
+                write!(writer, "\trepresentative")?;
+            }
+            writeln!(writer)?;
+            for e in entries {
+                write!(
+                    writer,
+                    "{}\t{}\t{}\t{}\t{}\t{:e}\t{}\t{}",
+                    e.header, e.target, e.from, e.to, e.strand, e.e_value, e.score, e.query
+                )?;
+                if annotated {
+                    write!(
+                        writer,
+                        "\t{}",
+                        e.features.as_deref().unwrap_or_default().join(",")
+                    )?;
+                }
+                if deduplicated {
+                    write!(
+                        writer,
+                        "\t{}",
+                        e.representative.as_deref().unwrap_or_default()
+                    )?;
+                }
+                writeln!(writer)?;
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut writer, entries)?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read a manifest written by [`write_manifest`], in the format its
+/// extension says.
+pub fn read_manifest<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read manifest {}", path.display()))?;
+    let entries = match Format::from_path(path) {
+        Format::Json => serde_json::from_str(&contents).map_err(anyhow::Error::from),
+        Format::Tsv => parse_tsv(&contents),
+    };
+    entries.with_context(|| format!("Could not read manifest {}", path.display()))
+}
+
+/// The entries of a TSV manifest, whose optional columns are found by the
+/// header row.
+fn parse_tsv(contents: &str) -> Result<Vec<Entry>> {
+    let mut lines = contents.lines();
+    let columns: Vec<&str> = lines.next().unwrap_or_default().split('\t').collect();
+    let column = |name: &str| columns.iter().position(|&c| c == name);
+    let features = column("features");
+    let representative = column("representative");
+    if columns.get(..8)
+        != Some(&[
+            "header", "target", "from", "to", "strand", "e_value", "score", "query",
+        ])
+    {
+        bail!("Not a manifest, as the header row is not as expected");
+    }
+
+    lines
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let field = |n: usize| {
+                fields
+                    .get(n)
+                    .copied()
+                    .with_context(|| format!("Line {} is missing columns", i + 2))
+            };
+            let optional = |n: Option<usize>| {
+                n.and_then(|n| fields.get(n))
+                    .filter(|value| !value.is_empty())
+                    .map(|value| value.to_string())
+            };
+            Ok(Entry {
+                header: field(0)?.to_string(),
+                target: field(1)?.to_string(),
+                from: field(2)?
+                    .parse()
+                    .with_context(|| format!("Invalid start on line {}", i + 2))?,
+                to: field(3)?
+                    .parse()
+                    .with_context(|| format!("Invalid end on line {}", i + 2))?,
+                strand: field(4)?.to_string(),
+                e_value: field(5)?
+                    .parse()
+                    .with_context(|| format!("Invalid E-value on line {}", i + 2))?,
+                score: field(6)?
+                    .parse()
+                    .with_context(|| format!("Invalid score on line {}", i + 2))?,
+                query: field(7)?.to_string(),
+                features: optional(features).map(|ids| ids.split(',').map(String::from).collect()),
+                representative: optional(representative),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,97 @@
+//! Merging of hits that are close together on the same target.
+
+use std::collections::HashMap;
+
+use crate::tblout::{Hit, Strand};
+
+/// Merge hits on the same target and strand that overlap or are within
+/// `distance` bases of each other into single hits spanning their union.
+///
+/// A merged hit takes everything but its coordinates from its best
+/// (lowest E-value) member, and takes the place of its first member.
+/// Hits without coordinates are left alone.
+pub fn merge_hits(hits: Vec<Hit>, distance: u64) -> Vec<Hit> {
+    let mut groups: HashMap<(&str, bool), Vec<usize>> = HashMap::new();
+    for (i, hit) in hits.iter().enumerate() {
+        if hit.ali().is_some() {
+            let key = (hit.target_name.as_str(), hit.strand == Strand::Positive);
+            groups.entry(key).or_default().push(i);
+        }
+    }
+
+    // the members of each merged hit
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    for mut indices in groups.into_values() {
+        indices.sort_by_key(|&i| span(hits[i].ali()).0);
+        let mut cluster: Vec<usize> = Vec::new();
+        let mut end = 0u64;
+        for i in indices {
+            let (lo, hi) = span(hits[i].ali());
+            if !cluster.is_empty() && lo > end.saturating_add(distance + 1) {
+                clusters.push(std::mem::take(&mut cluster));
+            }
+            end = if cluster.is_empty() { hi } else { end.max(hi) };
+            cluster.push(i);
+        }
+        clusters.push(cluster);
+    }
+
+    let mut merged: Vec<Option<Hit>> = hits.iter().map(|_| None).collect();
+    let mut absorbed = vec![false; hits.len()];
+    for cluster in clusters.into_iter().filter(|c| c.len() > 1) {
+        let first = *cluster.iter().min().expect("cluster is not empty");
+        for &i in &cluster {
+            absorbed[i] = true;
+        }
+        merged[first] = Some(merge(&hits, &cluster));
+    }
+
+    hits.into_iter()
+        .enumerate()
+        .filter_map(|(i, hit)| match merged[i].take() {
+            Some(m) => Some(m),
+            None if absorbed[i] => None,
+            None => Some(hit),
+        })
+        .collect()
+}
+
+/// Merge the hits at `cluster` into one.
+pub(crate) fn merge(hits: &[Hit], cluster: &[usize]) -> Hit {
+    let best = cluster
+        .iter()
+        .map(|&i| &hits[i])
+        .min_by(|a, b| a.e_value.total_cmp(&b.e_value))
+        .expect("cluster is not empty");
+
+    let union = |f: fn(&Hit) -> Option<(u64, u64)>| {
+        cluster
+            .iter()
+            .map(|&i| f(&hits[i]).map(|c| span(Some(c))))
+            .collect::<Option<Vec<_>>>()
+            .map(|spans| {
+                let lo = spans.iter().map(|s| s.0).min().unwrap_or_default();
+                let hi = spans.iter().map(|s| s.1).max().unwrap_or_default();
+                // minus strand coordinates run from high to low
+                match best.strand {
+                    Strand::Positive => (lo, hi),
+                    Strand::Negative => (hi, lo),
+                }
+            })
+    };
+
+    let mut hit = best.clone();
+    let ali = union(Hit::ali);
+    let env = union(Hit::env);
+    hit.ali_from = ali.map(|a| a.0);
+    hit.ali_to = ali.map(|a| a.1);
+    hit.env_from = env.map(|e| e.0);
+    hit.env_to = env.map(|e| e.1);
+    hit.domain = None;
+    hit
+}
+
+fn span(coords: Option<(u64, u64)>) -> (u64, u64) {
+    let (from, to) = coords.expect("only hits with coordinates are merged");
+    (from.min(to), from.max(to))
+}
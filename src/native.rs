@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use fasta::record::{Definition, Sequence};
+use memmap2::Mmap;
+use noodles_bgzf::{self as bgzf, gzi};
+use noodles_core::{Position, Region};
+use noodles_fasta as fasta;
+
+use crate::index;
+
+/// Anything the indexed reader can slice regions out of.
+trait Source: BufRead + Seek + Send {}
+
+impl<T: BufRead + Seek + Send> Source for T {}
+
+/// An uncompressed FASTA mapped into memory, shared by the fetchers of
+/// each thread.
+#[derive(Clone)]
+struct Mapped(Arc<Mmap>);
+
+impl AsRef<[u8]> for Mapped {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// An in-process replacement for `esl-sfetch -c`. The FASTA is indexed
+/// in memory (faidx style) and regions are sliced straight out of the
+/// file, so no external binaries are needed. Uncompressed FASTA is mapped
+/// into memory, so regions are copied straight out of the page cache
+/// instead of being read hit by hit.
+pub struct NativeFetcher {
+    path: PathBuf,
+    reader: fasta::IndexedReader<Box<dyn Source>>,
+    /// Set if the FASTA is BGZF-compressed.
+    gzi: Option<gzi::Index>,
+    /// Set if the FASTA is mapped into memory, which the reader reads too.
+    mmap: Option<Mapped>,
+    /// Where each sequence is in the index, shared by the fetchers of each
+    /// thread.
+    positions: Arc<HashMap<String, usize>>,
+    /// Descriptions are not part of the index, so they are looked up
+    /// lazily and cached per sequence.
+    descriptions: HashMap<String, Option<Vec<u8>>>,
+}
+
+impl NativeFetcher {
+    /// Index the FASTA at `path` and open it for random access.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let index = fasta::index(path)
+            .with_context(|| format!("Could not index fasta {}", path.display()))?;
+        Self::with_index(path, index)
+    }
+
+    /// Open the FASTA at `path` with an existing index.
+    pub fn with_index<P: AsRef<Path>>(path: P, index: fasta::fai::Index) -> Result<Self> {
+        let path = path.as_ref();
+        let mmap = map(path);
+        let reader: Box<dyn Source> = match &mmap {
+            Some(mmap) => Box::new(Cursor::new(mmap.clone())),
+            None => open(path, None)?,
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            positions: positions(&index),
+            reader: fasta::IndexedReader::new(reader, index),
+            gzi: None,
+            mmap,
+            descriptions: HashMap::new(),
+        })
+    }
+
+    /// Open the BGZF-compressed FASTA at `path` for random access, using
+    /// the `.fai` and `.gzi` next to it if there are any. Otherwise they are
+    /// built in memory, which takes one pass over the file but does not
+    /// need it decompressed to disk.
+    pub fn from_bgzf<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        let gzi_path = index::with_suffix(path, ".gzi");
+        let gzi = if gzi_path.exists() {
+            gzi::read(&gzi_path)
+                .with_context(|| format!("Could not read {}", gzi_path.display()))?
+        } else {
+            index::index_bgzf(path)?
+        };
+
+        let fai_path = index::with_suffix(path, ".fai");
+        let index = if fai_path.exists() {
+            fasta::fai::read(&fai_path)
+                .with_context(|| format!("Could not read {}", fai_path.display()))?
+        } else {
+            let file = File::open(path).context("Could not open fasta")?;
+            index::index_fasta(BufReader::new(bgzf::Reader::new(file)))
+                .with_context(|| format!("Could not index fasta {}", path.display()))?
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            positions: positions(&index),
+            reader: fasta::IndexedReader::new(open(path, Some(gzi.clone()))?, index),
+            gzi: Some(gzi),
+            mmap: None,
+            descriptions: HashMap::new(),
+        })
+    }
+
+    /// The index of the FASTA.
+    pub fn index(&self) -> &fasta::fai::Index {
+        self.reader.index()
+    }
+
+    /// The `.gzi` index of the FASTA, if it is BGZF-compressed.
+    pub fn gzi(&self) -> Option<&gzi::Index> {
+        self.gzi.as_ref()
+    }
+
+    /// Open the FASTA again with the same index, e.g. for another thread.
+    pub fn try_clone(&self) -> Result<Self> {
+        let index = self
+            .reader
+            .index()
+            .iter()
+            .map(|r| {
+                fasta::fai::Record::new(
+                    r.name(),
+                    r.length(),
+                    r.offset(),
+                    r.line_bases(),
+                    r.line_width(),
+                )
+            })
+            .collect();
+
+        let reader: Box<dyn Source> = match &self.mmap {
+            Some(mmap) => Box::new(Cursor::new(mmap.clone())),
+            None => open(&self.path, self.gzi.clone())?,
+        };
+        Ok(Self {
+            path: self.path.clone(),
+            reader: fasta::IndexedReader::new(reader, index),
+            gzi: self.gzi.clone(),
+            mmap: self.mmap.clone(),
+            positions: self.positions.clone(),
+            descriptions: self.descriptions.clone(),
+        })
+    }
+
+    /// Fetch `from..to` (1-based, inclusive) from the sequence `name`.
+    /// As with `esl-sfetch -c`, if `from > to` the reverse complement is
+    /// returned, and the record is named `name/from-to`.
+    pub fn fetch(&mut self, name: &str, from: u64, to: u64) -> Result<fasta::Record> {
+        let (start, end) = if from <= to { (from, to) } else { (to, from) };
+
+        let start = position(start)?;
+        let end = position(end)?;
+
+        let index_record = self.index_record(name)?;
+        let offset = index_record.offset();
+        if usize::from(end) as u64 > index_record.length() {
+            bail!(
+                "Coordinates {}..{} are out of range for {} (length {})",
+                from,
+                to,
+                name,
+                index_record.length()
+            );
+        }
+
+        let bases = match &self.mmap {
+            Some(mmap) => slice(mmap.as_ref(), index_record, from.min(to), from.max(to))?.into(),
+            None => {
+                let region = Region::new(name, start..=end);
+                self.reader
+                    .query(&region)
+                    .with_context(|| format!("Could not fetch {}:{}..{}", name, from, to))?
+                    .sequence()
+                    .clone()
+            }
+        };
+
+        let sequence = if from <= to {
+            bases
+        } else {
+            reverse_complement(bases.as_ref())
+        };
+
+        let description = self.description(name, offset)?;
+
+        let new_name = format!("{}/{}-{}", name, from, to);
+        let def = Definition::new(new_name.as_bytes(), description);
+
+        Ok(fasta::Record::new(def, sequence))
+    }
+
+    /// Fetch the whole of the sequence `name`, as `esl-sfetch` without `-c`
+    /// would.
+    pub fn fetch_full(&mut self, name: &str) -> Result<fasta::Record> {
+        let index_record = self.index_record(name)?;
+        let offset = index_record.offset();
+
+        let sequence = match &self.mmap {
+            Some(_) if index_record.length() == 0 => Sequence::default(),
+            Some(mmap) => slice(mmap.as_ref(), index_record, 1, index_record.length())?.into(),
+            None => {
+                let region = Region::new(name, ..);
+                self.reader
+                    .query(&region)
+                    .with_context(|| format!("Could not fetch {}", name))?
+                    .sequence()
+                    .clone()
+            }
+        };
+
+        let description = self.description(name, offset)?;
+        let def = Definition::new(name.as_bytes(), description);
+
+        Ok(fasta::Record::new(def, sequence))
+    }
+
+    /// The index record of the sequence `name`.
+    fn index_record(&self, name: &str) -> Result<&fasta::fai::Record> {
+        let i = self
+            .positions
+            .get(name)
+            .with_context(|| format!("Sequence {} not found in fasta", name))?;
+        Ok(&self.reader.index()[*i])
+    }
+
+    /// The description of `name`, whose sequence starts at `offset`.
+    fn description(&mut self, name: &str, offset: u64) -> Result<Option<Vec<u8>>> {
+        if let Some(d) = self.descriptions.get(name) {
+            return Ok(d.clone());
+        }
+        let d = self.read_description(offset)?;
+        self.descriptions.insert(name.to_string(), d.clone());
+        Ok(d)
+    }
+
+    /// Read the description from the definition line that ends just
+    /// before the sequence starting at `offset`.
+    fn read_description(&mut self, offset: u64) -> Result<Option<Vec<u8>>> {
+        let mut window = 4096;
+        loop {
+            let start = offset.saturating_sub(window);
+            let mut buf = vec![0; (offset - start) as usize];
+            let inner = self.reader.get_mut();
+            inner.seek(SeekFrom::Start(start))?;
+            inner.read_exact(&mut buf)?;
+
+            // drop the line ending of the definition line
+            while matches!(buf.last(), Some(b'\n' | b'\r')) {
+                buf.pop();
+            }
+
+            let line = match buf.iter().rposition(|&b| b == b'\n') {
+                Some(i) => &buf[i + 1..],
+                None if start == 0 => &buf[..],
+                None => {
+                    window *= 2;
+                    continue;
+                }
+            };
+
+            let line = line
+                .strip_prefix(b">")
+                .context("Malformed fasta definition line")?;
+            let description = line
+                .iter()
+                .position(|b| b.is_ascii_whitespace())
+                .map(|i| line[i..].trim_ascii_start().to_vec())
+                .filter(|d| !d.is_empty());
+
+            return Ok(description);
+        }
+    }
+}
+
+/// Open the FASTA at `path`, through its `.gzi` if it is BGZF-compressed.
+fn open(path: &Path, gzi: Option<gzi::Index>) -> Result<Box<dyn Source>> {
+    let file = File::open(path).context("Could not open fasta")?;
+    Ok(match gzi {
+        Some(gzi) => Box::new(bgzf::IndexedReader::new(file, gzi)),
+        None => Box::new(BufReader::new(file)),
+    })
+}
+
+/// Map the uncompressed FASTA at `path` into memory, if the filesystem
+/// allows it.
+fn map(path: &Path) -> Option<Mapped> {
+    let file = File::open(path).ok()?;
+    // SAFETY: the fasta is only read, and is either our own copy or an
+    // input that is not expected to change while the hits are extracted
+    let mmap = unsafe { Mmap::map(&file) }.ok()?;
+    Some(Mapped(Arc::new(mmap)))
+}
+
+/// The position in `index` of each sequence, by name. A name that is in the
+/// index more than once is found at its first.
+fn positions(index: &fasta::fai::Index) -> Arc<HashMap<String, usize>> {
+    let mut positions = HashMap::with_capacity(index.len());
+    for (i, record) in index.iter().enumerate() {
+        let name = String::from_utf8_lossy(record.name()).into_owned();
+        positions.entry(name).or_insert(i);
+    }
+    Arc::new(positions)
+}
+
+/// Bases `start..=end` (1-based) of the sequence of `record` in the mapped
+/// `fasta`, found from the line layout in the index.
+fn slice(fasta: &[u8], record: &fasta::fai::Record, start: u64, end: u64) -> Result<Vec<u8>> {
+    let byte = |pos: u64| {
+        let pos = pos - 1;
+        record.offset()
+            + pos / record.line_bases() * record.line_width()
+            + pos % record.line_bases()
+    };
+    let bytes = usize::try_from(byte(start))
+        .ok()
+        .zip(usize::try_from(byte(end) + 1).ok())
+        .and_then(|(from, to)| fasta.get(from..to))
+        .context("The fasta is shorter than its index")?;
+    Ok(bytes
+        .iter()
+        .copied()
+        .filter(|b| !matches!(b, b'\n' | b'\r'))
+        .collect())
+}
+
+fn position(n: u64) -> Result<Position> {
+    usize::try_from(n)
+        .ok()
+        .and_then(Position::new)
+        .with_context(|| format!("Invalid sequence coordinate: {}", n))
+}
+
+/// Reverse complement a nucleotide sequence, keeping soft-masked
+/// (lowercase) bases lowercase. Unknown characters are passed through.
+pub fn reverse_complement(seq: &[u8]) -> Sequence {
+    seq.iter()
+        .rev()
+        .map(|b| match b {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' | b'U' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' | b'u' => b'a',
+            b'R' => b'Y',
+            b'Y' => b'R',
+            b'K' => b'M',
+            b'M' => b'K',
+            b'B' => b'V',
+            b'V' => b'B',
+            b'D' => b'H',
+            b'H' => b'D',
+            b'r' => b'y',
+            b'y' => b'r',
+            b'k' => b'm',
+            b'm' => b'k',
+            b'b' => b'v',
+            b'v' => b'b',
+            b'd' => b'h',
+            b'h' => b'd',
+            other => *other,
+        })
+        .collect()
+}
@@ -0,0 +1,48 @@
+//! Extending hits to the open reading frames they are in.
+
+use crate::translate::GeneticCode;
+
+/// Extend the region `start..end` of `seq` to the open reading frame it
+/// is in, reading in the frame of `start`. The region is extended
+/// downstream up to and including the first stop codon after it, and
+/// upstream to the first start codon (ATG) after the stop codon before
+/// it, or left alone if there is no start codon in between.
+///
+/// A side is `None` if `seq` ends before its stop codon, so that a longer
+/// stretch of the sequence can be tried.
+pub(crate) fn extend_orf(
+    seq: &[u8],
+    start: usize,
+    end: usize,
+    code: &GeneticCode,
+) -> (Option<usize>, Option<usize>) {
+    let is_stop = |i: usize| code.amino_acid(&seq[i..i + 3]) == b'*';
+    let is_start = |i: usize| seq[i..i + 3].eq_ignore_ascii_case(b"ATG");
+
+    let mut orf_start = None;
+    let mut first_start = start;
+    let mut i = start;
+    while i >= 3 {
+        i -= 3;
+        if is_stop(i) {
+            orf_start = Some(first_start);
+            break;
+        }
+        if is_start(i) {
+            first_start = i;
+        }
+    }
+
+    // the codon holding the last base of the region
+    let mut orf_end = None;
+    let mut j = start + (end.max(start + 1) - start - 1) / 3 * 3;
+    while j + 3 <= seq.len() {
+        if is_stop(j) {
+            orf_end = Some((j + 3).max(end));
+            break;
+        }
+        j += 3;
+    }
+
+    (orf_start, orf_end)
+}
@@ -0,0 +1,107 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Seek, SeekFrom, StdoutLock, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use extract_nhmmer_tblout::index;
+use flate2::{write::GzEncoder, Compression};
+use noodles_bgzf as bgzf;
+
+/// Where the output of a subcommand goes. Stdout is locked once and
+/// buffered like the files, instead of being flushed line by line.
+pub enum Output {
+    Stdout(BufWriter<StdoutLock<'static>>),
+    File(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Bgzf(bgzf::Writer<BufWriter<File>>),
+}
+
+impl Output {
+    /// Write to `path`, or stdout if there isn't one. Files ending in `.gz`
+    /// are always compressed, with BGZF if `bgzf` is set.
+    pub fn new(path: Option<&Path>, compress: bool, bgzf: bool) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Output::Stdout(BufWriter::new(io::stdout().lock())));
+        };
+
+        let file = BufWriter::new(
+            File::create(path)
+                .with_context(|| format!("Could not create output {}", path.display()))?,
+        );
+        if bgzf {
+            Ok(Output::Bgzf(bgzf::Writer::new(file)))
+        } else if compress || path.extension().is_some_and(|e| e == "gz") {
+            Ok(Output::Gzip(GzEncoder::new(file, Compression::default())))
+        } else {
+            Ok(Output::File(file))
+        }
+    }
+
+    /// Carry on writing to the uncompressed file at `path`, dropping
+    /// anything after the first `offset` bytes.
+    pub fn resume(path: &Path, offset: u64) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Could not open output {}", path.display()))?;
+        file.set_len(offset)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Output::File(BufWriter::new(file)))
+    }
+
+    /// How much has been written to an uncompressed file, once flushed.
+    pub fn position(&mut self) -> io::Result<Option<u64>> {
+        match self {
+            Output::File(w) => {
+                w.flush()?;
+                w.get_mut().stream_position().map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Flush everything, writing the gzip trailer if compressing.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Stdout(mut w) => w.flush(),
+            Output::File(mut w) => w.flush(),
+            Output::Gzip(w) => w.finish()?.flush(),
+            Output::Bgzf(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(w) => w.write(buf),
+            Output::File(w) => w.write(buf),
+            Output::Gzip(w) => w.write(buf),
+            Output::Bgzf(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(w) => w.flush(),
+            Output::File(w) => w.flush(),
+            Output::Gzip(w) => w.flush(),
+            Output::Bgzf(w) => w.flush(),
+        }
+    }
+}
+
+/// Write a `.fai` index next to the fasta at `path`, and for BGZF a `.gzi`
+/// too, so that samtools can fetch from it directly.
+pub fn write_indexes(path: &Path, bgzf: bool) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let fai = if bgzf {
+        index::write_gzi(&index::with_suffix(path, ".gzi"), &index::index_bgzf(path)?)?;
+        index::index_fasta(BufReader::new(bgzf::Reader::new(file)))?
+    } else {
+        index::index_fasta(BufReader::new(file))?
+    };
+    index::write_fai(&index::with_suffix(path, ".fai"), &fai)
+}
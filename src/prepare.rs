@@ -0,0 +1,624 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command as Cmd, Stdio},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use flate2::read::MultiGzDecoder;
+use log::{debug, info, warn};
+use noodles_fasta::fai;
+
+use crate::{index::with_suffix, progress};
+
+/// How much of the fasta is checked for carriage returns.
+const SCAN_BYTES: u64 = 64 * 1024;
+
+/// The compression formats fasta is commonly distributed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Compression {
+    /// Detect the compression of `fasta` from its magic bytes, or failing
+    /// that its extension.
+    pub fn detect(fasta: &Path) -> Result<Option<Self>> {
+        let file =
+            File::open(fasta).with_context(|| format!("Could not open {}", fasta.display()))?;
+        let mut magic = Vec::new();
+        file.take(6).read_to_end(&mut magic)?;
+
+        let compression = match magic.as_slice() {
+            [0x1f, 0x8b, ..] => Some(Compression::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Compression::Zstd),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00] => Some(Compression::Xz),
+            [b'B', b'Z', b'h', ..] => Some(Compression::Bzip2),
+            _ => fasta
+                .extension()
+                .and_then(OsStr::to_str)
+                .and_then(Self::from_extension),
+        };
+        Ok(compression)
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "gz" => Some(Compression::Gzip),
+            "zst" => Some(Compression::Zstd),
+            "xz" => Some(Compression::Xz),
+            "bz2" => Some(Compression::Bzip2),
+            _ => None,
+        }
+    }
+
+    /// The name of the format.
+    fn name(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+        }
+    }
+
+    /// The program used to decompress to stdout.
+    fn program(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gunzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+        }
+    }
+
+    /// The command decompressing stdin to stdout with up to `threads`
+    /// threads: `pigz` for gzip, and `xz -T`. The others decompress on one
+    /// thread whatever.
+    fn command(&self, threads: usize) -> Cmd {
+        let mut cmd = match (self, threads) {
+            (Compression::Gzip, 2..) => {
+                let mut cmd = Cmd::new("pigz");
+                cmd.arg("-p").arg(threads.to_string());
+                cmd
+            }
+            (Compression::Xz, 2..) => {
+                let mut cmd = Cmd::new(self.program());
+                cmd.arg("-T").arg(threads.to_string());
+                cmd
+            }
+            _ => Cmd::new(self.program()),
+        };
+        cmd.arg("-dc");
+        cmd
+    }
+}
+
+/// The extensions of fasta (and FASTQ and 2bit) files, under any
+/// compression extension.
+const FASTA_EXTENSIONS: &[&str] = &[
+    "fa", "fasta", "fna", "fas", "ffn", "faa", "fq", "fastq", "2bit",
+];
+
+/// The file name of `path` without any compression extension.
+pub(crate) fn uncompressed_name(path: &Path) -> Option<&OsStr> {
+    match path.extension().and_then(OsStr::to_str) {
+        Some(extension) if Compression::from_extension(extension).is_some() => path.file_stem(),
+        _ => path.file_name(),
+    }
+}
+
+/// The fasta (or FASTQ or 2bit) files in `dir`, compressed or not, sorted
+/// by name.
+pub fn fasta_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries =
+        fs::read_dir(dir).with_context(|| format!("Could not read directory {}", dir.display()))?;
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let is_fasta = uncompressed_name(&path)
+            .map(Path::new)
+            .and_then(Path::extension)
+            .and_then(OsStr::to_str)
+            .is_some_and(|e| FASTA_EXTENSIONS.contains(&e));
+        if is_fasta && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Copy (or decompress) the fasta into `dir`, returning the path of the
+/// prepared copy. Uncompressed fasta with Unix line endings is linked
+/// rather than copied. With `show_progress`, copies show a progress bar.
+/// Gzip and xz are decompressed with up to `threads` threads, gzip with
+/// `pigz` if it is installed. FASTQ is converted to fasta, without its
+/// qualities.
+pub fn prepare_fasta(
+    fasta: &Path,
+    dir: &Path,
+    show_progress: bool,
+    threads: usize,
+) -> Result<PathBuf> {
+    if is_fastq(fasta)? {
+        info!("Input is FASTQ, converting it to fasta...");
+        let name = Path::new(uncompressed_name(fasta).context("Could not get file stem")?);
+        let new_fasta = dir.join(name.with_extension("fa"));
+        fastq_to_fasta(fasta, &new_fasta, show_progress)?;
+        return Ok(new_fasta);
+    }
+
+    // check if the fasta is compressed
+    // if it is, decompress it into tmpdir
+    // else just copy over
+    let new_fasta_path = if let Some(compression) = Compression::detect(fasta)? {
+        info!(
+            "Input fasta is {} compressed, decompressing...",
+            compression.name()
+        );
+
+        // drop the compression extension, if it has one
+        let fasta_file_name = uncompressed_name(fasta)
+            .context("Could not get file stem")?
+            .to_os_string();
+
+        let fasta_file =
+            File::create(dir.join(&fasta_file_name)).context("Could not create fasta file")?;
+        // the compressed fasta is fed in on stdin, to follow its progress
+        let spawn = |mut cmd: Cmd| {
+            cmd.stdin(Stdio::piped())
+                .stdout(Stdio::from(fasta_file.try_clone()?));
+            debug!("Running {:?}", cmd);
+            let program = cmd.get_program().to_string_lossy().into_owned();
+            cmd.spawn().map(|child| (child, program))
+        };
+        let (mut decompress, program) = match spawn(compression.command(threads)) {
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    && compression == Compression::Gzip
+                    && threads > 1 =>
+            {
+                warn!(
+                    "Could not find pigz, decompressing on one thread with {}",
+                    compression.program()
+                );
+                spawn(compression.command(1))
+            }
+            spawned => spawned,
+        }
+        .with_context(|| format!("Could not run {}", compression.program()))?;
+        let bar = progress::bytes(file_len(fasta)?, "Decompressing", show_progress);
+        let mut stdin = decompress.stdin.take().expect("stdin is piped");
+        let copied = std::io::copy(&mut bar.wrap_read(File::open(fasta)?), &mut stdin);
+        drop(stdin);
+        bar.finish_and_clear();
+        let output = decompress.wait_with_output()?;
+        // a broken pipe means the program failed, which is reported below
+        if output.status.success() {
+            copied.with_context(|| format!("Could not decompress {}", fasta.display()))?;
+        }
+        ensure!(
+            output.status.success(),
+            "{} could not decompress {}",
+            program,
+            fasta.display()
+        );
+
+        fasta_file_name
+    } else {
+        let fasta_file_name = fasta
+            .file_name()
+            .context("Could not get file name")?
+            .to_os_string();
+        let new_fasta = dir.join(&fasta_file_name);
+
+        if has_carriage_returns(fasta)? {
+            info!("Input fasta has Windows line endings, copying...");
+            strip_carriage_returns(fasta, &new_fasta, show_progress)?;
+        } else {
+            info!("Input fasta is not gzipped, linking...");
+            link(fasta, &new_fasta)?;
+        }
+
+        fasta_file_name
+    };
+
+    Ok(dir.join(new_fasta_path))
+}
+
+/// The sequence names a fasta has more than once, each with the name every
+/// copy has in the prepared fasta (the first keeps its own), and its length.
+pub type Duplicates = BTreeMap<String, Vec<(String, u64)>>;
+
+/// How many duplicated names an error or warning lists.
+const LISTED: usize = 5;
+
+/// Find the sequence names the prepared `fasta` (from `source`) has more
+/// than once in its `index`, which the backends can not tell apart. With
+/// `strict` this fails, naming them. Otherwise the later copies are renamed
+/// `name__2`, `name__3` and so on in `fasta`, replacing it if it is a link,
+/// which then needs indexing again. The fasta is only read again if there
+/// are duplicates.
+pub(crate) fn rename_duplicates(
+    source: &Path,
+    fasta: &Path,
+    index: &fai::Index,
+    strict: bool,
+) -> Result<Duplicates> {
+    let records: Vec<(String, u64)> = index
+        .iter()
+        .map(|r| (String::from_utf8_lossy(r.name()).into_owned(), r.length()))
+        .collect();
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (name, _) in &records {
+        *counts.entry(name).or_default() += 1;
+    }
+    let repeated: BTreeSet<&str> = counts
+        .iter()
+        .filter(|(_, &count)| count > 1)
+        .map(|(&name, _)| name)
+        .collect();
+    if repeated.is_empty() {
+        return Ok(Duplicates::new());
+    }
+    let mut listed: Vec<&str> = repeated.iter().take(LISTED).copied().collect();
+    if repeated.len() > LISTED {
+        listed.push("...");
+    }
+    ensure!(
+        !strict,
+        "{} has the same name for more than one sequence: {}",
+        source.display(),
+        listed.join(", ")
+    );
+    warn!(
+        "{} has the same name for more than one sequence ({}); the later copies are named name__2, name__3 and so on, and hits on them are put on the copy of the length in the tblout",
+        source.display(),
+        listed.join(", ")
+    );
+
+    // the new name of each record, if it is renamed
+    let mut taken: HashSet<String> = records.iter().map(|(name, _)| name.clone()).collect();
+    let mut duplicates = Duplicates::new();
+    let mut renamed = Vec::with_capacity(records.len());
+    for (name, len) in &records {
+        if !repeated.contains(name.as_str()) {
+            renamed.push(None);
+            continue;
+        }
+        let copies = duplicates.entry(name.clone()).or_default();
+        let new_name = match copies.len() {
+            0 => None,
+            n => (n + 1..)
+                .map(|i| format!("{}__{}", name, i))
+                .find(|new_name| !taken.contains(new_name)),
+        };
+        if let Some(new_name) = &new_name {
+            taken.insert(new_name.clone());
+        }
+        copies.push((new_name.clone().unwrap_or_else(|| name.clone()), *len));
+        renamed.push(new_name);
+    }
+
+    // rewrite the definition lines, leaving the rest as it is
+    let renaming = with_suffix(fasta, ".renaming");
+    {
+        let mut reader = BufReader::new(
+            File::open(fasta).with_context(|| format!("Could not open {}", fasta.display()))?,
+        );
+        let mut writer = BufWriter::new(
+            File::create(&renaming)
+                .with_context(|| format!("Could not create {}", renaming.display()))?,
+        );
+        let mut renamed = renamed.into_iter();
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            let new_name = match line.first() {
+                Some(b'>') => renamed.next().flatten(),
+                _ => None,
+            };
+            match new_name {
+                Some(new_name) => {
+                    // the description, if there is one, is kept
+                    let rest = line[1..]
+                        .iter()
+                        .position(u8::is_ascii_whitespace)
+                        .map_or(line.len(), |i| i + 1);
+                    writer.write_all(b">")?;
+                    writer.write_all(new_name.as_bytes())?;
+                    writer.write_all(&line[rest..])?;
+                }
+                None => writer.write_all(&line)?,
+            }
+            line.clear();
+        }
+        writer.flush()?;
+    }
+    fs::rename(&renaming, fasta)
+        .with_context(|| format!("Could not replace {}", fasta.display()))?;
+    Ok(duplicates)
+}
+
+/// How many times its size compressed fasta is taken to decompress to.
+/// DNA compresses to a little under a third of its size.
+const EXPANSION: u64 = 3;
+
+/// Fail with a clear error if `dir` does not have the space for the copy
+/// [`prepare_fasta`] would make of `fasta`, rather than failing part way
+/// through it. The size of decompressed fasta is estimated, and the check
+/// is skipped with a warning if the free space can not be found out.
+pub fn check_space(fasta: &Path, dir: &Path) -> Result<()> {
+    let len = file_len(fasta)?;
+    let needed = if Compression::detect(fasta)?.is_some() {
+        len.saturating_mul(EXPANSION)
+    } else if is_fastq(fasta)? || has_carriage_returns(fasta)? {
+        len
+    } else {
+        // linked, not copied
+        return Ok(());
+    };
+    let available = match free_space(dir) {
+        Ok(available) => available,
+        Err(e) => {
+            warn!(
+                "Could not check the free space in {}: {:#}",
+                dir.display(),
+                e
+            );
+            return Ok(());
+        }
+    };
+    debug!(
+        "{} needs about {} bytes, {} has {} free",
+        fasta.display(),
+        needed,
+        dir.display(),
+        available
+    );
+    ensure!(
+        needed <= available,
+        "Not enough space in {} to prepare {}: it needs about {} Mb, but there are only {} Mb free. Use --tmpdir to prepare it on a larger disk, or --prepare-in-place to prepare it next to the fasta",
+        dir.display(),
+        fasta.display(),
+        needed.div_ceil(1 << 20),
+        available >> 20
+    );
+    Ok(())
+}
+
+/// The bytes free in the file system `dir` is on, from `df`.
+fn free_space(dir: &Path) -> Result<u64> {
+    let output = Cmd::new("df")
+        .arg("-Pk")
+        .arg(dir)
+        .stderr(Stdio::null())
+        .output()
+        .context("Could not run df")?;
+    ensure!(output.status.success(), "df failed");
+    // the second line is the file system, with the KiB available fourth
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let available = stdout
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .context("Could not parse the output of df")?;
+    Ok(available.saturating_mul(1024))
+}
+
+/// Read the fasta at `fasta` as it is, decompressing it on the fly if it is
+/// compressed, instead of preparing a copy. Gzip (and so BGZF) is read
+/// in-process, the others through their programs.
+pub fn stream(fasta: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(fasta).with_context(|| format!("Could not open {}", fasta.display()))?;
+    Ok(match Compression::detect(fasta)? {
+        None => Box::new(BufReader::new(file)),
+        Some(Compression::Gzip) => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        Some(compression) => {
+            let mut cmd = compression.command(1);
+            cmd.stdin(file).stdout(Stdio::piped());
+            debug!("Running {:?}", cmd);
+            let mut child = cmd
+                .spawn()
+                .with_context(|| format!("Could not run {}", compression.program()))?;
+            let stdout = child.stdout.take().expect("stdout is piped");
+            Box::new(BufReader::new(Decompressing {
+                child,
+                stdout,
+                program: compression.program(),
+            }))
+        }
+    })
+}
+
+/// Whether the sequence file at `path` is FASTQ rather than fasta, going by
+/// its first record. It is decompressed to check if need be.
+pub fn is_fastq(path: &Path) -> Result<bool> {
+    let mut reader = stream(path)?;
+    let start = reader
+        .fill_buf()
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(start.trim_ascii_start().first() == Some(&b'@'))
+}
+
+/// Where a FASTQ reader is in a record.
+enum Fastq {
+    /// Before its `@` definition line.
+    Definition,
+    /// In its sequence, of the length read so far.
+    Sequence(usize),
+    /// In its qualities, with this many still to come.
+    Qualities(usize),
+}
+
+/// Call `f` on each line of the sequence file read by `reader` from `path`,
+/// without its line ending, as if it were fasta. Blank lines are skipped.
+/// FASTQ records are passed on as a `>` definition line and their sequence
+/// lines, without their qualities.
+pub(crate) fn fasta_lines<R, F>(mut reader: R, path: &Path, mut f: F) -> Result<()>
+where
+    R: BufRead,
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    let mut line = Vec::new();
+    // `None` for fasta, known from the first line
+    let mut fastq: Option<Option<Fastq>> = None;
+    for n in 1.. {
+        line.clear();
+        let read = reader
+            .read_until(b'\n', &mut line)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        let line = line.trim_ascii_end();
+        if line.is_empty() {
+            continue;
+        }
+        let state = fastq.get_or_insert_with(|| (line[0] == b'@').then_some(Fastq::Definition));
+        match state {
+            None => f(line)?,
+            Some(Fastq::Definition) => {
+                let Some(definition) = line.strip_prefix(b"@") else {
+                    bail!(
+                        "Expected a FASTQ record at line {} of {}",
+                        n,
+                        path.display()
+                    );
+                };
+                let mut header = Vec::with_capacity(line.len());
+                header.push(b'>');
+                header.extend_from_slice(definition);
+                f(&header)?;
+                *state = Some(Fastq::Sequence(0));
+            }
+            // the qualities start after a `+` line, and can start with `@`
+            Some(Fastq::Sequence(0)) if line[0] == b'+' => *state = Some(Fastq::Definition),
+            Some(Fastq::Sequence(len)) if line[0] == b'+' => {
+                *state = Some(Fastq::Qualities(*len));
+            }
+            Some(Fastq::Sequence(len)) => {
+                *len += line.len();
+                f(line)?;
+            }
+            Some(Fastq::Qualities(left)) => {
+                *left = left.saturating_sub(line.len());
+                if *left == 0 {
+                    *state = Some(Fastq::Definition);
+                }
+            }
+        }
+    }
+    ensure!(
+        matches!(fastq, None | Some(None) | Some(Some(Fastq::Definition))),
+        "The last FASTQ record of {} is cut short",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Convert the FASTQ at `src`, compressed or not, to fasta at `dst`.
+fn fastq_to_fasta(src: &Path, dst: &Path, show_progress: bool) -> Result<()> {
+    let bar = progress::bytes(file_len(src)?, "Converting", show_progress);
+    let open = || File::open(src).with_context(|| format!("Could not open {}", src.display()));
+    // the progress of other compressions is not followed
+    let reader: Box<dyn BufRead> = match Compression::detect(src)? {
+        None => Box::new(BufReader::new(bar.wrap_read(open()?))),
+        Some(Compression::Gzip) => {
+            Box::new(BufReader::new(MultiGzDecoder::new(bar.wrap_read(open()?))))
+        }
+        Some(_) => stream(src)?,
+    };
+    let mut writer = BufWriter::new(File::create(dst).context("Could not create fasta file")?);
+    fasta_lines(reader, src, |line| {
+        writer.write_all(line)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    })?;
+    writer.flush()?;
+    bar.finish_and_clear();
+    Ok(())
+}
+
+/// The output of a program decompressing a fasta, read as it comes.
+struct Decompressing {
+    child: Child,
+    stdout: ChildStdout,
+    program: &'static str,
+}
+
+impl Read for Decompressing {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        // a truncated or corrupt file ends the output early too
+        if n == 0 && !buf.is_empty() && !self.child.wait()?.success() {
+            return Err(io::Error::other(format!(
+                "{} could not decompress the fasta",
+                self.program
+            )));
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Decompressing {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Whether the start of the file has any `\r`.
+fn has_carriage_returns(fasta: &Path) -> Result<bool> {
+    let file = File::open(fasta).with_context(|| format!("Could not open {}", fasta.display()))?;
+    let mut start = Vec::new();
+    file.take(SCAN_BYTES).read_to_end(&mut start)?;
+    Ok(start.contains(&b'\r'))
+}
+
+/// The size of the file at `path`.
+fn file_len(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)
+        .with_context(|| format!("Could not read {}", path.display()))?
+        .len())
+}
+
+/// Copy `src` to `dst` line by line, without the `\r`s.
+fn strip_carriage_returns(src: &Path, dst: &Path, show_progress: bool) -> Result<()> {
+    let bar = progress::bytes(file_len(src)?, "Copying", show_progress);
+    let mut reader = BufReader::new(bar.wrap_read(File::open(src)?));
+    let mut writer = BufWriter::new(File::create(dst).context("Could not create fasta file")?);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        line.retain(|&b| b != b'\r');
+        writer.write_all(&line)?;
+        line.clear();
+    }
+    writer.flush()?;
+    bar.finish_and_clear();
+    Ok(())
+}
+
+/// Symlink `src` into place, so that indexes are still written to `dst`'s
+/// directory. Falls back to copying where symlinks are not available.
+fn link(src: &Path, dst: &Path) -> Result<()> {
+    let src = src
+        .canonicalize()
+        .with_context(|| format!("Could not find {}", src.display()))?;
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&src, dst).context("Could not link fasta")?;
+    #[cfg(not(unix))]
+    std::fs::copy(&src, dst).context("Could not copy fasta")?;
+
+    Ok(())
+}
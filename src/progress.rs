@@ -0,0 +1,42 @@
+//! Progress bars on stderr for the slow parts of a run. They are only drawn
+//! when stderr is a terminal.
+
+use std::time::Duration;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// A bar counting the `len` bytes of a file as it is read, labelled with
+/// `message`. Hidden unless `show` is set.
+pub fn bytes(len: u64, message: &str, show: bool) -> ProgressBar {
+    bar(
+        len,
+        message,
+        show,
+        "{msg} [{bar:40}] {bytes}/{total_bytes} ({eta})",
+    )
+}
+
+/// A bar counting `len` hits as they are extracted. Hidden unless `show`
+/// is set.
+pub fn hits(len: u64, show: bool) -> ProgressBar {
+    bar(
+        len,
+        "Extracting",
+        show,
+        "{msg} [{bar:40}] {pos}/{len} hits ({eta})",
+    )
+}
+
+fn bar(len: u64, message: &str, show: bool, template: &str) -> ProgressBar {
+    if !show {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(len).with_message(message.to_string());
+    bar.set_style(
+        ProgressStyle::with_template(template)
+            .expect("the templates are valid")
+            .progress_chars("=> "),
+    );
+    bar.enable_steady_tick(Duration::from_millis(200));
+    bar
+}
@@ -0,0 +1,204 @@
+//! A record of how the sequences of a run were made, for data management:
+//! the version and command line, checksums of the files read and written,
+//! the filter settings, and how many hits went where.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{BufWriter, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::ArgMatches;
+use extract_nhmmer_tblout::Extractor;
+use jiff::Timestamp;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// The arguments naming files that are read, with what they are.
+const INPUTS: &[(&str, &str)] = &[
+    ("config", "config"),
+    ("HMM", "hmm"),
+    ("targets", "targets"),
+    ("thresholds", "thresholds"),
+    ("hmm", "hmm"),
+    ("annotate", "annotation"),
+    ("exclude_bed", "excluded regions"),
+    ("rename_map", "rename map"),
+];
+
+/// The arguments naming files that are written, with what they are.
+const OUTPUTS: &[(&str, &str)] = &[
+    ("output", "sequences"),
+    ("bed", "bed"),
+    ("gff", "gff"),
+    ("sam", "alignments"),
+    ("manifest", "manifest"),
+];
+
+/// The provenance of the sequences of one run.
+#[derive(Debug, Serialize)]
+pub struct RunManifest {
+    tool: &'static str,
+    version: &'static str,
+    /// When the run finished, in UTC.
+    finished: String,
+    command_line: Vec<String>,
+    inputs: Vec<FileRecord>,
+    outputs: Vec<FileRecord>,
+    /// The value of every filter option, including defaults, as given on
+    /// the command line.
+    filters: BTreeMap<String, Value>,
+    counts: Counts,
+}
+
+/// A file read or written by the run.
+#[derive(Debug, Serialize)]
+struct FileRecord {
+    role: String,
+    path: PathBuf,
+    /// The size and checksum, unless it was stdin.
+    bytes: Option<u64>,
+    sha256: Option<String>,
+}
+
+impl FileRecord {
+    fn new(role: &str, path: &Path) -> Result<Self> {
+        let (bytes, sha256) = match path == Path::new("-") {
+            true => (None, None),
+            false => {
+                let (bytes, sha256) = checksum(path)?;
+                (Some(bytes), Some(sha256))
+            }
+        };
+        Ok(Self {
+            role: role.to_string(),
+            path: path.to_path_buf(),
+            bytes,
+            sha256,
+        })
+    }
+}
+
+/// How many hits went where.
+#[derive(Debug, Serialize)]
+struct Counts {
+    /// Read from the tblouts.
+    hits_read: usize,
+    /// Of those, how many passed the filters.
+    hits_passing_filters: usize,
+    /// Of those, how many were skipped as their targets are not in the
+    /// fasta.
+    hits_on_missing_targets: usize,
+    /// Sequences written, after any length filters, exclusions, merging
+    /// and stitching.
+    sequences_extracted: usize,
+    /// Lines of the tblouts that could not be parsed and were skipped.
+    lines_skipped: usize,
+}
+
+impl RunManifest {
+    /// Record what `extractor` is about to extract, with the files and
+    /// options of `matches`. The outputs are added by
+    /// [`RunManifest::finish`], once they are written.
+    pub fn new(extractor: &Extractor, matches: &ArgMatches) -> Result<Self> {
+        let mut inputs = Vec::new();
+        for tbl in extractor.tbls() {
+            inputs.push(FileRecord::new("tblout", tbl)?);
+        }
+        for fasta in extractor.sources() {
+            inputs.push(FileRecord::new("fasta", fasta)?);
+        }
+        for (id, role) in INPUTS {
+            if let Some(path) = path_arg(matches, id) {
+                inputs.push(FileRecord::new(role, path)?);
+            }
+        }
+
+        let filters = crate::cli::filter_args()
+            .into_iter()
+            .filter_map(|arg| {
+                let values: Vec<Value> = matches
+                    .get_raw(arg.get_id().as_str())?
+                    .map(|value| Value::String(value.to_string_lossy().into_owned()))
+                    .collect();
+                let name = arg.get_long().unwrap_or(arg.get_id().as_str()).to_string();
+                Some(match <[Value; 1]>::try_from(values) {
+                    Ok([value]) => (name, value),
+                    Err(values) => (name, Value::Array(values)),
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            tool: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            finished: String::new(),
+            command_line: env::args().collect(),
+            inputs,
+            outputs: Vec::new(),
+            filters,
+            counts: Counts {
+                hits_read: extractor.hits_read(),
+                hits_passing_filters: extractor.hits_passing(),
+                hits_on_missing_targets: extractor.missing_targets().values().sum(),
+                sequences_extracted: extractor.len(),
+                lines_skipped: extractor.skipped_lines(),
+            },
+        })
+    }
+
+    /// Add the files written, and write the manifest to `path`.
+    pub fn finish(mut self, matches: &ArgMatches, path: &Path) -> Result<()> {
+        for (id, role) in OUTPUTS {
+            if let Some(path) = path_arg(matches, id).filter(|path| path.is_file()) {
+                self.outputs.push(FileRecord::new(role, path)?);
+            }
+        }
+        self.finished = utc_now();
+
+        let file = File::create(path)
+            .with_context(|| format!("Could not create run manifest {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &self)
+            .with_context(|| format!("Could not write run manifest {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// The path given to the argument `id`, if this subcommand has it.
+fn path_arg<'a>(matches: &'a ArgMatches, id: &str) -> Option<&'a PathBuf> {
+    matches.try_get_one::<PathBuf>(id).ok().flatten()
+}
+
+/// The size of the file at `path` and its SHA-256, in hex.
+fn checksum(path: &Path) -> Result<(u64, String)> {
+    let mut file =
+        File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let mut sha256 = Sha256::new();
+    let mut bytes = 0;
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        sha256.update(&buf[..n]);
+        bytes += n as u64;
+    }
+    let hex = sha256
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok((bytes, hex))
+}
+
+/// The time now, in UTC, as an ISO 8601 timestamp to the second.
+fn utc_now() -> String {
+    Timestamp::now().strftime("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
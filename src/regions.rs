@@ -0,0 +1,105 @@
+//! Sets of regions on the target sequences, e.g. repeats to avoid.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+/// Regions on named sequences, kept sorted and merged so that overlaps can
+/// be found with a binary search. Coordinates are 1-based and inclusive.
+#[derive(Debug, Clone, Default)]
+pub struct Regions {
+    by_target: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl Regions {
+    /// Collect `(target, from, to)` regions, merging any that overlap or
+    /// touch.
+    pub fn new<I: IntoIterator<Item = (String, u64, u64)>>(regions: I) -> Self {
+        let mut by_target: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for (target, from, to) in regions {
+            by_target
+                .entry(target)
+                .or_default()
+                .push((from.min(to), from.max(to)));
+        }
+        for spans in by_target.values_mut() {
+            spans.sort_unstable();
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(spans.len());
+            for &(from, to) in spans.iter() {
+                match merged.last_mut() {
+                    Some(last) if from <= last.1.saturating_add(1) => last.1 = last.1.max(to),
+                    _ => merged.push((from, to)),
+                }
+            }
+            *spans = merged;
+        }
+        Self { by_target }
+    }
+
+    /// Read the regions in a BED file. Only the first three columns are
+    /// used, and `track`, `browser` and `#` lines are skipped.
+    pub fn from_bed<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read BED file {}", path.display()))?;
+
+        let mut regions = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let (Some(target), Some(start), Some(end)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                bail!(
+                    "{}:{}: expected a target, start and end",
+                    path.display(),
+                    i + 1
+                );
+            };
+            let parse = |field: &str| {
+                field.trim().parse::<u64>().with_context(|| {
+                    format!(
+                        "{}:{}: invalid coordinate \"{}\"",
+                        path.display(),
+                        i + 1,
+                        field
+                    )
+                })
+            };
+            let (start, end) = (parse(start)?, parse(end)?);
+            // BED is 0-based and half open
+            if end > start {
+                regions.push((target.to_string(), start + 1, end));
+            }
+        }
+        Ok(Self::new(regions))
+    }
+
+    /// The regions on `target` overlapping `from..=to`, in order.
+    pub fn overlapping(&self, target: &str, from: u64, to: u64) -> &[(u64, u64)] {
+        let Some(spans) = self.by_target.get(target) else {
+            return &[];
+        };
+        let (from, to) = (from.min(to), from.max(to));
+        // the spans are merged, so their ends are sorted too
+        let start = spans.partition_point(|&(_, end)| end < from);
+        let end = spans.partition_point(|&(start, _)| start <= to);
+        &spans[start..end.max(start)]
+    }
+
+    /// The number of bases of `from..=to` on `target` that are in a region.
+    pub fn overlap(&self, target: &str, from: u64, to: u64) -> u64 {
+        let (from, to) = (from.min(to), from.max(to));
+        self.overlapping(target, from, to)
+            .iter()
+            .map(|&(start, end)| end.min(to) - start.max(from) + 1)
+            .sum()
+    }
+}
@@ -0,0 +1,47 @@
+//! New names for the targets in headers, e.g. public accessions for the
+//! scaffold IDs of an assembly.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+/// Read a rename map, one target per line: its name in the fasta and the
+/// name to give it, separated by a tab. Blank lines and lines starting with
+/// `#` are skipped.
+pub fn read_rename_map<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Could not read rename map {}", path.display()))?;
+
+    let mut names = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((old, new)) = line.split_once('\t') else {
+            bail!(
+                "{}:{}: expected an old and a new name separated by a tab",
+                path.display(),
+                i + 1
+            );
+        };
+        let new = new
+            .split('\t')
+            .next()
+            .expect("split has a first item")
+            .trim();
+        if names
+            .insert(old.trim().to_string(), new.to_string())
+            .is_some()
+        {
+            bail!(
+                "{}:{}: {} is renamed more than once",
+                path.display(),
+                i + 1,
+                old.trim()
+            );
+        }
+    }
+    Ok(names)
+}
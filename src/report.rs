@@ -0,0 +1,497 @@
+//! A report summarising the hits of a run, as a self-contained HTML page or
+//! as markdown, for sharing with those who would rather not read a tblout.
+
+use std::{collections::BTreeMap, fmt::Write as _, io::Write, path::Path, str::FromStr};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    filter::HitFilter,
+    manifest::Entry,
+    stats::{self, Group, Summary},
+    tblout::Tblout,
+};
+
+/// The formats a report can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A single HTML page, with its styles inline.
+    Html,
+    /// GitHub flavoured markdown.
+    Markdown,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "html" => Ok(Format::Html),
+            "markdown" | "md" => Ok(Format::Markdown),
+            _ => bail!("Unknown report format \"{}\"", s),
+        }
+    }
+}
+
+impl Format {
+    /// Pick the format from the extension of `path`, defaulting to HTML.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("md" | "markdown") => Format::Markdown,
+            _ => Format::Html,
+        }
+    }
+}
+
+/// The number of hits with E-values in each decade, e.g. `1e-10..1e-9`.
+struct Histogram {
+    /// The exponent of the lowest decade.
+    lowest: i32,
+    counts: Vec<usize>,
+}
+
+impl Histogram {
+    fn of(e_values: impl Iterator<Item = f64>) -> Option<Self> {
+        // an E-value of 0 is below anything a float can hold
+        let exponents: Vec<i32> = e_values
+            .map(|e| match e > 0.0 {
+                true => e.log10().floor() as i32,
+                false => f64::MIN_POSITIVE.log10().floor() as i32,
+            })
+            .collect();
+        let lowest = *exponents.iter().min()?;
+        let highest = *exponents.iter().max()?;
+        let mut counts = vec![0; (highest - lowest + 1) as usize];
+        for exponent in exponents {
+            counts[(exponent - lowest) as usize] += 1;
+        }
+        Some(Self { lowest, counts })
+    }
+
+    /// Each decade, labelled, with its count.
+    fn bins(&self) -> impl Iterator<Item = (String, usize)> + '_ {
+        self.counts.iter().enumerate().map(|(i, &count)| {
+            let exponent = self.lowest + i as i32;
+            (format!("1e{}..1e{}", exponent, exponent + 1), count)
+        })
+    }
+}
+
+/// The passing hits on one target.
+struct Density {
+    target: String,
+    passing: usize,
+    /// The length of the target, if the tblout has it.
+    length: Option<u64>,
+    bases: u64,
+}
+
+impl Density {
+    /// Passing hits per megabase.
+    fn per_mb(&self) -> Option<f64> {
+        self.length
+            .filter(|&length| length > 0)
+            .map(|length| self.passing as f64 / length as f64 * 1e6)
+    }
+}
+
+/// One row of the table of extracted sequences.
+struct Row {
+    header: String,
+    target: String,
+    /// Where the sequence starts and ends on its target, if known.
+    region: Option<(u64, u64)>,
+    strand: String,
+    query: String,
+    e_value: f64,
+    score: f64,
+}
+
+/// Everything in a report, before it is written in either format.
+struct Report {
+    title: String,
+    program: String,
+    hits: usize,
+    passing: usize,
+    queries: BTreeMap<String, Summary>,
+    histogram: Option<Histogram>,
+    densities: Vec<Density>,
+    rows: Vec<Row>,
+    /// Whether the rows are the sequences written by a run, rather than
+    /// the hits that pass the filters.
+    from_manifest: bool,
+    max_rows: usize,
+}
+
+/// Write a report on the hits of `tblout` that pass `filter`: the hits of
+/// each query, a histogram of their E-values, the passing hits on each
+/// target, and a table of the extracted sequences. The sequences are read
+/// from the `manifest` of a run if there is one, or else are the passing
+/// hits. The target and sequence tables stop after `max_rows` rows.
+pub fn write_report<W: Write>(
+    mut writer: W,
+    tblout: &Tblout,
+    filter: &HitFilter,
+    manifest: Option<Vec<Entry>>,
+    format: Format,
+    max_rows: usize,
+) -> Result<()> {
+    let report = Report::new(tblout, filter, manifest, max_rows);
+    let text = match format {
+        Format::Html => report.html(),
+        Format::Markdown => report.markdown(),
+    };
+    writer.write_all(text.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+impl Report {
+    fn new(
+        tblout: &Tblout,
+        filter: &HitFilter,
+        manifest: Option<Vec<Entry>>,
+        max_rows: usize,
+    ) -> Self {
+        let hits = tblout.hits();
+        let keep = filter.select(hits);
+        let passing: Vec<_> = hits
+            .iter()
+            .zip(&keep)
+            .filter(|(_, &k)| k)
+            .map(|(hit, _)| hit)
+            .collect();
+
+        let mut lengths: BTreeMap<&str, u64> = BTreeMap::new();
+        for hit in hits {
+            if let Some(length) = hit.sq_len {
+                lengths.insert(&hit.target_name, length);
+            }
+        }
+        let mut densities: Vec<Density> = stats::summarise(hits, filter, Group::Target)
+            .into_iter()
+            .filter(|(_, summary)| summary.passing > 0)
+            .map(|(target, summary)| Density {
+                length: lengths.get(target.as_str()).copied(),
+                target,
+                passing: summary.passing,
+                bases: summary.bases,
+            })
+            .collect();
+        densities.sort_by(|a, b| b.passing.cmp(&a.passing).then(a.target.cmp(&b.target)));
+
+        let from_manifest = manifest.is_some();
+        let rows = match manifest {
+            Some(entries) => entries
+                .into_iter()
+                .map(|e| Row {
+                    header: e.header,
+                    target: e.target,
+                    region: Some((e.from, e.to)),
+                    strand: e.strand,
+                    query: e.query,
+                    e_value: e.e_value,
+                    score: e.score,
+                })
+                .collect(),
+            None => passing
+                .iter()
+                .map(|hit| Row {
+                    header: String::new(),
+                    target: hit.target_name.clone(),
+                    region: hit
+                        .ali()
+                        .map(|(from, to)| (from.min(to), from.max(to)))
+                        .or(hit.sq_len.map(|length| (1, length))),
+                    strand: hit.strand.to_string(),
+                    query: hit.query_name.clone(),
+                    e_value: hit.e_value,
+                    score: hit.score,
+                })
+                .collect(),
+        };
+
+        let meta = tblout.meta();
+        Self {
+            title: match meta.query_file.as_os_str().is_empty() {
+                true => "Hits".to_string(),
+                false => format!("Hits of {}", meta.query_file.display()),
+            },
+            program: match meta.program.is_empty() {
+                true => tblout.format().program().to_string(),
+                false => format!("{} {}", meta.program, meta.version)
+                    .trim_end()
+                    .to_string(),
+            },
+            hits: hits.len(),
+            passing: passing.len(),
+            queries: stats::summarise(hits, filter, Group::Query),
+            histogram: Histogram::of(passing.iter().map(|hit| hit.e_value)),
+            densities,
+            rows,
+            from_manifest,
+            max_rows,
+        }
+    }
+
+    /// The overview at the top, as label and value pairs.
+    fn overview(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("Program", self.program.clone()),
+            ("Hits", self.hits.to_string()),
+            ("Passing the filters", self.passing.to_string()),
+            ("Queries", self.queries.len().to_string()),
+            ("Targets hit", self.densities.len().to_string()),
+            ("Sequences", self.rows.len().to_string()),
+        ]
+    }
+
+    /// The columns of the per query table, and its rows.
+    fn query_table(&self) -> (Vec<&'static str>, Vec<Vec<String>>) {
+        let columns = vec![
+            "Query",
+            "Hits",
+            "Passing",
+            "Best E-value",
+            "Median score",
+            "Bases covered",
+        ];
+        let rows = self
+            .queries
+            .iter()
+            .map(|(query, s)| {
+                vec![
+                    query.clone(),
+                    s.hits.to_string(),
+                    s.passing.to_string(),
+                    s.e_values
+                        .map_or("NA".to_string(), |e| format!("{:e}", e.min)),
+                    s.scores
+                        .map_or("NA".to_string(), |s| format!("{:.1}", s.median)),
+                    s.bases.to_string(),
+                ]
+            })
+            .collect();
+        (columns, rows)
+    }
+
+    /// The columns of the per target table, and its rows.
+    fn target_table(&self) -> (Vec<&'static str>, Vec<Vec<String>>) {
+        let columns = vec![
+            "Target",
+            "Length",
+            "Passing hits",
+            "Hits per Mb",
+            "Bases covered",
+        ];
+        let rows = self
+            .densities
+            .iter()
+            .take(self.max_rows)
+            .map(|d| {
+                vec![
+                    d.target.clone(),
+                    d.length.map_or("NA".to_string(), |l| l.to_string()),
+                    d.passing.to_string(),
+                    d.per_mb().map_or("NA".to_string(), |n| format!("{:.2}", n)),
+                    d.bases.to_string(),
+                ]
+            })
+            .collect();
+        (columns, rows)
+    }
+
+    /// The columns of the sequence table, and its rows.
+    fn sequence_table(&self) -> (Vec<&'static str>, Vec<Vec<String>>) {
+        let mut columns = vec![
+            "Target", "From", "To", "Strand", "Query", "E-value", "Score",
+        ];
+        if self.from_manifest {
+            columns.insert(0, "Header");
+        }
+        let rows = self
+            .rows
+            .iter()
+            .take(self.max_rows)
+            .map(|r| {
+                let mut row = vec![
+                    r.target.clone(),
+                    r.region
+                        .map_or("NA".to_string(), |(from, _)| from.to_string()),
+                    r.region.map_or("NA".to_string(), |(_, to)| to.to_string()),
+                    r.strand.clone(),
+                    r.query.clone(),
+                    format!("{:e}", r.e_value),
+                    r.score.to_string(),
+                ];
+                if self.from_manifest {
+                    row.insert(0, r.header.clone());
+                }
+                row
+            })
+            .collect();
+        (columns, rows)
+    }
+
+    /// A note on how many rows of `total` were left out of a table.
+    fn truncated(&self, total: usize, what: &str) -> Option<String> {
+        (total > self.max_rows).then(|| {
+            format!(
+                "Only the first {} of {} {} are shown.",
+                self.max_rows, total, what
+            )
+        })
+    }
+
+    fn sequences_heading(&self) -> &'static str {
+        match self.from_manifest {
+            true => "Extracted sequences",
+            false => "Passing hits",
+        }
+    }
+
+    fn html(&self) -> String {
+        let mut html = String::new();
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+            escape(&self.title),
+            STYLE,
+            escape(&self.title)
+        );
+
+        html.push_str("<table class=\"overview\">\n");
+        for (label, value) in self.overview() {
+            let _ = writeln!(
+                html,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                label,
+                escape(&value)
+            );
+        }
+        html.push_str("</table>\n");
+
+        html.push_str("<h2>Hits per query</h2>\n");
+        let (columns, rows) = self.query_table();
+        html_table(&mut html, &columns, &rows);
+
+        html.push_str("<h2>E-values of the passing hits</h2>\n");
+        match &self.histogram {
+            Some(histogram) => {
+                let most = histogram.counts.iter().copied().max().unwrap_or(1).max(1);
+                html.push_str("<table class=\"histogram\">\n");
+                for (label, count) in histogram.bins() {
+                    let _ = writeln!(
+                        html,
+                        "<tr><th>{}</th><td>{}</td><td><div class=\"bar\" style=\"width: {:.1}%\"></div></td></tr>",
+                        label,
+                        count,
+                        100.0 * count as f64 / most as f64
+                    );
+                }
+                html.push_str("</table>\n");
+            }
+            None => html.push_str("<p>No hits pass the filters.</p>\n"),
+        }
+
+        html.push_str("<h2>Hits per target</h2>\n");
+        let (columns, rows) = self.target_table();
+        html_table(&mut html, &columns, &rows);
+        if let Some(note) = self.truncated(self.densities.len(), "targets") {
+            let _ = writeln!(html, "<p>{}</p>", note);
+        }
+
+        let _ = writeln!(html, "<h2>{}</h2>", self.sequences_heading());
+        let (columns, rows) = self.sequence_table();
+        html_table(&mut html, &columns, &rows);
+        if let Some(note) = self.truncated(self.rows.len(), "sequences") {
+            let _ = writeln!(html, "<p>{}</p>", note);
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    fn markdown(&self) -> String {
+        let mut md = String::new();
+        let _ = writeln!(md, "# {}\n", self.title);
+        for (label, value) in self.overview() {
+            let _ = writeln!(md, "- {}: {}", label, value);
+        }
+
+        md.push_str("\n## Hits per query\n\n");
+        let (columns, rows) = self.query_table();
+        markdown_table(&mut md, &columns, &rows);
+
+        md.push_str("\n## E-values of the passing hits\n\n");
+        match &self.histogram {
+            Some(histogram) => {
+                let most = histogram.counts.iter().copied().max().unwrap_or(1).max(1);
+                let rows: Vec<Vec<String>> = histogram
+                    .bins()
+                    .map(|(label, count)| {
+                        let width = (40 * count).div_ceil(most);
+                        vec![label, count.to_string(), "█".repeat(width)]
+                    })
+                    .collect();
+                markdown_table(&mut md, &["E-value", "Hits", ""], &rows);
+            }
+            None => md.push_str("No hits pass the filters.\n"),
+        }
+
+        md.push_str("\n## Hits per target\n\n");
+        let (columns, rows) = self.target_table();
+        markdown_table(&mut md, &columns, &rows);
+        if let Some(note) = self.truncated(self.densities.len(), "targets") {
+            let _ = writeln!(md, "\n{}", note);
+        }
+
+        let _ = writeln!(md, "\n## {}\n", self.sequences_heading());
+        let (columns, rows) = self.sequence_table();
+        markdown_table(&mut md, &columns, &rows);
+        if let Some(note) = self.truncated(self.rows.len(), "sequences") {
+            let _ = writeln!(md, "\n{}", note);
+        }
+        md
+    }
+}
+
+/// Kept short, so the page stays readable as it is.
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; } \
+table { border-collapse: collapse; margin-bottom: 1em; } \
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: left; } \
+.histogram td:last-child { width: 30em; } \
+.bar { background: #4a7fb0; height: 1em; }";
+
+fn html_table(html: &mut String, columns: &[&str], rows: &[Vec<String>]) {
+    html.push_str("<table>\n<tr>");
+    for column in columns {
+        let _ = write!(html, "<th>{}</th>", column);
+    }
+    html.push_str("</tr>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            let _ = write!(html, "<td>{}</td>", escape(cell));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+}
+
+fn markdown_table(md: &mut String, columns: &[&str], rows: &[Vec<String>]) {
+    let _ = writeln!(md, "| {} |", columns.join(" | "));
+    let _ = writeln!(md, "|{}", "---|".repeat(columns.len()));
+    for row in rows {
+        let cells: Vec<String> = row.iter().map(|cell| cell.replace('|', "\\|")).collect();
+        let _ = writeln!(md, "| {} |", cells.join(" | "));
+    }
+}
+
+/// `s` with the characters HTML gives a meaning to escaped.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
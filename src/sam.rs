@@ -0,0 +1,321 @@
+//! Writing the extracted sequences as alignments on the targets they came
+//! from, in SAM or BAM, to look at the hits in a genome browser such as IGV.
+
+use std::{collections::HashMap, io::Write, num::NonZeroUsize, path::Path};
+
+use anyhow::{ensure, Context, Result};
+use noodles_bam as bam;
+use noodles_bgzf as bgzf;
+use noodles_core::Position;
+use noodles_sam::{
+    self as sam,
+    alignment::{
+        record::{
+            cigar::{self, op::Kind},
+            data::field::Tag,
+            Flags,
+        },
+        record_buf::{data::field::Value, Cigar, Name, Sequence},
+        RecordBuf,
+    },
+    header::record::value::{
+        map::{
+            self,
+            header::{tag as header_tag, Version},
+            program::tag as program_tag,
+            Program, ReferenceSequence,
+        },
+        Map,
+    },
+};
+
+use crate::tblout::Strand;
+
+/// The formats the alignments can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Plain text SAM.
+    Sam,
+    /// BGZF compressed, binary BAM.
+    Bam,
+}
+
+impl Format {
+    /// Pick the format from the extension of `path`, defaulting to SAM.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("bam") => Format::Bam,
+            _ => Format::Sam,
+        }
+    }
+}
+
+/// An operation of a CIGAR string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Bases of the sequence aligned to the target.
+    Match,
+    /// Bases of the sequence that are not on the target, e.g. where
+    /// stitched hits overlap.
+    Insertion,
+    /// Bases of the target skipped over, e.g. between stitched hits.
+    Skip,
+}
+
+impl Op {
+    fn kind(&self) -> Kind {
+        match self {
+            Op::Match => Kind::Match,
+            Op::Insertion => Kind::Insertion,
+            Op::Skip => Kind::Skip,
+        }
+    }
+}
+
+/// An extracted sequence, aligned to the region of its target it came
+/// from.
+#[derive(Debug, Clone)]
+pub struct Alignment<'a> {
+    /// The header of the extracted sequence.
+    pub name: &'a str,
+    pub target: &'a str,
+    /// Where the alignment starts on the target (1-based).
+    pub pos: u64,
+    pub strand: Strand,
+    pub cigar: Vec<(u64, Op)>,
+    /// The sequence, on the forward strand of the target.
+    pub sequence: &'a [u8],
+    /// The query the hit is of, with its E-value and score.
+    pub query: &'a str,
+    pub e_value: f64,
+    pub score: f64,
+}
+
+impl Alignment<'_> {
+    /// The alignment as a record on the target numbered `id` in the header.
+    /// The score is rounded for `AS`, and is as it is in `ZS`, with the
+    /// E-value and query in `ZE` and `ZQ`. The E-value is a string as BAM
+    /// floats are too small for the lowest of them.
+    fn record(&self, id: usize) -> Result<RecordBuf> {
+        check_read_name(self.name)?;
+        let cigar: Cigar = self
+            .cigar
+            .iter()
+            .map(|&(len, op)| Ok(cigar::Op::new(op.kind(), usize::try_from(len)?)))
+            .collect::<Result<_>>()?;
+        ensure!(
+            cigar.read_length() == self.sequence.len(),
+            "The alignment of {} covers {} bases, but its sequence has {}",
+            self.name,
+            cigar.read_length(),
+            self.sequence.len()
+        );
+        let flags = match self.strand {
+            Strand::Positive => Flags::empty(),
+            Strand::Negative => Flags::REVERSE_COMPLEMENTED,
+        };
+        let data = [
+            (
+                Tag::ALIGNMENT_SCORE,
+                Value::Int32(self.score.round() as i32),
+            ),
+            (Tag::new(b'Z', b'S'), Value::Float(self.score as f32)),
+            (
+                Tag::new(b'Z', b'E'),
+                Value::String(format!("{:e}", self.e_value).into()),
+            ),
+            (Tag::new(b'Z', b'Q'), Value::String(self.query.into())),
+        ];
+        Ok(RecordBuf::builder()
+            .set_name(Name::from(self.name.as_bytes()))
+            .set_flags(flags)
+            .set_reference_sequence_id(id)
+            .set_alignment_start(
+                Position::try_from(usize::try_from(self.pos)?).context("Alignments start at 1")?,
+            )
+            .set_cigar(cigar)
+            .set_sequence(Sequence::from(self.sequence))
+            .set_data(data.into_iter().collect())
+            .build())
+    }
+}
+
+/// Fail unless `name` can be the read name of a SAM record: 1 to 254
+/// printable characters, with no spaces and no `@` at the start.
+pub fn check_read_name(name: &str) -> Result<()> {
+    ensure!(
+        (1..=254).contains(&name.len())
+            && !name.starts_with('@')
+            && name.bytes().all(|b| b.is_ascii_graphic()),
+        "The header \"{}\" can not be a SAM read name, which has to be 1 to 254 printable characters with no spaces or @ at the start",
+        name
+    );
+    Ok(())
+}
+
+/// Writes alignments as SAM or BAM, after a header listing the targets.
+pub struct SamWriter<W: Write> {
+    inner: Inner<W>,
+    header: sam::Header,
+    /// The index of each target in the header, which records refer to them
+    /// by.
+    ids: HashMap<String, usize>,
+}
+
+enum Inner<W: Write> {
+    Sam(sam::io::Writer<W>),
+    Bam(bam::io::Writer<bgzf::Writer<W>>),
+}
+
+impl<W: Write> SamWriter<W> {
+    /// Write the header for `targets`, with their lengths, to `writer`.
+    pub fn new(writer: W, format: Format, targets: &[(String, u64)]) -> Result<Self> {
+        let mut builder = sam::Header::builder().set_header(
+            Map::<map::Header>::builder()
+                .set_version(Version::new(1, 6))
+                .insert(header_tag::SORT_ORDER, "unsorted")
+                .build()?,
+        );
+        for (name, length) in targets {
+            let length = NonZeroUsize::try_from(usize::try_from(*length)?)
+                .with_context(|| format!("Sequence {} is empty", name))?;
+            builder = builder
+                .add_reference_sequence(name.as_str(), Map::<ReferenceSequence>::new(length));
+        }
+        let header = builder
+            .add_program(
+                env!("CARGO_PKG_NAME"),
+                Map::<Program>::builder()
+                    .insert(program_tag::NAME, env!("CARGO_PKG_NAME"))
+                    .insert(program_tag::VERSION, env!("CARGO_PKG_VERSION"))
+                    .build()?,
+            )
+            .build();
+
+        let mut inner = match format {
+            Format::Sam => Inner::Sam(sam::io::Writer::new(writer)),
+            Format::Bam => Inner::Bam(bam::io::Writer::new(writer)),
+        };
+        inner.writer().write_alignment_header(&header)?;
+        let ids = targets
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (name.clone(), i))
+            .collect();
+        Ok(Self { inner, header, ids })
+    }
+
+    /// Write one alignment.
+    pub fn write(&mut self, alignment: &Alignment) -> Result<()> {
+        let id = *self
+            .ids
+            .get(alignment.target)
+            .with_context(|| format!("Sequence {} not found in fasta", alignment.target))?;
+        let record = alignment.record(id)?;
+        self.inner
+            .writer()
+            .write_alignment_record(&self.header, &record)
+            .with_context(|| format!("Could not write the alignment of {}", alignment.name))?;
+        Ok(())
+    }
+
+    /// Flush the alignments, and end the BGZF stream of a BAM.
+    pub fn finish(self) -> Result<()> {
+        match self.inner {
+            Inner::Sam(writer) => writer.into_inner().flush()?,
+            Inner::Bam(writer) => writer.into_inner().finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Inner<W> {
+    fn writer(&mut self) -> &mut dyn sam::alignment::io::Write {
+        match self {
+            Inner::Sam(writer) => writer,
+            Inner::Bam(writer) => writer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alignment(sequence: &[u8]) -> Alignment<'_> {
+        Alignment {
+            name: "chr1/11-15",
+            target: "chr1",
+            pos: 11,
+            strand: Strand::Negative,
+            cigar: vec![(sequence.len() as u64, Op::Match)],
+            sequence,
+            query: "q1",
+            e_value: 1e-20,
+            score: 50.5,
+        }
+    }
+
+    #[test]
+    fn sam_record() {
+        let mut out = Vec::new();
+        let mut writer =
+            SamWriter::new(&mut out, Format::Sam, &[("chr1".to_string(), 77)]).unwrap();
+        writer.write(&alignment(b"ACGTA")).unwrap();
+        writer.finish().unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "@HD\tVN:1.6\tSO:unsorted");
+        assert_eq!(lines[1], "@SQ\tSN:chr1\tLN:77");
+        assert_eq!(
+            lines[3],
+            "chr1/11-15\t16\tchr1\t11\t255\t5M\t*\t0\t0\tACGTA\t*\tAS:i:51\tZS:f:50.5\tZE:Z:1e-20\tZQ:Z:q1"
+        );
+    }
+
+    #[test]
+    fn bam_round_trips() {
+        let mut out = Vec::new();
+        let mut writer = SamWriter::new(
+            &mut out,
+            Format::Bam,
+            &[("chr0".to_string(), 10), ("chr1".to_string(), 77)],
+        )
+        .unwrap();
+        writer.write(&alignment(b"ACGTA")).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = bam::io::Reader::new(&out[..]);
+        let header = reader.read_header().unwrap();
+        assert_eq!(header.reference_sequences().len(), 2);
+        let records: Vec<RecordBuf> = reader
+            .record_bufs(&header)
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        let [record] = &records[..] else {
+            panic!("expected one record, found {}", records.len())
+        };
+        assert_eq!(record.reference_sequence_id(), Some(1));
+        assert_eq!(record.alignment_start(), Position::new(11));
+        assert_eq!(record.flags(), Flags::REVERSE_COMPLEMENTED);
+        assert_eq!(record.sequence().as_ref(), b"ACGTA");
+    }
+
+    #[test]
+    fn sequence_must_match_the_cigar() {
+        let mut writer =
+            SamWriter::new(Vec::new(), Format::Sam, &[("chr1".to_string(), 77)]).unwrap();
+        let mut short = alignment(b"ACG");
+        short.cigar = vec![(5, Op::Match)];
+        assert!(writer.write(&short).is_err());
+    }
+
+    #[test]
+    fn read_names() {
+        assert!(check_read_name("chr1/11-15:E1e-20").is_ok());
+        assert!(check_read_name("chr1/11-15 desc").is_err());
+        assert!(check_read_name("@chr1").is_err());
+        assert!(check_read_name("").is_err());
+    }
+}
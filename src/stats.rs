@@ -0,0 +1,215 @@
+//! Summaries of the hits in a tblout.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{filter::HitFilter, tblout::Hit};
+
+/// What hits are grouped by for summarising.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Group {
+    /// The query profile.
+    #[default]
+    Query,
+    /// The target sequence.
+    Target,
+}
+
+impl FromStr for Group {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "query" => Ok(Group::Query),
+            "target" => Ok(Group::Target),
+            _ => bail!("Unknown grouping \"{}\"", s),
+        }
+    }
+}
+
+impl Group {
+    /// The name of the group column.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Group::Query => "query",
+            Group::Target => "target",
+        }
+    }
+
+    fn key<'a>(&self, hit: &'a Hit) -> &'a str {
+        match self {
+            Group::Query => &hit.query_name,
+            Group::Target => &hit.target_name,
+        }
+    }
+}
+
+/// Summary statistics for a group of hits.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    /// The number of hits.
+    pub hits: usize,
+    /// The number of hits passing the filters.
+    pub passing: usize,
+    /// The lowest, median and highest E-value.
+    pub e_values: Option<Spread>,
+    /// The lowest, median and highest bit score.
+    pub scores: Option<Spread>,
+    /// The number of target bases covered by at least one hit. Hits without
+    /// coordinates cover their whole target, if its length is known.
+    pub bases: u64,
+}
+
+/// The lowest, median and highest of some values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spread {
+    /// The lowest value.
+    pub min: f64,
+    /// The middle value, or the mean of the two middle values.
+    pub median: f64,
+    /// The highest value.
+    pub max: f64,
+}
+
+impl Spread {
+    fn of(mut values: Vec<f64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        let mid = values.len() / 2;
+        let median = if values.len().is_multiple_of(2) {
+            (values[mid - 1] + values[mid]) / 2.0
+        } else {
+            values[mid]
+        };
+        Some(Self {
+            min: values[0],
+            median,
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+/// The hits of a group, as they are summarised.
+#[derive(Default)]
+struct Tally {
+    hits: usize,
+    passing: usize,
+    e_values: Vec<f64>,
+    scores: Vec<f64>,
+    /// The spans of the hits on each target.
+    spans: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl Tally {
+    fn add(&mut self, hit: &Hit, passes: bool) {
+        self.hits += 1;
+        if passes {
+            self.passing += 1;
+        }
+        self.e_values.push(hit.e_value);
+        self.scores.push(hit.score);
+        let span = match hit.ali() {
+            Some((from, to)) => Some((from.min(to), from.max(to))),
+            None => hit.sq_len.map(|len| (1, len)),
+        };
+        if let Some(span) = span {
+            self.spans
+                .entry(hit.target_name.clone())
+                .or_default()
+                .push(span);
+        }
+    }
+
+    fn finish(self) -> Summary {
+        Summary {
+            hits: self.hits,
+            passing: self.passing,
+            e_values: Spread::of(self.e_values),
+            scores: Spread::of(self.scores),
+            bases: self.spans.into_values().map(covered).sum(),
+        }
+    }
+}
+
+/// The number of bases in the union of `spans`.
+fn covered(mut spans: Vec<(u64, u64)>) -> u64 {
+    spans.sort_unstable();
+    let mut bases = 0;
+    let mut end = 0;
+    for (from, to) in spans {
+        if to > end {
+            bases += to - from.max(end + 1) + 1;
+            end = to;
+        }
+    }
+    bases
+}
+
+/// Summarise `hits` per query profile or target sequence.
+pub fn summarise(hits: &[Hit], filter: &HitFilter, group: Group) -> BTreeMap<String, Summary> {
+    let mut tallies: BTreeMap<String, Tally> = BTreeMap::new();
+    for (hit, passes) in hits.iter().zip(filter.select(hits)) {
+        tallies
+            .entry(group.key(hit).to_string())
+            .or_default()
+            .add(hit, passes);
+    }
+    tallies
+        .into_iter()
+        .map(|(key, tally)| (key, tally.finish()))
+        .collect()
+}
+
+/// `e_value` in scientific notation to three significant figures, without
+/// trailing zeros, so that a median between two E-values reads as they do,
+/// e.g. `5e-11` and not `5.0000000005000005e-11`.
+fn format_e_value(e_value: f64) -> String {
+    let formatted = format!("{:.2e}", e_value);
+    match formatted.split_once('e') {
+        Some((mantissa, exponent)) if mantissa.contains('.') => {
+            let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+            format!("{}e{}", mantissa, exponent)
+        }
+        _ => formatted,
+    }
+}
+
+/// Write the summaries as a TSV table, with the groups in the first column.
+pub fn write_summaries<W: Write>(
+    mut writer: W,
+    group: Group,
+    summaries: &BTreeMap<String, Summary>,
+) -> Result<()> {
+    writeln!(
+        writer,
+        "{}\thits\tpassing\tmin_evalue\tmedian_evalue\tmax_evalue\tmin_score\tmedian_score\tmax_score\tbases_covered",
+        group.name()
+    )?;
+    let na = || "NA".to_string();
+    for (key, s) in summaries {
+        let e_values = s.e_values.map_or([na(), na(), na()], |e| {
+            [e.min, e.median, e.max].map(format_e_value)
+        });
+        let scores = s.scores.map_or([na(), na(), na()], |s| {
+            [s.min, s.median, s.max].map(|s| s.to_string())
+        });
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            key,
+            s.hits,
+            s.passing,
+            e_values.join("\t"),
+            scores.join("\t"),
+            s.bases,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Stitching of the hits of a query on the same target into one sequence,
+//! e.g. the exons of a marker found as separate hits.
+
+use std::collections::HashMap;
+
+use crate::{
+    merge::merge,
+    tblout::{Hit, Strand},
+};
+
+/// Group the hits of the same query on the same target and strand, to be
+/// joined into one sequence. Each group lists its hits in the order they
+/// are joined, 5' to 3' on their strand, and groups come in the order of
+/// their first hit in `hits`. Hits without coordinates are left alone.
+pub fn stitch_groups(hits: &[Hit]) -> Vec<Vec<usize>> {
+    let mut found: HashMap<(&str, &str, bool), usize> = HashMap::new();
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, hit) in hits.iter().enumerate() {
+        if hit.ali().is_none() {
+            groups.push(vec![i]);
+            continue;
+        }
+        let key = (
+            hit.target_name.as_str(),
+            hit.query_name.as_str(),
+            hit.strand == Strand::Positive,
+        );
+        match found.get(&key) {
+            Some(&group) => groups[group].push(i),
+            None => {
+                found.insert(key, groups.len());
+                groups.push(vec![i]);
+            }
+        }
+    }
+
+    for group in &mut groups {
+        group.sort_by_key(|&i| hits[i].ali().map(|(from, to)| from.min(to)));
+        // minus strand hits are joined from the highest coordinate down
+        if hits[group[0]].strand == Strand::Negative {
+            group.reverse();
+        }
+    }
+    groups
+}
+
+/// A hit spanning the hits at `group`, as they would be merged. It takes
+/// everything but its coordinates from the best (lowest E-value) of them.
+pub fn stitched_hit(hits: &[Hit], group: &[usize]) -> Hit {
+    merge(hits, group)
+}
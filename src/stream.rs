@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use fasta::record::Definition;
+use noodles_fasta as fasta;
+
+use crate::{
+    fetcher::{Region, SequenceFetcher},
+    native::reverse_complement,
+    prepare,
+};
+
+/// Fetches regions by reading through the fasta, without copying or
+/// indexing it. The lengths of the sequences are read once up front, and
+/// then each batch of regions takes one more pass, so this is only quicker
+/// than indexing for a handful of hits. Compressed fasta is decompressed
+/// on the fly.
+#[derive(Debug, Clone)]
+pub struct StreamFetcher {
+    path: PathBuf,
+    lengths: HashMap<String, u64>,
+    descriptions: HashMap<String, Vec<u8>>,
+}
+
+/// A region being collected, as an index into the batch.
+struct Wanted {
+    index: usize,
+    lo: u64,
+    hi: u64,
+}
+
+impl StreamFetcher {
+    /// Read the names, lengths and descriptions of the sequences in the
+    /// fasta at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut lengths = HashMap::new();
+        let mut descriptions = HashMap::new();
+        let mut current: Option<String> = None;
+        read_lines(path, |line| {
+            if let Some(definition) = line.strip_prefix(b">") {
+                let (name, description) = split_definition(definition);
+                if lengths.insert(name.clone(), 0).is_some() {
+                    bail!("Sequence {} is in {} more than once", name, path.display());
+                }
+                if let Some(description) = description {
+                    descriptions.insert(name.clone(), description);
+                }
+                current = Some(name);
+            } else if let Some(name) = &current {
+                *lengths.get_mut(name).expect("inserted at its definition") += line.len() as u64;
+            }
+            Ok(())
+        })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            lengths,
+            descriptions,
+        })
+    }
+}
+
+impl SequenceFetcher for StreamFetcher {
+    fn fetch(&mut self, region: Region<'_>) -> Result<fasta::Record> {
+        let mut records = self.fetch_batch(&[region])?;
+        Ok(records.remove(0))
+    }
+
+    fn fetch_batch(&mut self, regions: &[Region<'_>]) -> Result<Vec<fasta::Record>> {
+        // the regions wanted on each sequence, by start
+        let mut wanted: HashMap<&str, Vec<Wanted>> = HashMap::new();
+        for (index, &(name, coords)) in regions.iter().enumerate() {
+            let length = *self
+                .lengths
+                .get(name)
+                .with_context(|| format!("Sequence {} not found in fasta", name))?;
+            let (lo, hi) = match coords {
+                Some((from, to)) => (from.min(to), from.max(to)),
+                None => (1, length),
+            };
+            if lo < 1 || hi > length {
+                bail!(
+                    "Coordinates {}..{} are out of range for {} (length {})",
+                    lo,
+                    hi,
+                    name,
+                    length
+                );
+            }
+            wanted
+                .entry(name)
+                .or_default()
+                .push(Wanted { index, lo, hi });
+        }
+        for regions in wanted.values_mut() {
+            regions.sort_by_key(|w| w.lo);
+        }
+
+        let mut sequences: Vec<Vec<u8>> = regions
+            .iter()
+            .map(|&(name, coords)| {
+                let (lo, hi) = coords.unwrap_or((1, self.lengths[name]));
+                Vec::with_capacity((lo.max(hi) - lo.min(hi) + 1) as usize)
+            })
+            .collect();
+        let mut current: Option<&[Wanted]> = None;
+        // the bases read of the current sequence, and the first region not
+        // yet past
+        let (mut read, mut first) = (0, 0);
+        read_lines(&self.path, |line| {
+            if let Some(definition) = line.strip_prefix(b">") {
+                let (name, _) = split_definition(definition);
+                current = wanted.get(name.as_str()).map(Vec::as_slice);
+                (read, first) = (0, 0);
+                return Ok(());
+            }
+            let Some(regions) = current else {
+                return Ok(());
+            };
+            let (start, end) = (read + 1, read + line.len() as u64);
+            read = end;
+            while regions.get(first).is_some_and(|w| w.hi < start) {
+                first += 1;
+            }
+            for w in regions[first..].iter().take_while(|w| w.lo <= end) {
+                if w.hi >= start {
+                    let (from, to) = (w.lo.max(start), w.hi.min(end));
+                    sequences[w.index]
+                        .extend_from_slice(&line[(from - start) as usize..=(to - start) as usize]);
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(regions
+            .iter()
+            .zip(sequences)
+            .map(|(&(name, coords), sequence)| {
+                let sequence = match coords {
+                    Some((from, to)) if from > to => reverse_complement(&sequence),
+                    _ => sequence.into(),
+                };
+                let description = self.descriptions.get(name).cloned();
+                fasta::Record::new(Definition::new(name, description), sequence)
+            })
+            .collect())
+    }
+
+    fn batch_size(&self) -> usize {
+        // every batch is a pass over the fasta
+        usize::MAX
+    }
+
+    fn lengths(&self) -> Result<HashMap<String, u64>> {
+        Ok(self.lengths.clone())
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn SequenceFetcher>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// Call `f` on each line of the fasta at `path`, without its line ending.
+/// Blank lines are skipped, and FASTQ is read as fasta.
+fn read_lines<F>(path: &Path, f: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    prepare::fasta_lines(prepare::stream(path)?, path, f)
+}
+
+/// The name and description of a definition line without its `>`.
+fn split_definition(definition: &[u8]) -> (String, Option<Vec<u8>>) {
+    match definition.iter().position(|b| b.is_ascii_whitespace()) {
+        Some(i) => {
+            let description = definition[i..].trim_ascii_start();
+            (
+                String::from_utf8_lossy(&definition[..i]).into_owned(),
+                Some(description.to_vec()).filter(|d| !d.is_empty()),
+            )
+        }
+        None => (String::from_utf8_lossy(definition).into_owned(), None),
+    }
+}
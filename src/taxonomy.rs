@@ -0,0 +1,135 @@
+//! Species labels from NCBI taxonomy, so that they do not have to be
+//! written out by hand for each genome.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+/// How a scientific name is turned into a species label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelFormat {
+    /// The whole name, e.g. `Homo_sapiens`.
+    Full,
+    /// The initial of the genus and the species, e.g. `H_sapiens`.
+    Initial,
+    /// The first letters of the genus and of the species, e.g. `Hsap` with
+    /// 1 and 3.
+    Abbrev(usize, usize),
+}
+
+impl Default for LabelFormat {
+    fn default() -> Self {
+        LabelFormat::Abbrev(1, 3)
+    }
+}
+
+impl FromStr for LabelFormat {
+    type Err = anyhow::Error;
+
+    /// `full`, `initial`, `abbrev` (as `1+3`), or `G+S` for the first `G`
+    /// letters of the genus and `S` of the species.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(LabelFormat::Full),
+            "initial" => Ok(LabelFormat::Initial),
+            "abbrev" => Ok(LabelFormat::default()),
+            _ => {
+                let lengths = s
+                    .split_once('+')
+                    .and_then(|(g, s)| Some((g.parse().ok()?, s.parse().ok()?)));
+                match lengths {
+                    Some((genus, species)) => Ok(LabelFormat::Abbrev(genus, species)),
+                    None => bail!("Unknown species label format \"{}\"", s),
+                }
+            }
+        }
+    }
+}
+
+impl LabelFormat {
+    /// The label for the scientific name `name`. Anything but letters,
+    /// digits, `-` and `_` is left out, apart from spaces which become `_`
+    /// in full names.
+    pub fn label(&self, name: &str) -> String {
+        // e.g. "[Clostridium] scindens"
+        let name: String = name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+            .collect();
+        let mut words = name.split_whitespace();
+        let genus = words.next().unwrap_or_default();
+        let species = words.next().unwrap_or_default();
+        match self {
+            LabelFormat::Full => name.split_whitespace().collect::<Vec<_>>().join("_"),
+            LabelFormat::Initial if species.is_empty() => genus.to_string(),
+            LabelFormat::Initial => {
+                format!("{}_{}", genus.chars().next().unwrap_or_default(), species)
+            }
+            LabelFormat::Abbrev(g, s) if species.is_empty() => genus.chars().take(g + s).collect(),
+            LabelFormat::Abbrev(g, s) => {
+                let genus: String = genus.chars().take(*g).collect();
+                let species: String = species.chars().take(*s).collect();
+                format!("{}{}", genus, species.to_lowercase())
+            }
+        }
+    }
+}
+
+/// Read the scientific names of `taxids` from an NCBI taxdump: a directory
+/// with a `names.dmp`, a `names.dmp` itself, or a table with a taxid and a
+/// name on each line, separated by a tab. Taxids that are not there are
+/// left out.
+pub fn read_names<P: AsRef<Path>>(path: P, taxids: &HashSet<u64>) -> Result<HashMap<u64, String>> {
+    let mut path = path.as_ref().to_path_buf();
+    if path.is_dir() {
+        path.push("names.dmp");
+    }
+    // names.dmp is too large to read into memory just for a few names
+    let reader = BufReader::new(
+        File::open(&path).with_context(|| format!("Could not read taxonomy {}", path.display()))?,
+    );
+    let dmp = path.extension().is_some_and(|e| e == "dmp");
+
+    let mut names = HashMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.with_context(|| format!("Could not read taxonomy {}", path.display()))?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let Some(taxid) = fields.next() else {
+            continue;
+        };
+        let taxid: u64 = taxid.trim().parse().with_context(|| {
+            format!("{}:{}: invalid taxid \"{}\"", path.display(), i + 1, taxid)
+        })?;
+        if !taxids.contains(&taxid) {
+            continue;
+        }
+        let name = if dmp {
+            // tax_id | name_txt | unique name | name class |
+            let fields: Vec<&str> = line.split("\t|\t").map(str::trim).collect();
+            if fields.get(3).map(|c| c.trim_end_matches(['\t', '|'])) != Some("scientific name") {
+                continue;
+            }
+            fields[1]
+        } else {
+            match fields.next() {
+                Some(name) => name.trim(),
+                None => bail!(
+                    "{}:{}: expected a taxid and a name separated by a tab",
+                    path.display(),
+                    i + 1
+                ),
+            }
+        };
+        names.insert(taxid, name.to_string());
+    }
+    Ok(names)
+}
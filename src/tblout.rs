@@ -0,0 +1,993 @@
+//! Parsing of HMMER tblout files, shared by all of the subcommands.
+//!
+//! Unlike `hmm_tblout`, the original text of every line is kept so that
+//! filtered tblouts can be written back out unchanged.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+pub use hmm_tblout::Strand;
+use log::warn;
+
+use crate::filter::HitFilter;
+
+/// The tblout layouts that are understood.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `nhmmer`, with alignment coordinates on the target.
+    Nhmmer,
+    /// `hmmsearch`, with per-sequence hits and no coordinates. `phmmer`
+    /// and `jackhmmer` write the same layout.
+    Hmmsearch,
+    /// A `--domtblout` from a protein search, with one line per domain.
+    Domtblout,
+    /// Infernal's `cmsearch`, with an explicit strand column.
+    Cmsearch,
+    /// Infernal's `cmscan`, where the sequence is the query.
+    Cmscan,
+    /// BLAST tabular output (`-outfmt 6`), where the subject is the target.
+    Blast6,
+    /// MMseqs2 tabular output (`convertalis`), in the given columns.
+    Mmseqs(Columns),
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nhmmer" => Ok(Format::Nhmmer),
+            "hmmsearch" | "phmmer" | "jackhmmer" => Ok(Format::Hmmsearch),
+            "cmsearch" => Ok(Format::Cmsearch),
+            "cmscan" => Ok(Format::Cmscan),
+            "blast6" => Ok(Format::Blast6),
+            "mmseqs" => Ok(Format::Mmseqs(Columns::default())),
+            _ => bail!("The program \"{}\" is not supported", s),
+        }
+    }
+}
+
+impl Format {
+    /// The program that writes this layout.
+    pub fn program(&self) -> &'static str {
+        match self {
+            Format::Nhmmer => "nhmmer",
+            Format::Hmmsearch | Format::Domtblout => "hmmsearch",
+            Format::Cmsearch => "cmsearch",
+            Format::Cmscan => "cmscan",
+            Format::Blast6 => "blast",
+            Format::Mmseqs(_) => "mmseqs",
+        }
+    }
+
+    /// Whether the targets are protein sequences.
+    pub fn is_protein(&self) -> bool {
+        matches!(self, Format::Hmmsearch | Format::Domtblout)
+    }
+
+    /// Guess the format from the layout of a data line, by the columns
+    /// each layout always has numbers in. Descriptions can have any number
+    /// of words, so they are not counted on.
+    fn sniff(line: &str) -> Option<Self> {
+        let (fields, _) = split_fields(line, 22);
+        let is_float = |i: usize| fields.get(i).is_some_and(|f| f.parse::<f64>().is_ok());
+        let is_int = |i: usize| fields.get(i).is_some_and(|f| f.parse::<u64>().is_ok());
+        let floats = |columns: &[usize]| columns.iter().all(|&i| is_float(i));
+        let ints = |columns: &[usize]| columns.iter().all(|&i| is_int(i));
+
+        if matches!(fields.get(4), Some(&"cm" | &"hmm"))
+            && matches!(fields.get(9), Some(&"+" | &"-"))
+        {
+            // Infernal does not say whether it was cmsearch or cmscan
+            Some(Format::Cmsearch)
+        } else if matches!(fields.get(11), Some(&"+" | &"-")) && ints(&[4, 5, 6, 7, 8, 9, 10]) {
+            Some(Format::Nhmmer)
+        } else if ints(&[2, 5, 9, 10, 15, 16, 17, 18, 19, 20])
+            && floats(&[6, 7, 8, 11, 12, 13, 14, 21])
+        {
+            Some(Format::Domtblout)
+        } else if floats(&[4, 5, 6, 7, 8, 9, 10]) && ints(&[11, 12, 13, 14, 15, 16, 17]) {
+            Some(Format::Hmmsearch)
+        } else if fields.len() == 12 && is_float(2) && is_float(10) {
+            Some(Format::Blast6)
+        } else {
+            None
+        }
+    }
+}
+
+/// Where the columns read are in MMseqs2 tabular output, which can be laid
+/// out with `--format-output`. The default is the BLAST-like m8 layout
+/// `convertalis` writes unless told otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Columns {
+    query: usize,
+    target: usize,
+    qstart: Option<usize>,
+    qend: Option<usize>,
+    tstart: usize,
+    tend: usize,
+    evalue: usize,
+    bits: usize,
+    qlen: Option<usize>,
+    tlen: Option<usize>,
+    theader: Option<usize>,
+    /// The number of columns.
+    len: usize,
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        // query,target,fident,alnlen,mismatch,gapopen,qstart,qend,tstart,tend,evalue,bits
+        Self {
+            query: 0,
+            target: 1,
+            qstart: Some(6),
+            qend: Some(7),
+            tstart: 8,
+            tend: 9,
+            evalue: 10,
+            bits: 11,
+            qlen: None,
+            tlen: None,
+            theader: None,
+            len: 12,
+        }
+    }
+}
+
+impl FromStr for Columns {
+    type Err = anyhow::Error;
+
+    /// Parse the comma separated column names given to `--format-output`.
+    /// Columns that are not needed are skipped, but `query`, `target`,
+    /// `tstart`, `tend`, `evalue` and `bits` have to be there.
+    fn from_str(s: &str) -> Result<Self> {
+        let names: Vec<&str> = s.split(',').map(str::trim).collect();
+        let find = |name: &str| names.iter().position(|&n| n == name);
+        let require = |name: &str| {
+            find(name).with_context(|| format!("The columns \"{}\" have no {} column", s, name))
+        };
+        Ok(Self {
+            query: require("query")?,
+            target: require("target")?,
+            qstart: find("qstart"),
+            qend: find("qend"),
+            tstart: require("tstart")?,
+            tend: require("tend")?,
+            evalue: require("evalue")?,
+            bits: require("bits")?,
+            qlen: find("qlen"),
+            tlen: find("tlen"),
+            theader: find("theader"),
+            len: names.len(),
+        })
+    }
+}
+
+/// The metadata in the comment block at the end of a tblout.
+#[derive(Debug, Clone, Default)]
+pub struct Meta {
+    /// The program that produced the file, e.g. `nhmmer`.
+    pub program: String,
+    /// The version of the program.
+    pub version: String,
+    /// The query (HMM) file.
+    pub query_file: PathBuf,
+    /// The target (sequence) file.
+    pub target_file: PathBuf,
+    /// The command line options used.
+    pub options: String,
+}
+
+impl Meta {
+    /// Fill in a field from a metadata comment line, if it is one.
+    fn parse_line(&mut self, line: &str) {
+        let Some((key, value)) = line.trim_start_matches('#').split_once(':') else {
+            return;
+        };
+        let value = value.trim().to_string();
+        match key.trim() {
+            "Program" => self.program = value,
+            "Version" => self.version = value,
+            "Query file" => self.query_file = PathBuf::from(value),
+            "Target file" => self.target_file = PathBuf::from(value),
+            "Option settings" => self.options = value,
+            _ => (),
+        }
+    }
+
+    /// The value given to a command line option, e.g. `--incE`.
+    pub fn option(&self, name: &str) -> Option<&str> {
+        let mut words = self.options.split_whitespace();
+        words.find(|&w| w == name)?;
+        words.next()
+    }
+
+    /// Whether `hit` would have passed the inclusion thresholds of the
+    /// search, defaulting to HMMER's E-value of 0.01.
+    fn includes(&self, hit: &Hit, format: Format) -> bool {
+        let (inc_e, inc_t) = match format {
+            Format::Domtblout => ("--incdomE", "--incdomT"),
+            _ => ("--incE", "--incT"),
+        };
+        match self.option(inc_t).and_then(|t| t.parse::<f64>().ok()) {
+            Some(inc_t) => hit.score >= inc_t,
+            None => {
+                let inc_e = self
+                    .option(inc_e)
+                    .and_then(|e| e.parse().ok())
+                    .unwrap_or(0.01);
+                hit.e_value <= inc_e
+            }
+        }
+    }
+}
+
+/// A single hit (one non-comment line) in a tblout. Coordinates are
+/// only present for programs that report them; a hit without alignment
+/// coordinates covers its whole target sequence.
+#[derive(Debug, Clone)]
+pub struct Hit {
+    /// The name of the target sequence.
+    pub target_name: String,
+    /// The accession of the target sequence, or `-`.
+    pub target_accession: String,
+    /// The name of the query profile.
+    pub query_name: String,
+    /// The accession of the query profile, or `-`.
+    pub query_accession: String,
+    /// Where the hit starts in the profile.
+    pub hmm_from: Option<u64>,
+    /// Where the hit ends in the profile.
+    pub hmm_to: Option<u64>,
+    /// Where the alignment starts in the target (`> ali_to` on the minus strand).
+    pub ali_from: Option<u64>,
+    /// Where the alignment ends in the target.
+    pub ali_to: Option<u64>,
+    /// Where the envelope starts in the target.
+    pub env_from: Option<u64>,
+    /// Where the envelope ends in the target.
+    pub env_to: Option<u64>,
+    /// The length of the target sequence.
+    pub sq_len: Option<u64>,
+    /// The length of the query profile.
+    pub query_len: Option<u64>,
+    /// The number of the domain within the target, for domain hits.
+    pub domain: Option<u64>,
+    /// The round of the search the hit was found in, counting from 1, for
+    /// iterative searches (`jackhmmer`).
+    pub round: Option<u64>,
+    /// The strand of the hit. Protein hits are always on the plus strand.
+    pub strand: Strand,
+    /// The E-value of the hit (of the full sequence for protein hits).
+    pub e_value: f64,
+    /// The bit score of the hit.
+    pub score: f64,
+    /// The biased-composition correction.
+    pub bias: f64,
+    /// Whether the program included the hit in its significant results
+    /// (`!` in the `inc` column). Programs without the column are judged
+    /// by the `--incE`/`--incT` thresholds in the metadata.
+    pub included: Option<bool>,
+    /// The description of the target, if any.
+    pub description: Option<String>,
+    /// The original line, without the line ending.
+    pub line: String,
+}
+
+impl Hit {
+    /// Parse a single tblout line in the layout of `format`.
+    pub fn from_line(line: &str, format: Format) -> Result<Self> {
+        match format {
+            Format::Nhmmer => Self::from_dna_line(line),
+            Format::Hmmsearch => Self::from_protein_line(line),
+            Format::Domtblout => Self::from_domain_line(line),
+            Format::Cmsearch => Self::from_infernal_line(line, false),
+            Format::Cmscan => Self::from_infernal_line(line, true),
+            Format::Blast6 => Self::from_blast_line(line),
+            Format::Mmseqs(columns) => Self::from_mmseqs_line(line, &columns),
+        }
+    }
+
+    fn from_dna_line(line: &str) -> Result<Self> {
+        let (fields, rest) = split_fields(line, 15);
+        check_columns(&fields, 15, line)?;
+
+        Ok(Self {
+            target_name: fields[0].to_string(),
+            target_accession: fields[1].to_string(),
+            query_name: fields[2].to_string(),
+            query_accession: fields[3].to_string(),
+            hmm_from: Some(parse(fields[4], "hmmfrom")?),
+            hmm_to: Some(parse(fields[5], "hmm to")?),
+            ali_from: Some(parse(fields[6], "alifrom")?),
+            ali_to: Some(parse(fields[7], "ali to")?),
+            env_from: Some(parse(fields[8], "envfrom")?),
+            env_to: Some(parse(fields[9], "env to")?),
+            sq_len: Some(parse(fields[10], "sq len")?),
+            query_len: None,
+            domain: None,
+            round: None,
+            strand: fields[11]
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid strand \"{}\"", fields[11]))?,
+            e_value: parse(fields[12], "E-value")?,
+            score: parse(fields[13], "score")?,
+            bias: parse(fields[14], "bias")?,
+            included: None,
+            description: description(rest),
+            line: line.to_string(),
+        })
+    }
+
+    fn from_protein_line(line: &str) -> Result<Self> {
+        let (fields, rest) = split_fields(line, 18);
+        check_columns(&fields, 18, line)?;
+
+        Ok(Self {
+            target_name: fields[0].to_string(),
+            target_accession: fields[1].to_string(),
+            query_name: fields[2].to_string(),
+            query_accession: fields[3].to_string(),
+            hmm_from: None,
+            hmm_to: None,
+            ali_from: None,
+            ali_to: None,
+            env_from: None,
+            env_to: None,
+            sq_len: None,
+            query_len: None,
+            domain: None,
+            round: None,
+            strand: Strand::Positive,
+            e_value: parse(fields[4], "E-value")?,
+            score: parse(fields[5], "score")?,
+            bias: parse(fields[6], "bias")?,
+            // the number of included domains
+            included: Some(parse::<u64>(fields[17], "inc")? > 0),
+            description: description(rest),
+            line: line.to_string(),
+        })
+    }
+
+    fn from_domain_line(line: &str) -> Result<Self> {
+        let (fields, rest) = split_fields(line, 22);
+        check_columns(&fields, 22, line)?;
+
+        Ok(Self {
+            target_name: fields[0].to_string(),
+            target_accession: fields[1].to_string(),
+            query_name: fields[3].to_string(),
+            query_accession: fields[4].to_string(),
+            hmm_from: Some(parse(fields[15], "hmm from")?),
+            hmm_to: Some(parse(fields[16], "hmm to")?),
+            ali_from: Some(parse(fields[17], "ali from")?),
+            ali_to: Some(parse(fields[18], "ali to")?),
+            env_from: Some(parse(fields[19], "env from")?),
+            env_to: Some(parse(fields[20], "env to")?),
+            sq_len: Some(parse(fields[2], "tlen")?),
+            query_len: Some(parse(fields[5], "qlen")?),
+            domain: Some(parse(fields[9], "#")?),
+            round: None,
+            strand: Strand::Positive,
+            // the independent E-value, score and bias of the domain
+            e_value: parse(fields[12], "i-Evalue")?,
+            score: parse(fields[13], "score")?,
+            bias: parse(fields[14], "bias")?,
+            included: None,
+            description: description(rest),
+            line: line.to_string(),
+        })
+    }
+
+    /// Parse an Infernal (`--fmt 1`) tblout line. For `cmscan` the target
+    /// and query columns are swapped, so that the target is always the
+    /// sequence.
+    fn from_infernal_line(line: &str, scan: bool) -> Result<Self> {
+        let (fields, rest) = split_fields(line, 17);
+        check_columns(&fields, 17, line)?;
+
+        let (target, query) = if scan { (2, 0) } else { (0, 2) };
+
+        Ok(Self {
+            target_name: fields[target].to_string(),
+            target_accession: fields[target + 1].to_string(),
+            query_name: fields[query].to_string(),
+            query_accession: fields[query + 1].to_string(),
+            hmm_from: Some(parse(fields[5], "mdl from")?),
+            hmm_to: Some(parse(fields[6], "mdl to")?),
+            ali_from: Some(parse(fields[7], "seq from")?),
+            ali_to: Some(parse(fields[8], "seq to")?),
+            env_from: None,
+            env_to: None,
+            sq_len: None,
+            query_len: None,
+            domain: None,
+            round: None,
+            strand: fields[9]
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid strand \"{}\"", fields[9]))?,
+            bias: parse(fields[13], "bias")?,
+            score: parse(fields[14], "score")?,
+            e_value: parse(fields[15], "E-value")?,
+            included: Some(fields[16] == "!"),
+            description: description(rest),
+            line: line.to_string(),
+        })
+    }
+
+    /// Parse a BLAST `-outfmt 6` line. The subject is the target, and the
+    /// hit is on the minus strand if its subject start is after its end.
+    /// The query coordinates take the place of the profile ones.
+    fn from_blast_line(line: &str) -> Result<Self> {
+        let (fields, _) = split_fields(line, 12);
+        check_columns(&fields, 12, line)?;
+
+        let ali_from = parse(fields[8], "sstart")?;
+        let ali_to = parse(fields[9], "send")?;
+        Ok(Self {
+            target_name: fields[1].to_string(),
+            target_accession: "-".to_string(),
+            query_name: fields[0].to_string(),
+            query_accession: "-".to_string(),
+            hmm_from: Some(parse(fields[6], "qstart")?),
+            hmm_to: Some(parse(fields[7], "qend")?),
+            ali_from: Some(ali_from),
+            ali_to: Some(ali_to),
+            env_from: None,
+            env_to: None,
+            sq_len: None,
+            query_len: None,
+            domain: None,
+            round: None,
+            strand: if ali_from > ali_to {
+                Strand::Negative
+            } else {
+                Strand::Positive
+            },
+            e_value: parse(fields[10], "evalue")?,
+            score: parse(fields[11], "bitscore")?,
+            bias: 0.0,
+            included: None,
+            description: None,
+            line: line.to_string(),
+        })
+    }
+
+    /// Parse a line of MMseqs2 tabular output in the layout of `columns`.
+    /// As with BLAST, the target is on the minus strand if its start is
+    /// after its end.
+    fn from_mmseqs_line(line: &str, columns: &Columns) -> Result<Self> {
+        // the columns are separated by tabs, as headers can have spaces
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        check_columns(&fields, columns.len, line)?;
+        let optional = |column: Option<usize>, name| -> Result<Option<u64>> {
+            column.map(|i| parse(fields[i], name)).transpose()
+        };
+
+        let ali_from = parse(fields[columns.tstart], "tstart")?;
+        let ali_to = parse(fields[columns.tend], "tend")?;
+        Ok(Self {
+            target_name: fields[columns.target].to_string(),
+            target_accession: "-".to_string(),
+            query_name: fields[columns.query].to_string(),
+            query_accession: "-".to_string(),
+            hmm_from: optional(columns.qstart, "qstart")?,
+            hmm_to: optional(columns.qend, "qend")?,
+            ali_from: Some(ali_from),
+            ali_to: Some(ali_to),
+            env_from: None,
+            env_to: None,
+            sq_len: optional(columns.tlen, "tlen")?,
+            query_len: optional(columns.qlen, "qlen")?,
+            domain: None,
+            round: None,
+            strand: if ali_from > ali_to {
+                Strand::Negative
+            } else {
+                Strand::Positive
+            },
+            e_value: parse(fields[columns.evalue], "evalue")?,
+            score: parse(fields[columns.bits], "bits")?,
+            bias: 0.0,
+            included: None,
+            // the target header is its name and then its description
+            description: columns
+                .theader
+                .and_then(|i| fields[i].split_once(char::is_whitespace))
+                .and_then(|(_, d)| description(d)),
+            line: line.to_string(),
+        })
+    }
+
+    /// The envelope coordinates, if the program reports them.
+    pub fn env(&self) -> Option<(u64, u64)> {
+        self.env_from.zip(self.env_to)
+    }
+
+    /// The alignment coordinates, if the program reports them.
+    pub fn ali(&self) -> Option<(u64, u64)> {
+        self.ali_from.zip(self.ali_to)
+    }
+}
+
+fn check_columns(fields: &[&str], n: usize, line: &str) -> Result<()> {
+    if fields.len() < n {
+        bail!(
+            "expected at least {} columns, found {} in \"{}\"",
+            n,
+            fields.len(),
+            line
+        );
+    }
+    Ok(())
+}
+
+fn description(rest: &str) -> Option<String> {
+    match rest.trim() {
+        "" | "-" => None,
+        d => Some(d.to_string()),
+    }
+}
+
+fn parse<T>(field: &str, column: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    field
+        .parse()
+        .with_context(|| format!("invalid {} \"{}\"", column, field))
+}
+
+/// Split off the first `n` whitespace separated fields, returning them
+/// along with the rest of the line.
+fn split_fields(line: &str, n: usize) -> (Vec<&str>, &str) {
+    let mut fields = Vec::with_capacity(n);
+    let mut rest = line;
+    while fields.len() < n {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        fields.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    (fields, rest)
+}
+
+/// Number the rounds of a `jackhmmer` search the hits were found in.
+/// Every round appends its hits to the tblout without marking where they
+/// start, but within a round each target (or domain) is only listed once,
+/// in order of the E-value of its sequence. So a round starts at a hit
+/// that was already listed in the round before it, or that has a lower
+/// E-value than the hit before it. Rounds are counted for each query.
+fn number_rounds(hits: &mut [Hit], format: Format) {
+    let mut query = None;
+    let mut round = 0;
+    let mut seen: HashSet<(String, Option<u64>)> = HashSet::new();
+    let mut last_e_value = f64::NEG_INFINITY;
+    for hit in hits {
+        let e_value = match format {
+            // the E-value of the domain's sequence, which the domains are
+            // ordered by
+            Format::Domtblout => split_fields(&hit.line, 7)
+                .0
+                .get(6)
+                .and_then(|e| e.parse().ok())
+                .unwrap_or(hit.e_value),
+            _ => hit.e_value,
+        };
+        let key = (hit.target_name.clone(), hit.domain);
+        if query.as_ref() != Some(&hit.query_name) {
+            query = Some(hit.query_name.clone());
+            round = 1;
+            seen.clear();
+        } else if e_value < last_e_value || seen.contains(&key) {
+            round += 1;
+            seen.clear();
+        }
+        seen.insert(key);
+        last_e_value = e_value;
+        hit.round = Some(round);
+    }
+}
+
+/// A parsed tblout, keeping its comment lines.
+#[derive(Debug, Clone)]
+pub struct Tblout {
+    meta: Meta,
+    format: Format,
+    /// Comment lines before the first hit (the column headers).
+    header: Vec<String>,
+    /// Comment lines after the last hit (the metadata).
+    footer: Vec<String>,
+    hits: Vec<Hit>,
+    /// The number of lines that could not be parsed and were skipped.
+    skipped: usize,
+    /// The number of hits dropped by [`Tblout::apply_filter`].
+    filtered: usize,
+}
+
+impl Tblout {
+    /// Read a tblout from a file. If `format` is `None` it is taken from
+    /// the program in the metadata, or guessed from the layout of the hits.
+    /// A path of `-` reads from stdin. See [`Tblout::from_reader`] for
+    /// `lenient`.
+    pub fn from_path<P: AsRef<Path>>(
+        path: P,
+        format: Option<Format>,
+        lenient: bool,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            return Self::from_reader(io::stdin().lock(), format, lenient)
+                .context("Could not read tblout from stdin");
+        }
+        let file = File::open(path)
+            .with_context(|| format!("Could not open tblout {}", path.display()))?;
+        Self::from_reader(BufReader::new(file), format, lenient)
+            .with_context(|| format!("Could not read tblout {}", path.display()))
+    }
+
+    /// Read several tblouts as one, with the hits in file order. See
+    /// [`Tblout::append`].
+    pub fn from_paths<P: AsRef<Path>>(
+        paths: &[P],
+        format: Option<Format>,
+        lenient: bool,
+    ) -> Result<Self> {
+        let (first, rest) = paths.split_first().context("No tblout given")?;
+        let mut tblout = Self::from_path(first, format, lenient)?;
+        for path in rest {
+            let path = path.as_ref();
+            tblout
+                .append(Self::from_path(path, format, lenient)?)
+                .with_context(|| format!("Could not combine tblout {}", path.display()))?;
+        }
+        Ok(tblout)
+    }
+
+    /// Read a tblout from anything buffered. A line that can not be parsed
+    /// is an error, unless `lenient`, when it is logged and skipped instead
+    /// (see [`Tblout::skipped`]).
+    pub fn from_reader<R: BufRead>(
+        reader: R,
+        format: Option<Format>,
+        lenient: bool,
+    ) -> Result<Self> {
+        let mut meta = Meta::default();
+        let mut header = Vec::new();
+        let mut footer = Vec::new();
+        // the metadata is at the end, so hold on to the lines until the
+        // layout is known
+        let mut lines = Vec::new();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end_matches('\r');
+
+            if line.starts_with('#') {
+                meta.parse_line(line);
+                if lines.is_empty() {
+                    header.push(line.to_string());
+                } else {
+                    footer.push(line.to_string());
+                }
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            lines.push((i + 1, line.to_string()));
+        }
+
+        // the layout of the hits is more specific than the program (hmmsearch
+        // writes both tblouts and domtblouts), so it is checked first
+        let sniffed = match lines.first().and_then(|(_, l)| Format::sniff(l)) {
+            // the Infernal layouts are the same but for which is the sequence
+            Some(Format::Cmsearch) if meta.program == "cmscan" => Some(Format::Cmscan),
+            sniffed => sniffed,
+        };
+        let format = match format.or(sniffed) {
+            Some(f) => f,
+            None => match meta.program.parse::<Format>() {
+                Ok(f) => f,
+                Err(_) if lines.is_empty() => Format::Nhmmer,
+                Err(e) => return Err(e.context("Could not work out the tblout layout")),
+            },
+        };
+
+        let mut hits = Vec::with_capacity(lines.len());
+        let mut skipped = 0;
+        for (n, line) in &lines {
+            match Hit::from_line(line, format).with_context(|| format!("line {}", n)) {
+                Ok(hit) => hits.push(hit),
+                Err(e) if lenient => {
+                    warn!("Skipping {:#}", e);
+                    skipped += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        for hit in hits.iter_mut().filter(|hit| hit.included.is_none()) {
+            hit.included = Some(meta.includes(hit, format));
+        }
+        if meta.program == "jackhmmer" {
+            number_rounds(&mut hits, format);
+        }
+
+        Ok(Self {
+            meta,
+            format,
+            header,
+            footer,
+            hits,
+            skipped,
+            filtered: 0,
+        })
+    }
+
+    /// The metadata of the search.
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
+    /// The layout the hits were parsed with.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The hits, in file order.
+    pub fn hits(&self) -> &[Hit] {
+        &self.hits
+    }
+
+    /// The number of lines that were skipped as they could not be parsed,
+    /// when read leniently.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// The number of hits that did not pass the filters applied with
+    /// [`Tblout::apply_filter`].
+    pub fn filtered(&self) -> usize {
+        self.filtered
+    }
+
+    /// Consume the tblout, returning the hits.
+    pub fn into_hits(self) -> Vec<Hit> {
+        self.hits
+    }
+
+    /// Add the hits of `other` after these. The column headers and metadata
+    /// are kept from the first tblout, and the metadata of `other` is
+    /// written after it. Both must have the same layout.
+    pub fn append(&mut self, other: Tblout) -> Result<()> {
+        if other.format != self.format {
+            bail!(
+                "Can not combine {} hits with {} hits",
+                other.format.program(),
+                self.format.program()
+            );
+        }
+        self.hits.extend(other.hits);
+        self.footer.extend(other.footer);
+        self.skipped += other.skipped;
+        self.filtered += other.filtered;
+        Ok(())
+    }
+
+    /// Keep only the hits for which `f` returns true.
+    pub fn retain<F: FnMut(&Hit) -> bool>(&mut self, f: F) {
+        self.hits.retain(f);
+    }
+
+    /// Keep only the hits selected by `filter`.
+    pub fn apply_filter(&mut self, filter: &HitFilter) {
+        let before = self.hits.len();
+        let mut keep = filter.select(&self.hits).into_iter();
+        self.hits.retain(|_| keep.next().unwrap_or(false));
+        self.filtered += before - self.hits.len();
+    }
+
+    /// Write the tblout back out, comments and all.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for line in &self.header {
+            writeln!(writer, "{}", line)?;
+        }
+        for hit in &self.hits {
+            writeln!(writer, "{}", hit.line)?;
+        }
+        for line in &self.footer {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NHMMER: &str = "chr1  -  LINE1  -  1  300  1200  901  1210  890  5000  -  1e-20  50.0  0.5  L1 fragment on the minus strand";
+    const HMMSEARCH: &str = "sp|P12345|KIN_HUMAN  -  Pkinase  PF00069.28  1.2e-50  170.3  0.0  1.5e-50  170.0  0.0  1.0  1  0  0  1  1  1  1  putative membrane protein kinase alpha";
+    const DOMTBLOUT: &str = "sp|P12345|KIN_HUMAN  -  350  Pkinase  PF00069.28  264  1.2e-50  170.3  0.0  1  1  2.1e-53  1.6e-50  169.9  0.0  1  264  20  280  20  281  0.97  putative membrane protein kinase alpha";
+    const CMSEARCH: &str = "chr1  -  tRNA  RF00005  cm  1  71  1000  1071  +  no  1  0.55  0.0  60.2  1.1e-12  !  tRNA gene on the plus strand";
+    const CMSCAN: &str =
+        "tRNA  RF00005  chr2  -  cm  1  71  2071  2000  -  no  1  0.55  0.1  58.7  3.4e-12  ?  -";
+    const BLAST6: &str = "q1\tchr3\t98.50\t200\t3\t0\t1\t200\t5200\t5001\t2.5e-95\t350";
+    const MMSEQS: &str = "q1\tchr4 contig 4\t0.985\t200\t3\t0\t1\t200\t701\t900\t4.1e-60\t230";
+
+    /// Read `line` as the only hit of a tblout from `program`.
+    fn read(line: &str, program: &str) -> Tblout {
+        let text = format!(
+            "# target name\n{}\n#\n# Program:         {}\n",
+            line, program
+        );
+        Tblout::from_reader(text.as_bytes(), None, false).unwrap()
+    }
+
+    #[test]
+    fn sniffs_layouts_with_long_descriptions() {
+        assert_eq!(Format::sniff(NHMMER), Some(Format::Nhmmer));
+        assert_eq!(Format::sniff(HMMSEARCH), Some(Format::Hmmsearch));
+        assert_eq!(Format::sniff(DOMTBLOUT), Some(Format::Domtblout));
+        assert_eq!(Format::sniff(CMSEARCH), Some(Format::Cmsearch));
+    }
+
+    #[test]
+    fn reads_long_descriptions() {
+        for (line, program, format, description) in [
+            (
+                NHMMER,
+                "nhmmer",
+                Format::Nhmmer,
+                "L1 fragment on the minus strand",
+            ),
+            (
+                HMMSEARCH,
+                "hmmsearch",
+                Format::Hmmsearch,
+                "putative membrane protein kinase alpha",
+            ),
+            (
+                DOMTBLOUT,
+                "hmmsearch",
+                Format::Domtblout,
+                "putative membrane protein kinase alpha",
+            ),
+            (
+                CMSEARCH,
+                "cmsearch",
+                Format::Cmsearch,
+                "tRNA gene on the plus strand",
+            ),
+        ] {
+            let tblout = read(line, program);
+            assert_eq!(tblout.format(), format);
+            assert_eq!(
+                tblout.hits()[0].description.as_deref(),
+                Some(description),
+                "{}",
+                program
+            );
+        }
+    }
+
+    #[test]
+    fn sniffs_each_layout() {
+        assert_eq!(Format::sniff(CMSCAN), Some(Format::Cmsearch));
+        assert_eq!(Format::sniff(BLAST6), Some(Format::Blast6));
+        assert_eq!(Format::sniff("q1  chr1  not a hit"), None);
+    }
+
+    #[test]
+    fn reads_nhmmer() {
+        let hit = read(NHMMER, "nhmmer").into_hits().remove(0);
+        assert_eq!(hit.target_name, "chr1");
+        assert_eq!(hit.query_name, "LINE1");
+        assert_eq!((hit.hmm_from, hit.hmm_to), (Some(1), Some(300)));
+        assert_eq!(hit.ali(), Some((1200, 901)));
+        assert_eq!(hit.env(), Some((1210, 890)));
+        assert_eq!(hit.sq_len, Some(5000));
+        assert_eq!(hit.strand, Strand::Negative);
+        assert_eq!((hit.e_value, hit.score, hit.bias), (1e-20, 50.0, 0.5));
+    }
+
+    #[test]
+    fn reads_hmmsearch() {
+        let hit = read(HMMSEARCH, "hmmsearch").into_hits().remove(0);
+        assert_eq!(hit.target_name, "sp|P12345|KIN_HUMAN");
+        assert_eq!(hit.query_name, "Pkinase");
+        assert_eq!(hit.query_accession, "PF00069.28");
+        assert_eq!(hit.ali(), None);
+        assert_eq!(hit.strand, Strand::Positive);
+        assert_eq!((hit.e_value, hit.score), (1.2e-50, 170.3));
+        assert_eq!(hit.included, Some(true));
+    }
+
+    #[test]
+    fn reads_domtblout() {
+        let hit = read(DOMTBLOUT, "hmmsearch").into_hits().remove(0);
+        assert_eq!(hit.query_name, "Pkinase");
+        assert_eq!((hit.sq_len, hit.query_len), (Some(350), Some(264)));
+        assert_eq!(hit.domain, Some(1));
+        assert_eq!(hit.ali(), Some((20, 280)));
+        assert_eq!(hit.env(), Some((20, 281)));
+        // the independent E-value of the domain
+        assert_eq!((hit.e_value, hit.score), (1.6e-50, 169.9));
+    }
+
+    #[test]
+    fn reads_cmsearch() {
+        let hit = read(CMSEARCH, "cmsearch").into_hits().remove(0);
+        assert_eq!(hit.target_name, "chr1");
+        assert_eq!(hit.query_name, "tRNA");
+        assert_eq!(hit.query_accession, "RF00005");
+        assert_eq!(hit.ali(), Some((1000, 1071)));
+        assert_eq!(hit.strand, Strand::Positive);
+        assert_eq!((hit.e_value, hit.score), (1.1e-12, 60.2));
+        assert_eq!(hit.included, Some(true));
+    }
+
+    #[test]
+    fn reads_cmscan() {
+        let tblout = read(CMSCAN, "cmscan");
+        assert_eq!(tblout.format(), Format::Cmscan);
+        let hit = &tblout.hits()[0];
+        // the sequence is the query of cmscan, but the target of the hit
+        assert_eq!(hit.target_name, "chr2");
+        assert_eq!(hit.query_name, "tRNA");
+        assert_eq!(hit.query_accession, "RF00005");
+        assert_eq!(hit.ali(), Some((2071, 2000)));
+        assert_eq!(hit.strand, Strand::Negative);
+        assert_eq!(hit.included, Some(false));
+        assert_eq!(hit.description, None);
+    }
+
+    #[test]
+    fn reads_blast6() {
+        let tblout = Tblout::from_reader(BLAST6.as_bytes(), None, false).unwrap();
+        assert_eq!(tblout.format(), Format::Blast6);
+        let hit = &tblout.hits()[0];
+        assert_eq!(hit.target_name, "chr3");
+        assert_eq!(hit.query_name, "q1");
+        assert_eq!((hit.hmm_from, hit.hmm_to), (Some(1), Some(200)));
+        assert_eq!(hit.ali(), Some((5200, 5001)));
+        assert_eq!(hit.strand, Strand::Negative);
+        assert_eq!((hit.e_value, hit.score), (2.5e-95, 350.0));
+    }
+
+    #[test]
+    fn reads_mmseqs() {
+        let format = Format::Mmseqs(Columns::default());
+        let tblout = Tblout::from_reader(MMSEQS.as_bytes(), Some(format), false).unwrap();
+        let hit = &tblout.hits()[0];
+        // the columns are split on tabs only
+        assert_eq!(hit.target_name, "chr4 contig 4");
+        assert_eq!(hit.query_name, "q1");
+        assert_eq!(hit.ali(), Some((701, 900)));
+        assert_eq!(hit.strand, Strand::Positive);
+        assert_eq!((hit.e_value, hit.score), (4.1e-60, 230.0));
+    }
+
+    #[test]
+    fn lenient_skips_bad_lines() {
+        let text = format!("{}\nchr1  -  LINE1  -  1  300  oops\n{}\n", NHMMER, NHMMER);
+        assert!(Tblout::from_reader(text.as_bytes(), Some(Format::Nhmmer), false).is_err());
+
+        let tblout = Tblout::from_reader(text.as_bytes(), Some(Format::Nhmmer), true).unwrap();
+        assert_eq!(tblout.hits().len(), 2);
+        assert_eq!(tblout.skipped(), 1);
+    }
+}
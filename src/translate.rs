@@ -0,0 +1,162 @@
+//! Translating extracted nucleotide sequences into protein.
+
+use anyhow::{bail, Result};
+
+/// The NCBI genetic code tables that are supported, with the amino acid of
+/// each codon in `TTT, TTC, TTA, TTG, TCT, ..., GGG` order.
+const TABLES: &[(u8, &str, &[u8; 64])] = &[
+    (
+        1,
+        "Standard",
+        b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        2,
+        "Vertebrate mitochondrial",
+        b"FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNKKSS**VVVVAAAADDEEGGGG",
+    ),
+    (
+        3,
+        "Yeast mitochondrial",
+        b"FFLLSSSSYY**CCWWTTTTPPPPHHQQRRRRIIMMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        4,
+        "Mold, protozoan and coelenterate mitochondrial; Mycoplasma",
+        b"FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        5,
+        "Invertebrate mitochondrial",
+        b"FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNKKSSSSVVVVAAAADDEEGGGG",
+    ),
+    (
+        6,
+        "Ciliate, dasycladacean and Hexamita nuclear",
+        b"FFLLSSSSYYQQCC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        9,
+        "Echinoderm and flatworm mitochondrial",
+        b"FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIIMTTTTNNNKSSSSVVVVAAAADDEEGGGG",
+    ),
+    (
+        10,
+        "Euplotid nuclear",
+        b"FFLLSSSSYY**CCCWLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        11,
+        "Bacterial, archaeal and plant plastid",
+        b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        12,
+        "Alternative yeast nuclear",
+        b"FFLLSSSSYY**CC*WLLLSPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        13,
+        "Ascidian mitochondrial",
+        b"FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNKKSSGGVVVVAAAADDEEGGGG",
+    ),
+    (
+        14,
+        "Alternative flatworm mitochondrial",
+        b"FFLLSSSSYYY*CCWWLLLLPPPPHHQQRRRRIIIMTTTTNNNKSSSSVVVVAAAADDEEGGGG",
+    ),
+    (
+        16,
+        "Chlorophycean mitochondrial",
+        b"FFLLSSSSYY*LCC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        21,
+        "Trematode mitochondrial",
+        b"FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNNKSSSSVVVVAAAADDEEGGGG",
+    ),
+    (
+        22,
+        "Scenedesmus obliquus mitochondrial",
+        b"FFLLSS*SYY*LCC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+    (
+        23,
+        "Thraustochytrium mitochondrial",
+        b"FF*LSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG",
+    ),
+];
+
+/// A genetic code, by its NCBI table number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeneticCode {
+    id: u8,
+    amino_acids: &'static [u8; 64],
+}
+
+impl Default for GeneticCode {
+    fn default() -> Self {
+        Self::new(1).expect("the standard code is supported")
+    }
+}
+
+impl GeneticCode {
+    /// The code with NCBI table number `id`.
+    pub fn new(id: u8) -> Result<Self> {
+        match TABLES.iter().find(|(table, _, _)| *table == id) {
+            Some(&(id, _, amino_acids)) => Ok(Self { id, amino_acids }),
+            None => {
+                let supported: Vec<String> =
+                    Self::supported().map(|(id, _)| id.to_string()).collect();
+                bail!(
+                    "Unknown genetic code {} (supported: {})",
+                    id,
+                    supported.join(", ")
+                )
+            }
+        }
+    }
+
+    /// The numbers and names of the supported codes.
+    pub fn supported() -> impl Iterator<Item = (u8, &'static str)> {
+        TABLES.iter().map(|&(id, name, _)| (id, name))
+    }
+
+    /// The NCBI table number of the code.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Translate `seq` from its first base, dropping any incomplete codon at
+    /// the end. Stops are `*`, and codons with anything but ACGTU are `X`.
+    pub fn translate(&self, seq: &[u8]) -> Vec<u8> {
+        seq.chunks_exact(3)
+            .map(|codon| self.amino_acid(codon))
+            .collect()
+    }
+
+    /// The amino acid `codon` codes for, `*` for a stop and `X` if it has
+    /// anything but ACGTU.
+    pub fn amino_acid(&self, codon: &[u8]) -> u8 {
+        let mut i = 0;
+        for &base in &codon[..3] {
+            i = i * 4
+                + match base.to_ascii_uppercase() {
+                    b'T' | b'U' => 0,
+                    b'C' => 1,
+                    b'A' => 2,
+                    b'G' => 3,
+                    _ => return b'X',
+                };
+        }
+        self.amino_acids[i]
+    }
+}
+
+/// `protein` up to its first stop.
+pub fn trim_to_stop(protein: &[u8]) -> &[u8] {
+    match protein.iter().position(|&aa| aa == b'*') {
+        Some(i) => &protein[..i],
+        None => protein,
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use fasta::record::Definition;
+use memmap2::Mmap;
+use noodles_fasta as fasta;
+
+use crate::{
+    fetcher::{Region, SequenceFetcher},
+    native::reverse_complement,
+};
+
+/// The signature at the start of a 2bit file, in its byte order.
+const SIGNATURE: u32 = 0x1A41_2743;
+
+/// The bases of each two bit code, four to a byte, first base highest.
+const BASES: [u8; 4] = [b'T', b'C', b'A', b'G'];
+
+/// Fetches regions straight out of a UCSC `.2bit` genome, without
+/// converting it to fasta. The file is mapped into memory, and only the
+/// bytes of each region are decoded, with its runs of `N` and soft-masked
+/// (lowercase) stretches.
+#[derive(Clone)]
+pub struct TwoBitFetcher {
+    path: PathBuf,
+    data: Arc<Mmap>,
+    big_endian: bool,
+    /// Where the record of each sequence starts.
+    offsets: HashMap<String, usize>,
+    /// The layout of each sequence fetched from so far.
+    layouts: HashMap<String, Arc<Layout>>,
+}
+
+/// The layout of one sequence in a 2bit file.
+struct Layout {
+    length: u64,
+    /// The runs of `N` and the soft-masked stretches, as 0-based starts
+    /// and lengths.
+    n_blocks: Vec<(u64, u64)>,
+    mask_blocks: Vec<(u64, u64)>,
+    /// Where its packed bases start.
+    dna: usize,
+}
+
+/// Whether the file at `path` is a 2bit genome, going by its signature.
+pub fn is_twobit(path: &Path) -> Result<bool> {
+    let file = File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let mut magic = Vec::new();
+    file.take(4).read_to_end(&mut magic)?;
+    let Ok(magic) = <[u8; 4]>::try_from(magic) else {
+        return Ok(false);
+    };
+    // in either byte order
+    let magic = u32::from_le_bytes(magic);
+    Ok(magic == SIGNATURE || magic == SIGNATURE.swap_bytes())
+}
+
+impl TwoBitFetcher {
+    /// Map the 2bit file at `path` and read the names of its sequences.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Could not open 2bit {}", path.display()))?;
+        // SAFETY: the genome is only read, and is not expected to change
+        // while the hits are extracted
+        let data = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("Could not read 2bit {}", path.display()))?;
+        let mut fetcher = Self {
+            path: path.to_path_buf(),
+            data: Arc::new(data),
+            big_endian: false,
+            offsets: HashMap::new(),
+            layouts: HashMap::new(),
+        };
+        fetcher
+            .read_index()
+            .with_context(|| format!("Could not read 2bit {}", path.display()))?;
+        Ok(fetcher)
+    }
+
+    /// Read the header and the index of sequence names after it.
+    fn read_index(&mut self) -> Result<()> {
+        let signature = self.u32(0)?;
+        self.big_endian = signature != SIGNATURE;
+        ensure!(
+            self.u32(0)? == SIGNATURE,
+            "Not a 2bit file (signature {:#x})",
+            signature
+        );
+        // version 1 has 64 bit offsets for genomes over 4 GB
+        let wide = match self.u32(4)? {
+            0 => false,
+            1 => true,
+            version => bail!("Unknown 2bit version {}", version),
+        };
+        let count = self.u32(8)?;
+
+        let mut pos = 16;
+        for _ in 0..count {
+            let len = *self.data.get(pos).context("The index is cut short")? as usize;
+            let name = self
+                .data
+                .get(pos + 1..pos + 1 + len)
+                .context("The index is cut short")?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            pos += 1 + len;
+            let offset = match wide {
+                true => self.u64(pos)?,
+                false => self.u32(pos)? as u64,
+            };
+            pos += if wide { 8 } else { 4 };
+            let offset = usize::try_from(offset).context("The genome is too large")?;
+            if self.offsets.insert(name.clone(), offset).is_some() {
+                bail!("Sequence {} is in it more than once", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// The layout of the sequence `name`, read once and then kept.
+    fn layout(&mut self, name: &str) -> Result<Arc<Layout>> {
+        if let Some(layout) = self.layouts.get(name) {
+            return Ok(layout.clone());
+        }
+        let &offset = self
+            .offsets
+            .get(name)
+            .with_context(|| format!("Sequence {} not found in 2bit", name))?;
+        let length = self.u32(offset)? as u64;
+        let mut pos = offset + 4;
+        let n_blocks = self.blocks(&mut pos)?;
+        let mask_blocks = self.blocks(&mut pos)?;
+        // and a reserved word
+        let layout = Arc::new(Layout {
+            length,
+            n_blocks,
+            mask_blocks,
+            dna: pos + 4,
+        });
+        self.layouts.insert(name.to_string(), layout.clone());
+        Ok(layout)
+    }
+
+    /// Read a count of blocks, their starts and then their sizes from
+    /// `pos` on, moving it past them.
+    fn blocks(&self, pos: &mut usize) -> Result<Vec<(u64, u64)>> {
+        let count = self.u32(*pos)? as usize;
+        let starts = *pos + 4;
+        let sizes = starts + 4 * count;
+        *pos = sizes + 4 * count;
+        (0..count)
+            .map(|i| {
+                Ok((
+                    self.u32(starts + 4 * i)? as u64,
+                    self.u32(sizes + 4 * i)? as u64,
+                ))
+            })
+            .collect()
+    }
+
+    /// Bases `lo..=hi` (1-based) of the sequence `name`, forwards.
+    fn bases(&mut self, name: &str, lo: u64, hi: u64) -> Result<Vec<u8>> {
+        let layout = self.layout(name)?;
+        if lo < 1 || hi > layout.length || lo > hi {
+            bail!(
+                "Coordinates {}..{} are out of range for {} (length {})",
+                lo,
+                hi,
+                name,
+                layout.length
+            );
+        }
+        let (start, end) = (lo - 1, hi);
+        let packed = self
+            .data
+            .get(layout.dna + (start / 4) as usize..layout.dna + end.div_ceil(4) as usize)
+            .context("The 2bit file is shorter than its index")?;
+        let mut bases: Vec<u8> = (start..end)
+            .map(|i| {
+                let byte = packed[(i / 4 - start / 4) as usize];
+                BASES[(byte >> (6 - 2 * (i % 4))) as usize & 3]
+            })
+            .collect();
+
+        let overlaps = |blocks: &[(u64, u64)]| {
+            blocks
+                .iter()
+                .map(|&(from, len)| (from.max(start), (from + len).min(end)))
+                .filter(|&(from, to)| from < to)
+                .map(|(from, to)| ((from - start) as usize, (to - start) as usize))
+                .collect::<Vec<_>>()
+        };
+        for (from, to) in overlaps(&layout.n_blocks) {
+            bases[from..to].fill(b'N');
+        }
+        for (from, to) in overlaps(&layout.mask_blocks) {
+            bases[from..to].make_ascii_lowercase();
+        }
+        Ok(bases)
+    }
+
+    fn u32(&self, pos: usize) -> Result<u32> {
+        let bytes: [u8; 4] = self
+            .data
+            .get(pos..pos + 4)
+            .context("The 2bit file is cut short")?
+            .try_into()
+            .expect("four bytes");
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn u64(&self, pos: usize) -> Result<u64> {
+        let bytes: [u8; 8] = self
+            .data
+            .get(pos..pos + 8)
+            .context("The 2bit file is cut short")?
+            .try_into()
+            .expect("eight bytes");
+        Ok(match self.big_endian {
+            true => u64::from_be_bytes(bytes),
+            false => u64::from_le_bytes(bytes),
+        })
+    }
+}
+
+impl SequenceFetcher for TwoBitFetcher {
+    fn fetch(&mut self, (name, coords): Region<'_>) -> Result<fasta::Record> {
+        let sequence = match coords {
+            Some((from, to)) if from > to => reverse_complement(&self.bases(name, to, from)?),
+            Some((from, to)) => self.bases(name, from, to)?.into(),
+            None => match self.layout(name)?.length {
+                0 => Vec::new().into(),
+                length => self.bases(name, 1, length)?.into(),
+            },
+        };
+        // 2bit keeps no descriptions
+        Ok(fasta::Record::new(Definition::new(name, None), sequence))
+    }
+
+    fn lengths(&self) -> Result<HashMap<String, u64>> {
+        self.offsets
+            .iter()
+            .map(|(name, &offset)| Ok((name.clone(), self.u32(offset)? as u64)))
+            .collect::<Result<_>>()
+            .with_context(|| format!("Could not read 2bit {}", self.path.display()))
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn SequenceFetcher>> {
+        Ok(Box::new(self.clone()))
+    }
+}
@@ -0,0 +1,117 @@
+//! A working directory the prepared fasta and its index are kept in, so
+//! later runs against the same genome can skip preparing and indexing it.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::prepare::Duplicates;
+
+/// The file in each genome's directory recording what was prepared there.
+const STAMP: &str = "prepared.json";
+
+/// What was prepared in a genome's directory, and from what.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Stamp {
+    /// The fasta it was prepared from.
+    source: PathBuf,
+    /// Its size and modification time (in nanoseconds since the epoch),
+    /// to tell whether it has changed since.
+    len: u64,
+    modified: u128,
+    /// The file name of the prepared fasta.
+    fasta: PathBuf,
+    /// The names it had more than once, and what their copies were renamed.
+    #[serde(default)]
+    duplicates: Duplicates,
+}
+
+impl Stamp {
+    fn new(source: &Path, fasta: &Path) -> Result<Self> {
+        let source = source
+            .canonicalize()
+            .with_context(|| format!("Could not find {}", source.display()))?;
+        let metadata = fs::metadata(&source)
+            .with_context(|| format!("Could not read {}", source.display()))?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        Ok(Self {
+            source,
+            len: metadata.len(),
+            modified,
+            fasta: fasta.file_name().context("Could not get file name")?.into(),
+            duplicates: Duplicates::new(),
+        })
+    }
+}
+
+/// The directory in `workdir` for the fasta at `source`, named after it
+/// and told apart from others of the same name by a hash of its path.
+pub(crate) fn genome_dir(workdir: &Path, source: &Path) -> Result<PathBuf> {
+    let path = source
+        .canonicalize()
+        .with_context(|| format!("Could not find {}", source.display()))?;
+    let name = path
+        .file_name()
+        .context("Could not get file name")?
+        .to_string_lossy();
+    // FNV-1a, which unlike the std hasher does not change between releases
+    let hash = path
+        .as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325u64, |h, &b| {
+            (h ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+    Ok(workdir.join(format!("{}-{:016x}", name, hash)))
+}
+
+/// The fasta prepared from `source` in `dir` by an earlier run, with its
+/// duplicated names, if it is there and `source` has not changed since.
+pub(crate) fn prepared(dir: &Path, source: &Path) -> Result<Option<(PathBuf, Duplicates)>> {
+    let Ok(contents) = fs::read_to_string(dir.join(STAMP)) else {
+        return Ok(None);
+    };
+    let Ok(stamp) = serde_json::from_str::<Stamp>(&contents) else {
+        return Ok(None);
+    };
+    let fasta = dir.join(&stamp.fasta);
+    let current = Stamp {
+        duplicates: stamp.duplicates.clone(),
+        ..Stamp::new(source, &fasta)?
+    };
+    Ok((current == stamp && fasta.exists()).then_some((fasta, stamp.duplicates)))
+}
+
+/// Empty `dir` of anything prepared in it before, to prepare the fasta
+/// again.
+pub(crate) fn clear(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir).with_context(|| format!("Could not clear {}", dir.display()))?;
+    }
+    fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))
+}
+
+/// Record that `fasta` in `dir` was prepared from `source`, with the
+/// `duplicates` renamed in it, once it and its index are complete.
+pub(crate) fn record(
+    dir: &Path,
+    source: &Path,
+    fasta: &Path,
+    duplicates: &Duplicates,
+) -> Result<()> {
+    let stamp = Stamp {
+        duplicates: duplicates.clone(),
+        ..Stamp::new(source, fasta)?
+    };
+    let stamp = serde_json::to_string(&stamp)?;
+    let path = dir.join(STAMP);
+    fs::write(&path, stamp).with_context(|| format!("Could not write {}", path.display()))
+}